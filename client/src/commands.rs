@@ -1,4 +1,13 @@
-use nxusb::prefixes::{CommandPrefix, ReadPrefix, WritePrefix};
+use nxusb::checksum::{self, Checksum, ChecksumKind};
+use nxusb::delta::{self, Decoded, DeltaDecoder, DeltaEncoder, DeltaOp, DeltaPlanner, Signature, SignatureBuilder, DELTA_HEADER_LENGTH};
+use nxusb::frame::{AbortPolicy, Frame, FrameKind, MAX_FRAME_PAYLOAD};
+use nxusb::fsinfo::FsInfo;
+use nxusb::handshake::{self, PROTOCOL_VERSION, SUPPORTED_FEATURES};
+use nxusb::hashing::{self, HashRecord};
+use nxusb::listing::{EntryKind, ListEntry, ListPage};
+use nxusb::prefixes::{self, CommandPrefix, FsInfoPrefix, HandshakePrefix, HashPrefix, ListPrefix, MakeDirPrefix, MovePrefix, PrefixFlags, ProbePrefix, ReadDeltaPrefix, ReadPrefix, RemovePrefix, SignaturePrefix, StatPrefix, WritePrefix, READ_HEADER_LENGTH};
+use nxusb::removal::{self, RemovalRecord};
+use nxusb::response::{Response, ResponseCode};
 
 macro_rules! dprintln {
     () => ({
@@ -15,11 +24,920 @@ pub trait ClientCommandState<T: CommandPrefix> {
 
     fn needs_push(&self) -> bool;
 
-    fn push_block(&mut self, block: &mut [u8]) -> Result<usize, String>;
+    /// Gets the next frame to send to the server, with a payload of at most
+    /// `max_payload` bytes.
+    fn push_frame(&mut self, max_payload: usize) -> Result<Frame, String>;
 
     fn needs_pull(&self) -> bool;
 
-    fn pull_block(&mut self, buffer: &[u8]) -> Result<usize, String>;
+    /// Passes a frame from the server to the command.
+    fn pull_frame(&mut self, frame: Frame) -> Result<(), String>;
+
+    /// Stops the command once the server has acknowledged an abort, deleting
+    /// or keeping anything it stored locally as the policy says.
+    fn abort(&mut self, _policy: AbortPolicy) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Takes the next frame of the bytes a command sends after its prefix, such
+/// as its file name, moving `push_idx` past them.
+fn push_name_frame(bytes: &[u8], push_idx: &mut usize, max_payload: usize) -> Frame {
+    let end = (*push_idx + max_payload).min(bytes.len());
+    let payload = bytes[*push_idx..end].to_vec();
+    *push_idx = end;
+    Frame::data(payload)
+}
+
+/// Builds the handshake this client opens a connection with.
+pub fn client_handshake() -> HandshakePrefix {
+    HandshakePrefix {
+        protocol_version: PROTOCOL_VERSION,
+        build_version: handshake::parse_build_version(
+            env!("CARGO_PKG_VERSION_MAJOR"),
+            env!("CARGO_PKG_VERSION_MINOR"),
+            env!("CARGO_PKG_VERSION_PATCH"),
+        ),
+        max_frame_payload: MAX_FRAME_PAYLOAD as u16,
+        features: SUPPORTED_FEATURES,
+    }
+}
+
+#[derive(Debug)]
+pub struct HandshakeState {
+    pub prefix: HandshakePrefix,
+    pub server: Option<HandshakePrefix>,
+}
+
+impl HandshakeState {
+    pub fn new_handshake(prefix: HandshakePrefix) -> Self {
+        HandshakeState {
+            prefix,
+            server: None,
+        }
+    }
+}
+
+impl ClientCommandState<HandshakePrefix> for HandshakeState {
+    fn prefix(&self) -> HandshakePrefix {
+        self.prefix
+    }
+
+    fn needs_push(&self) -> bool {
+        false
+    }
+
+    fn push_frame(&mut self, _max_payload: usize) -> Result<Frame, String> {
+        Err("Handshake has nothing to push after its prefix.".to_owned())
+    }
+
+    fn needs_pull(&self) -> bool {
+        self.server.is_none()
+    }
+
+    fn pull_frame(&mut self, frame: Frame) -> Result<(), String> {
+        let server: HandshakePrefix = frame.parse_prefix().map_err(|e| format!(
+            "Server did not answer with a handshake ({}); it is probably older than this client and needs to be updated.",
+            e
+        ))?;
+        handshake::check_compatible(&self.prefix, &server)?;
+        self.server = Some(server);
+        Ok(())
+    }
+}
+
+/// Builds the bytes that start a command's data: the file name, then the
+/// offset if the flags have `OFFSET`, then the byte count if they have
+/// `LIMIT`.
+fn name_and_range(name: &str, flags: PrefixFlags, offset: u64, limit: u64) -> Vec<u8> {
+    let mut bytes = name.as_bytes().to_vec();
+    if flags.contains(PrefixFlags::OFFSET) {
+        bytes.extend_from_slice(&prefixes::extract_bytes_u64(offset));
+    }
+    if flags.contains(PrefixFlags::LIMIT) {
+        bytes.extend_from_slice(&prefixes::extract_bytes_u64(limit));
+    }
+    bytes
+}
+
+/// Hashes the first `length` bytes of a local file.
+pub fn digest_start<FileType: FileRetriever>(file: &mut FileType, length: u64, kind: ChecksumKind) -> Result<Vec<u8>, String> {
+    let mut check = Checksum::new(kind);
+    let mut remaining = length;
+    let mut buffer = vec![0u8; 4096];
+    while remaining > 0 {
+        let want = remaining.min(buffer.len() as u64) as usize;
+        let read = file.read_bytes(&mut buffer[0..want])?;
+        if read == 0 {
+            return Err(format!("File {} ended before {} bytes could be hashed.", file.name(), length));
+        }
+        check.update(&buffer[0..read]);
+        remaining -= read as u64;
+    }
+    Ok(check.finish())
+}
+
+/// The most a delta reads from a local file at a time.
+const DELTA_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Works out the signature of a local file in blocks of `block_size`, reading
+/// it from the start.
+pub fn signature_of<FileType: FileRetriever>(file: &mut FileType, block_size: u32, kind: ChecksumKind) -> Result<Signature, String> {
+    file.seek(0)?;
+    let mut builder = SignatureBuilder::new(block_size, kind);
+    let mut buffer = vec![0u8; DELTA_BUFFER_SIZE];
+    loop {
+        let read = file.read_bytes(&mut buffer)?;
+        if read == 0 {
+            return Ok(builder.finish());
+        }
+        builder.input(&buffer[0..read]);
+    }
+}
+
+/// Works out the instructions that rebuild a local file from the old copy
+/// with the given signature, and the digest of the whole local file, reading
+/// it from the start.
+pub fn plan_delta<FileType: FileRetriever>(file: &mut FileType, signature: &Signature) -> Result<(Vec<DeltaOp>, Vec<u8>), String> {
+    file.seek(0)?;
+    let mut planner = DeltaPlanner::new(signature.clone());
+    let mut check = Checksum::new(signature.kind);
+    let mut buffer = vec![0u8; DELTA_BUFFER_SIZE];
+    loop {
+        let read = file.read_bytes(&mut buffer)?;
+        if read == 0 {
+            return Ok((planner.finish(), check.finish()));
+        }
+        planner.input(&buffer[0..read]);
+        check.update(&buffer[0..read]);
+    }
+}
+
+/// Asks the server how long a file is and for the checksum of its start, so
+/// that a transfer can pick up where an earlier one stopped.
+#[derive(Debug)]
+pub struct ProbeState {
+    pub prefix: ProbePrefix,
+    pub file_name: String,
+    push_idx: usize,
+    pub file_size: Option<u64>,
+    pub response: Option<Response>,
+}
+
+impl ProbeState {
+    pub fn new_probe(prefix: ProbePrefix, file_name: &str) -> Result<Self, String> {
+        if prefix.file_name_length != file_name.len() as u16 {
+            return Err(format!("Could not verify prefix matches this file: got name {:?} which doesn't have length {}", file_name, prefix.file_name_length));
+        }
+        Ok(ProbeState {
+            prefix,
+            file_name: file_name.to_owned(),
+            push_idx: 0,
+            file_size: None,
+            response: None,
+        })
+    }
+}
+
+impl ClientCommandState<ProbePrefix> for ProbeState {
+    fn prefix(&self) -> ProbePrefix {
+        self.prefix
+    }
+
+    fn needs_push(&self) -> bool {
+        self.push_idx < self.file_name.len()
+    }
+
+    fn push_frame(&mut self, max_payload: usize) -> Result<Frame, String> {
+        Ok(push_name_frame(self.file_name.as_bytes(), &mut self.push_idx, max_payload))
+    }
+
+    fn needs_pull(&self) -> bool {
+        !self.needs_push() && self.response.is_none()
+    }
+
+    fn pull_frame(&mut self, frame: Frame) -> Result<(), String> {
+        match frame.kind {
+            FrameKind::Data if frame.payload.len() == READ_HEADER_LENGTH && self.file_size.is_none() => {
+                self.file_size = Some(prefixes::combine_bytes_u64(&frame.payload));
+                Ok(())
+            }
+            FrameKind::Response => {
+                let response = frame.parse_response()?;
+                dprintln!("Server probed {}: {}", self.file_name, response);
+                if response.is_ok() && self.file_size.is_none() {
+                    return Err(format!("Server probed {} without sending its length.", self.file_name));
+                }
+                self.response = Some(response);
+                Ok(())
+            }
+            kind => Err(format!(
+                "Expected a file length or a response but got a {:?} frame with {} bytes.",
+                kind,
+                frame.payload.len()
+            )),
+        }
+    }
+}
+
+/// Asks the server for one page of the entries in a directory.
+#[derive(Debug)]
+pub struct ListState {
+    pub prefix: ListPrefix,
+    pub dir_name: String,
+    push_idx: usize,
+    page_bytes: Vec<u8>,
+    checksum: Option<Checksum>,
+    pub page: Option<ListPage>,
+    pub response: Option<Response>,
+}
+
+impl ListState {
+    pub fn new_list(prefix: ListPrefix, dir_name: &str) -> Result<Self, String> {
+        if prefix.file_name_length != dir_name.len() as u16 {
+            return Err(format!("Could not verify prefix matches this directory: got name {:?} which doesn't have length {}", dir_name, prefix.file_name_length));
+        }
+        let kind = prefix.flags.checksum().ok_or(format!("Unknown checksum kind in flags {:#06x}.", prefix.flags.bits()))?;
+        Ok(ListState {
+            prefix,
+            dir_name: dir_name.to_owned(),
+            push_idx: 0,
+            page_bytes: Vec::new(),
+            checksum: Some(Checksum::new(kind)),
+            page: None,
+            response: None,
+        })
+    }
+
+    /// Takes the page out of a finished listing, or the server's error if
+    /// the directory could not be listed.
+    pub fn into_page(self) -> Result<ListPage, String> {
+        match (self.response, self.page) {
+            (Some(response), Some(page)) => response.into_result().map(|_| page),
+            (Some(response), None) => response.into_result().and(Err(format!("Server listed {} without sending any entries.", self.dir_name))),
+            (None, _) => Err(format!("Listing of {} finished without a response.", self.dir_name)),
+        }
+    }
+}
+
+impl ClientCommandState<ListPrefix> for ListState {
+    fn prefix(&self) -> ListPrefix {
+        self.prefix
+    }
+
+    fn needs_push(&self) -> bool {
+        self.push_idx < self.dir_name.len()
+    }
+
+    fn push_frame(&mut self, max_payload: usize) -> Result<Frame, String> {
+        Ok(push_name_frame(self.dir_name.as_bytes(), &mut self.push_idx, max_payload))
+    }
+
+    fn needs_pull(&self) -> bool {
+        !self.needs_push() && self.response.is_none()
+    }
+
+    fn pull_frame(&mut self, frame: Frame) -> Result<(), String> {
+        match frame.kind {
+            FrameKind::Data => {
+                if let Some(ck) = &mut self.checksum {
+                    ck.update(&frame.payload);
+                }
+                self.page_bytes.extend_from_slice(&frame.payload);
+                Ok(())
+            }
+            FrameKind::Response => {
+                let response = frame.parse_response()?;
+                dprintln!("Server listed {}: {}", self.dir_name, response);
+                let kind = self.checksum.as_ref().map(|ck| ck.kind()).unwrap_or(ChecksumKind::None);
+                let digest = checksum::take_digest(&mut self.checksum);
+                if response.is_ok() {
+                    if kind != ChecksumKind::None && digest != response.checksum {
+                        return Err(format!(
+                            "Checksum mismatch for the listing of {}: received {} but the server sent {}.",
+                            self.dir_name,
+                            checksum::to_hex(&digest),
+                            checksum::to_hex(&response.checksum)
+                        ));
+                    }
+                    self.page = Some(ListPage::parse(&self.page_bytes)?);
+                }
+                self.response = Some(response);
+                Ok(())
+            }
+            kind => Err(format!(
+                "Expected directory entries or a response but got a {:?} frame.",
+                kind
+            )),
+        }
+    }
+}
+
+/// Asks the server for the block signature of a file, to work out a delta
+/// against it.
+#[derive(Debug)]
+pub struct SignatureState {
+    pub prefix: SignaturePrefix,
+    pub file_name: String,
+    push_idx: usize,
+    signature_bytes: Vec<u8>,
+    checksum: Option<Checksum>,
+    pub signature: Option<Signature>,
+    pub response: Option<Response>,
+}
+
+impl SignatureState {
+    pub fn new_signature(prefix: SignaturePrefix, file_name: &str) -> Result<Self, String> {
+        if prefix.file_name_length != file_name.len() as u16 {
+            return Err(format!("Could not verify prefix matches this file: got name {:?} which doesn't have length {}", file_name, prefix.file_name_length));
+        }
+        let kind = prefix.flags.checksum().ok_or(format!("Unknown checksum kind in flags {:#06x}.", prefix.flags.bits()))?;
+        if kind == ChecksumKind::None {
+            return Err(format!("Cannot ask for the signature of {} without a checksum kind.", file_name));
+        }
+        Ok(SignatureState {
+            prefix,
+            file_name: file_name.to_owned(),
+            push_idx: 0,
+            signature_bytes: Vec::new(),
+            checksum: Some(Checksum::new(kind)),
+            signature: None,
+            response: None,
+        })
+    }
+
+    /// Takes the signature out of a finished command, or `None` if there is
+    /// no file at the path.
+    pub fn into_signature(self) -> Result<Option<Signature>, String> {
+        match (self.response, self.signature) {
+            (Some(ref response), _) if response.code == ResponseCode::NotFound => Ok(None),
+            (Some(response), Some(signature)) => response.into_result().map(|_| Some(signature)),
+            (Some(response), None) => response.into_result().and(Err(format!("Server answered for {} without sending a signature.", self.file_name))),
+            (None, _) => Err(format!("Signature of {} finished without a response.", self.file_name)),
+        }
+    }
+}
+
+impl ClientCommandState<SignaturePrefix> for SignatureState {
+    fn prefix(&self) -> SignaturePrefix {
+        self.prefix
+    }
+
+    fn needs_push(&self) -> bool {
+        self.push_idx < self.file_name.len()
+    }
+
+    fn push_frame(&mut self, max_payload: usize) -> Result<Frame, String> {
+        Ok(push_name_frame(self.file_name.as_bytes(), &mut self.push_idx, max_payload))
+    }
+
+    fn needs_pull(&self) -> bool {
+        !self.needs_push() && self.response.is_none()
+    }
+
+    fn pull_frame(&mut self, frame: Frame) -> Result<(), String> {
+        match frame.kind {
+            FrameKind::Data => {
+                if let Some(ck) = &mut self.checksum {
+                    ck.update(&frame.payload);
+                }
+                self.signature_bytes.extend_from_slice(&frame.payload);
+                Ok(())
+            }
+            FrameKind::Response => {
+                let response = frame.parse_response()?;
+                dprintln!("Server sent the signature of {}: {}", self.file_name, response);
+                let kind = self.checksum.as_ref().map(|ck| ck.kind()).unwrap_or(ChecksumKind::None);
+                let digest = checksum::take_digest(&mut self.checksum);
+                if response.is_ok() {
+                    if digest != response.checksum {
+                        return Err(format!(
+                            "Checksum mismatch for the signature of {}: received {} but the server sent {}.",
+                            self.file_name,
+                            checksum::to_hex(&digest),
+                            checksum::to_hex(&response.checksum)
+                        ));
+                    }
+                    self.signature = Some(Signature::parse(&self.signature_bytes, kind)?);
+                }
+                self.response = Some(response);
+                Ok(())
+            }
+            kind => Err(format!(
+                "Expected signature blocks or a response but got a {:?} frame.",
+                kind
+            )),
+        }
+    }
+}
+
+/// Asks the server to describe a single path.
+#[derive(Debug)]
+pub struct StatState {
+    pub prefix: StatPrefix,
+    pub file_name: String,
+    push_idx: usize,
+    pub entry: Option<ListEntry>,
+    pub response: Option<Response>,
+}
+
+impl StatState {
+    pub fn new_stat(prefix: StatPrefix, file_name: &str) -> Result<Self, String> {
+        if prefix.file_name_length != file_name.len() as u16 {
+            return Err(format!("Could not verify prefix matches this file: got name {:?} which doesn't have length {}", file_name, prefix.file_name_length));
+        }
+        Ok(StatState {
+            prefix,
+            file_name: file_name.to_owned(),
+            push_idx: 0,
+            entry: None,
+            response: None,
+        })
+    }
+
+    /// Takes the entry out of a finished stat, or `None` if nothing is at the
+    /// path.
+    pub fn into_entry(self) -> Result<Option<ListEntry>, String> {
+        match (self.response, self.entry) {
+            (Some(ref response), _) if response.code == ResponseCode::NotFound => Ok(None),
+            (Some(response), Some(entry)) => response.into_result().map(|_| Some(entry)),
+            (Some(response), None) => response.into_result().and(Err(format!("Server described {} without sending an entry.", self.file_name))),
+            (None, _) => Err(format!("Stat of {} finished without a response.", self.file_name)),
+        }
+    }
+}
+
+impl ClientCommandState<StatPrefix> for StatState {
+    fn prefix(&self) -> StatPrefix {
+        self.prefix
+    }
+
+    fn needs_push(&self) -> bool {
+        self.push_idx < self.file_name.len()
+    }
+
+    fn push_frame(&mut self, max_payload: usize) -> Result<Frame, String> {
+        Ok(push_name_frame(self.file_name.as_bytes(), &mut self.push_idx, max_payload))
+    }
+
+    fn needs_pull(&self) -> bool {
+        !self.needs_push() && self.response.is_none()
+    }
+
+    fn pull_frame(&mut self, frame: Frame) -> Result<(), String> {
+        match frame.kind {
+            FrameKind::Data if self.entry.is_none() => {
+                let (entry, used) = ListEntry::parse(&frame.payload)?;
+                if used != frame.payload.len() {
+                    return Err(format!("Got {} bytes past the end of the entry for {}.", frame.payload.len() - used, self.file_name));
+                }
+                self.entry = Some(entry);
+                Ok(())
+            }
+            FrameKind::Response => {
+                let response = frame.parse_response()?;
+                dprintln!("Server described {}: {}", self.file_name, response);
+                self.response = Some(response);
+                Ok(())
+            }
+            kind => Err(format!(
+                "Expected an entry or a response but got a {:?} frame with {} bytes.",
+                kind,
+                frame.payload.len()
+            )),
+        }
+    }
+}
+
+/// Asks the server to describe the filesystem a path is on.
+#[derive(Debug)]
+pub struct FsInfoState {
+    pub prefix: FsInfoPrefix,
+    pub file_name: String,
+    push_idx: usize,
+    pub info: Option<FsInfo>,
+    pub response: Option<Response>,
+}
+
+impl FsInfoState {
+    pub fn new_fs_info(prefix: FsInfoPrefix, file_name: &str) -> Result<Self, String> {
+        if prefix.file_name_length != file_name.len() as u16 {
+            return Err(format!("Could not verify prefix matches this file: got name {:?} which doesn't have length {}", file_name, prefix.file_name_length));
+        }
+        Ok(FsInfoState {
+            prefix,
+            file_name: file_name.to_owned(),
+            push_idx: 0,
+            info: None,
+            response: None,
+        })
+    }
+
+    /// Takes the filesystem info out of a finished command.
+    pub fn into_info(self) -> Result<FsInfo, String> {
+        match (self.response, self.info) {
+            (Some(response), Some(info)) => response.into_result().map(|_| info),
+            (Some(response), None) => response.into_result().and(Err(format!("Server answered for {} without sending filesystem info.", self.file_name))),
+            (None, _) => Err(format!("Filesystem info for {} finished without a response.", self.file_name)),
+        }
+    }
+}
+
+impl ClientCommandState<FsInfoPrefix> for FsInfoState {
+    fn prefix(&self) -> FsInfoPrefix {
+        self.prefix
+    }
+
+    fn needs_push(&self) -> bool {
+        self.push_idx < self.file_name.len()
+    }
+
+    fn push_frame(&mut self, max_payload: usize) -> Result<Frame, String> {
+        Ok(push_name_frame(self.file_name.as_bytes(), &mut self.push_idx, max_payload))
+    }
+
+    fn needs_pull(&self) -> bool {
+        !self.needs_push() && self.response.is_none()
+    }
+
+    fn pull_frame(&mut self, frame: Frame) -> Result<(), String> {
+        match frame.kind {
+            FrameKind::Data if self.info.is_none() => {
+                self.info = Some(FsInfo::parse(&frame.payload)?);
+                Ok(())
+            }
+            FrameKind::Response => {
+                let response = frame.parse_response()?;
+                dprintln!("Server described the filesystem of {}: {}", self.file_name, response);
+                self.response = Some(response);
+                Ok(())
+            }
+            kind => Err(format!(
+                "Expected filesystem info or a response but got a {:?} frame with {} bytes.",
+                kind,
+                frame.payload.len()
+            )),
+        }
+    }
+}
+
+/// Asks the server to create a directory.
+#[derive(Debug)]
+pub struct MakeDirState {
+    pub prefix: MakeDirPrefix,
+    pub dir_name: String,
+    push_idx: usize,
+    pub response: Option<Response>,
+}
+
+impl MakeDirState {
+    pub fn new_make_dir(prefix: MakeDirPrefix, dir_name: &str) -> Result<Self, String> {
+        if prefix.file_name_length != dir_name.len() as u16 {
+            return Err(format!("Could not verify prefix matches this directory: got name {:?} which doesn't have length {}", dir_name, prefix.file_name_length));
+        }
+        Ok(MakeDirState {
+            prefix,
+            dir_name: dir_name.to_owned(),
+            push_idx: 0,
+            response: None,
+        })
+    }
+}
+
+impl ClientCommandState<MakeDirPrefix> for MakeDirState {
+    fn prefix(&self) -> MakeDirPrefix {
+        self.prefix
+    }
+
+    fn needs_push(&self) -> bool {
+        self.push_idx < self.dir_name.len()
+    }
+
+    fn push_frame(&mut self, max_payload: usize) -> Result<Frame, String> {
+        Ok(push_name_frame(self.dir_name.as_bytes(), &mut self.push_idx, max_payload))
+    }
+
+    fn needs_pull(&self) -> bool {
+        !self.needs_push() && self.response.is_none()
+    }
+
+    fn pull_frame(&mut self, frame: Frame) -> Result<(), String> {
+        match frame.kind {
+            FrameKind::Response => {
+                let response = frame.parse_response()?;
+                dprintln!("Server made directory {}: {}", self.dir_name, response);
+                self.response = Some(response);
+                Ok(())
+            }
+            kind => Err(format!("Expected a response but got a {:?} frame.", kind)),
+        }
+    }
+}
+
+/// Asks the server to move or rename a path.
+#[derive(Debug)]
+pub struct MoveState {
+    pub prefix: MovePrefix,
+    pub from: String,
+    pub to: String,
+    names: Vec<u8>,
+    push_idx: usize,
+    pub response: Option<Response>,
+}
+
+impl MoveState {
+    pub fn new_move(prefix: MovePrefix, from: &str, to: &str) -> Result<Self, String> {
+        if prefix.file_name_length != from.len() as u16 || prefix.dest_name_length != to.len() as u16 {
+            return Err(format!(
+                "Could not verify prefix matches these paths: got names {:?} and {:?} which don't have lengths {} and {}",
+                from, to, prefix.file_name_length, prefix.dest_name_length
+            ));
+        }
+        let mut names = from.as_bytes().to_vec();
+        names.extend_from_slice(to.as_bytes());
+        Ok(MoveState {
+            prefix,
+            from: from.to_owned(),
+            to: to.to_owned(),
+            names,
+            push_idx: 0,
+            response: None,
+        })
+    }
+}
+
+impl ClientCommandState<MovePrefix> for MoveState {
+    fn prefix(&self) -> MovePrefix {
+        self.prefix
+    }
+
+    fn needs_push(&self) -> bool {
+        self.push_idx < self.names.len()
+    }
+
+    fn push_frame(&mut self, max_payload: usize) -> Result<Frame, String> {
+        Ok(push_name_frame(&self.names, &mut self.push_idx, max_payload))
+    }
+
+    fn needs_pull(&self) -> bool {
+        !self.needs_push() && self.response.is_none()
+    }
+
+    fn pull_frame(&mut self, frame: Frame) -> Result<(), String> {
+        match frame.kind {
+            FrameKind::Response => {
+                let response = frame.parse_response()?;
+                dprintln!("Server moved {} to {}: {}", self.from, self.to, response);
+                self.response = Some(response);
+                Ok(())
+            }
+            kind => Err(format!("Expected a response but got a {:?} frame.", kind)),
+        }
+    }
+}
+
+/// Asks the server to delete a path, collecting its report of what happened
+/// to each entry.
+#[derive(Debug)]
+pub struct RemoveState {
+    pub prefix: RemovePrefix,
+    pub path: String,
+    push_idx: usize,
+    report_bytes: Vec<u8>,
+    checksum: Option<Checksum>,
+    pub records: Vec<RemovalRecord>,
+    pub response: Option<Response>,
+}
+
+impl RemoveState {
+    pub fn new_remove(prefix: RemovePrefix, path: &str) -> Result<Self, String> {
+        if prefix.file_name_length != path.len() as u16 {
+            return Err(format!("Could not verify prefix matches this path: got name {:?} which doesn't have length {}", path, prefix.file_name_length));
+        }
+        let kind = prefix.flags.checksum().ok_or(format!("Unknown checksum kind in flags {:#06x}.", prefix.flags.bits()))?;
+        Ok(RemoveState {
+            prefix,
+            path: path.to_owned(),
+            push_idx: 0,
+            report_bytes: Vec::new(),
+            checksum: Some(Checksum::new(kind)),
+            records: Vec::new(),
+            response: None,
+        })
+    }
+
+    /// Takes the records out of a finished remove. Fails only if the server
+    /// refused the command outright; entries that could not be deleted are
+    /// reported in their records instead.
+    pub fn into_records(self) -> Result<Vec<RemovalRecord>, String> {
+        match self.response {
+            Some(ref response) if !response.is_ok() && self.records.is_empty() => response.clone().into_result().map(|_| Vec::new()),
+            Some(_) => Ok(self.records),
+            None => Err(format!("Removing {} finished without a response.", self.path)),
+        }
+    }
+}
+
+impl ClientCommandState<RemovePrefix> for RemoveState {
+    fn prefix(&self) -> RemovePrefix {
+        self.prefix
+    }
+
+    fn needs_push(&self) -> bool {
+        self.push_idx < self.path.len()
+    }
+
+    fn push_frame(&mut self, max_payload: usize) -> Result<Frame, String> {
+        Ok(push_name_frame(self.path.as_bytes(), &mut self.push_idx, max_payload))
+    }
+
+    fn needs_pull(&self) -> bool {
+        !self.needs_push() && self.response.is_none()
+    }
+
+    fn pull_frame(&mut self, frame: Frame) -> Result<(), String> {
+        match frame.kind {
+            FrameKind::Data => {
+                if let Some(ck) = &mut self.checksum {
+                    ck.update(&frame.payload);
+                }
+                self.report_bytes.extend_from_slice(&frame.payload);
+                Ok(())
+            }
+            FrameKind::Response => {
+                let response = frame.parse_response()?;
+                dprintln!("Server removed {}: {}", self.path, response);
+                let kind = self.checksum.as_ref().map(|ck| ck.kind()).unwrap_or(ChecksumKind::None);
+                let digest = checksum::take_digest(&mut self.checksum);
+                if !self.report_bytes.is_empty() {
+                    if kind != ChecksumKind::None && digest != response.checksum {
+                        return Err(format!(
+                            "Checksum mismatch for the removal report of {}: received {} but the server sent {}.",
+                            self.path,
+                            checksum::to_hex(&digest),
+                            checksum::to_hex(&response.checksum)
+                        ));
+                    }
+                    self.records = removal::parse_records(&self.report_bytes)?;
+                }
+                self.response = Some(response);
+                Ok(())
+            }
+            kind => Err(format!(
+                "Expected removal records or a response but got a {:?} frame.",
+                kind
+            )),
+        }
+    }
+}
+
+/// Asks the server to hash a file or tree, collecting the record it sends for
+/// each file.
+#[derive(Debug)]
+pub struct HashState {
+    pub prefix: HashPrefix,
+    pub path: String,
+    push_idx: usize,
+    report_bytes: Vec<u8>,
+    checksum: Option<Checksum>,
+    pub records: Vec<HashRecord>,
+    pub response: Option<Response>,
+}
+
+impl HashState {
+    pub fn new_hash(prefix: HashPrefix, path: &str) -> Result<Self, String> {
+        if prefix.file_name_length != path.len() as u16 {
+            return Err(format!("Could not verify prefix matches this path: got name {:?} which doesn't have length {}", path, prefix.file_name_length));
+        }
+        let kind = prefix.flags.checksum().ok_or(format!("Unknown checksum kind in flags {:#06x}.", prefix.flags.bits()))?;
+        if kind == ChecksumKind::None {
+            return Err("Hashing needs a checksum kind.".to_owned());
+        }
+        Ok(HashState {
+            prefix,
+            path: path.to_owned(),
+            push_idx: 0,
+            report_bytes: Vec::new(),
+            checksum: Some(Checksum::new(kind)),
+            records: Vec::new(),
+            response: None,
+        })
+    }
+
+    /// Takes the records out of a finished hash. Fails only if the server
+    /// refused the command outright; files that could not be hashed are
+    /// reported in their records instead.
+    pub fn into_records(self) -> Result<Vec<HashRecord>, String> {
+        match self.response {
+            Some(ref response) if !response.is_ok() && self.records.is_empty() => response.clone().into_result().map(|_| Vec::new()),
+            Some(_) => Ok(self.records),
+            None => Err(format!("Hashing {} finished without a response.", self.path)),
+        }
+    }
+}
+
+impl ClientCommandState<HashPrefix> for HashState {
+    fn prefix(&self) -> HashPrefix {
+        self.prefix
+    }
+
+    fn needs_push(&self) -> bool {
+        self.push_idx < self.path.len()
+    }
+
+    fn push_frame(&mut self, max_payload: usize) -> Result<Frame, String> {
+        Ok(push_name_frame(self.path.as_bytes(), &mut self.push_idx, max_payload))
+    }
+
+    fn needs_pull(&self) -> bool {
+        !self.needs_push() && self.response.is_none()
+    }
+
+    fn pull_frame(&mut self, frame: Frame) -> Result<(), String> {
+        match frame.kind {
+            FrameKind::Data => {
+                if let Some(ck) = &mut self.checksum {
+                    ck.update(&frame.payload);
+                }
+                self.report_bytes.extend_from_slice(&frame.payload);
+                Ok(())
+            }
+            FrameKind::Response => {
+                // Not logged, so that the manifest is all that goes to stdout.
+                let response = frame.parse_response()?;
+                let kind = self.checksum.as_ref().map(|ck| ck.kind()).unwrap_or(ChecksumKind::None);
+                let digest = checksum::take_digest(&mut self.checksum);
+                if !self.report_bytes.is_empty() {
+                    if digest != response.checksum {
+                        return Err(format!(
+                            "Checksum mismatch for the hash report of {}: received {} but the server sent {}.",
+                            self.path,
+                            checksum::to_hex(&digest),
+                            checksum::to_hex(&response.checksum)
+                        ));
+                    }
+                    self.records = hashing::parse_records(&self.report_bytes, kind)?;
+                }
+                self.response = Some(response);
+                Ok(())
+            }
+            kind => Err(format!(
+                "Expected hash records or a response but got a {:?} frame.",
+                kind
+            )),
+        }
+    }
+}
+
+/// Formats a Unix time in seconds as `YYYY-MM-DD HH:MM` in UTC.
+pub fn format_time(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let minutes = (secs % 86400) / 60;
+    // Converts days since 1970-01-01 to a civil date, counting in 400 year
+    // eras that start on March 1st.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, minutes / 60, minutes % 60)
+}
+
+/// Formats a directory entry as a line of `ls -l` style output: its kind,
+/// size, modification time and name.
+pub fn format_list_entry(entry: &ListEntry) -> String {
+    let kind = match entry.kind {
+        EntryKind::File => '-',
+        EntryKind::Dir => 'd',
+        EntryKind::Other => '?',
+    };
+    format!("{} {:>12} {} {}", kind, entry.size, format_time(entry.modified), entry.name)
+}
+
+/// Formats filesystem info as a line of `df` style output.
+pub fn format_fs_info(mount: &str, info: &FsInfo) -> String {
+    format!(
+        "{} {} {:>14} total {:>14} free {:>8} per cluster",
+        mount,
+        info.kind.name(),
+        info.total_bytes,
+        info.free_bytes,
+        info.cluster_size
+    )
+}
+
+/// The root of the mount a path on the Switch is on: everything up to and
+/// including the `:/` of a device name like `sdmc:/`, or `/` for a path
+/// without one.
+pub fn mount_root(switch_path: &str) -> &str {
+    match switch_path.find(":/") {
+        Some(idx) => &switch_path[0..idx + 2],
+        None => "/",
+    }
 }
 
 #[derive(Debug)]
@@ -27,10 +945,18 @@ pub struct ReadState<StoreType: FileContentStorer> {
     pub prefix: ReadPrefix,
     pub file_name: String,
     pub output_name: String,
+    /// Where in the file the read starts.
+    pub offset: u64,
+    /// Whether the local file already holds everything before the offset,
+    /// rather than starting at it.
+    resumed: bool,
+    request: Vec<u8>,
     store: Option<StoreType>,
     push_idx: usize,
-    pull_idx: usize,
-    pub file_size: usize,
+    pull_idx: u64,
+    pub file_size: u64,
+    checksum: Option<Checksum>,
+    pub response: Option<Response>,
 }
 
 
@@ -40,7 +966,50 @@ impl<StoreType: FileContentStorer> ReadState<StoreType> {
         file_name: &str,
         output_name: &str,
     ) -> Result<Self, String> {
-        dprintln!("Now starting read of file {} to local storage {}.", file_name, output_name);
+        Self::new_resumed_read(prefix, file_name, output_name, 0)
+    }
+
+    /// Starts a read that carries on from `offset`, adding to the local file
+    /// after its first `offset` bytes. The prefix must have the `OFFSET` flag
+    /// unless the offset is 0.
+    pub fn new_resumed_read(
+        prefix: ReadPrefix,
+        file_name: &str,
+        output_name: &str,
+        offset: u64,
+    ) -> Result<Self, String> {
+        Self::new_state(prefix, file_name, output_name, offset, 0, true)
+    }
+
+    /// Starts a read of at most `length` bytes from `offset` into a new local
+    /// file. The prefix must have the `LIMIT` flag, and the `OFFSET` flag
+    /// unless the offset is 0.
+    pub fn new_ranged_read(
+        prefix: ReadPrefix,
+        file_name: &str,
+        output_name: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Self, String> {
+        if !prefix.flags.contains(PrefixFlags::LIMIT) {
+            return Err(format!("Cannot read a range of {} without the limit flag.", file_name));
+        }
+        Self::new_state(prefix, file_name, output_name, offset, length, false)
+    }
+
+    fn new_state(
+        prefix: ReadPrefix,
+        file_name: &str,
+        output_name: &str,
+        offset: u64,
+        limit: u64,
+        resumed: bool,
+    ) -> Result<Self, String> {
+        dprintln!("Now starting read of file {} to local storage {} at offset {}.", file_name, output_name, offset);
+        if offset > 0 && !prefix.flags.contains(PrefixFlags::OFFSET) {
+            return Err(format!("Cannot read {} from offset {} without the offset flag.", file_name, offset));
+        }
+        let kind = prefix.flags.checksum().ok_or(format!("Unknown checksum kind in flags {:#06x}.", prefix.flags.bits()))?;
         if prefix.file_name_length != file_name.len() as u16 {
             Err(format!("Could not verify prefix matches this file: got name {:?} which doesn't have length {}", file_name, prefix.file_name_length))
         } else {
@@ -48,13 +1017,63 @@ impl<StoreType: FileContentStorer> ReadState<StoreType> {
                 prefix,
                 file_name: file_name.to_owned(),
                 output_name: output_name.to_owned(),
+                offset,
+                resumed,
+                request: name_and_range(file_name, prefix.flags, offset, limit),
                 store: None,
                 push_idx: 0,
                 pull_idx: 0,
                 file_size: 0,
+                checksum: Some(Checksum::new(kind)),
+                response: None,
             })
         }
     }
+
+    /// Checks the server's response against what was received, removing the
+    /// local file if the read failed or the checksums don't match.
+    fn finish(&mut self, response: Response) -> Result<(), String> {
+        let kind = self.checksum.as_ref().map(|ck| ck.kind()).unwrap_or(ChecksumKind::None);
+        let digest = checksum::take_digest(&mut self.checksum);
+        let received = self.pull_idx.saturating_sub(READ_HEADER_LENGTH as u64);
+        let result = if !response.is_ok() {
+            response.clone().into_result()
+        } else if self.pull_idx < READ_HEADER_LENGTH as u64 || received < self.file_size {
+            Err(format!(
+                "Server ended the read of {} after {} of {} bytes.",
+                self.file_name, received, self.file_size
+            ))
+        } else if kind != ChecksumKind::None && digest != response.checksum {
+            Err(format!(
+                "Checksum mismatch for {}: received content hashes to {} but the server sent {}.",
+                self.file_name,
+                checksum::to_hex(&digest),
+                checksum::to_hex(&response.checksum)
+            ))
+        } else {
+            Ok(())
+        };
+        self.response = Some(response);
+        if result.is_ok() && self.store.is_none() {
+            self.store = Some(self.open_store()?);
+        }
+        if result.is_err() {
+            if let Some(store) = self.store.take() {
+                dprintln!("Removing partial file {}.", self.output_name);
+                store.remove()?;
+            }
+        }
+        result
+    }
+
+    /// Opens the local file, keeping what it already holds if resuming.
+    fn open_store(&self) -> Result<StoreType, String> {
+        if self.resumed && self.offset > 0 {
+            StoreType::resume(&self.output_name, self.offset)
+        } else {
+            StoreType::for_name(&self.output_name, self.file_size)
+        }
+    }
 }
 
 impl<StoreType: FileContentStorer> ClientCommandState<ReadPrefix> for ReadState<StoreType> {
@@ -63,85 +1082,399 @@ impl<StoreType: FileContentStorer> ClientCommandState<ReadPrefix> for ReadState<
     }
 
     fn needs_push(&self) -> bool {
-        self.push_idx < self.prefix.file_name_length as usize - 1
+        self.push_idx < self.request.len()
     }
 
-    fn push_block(&mut self, block: &mut [u8]) -> Result<usize, String> {
-        let mut cur_pushed = 0;
-        while cur_pushed < block.len()
-            && self.push_idx + cur_pushed < self.prefix.file_name_length as usize
-        {
-            block[cur_pushed] = self.file_name.as_bytes()[self.push_idx + cur_pushed];
-            cur_pushed += 1;
-        }
-        self.push_idx += cur_pushed;
-        Ok(cur_pushed)
+    fn push_frame(&mut self, max_payload: usize) -> Result<Frame, String> {
+        Ok(push_name_frame(&self.request, &mut self.push_idx, max_payload))
     }
 
     fn needs_pull(&self) -> bool {
-        !self.needs_push() && self.pull_idx < self.file_size + 4
+        !self.needs_push() && self.response.is_none()
     }
 
-    fn pull_block(&mut self, buffer: &[u8]) -> Result<usize, String> {
+    fn pull_frame(&mut self, frame: Frame) -> Result<(), String> {
+        match frame.kind {
+            FrameKind::Response => {
+                let response = frame.parse_response()?;
+                dprintln!("Server finished read of {}: {}", self.file_name, response);
+                self.finish(response)
+            }
+            FrameKind::Data => {
+                let pulled = self.pull_data(&frame.payload)?;
+                if pulled < frame.payload.len() {
+                    return Err(format!(
+                        "Got {} bytes past the end of the file {}.",
+                        frame.payload.len() - pulled,
+                        self.file_name
+                    ));
+                }
+                Ok(())
+            }
+            kind => Err(format!(
+                "Expected file data or a response but got a {:?} frame.",
+                kind
+            )),
+        }
+    }
+
+    fn abort(&mut self, policy: AbortPolicy) -> Result<(), String> {
+        match (self.store.take(), policy) {
+            (Some(store), AbortPolicy::Delete) => {
+                dprintln!("Removing partial file {}.", self.output_name);
+                store.remove()
+            }
+            (Some(_), AbortPolicy::Keep) => {
+                dprintln!("Keeping partial file {}.", self.output_name);
+                Ok(())
+            }
+            (None, _) => Ok(()),
+        }
+    }
+}
+
+impl<StoreType: FileContentStorer> ReadState<StoreType> {
+    /// Takes the file length and then file content from the start of the
+    /// buffer, returning the number of bytes used.
+    fn pull_data(&mut self, buffer: &[u8]) -> Result<usize, String> {
         let block_sz = buffer.len();
         let mut cur_pulled = 0;
+        let header_len = READ_HEADER_LENGTH as u64;
 
         //Extract the file length 
-        while self.pull_idx + cur_pulled < 4 && cur_pulled < block_sz {
+        while self.pull_idx + (cur_pulled as u64) < header_len && cur_pulled < block_sz {
             let read_byte = buffer[cur_pulled];
-            let byte_offset = 3 - (self.pull_idx + cur_pulled);
+            let byte_offset = header_len - 1 - (self.pull_idx + cur_pulled as u64);
             let bit_offset = byte_offset * 8;
-            self.file_size |= (read_byte as usize) << bit_offset;
+            self.file_size |= (read_byte as u64) << bit_offset;
             cur_pulled += 1;
         }
-        if self.pull_idx + cur_pulled < 4 {
-            self.pull_idx += cur_pulled;
+        if self.pull_idx + (cur_pulled as u64) < header_len {
+            self.pull_idx += cur_pulled as u64;
             return Ok(cur_pulled);
         }
 
-        dprintln!("Now have {}/{} bytes of the file {}.", self.pull_idx + cur_pulled - 4, self.file_size, self.file_name);
+        dprintln!("Now have {}/{} bytes of the file {}.", self.pull_idx + cur_pulled as u64 - header_len, self.file_size, self.file_name);
 
+        let bytes_remaining = self.file_size + header_len - self.pull_idx - cur_pulled as u64; 
+        if bytes_remaining == 0 {
+            self.pull_idx += cur_pulled as u64;
+            return Ok(cur_pulled);
+        }
         if self.store.is_none() {
-            let fl = StoreType::for_name(&self.output_name, self.file_size)?;
+            let fl = self.open_store()?;
             self.store = Some(fl);
         }
 
-        let bytes_remaining = self.file_size - self.pull_idx + 4 - cur_pulled; 
-        let bytes_to_push = if bytes_remaining >= block_sz - cur_pulled {
+        let bytes_to_push = if bytes_remaining >= (block_sz - cur_pulled) as u64 {
             &buffer[cur_pulled ..]
         } else {
-            &buffer[cur_pulled .. cur_pulled + bytes_remaining]
+            &buffer[cur_pulled .. cur_pulled + bytes_remaining as usize]
         };
         let fl = self
             .store
             .as_mut()
             .ok_or("Store is somehow none after creation!")?;
         let rval = fl.push_bytes(bytes_to_push)?;
+        if let Some(ck) = &mut self.checksum {
+            ck.update(&bytes_to_push[0..rval]);
+        }
         cur_pulled += rval;
-        self.pull_idx += cur_pulled;
+        self.pull_idx += cur_pulled as u64;
         Ok(cur_pulled)
     }
 }
 
+/// Reads a file from the server as a delta against the signature of an old
+/// local copy of it, rebuilding the new version in `output_name` from the
+/// new bytes in the delta and ranges of the old copy at `basis_name`.
+pub struct ReadDeltaState<StoreType: FileContentStorer, FileType: FileRetriever> {
+    pub prefix: ReadDeltaPrefix,
+    pub file_name: String,
+    pub output_name: String,
+    request: Vec<u8>,
+    push_idx: usize,
+    basis: FileType,
+    store: Option<StoreType>,
+    header: Vec<u8>,
+    pub file_size: u64,
+    decoder: DeltaDecoder,
+    /// How many bytes of the file have been rebuilt.
+    written: u64,
+    /// How many of them came over the line rather than from the old copy.
+    pub literal_bytes: u64,
+    checksum: Option<Checksum>,
+    pub response: Option<Response>,
+}
+
+impl<StoreType: FileContentStorer, FileType: FileRetriever> ReadDeltaState<StoreType, FileType> {
+    pub fn new_read_delta(
+        prefix: ReadDeltaPrefix,
+        file_name: &str,
+        output_name: &str,
+        basis_name: &str,
+        signature: &Signature,
+    ) -> Result<Self, String> {
+        if prefix.file_name_length != file_name.len() as u16 {
+            return Err(format!("Could not verify prefix matches this file: got name {:?} which doesn't have length {}", file_name, prefix.file_name_length));
+        }
+        let kind = prefix.flags.checksum().ok_or(format!("Unknown checksum kind in flags {:#06x}.", prefix.flags.bits()))?;
+        if kind != signature.kind {
+            return Err(format!("The signature of {} has {:?} blocks but the prefix asks for {:?}.", basis_name, signature.kind, kind));
+        }
+        let mut request = file_name.as_bytes().to_vec();
+        request.extend_from_slice(&signature.serialize());
+        if prefix.signature_length != (request.len() - file_name.len()) as u64 {
+            return Err(format!("Error verifying prefix: the signature of {} does not have length {}.", basis_name, prefix.signature_length));
+        }
+        dprintln!("Now starting delta read of file {} to local storage {} against {}.", file_name, output_name, basis_name);
+        Ok(ReadDeltaState {
+            prefix,
+            file_name: file_name.to_owned(),
+            output_name: output_name.to_owned(),
+            request,
+            push_idx: 0,
+            basis: FileType::open_file(basis_name)?,
+            store: None,
+            header: Vec::with_capacity(DELTA_HEADER_LENGTH),
+            file_size: 0,
+            decoder: DeltaDecoder::new(),
+            written: 0,
+            literal_bytes: 0,
+            checksum: Some(Checksum::new(kind)),
+            response: None,
+        })
+    }
+
+    /// Takes the header and then the delta stream from the buffer, carrying
+    /// out the instructions in it.
+    fn pull_data(&mut self, buffer: &[u8]) -> Result<(), String> {
+        let mut used = 0;
+        if self.header.len() < DELTA_HEADER_LENGTH {
+            used = (DELTA_HEADER_LENGTH - self.header.len()).min(buffer.len());
+            self.header.extend_from_slice(&buffer[0..used]);
+            if self.header.len() < DELTA_HEADER_LENGTH {
+                return Ok(());
+            }
+            self.file_size = prefixes::combine_bytes_u64(&self.header[0..8]);
+        }
+        let stream = &buffer[used..];
+        if self.store.is_none() && !stream.is_empty() {
+            self.store = Some(StoreType::for_name(&self.output_name, self.file_size)?);
+        }
+        let mut taken = 0;
+        while taken < stream.len() {
+            let (n, piece) = self.decoder.input(&stream[taken..])?;
+            taken += n;
+            match piece {
+                Some(Decoded::Literal(bytes)) => {
+                    self.literal_bytes += bytes.len() as u64;
+                    self.store_bytes(bytes)?;
+                }
+                Some(Decoded::Copy { offset, length }) => self.copy_basis(offset, length)?,
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies a range of the old local copy into the new file.
+    fn copy_basis(&mut self, offset: u64, length: u64) -> Result<(), String> {
+        self.basis.seek(offset)?;
+        let mut buffer = vec![0u8; length.min(DELTA_BUFFER_SIZE as u64) as usize];
+        let mut copied = 0;
+        while copied < length {
+            let want = (length - copied).min(buffer.len() as u64) as usize;
+            let read = self.basis.read_bytes(&mut buffer[0..want])?;
+            if read == 0 {
+                return Err(format!(
+                    "The delta of {} copies byte {} of {}, past its end.",
+                    self.file_name,
+                    offset + copied,
+                    self.basis.name()
+                ));
+            }
+            self.store_bytes(&buffer[0..read])?;
+            copied += read as u64;
+        }
+        Ok(())
+    }
+
+    /// Hashes rebuilt bytes and adds them to the new file.
+    fn store_bytes(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.written += bytes.len() as u64;
+        if self.written > self.file_size {
+            return Err(format!("The delta of {} rebuilds more than its {} bytes.", self.file_name, self.file_size));
+        }
+        if let Some(ck) = &mut self.checksum {
+            ck.update(bytes);
+        }
+        let store = self.store.as_mut().ok_or("Store is somehow none after creation!")?;
+        let mut stored = 0;
+        while stored < bytes.len() {
+            stored += store.push_bytes(&bytes[stored..])?;
+        }
+        Ok(())
+    }
+
+    /// Checks the server's response against the rebuilt file, removing it if
+    /// the read failed or the checksums don't match.
+    fn finish(&mut self, response: Response) -> Result<(), String> {
+        let digest = checksum::take_digest(&mut self.checksum);
+        let result = if !response.is_ok() {
+            response.clone().into_result()
+        } else if self.header.len() < DELTA_HEADER_LENGTH {
+            Err(format!("Server ended the delta of {} before its header.", self.file_name))
+        } else if !self.decoder.is_between_ops() || self.written != self.file_size {
+            Err(format!(
+                "The delta of {} rebuilt {} of its {} bytes.",
+                self.file_name, self.written, self.file_size
+            ))
+        } else if digest != response.checksum {
+            Err(format!(
+                "Checksum mismatch for {}: the rebuilt file hashes to {} but the server sent {}.",
+                self.file_name,
+                checksum::to_hex(&digest),
+                checksum::to_hex(&response.checksum)
+            ))
+        } else {
+            Ok(())
+        };
+        self.response = Some(response);
+        if result.is_ok() && self.store.is_none() {
+            self.store = Some(StoreType::for_name(&self.output_name, self.file_size)?);
+        }
+        if result.is_err() {
+            if let Some(store) = self.store.take() {
+                dprintln!("Removing partial file {}.", self.output_name);
+                store.remove()?;
+            }
+        }
+        result
+    }
+}
+
+impl<StoreType: FileContentStorer, FileType: FileRetriever> ClientCommandState<ReadDeltaPrefix> for ReadDeltaState<StoreType, FileType> {
+    fn prefix(&self) -> ReadDeltaPrefix {
+        self.prefix
+    }
+
+    fn needs_push(&self) -> bool {
+        self.push_idx < self.request.len()
+    }
+
+    fn push_frame(&mut self, max_payload: usize) -> Result<Frame, String> {
+        Ok(push_name_frame(&self.request, &mut self.push_idx, max_payload))
+    }
+
+    fn needs_pull(&self) -> bool {
+        !self.needs_push() && self.response.is_none()
+    }
+
+    fn pull_frame(&mut self, frame: Frame) -> Result<(), String> {
+        let result = match frame.kind {
+            FrameKind::Response => {
+                let response = frame.parse_response()?;
+                dprintln!("Server finished delta read of {}: {}", self.file_name, response);
+                self.finish(response)
+            }
+            FrameKind::Data => self.pull_data(&frame.payload),
+            kind => Err(format!(
+                "Expected a delta or a response but got a {:?} frame.",
+                kind
+            )),
+        };
+        if result.is_err() {
+            self.abort(AbortPolicy::Delete)?;
+        }
+        result
+    }
+
+    /// A partly rebuilt file is no use for resuming, so it is removed
+    /// whatever the policy.
+    fn abort(&mut self, _policy: AbortPolicy) -> Result<(), String> {
+        match self.store.take() {
+            Some(store) => {
+                dprintln!("Removing partial file {}.", self.output_name);
+                store.remove()
+            }
+            None => Ok(()),
+        }
+    }
+}
+
 pub struct WriteState<FileType : FileRetriever> {
     pub prefix : WritePrefix, 
     pub file : FileType, 
     pub switch_name : String, 
-    push_idx : usize, 
+    /// Where in the file the write starts; the server already holds
+    /// everything before it.
+    pub offset : u64,
+    request : Vec<u8>,
+    push_idx : u64, 
+    checksum : Option<Checksum>,
+    digest : Vec<u8>,
+    digest_len : u64,
+    /// The instructions being sent in place of the content, for a `DELTA`
+    /// write.
+    delta : Option<DeltaEncoder>,
+    pub response : Option<Response>,
 }
 impl <FileType : FileRetriever>  WriteState<FileType> { 
     pub fn new_write(prefix : WritePrefix, switch_path : &str, computer_path : &str) -> Result<Self, String> {
-        let file = FileType::open_file(computer_path)?;
+        Self::new_resumed_write(prefix, switch_path, computer_path, 0)
+    }
+
+    /// Starts a write that carries on from `offset`, sending only the content
+    /// after it. The prefix must have the `OFFSET` flag unless the offset is 0,
+    /// and its file length is the number of bytes after the offset.
+    pub fn new_resumed_write(prefix : WritePrefix, switch_path : &str, computer_path : &str, offset : u64) -> Result<Self, String> {
+        let mut file = FileType::open_file(computer_path)?;
         if switch_path.len() != prefix.file_name_length as usize {
             return Err(format!("Error verifying prefix: path {} does not have length {}.", switch_path, prefix.file_name_length));
         }
+        if offset > 0 && !prefix.flags.contains(PrefixFlags::OFFSET) {
+            return Err(format!("Cannot write {} from offset {} without the offset flag.", switch_path, offset));
+        }
+        if offset > 0 {
+            file.seek(offset)?;
+        }
+        let kind = prefix.flags.checksum().ok_or(format!("Unknown checksum kind in flags {:#06x}.", prefix.flags.bits()))?;
         Ok(WriteState {
             prefix, 
             file, 
             switch_name : switch_path.to_owned(), 
-            push_idx : 0
+            offset,
+            request : name_and_range(switch_path, prefix.flags, offset, 0),
+            push_idx : 0,
+            checksum : Some(Checksum::new(kind)),
+            digest : Vec::new(),
+            digest_len : kind.digest_len() as u64,
+            delta : None,
+            response : None,
         })
     }
+
+    /// Starts a write that sends the instructions in `ops` instead of the
+    /// content, to rebuild the file from the old copy the server holds. The
+    /// prefix must have the `DELTA` flag, and its file length is the length
+    /// of the delta stream. `digest` is the checksum of the whole local file.
+    pub fn new_delta_write(prefix : WritePrefix, switch_path : &str, computer_path : &str, ops : Vec<DeltaOp>, digest : Vec<u8>) -> Result<Self, String> {
+        if !prefix.flags.contains(PrefixFlags::DELTA) {
+            return Err(format!("Cannot send a delta of {} without the delta flag.", switch_path));
+        }
+        if prefix.file_length != delta::encoded_len(&ops) {
+            return Err(format!("Error verifying prefix: the delta of {} does not have length {}.", switch_path, prefix.file_length));
+        }
+        let mut state = Self::new_resumed_write(prefix, switch_path, computer_path, 0)?;
+        if digest.len() as u64 != state.digest_len {
+            return Err(format!("A delta write of {} needs a digest of {} bytes.", switch_path, state.digest_len));
+        }
+        state.checksum = None;
+        state.digest = digest;
+        state.delta = Some(DeltaEncoder::new(ops));
+        Ok(state)
+    }
 }
 impl <FileType : FileRetriever> ClientCommandState<WritePrefix> for WriteState<FileType> {
     fn prefix(&self) -> WritePrefix {
@@ -149,40 +1482,102 @@ impl <FileType : FileRetriever> ClientCommandState<WritePrefix> for WriteState<F
     }
 
     fn needs_push(&self) -> bool {
-        self.push_idx < self.prefix.file_length as usize + self.prefix().file_name_length as usize
+        self.push_idx < self.prefix.file_length + self.request.len() as u64 + self.digest_len
     }
 
-    fn push_block(&mut self, block: &mut [u8]) -> Result<usize, String> {
-        let mut cur_pushed = 0; 
-        while self.push_idx + cur_pushed < self.prefix.file_name_length as usize && cur_pushed < block.len() {
-            block[cur_pushed] = self.switch_name.as_bytes()[self.push_idx + cur_pushed];
-            cur_pushed += 1;
-        }
-        while self.push_idx + cur_pushed < self.prefix.file_length  as usize && cur_pushed < block.len() {
-            let space_left = &mut block[cur_pushed ..];
-            cur_pushed += self.file.read_bytes(space_left)?;
-        }
-        self.push_idx += cur_pushed;
-        Ok(cur_pushed)
+    fn push_frame(&mut self, max_payload: usize) -> Result<Frame, String> {
+        let total = self.prefix.file_length + self.request.len() as u64 + self.digest_len;
+        let mut payload = vec![0u8; (total - self.push_idx).min(max_payload as u64) as usize];
+        let pushed = self.push_data(&mut payload)?;
+        payload.truncate(pushed);
+        Ok(Frame::data(payload))
     }
 
     fn needs_pull(&self) -> bool {
-        false
+        !self.needs_push() && self.response.is_none()
     }
 
-    fn pull_block(&mut self, _buffer: &[u8]) -> Result<usize, String> {
-        Ok(0)
+    fn pull_frame(&mut self, frame: Frame) -> Result<(), String> {
+        let response = frame.parse_response()?;
+        dprintln!("Server finished write of {}: {}", self.switch_name, response);
+        self.response = Some(response.clone());
+        response.clone().into_result()?;
+        if self.digest_len > 0 && self.digest != response.checksum {
+            return Err(format!(
+                "Checksum mismatch for {}: sent content hashes to {} but the server got {}.",
+                self.switch_name,
+                checksum::to_hex(&self.digest),
+                checksum::to_hex(&response.checksum)
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl <FileType : FileRetriever> WriteState<FileType> {
+    /// Fills the block with the file name and offset, then file content or
+    /// the delta stream, then the digest, returning the number of bytes
+    /// filled.
+    fn push_data(&mut self, block: &mut [u8]) -> Result<usize, String> {
+        let mut cur_pushed = 0; 
+        let name_length = self.request.len() as u64;
+        while self.push_idx + (cur_pushed as u64) < name_length && cur_pushed < block.len() {
+            block[cur_pushed] = self.request[self.push_idx as usize + cur_pushed];
+            cur_pushed += 1;
+        }
+        let content_end = name_length + self.prefix.file_length;
+        while self.push_idx + (cur_pushed as u64) < content_end && cur_pushed < block.len() {
+            let bytes_left = (content_end - self.push_idx - cur_pushed as u64).min((block.len() - cur_pushed) as u64) as usize;
+            let space_left = &mut block[cur_pushed .. cur_pushed + bytes_left];
+            let read = match &mut self.delta {
+                Some(encoder) => {
+                    let file = &mut self.file;
+                    encoder.fill(space_left, |offset, buffer| {
+                        file.seek(offset)?;
+                        file.read_bytes(buffer)
+                    })?
+                }
+                None => self.file.read_bytes(space_left)?,
+            };
+            if read == 0 {
+                return Err(format!("File {} ended before its expected length of {} bytes.", self.file.name(), self.prefix.file_length));
+            }
+            if let Some(ck) = &mut self.checksum {
+                ck.update(&space_left[0..read]);
+            }
+            cur_pushed += read;
+        }
+        if self.push_idx + (cur_pushed as u64) >= content_end {
+            if let Some(ck) = self.checksum.take() {
+                self.digest = ck.finish();
+            }
+            while self.push_idx + (cur_pushed as u64) < content_end + self.digest_len && cur_pushed < block.len() {
+                let digest_idx = (self.push_idx + cur_pushed as u64 - content_end) as usize;
+                block[cur_pushed] = self.digest[digest_idx];
+                cur_pushed += 1;
+            }
+        }
+        self.push_idx += cur_pushed as u64;
+        Ok(cur_pushed)
     }
 }
 
 pub trait FileRetriever: Sized {
     fn open_file(&str) -> Result<Self, String> ;
     fn name(&self) -> &str;
-    fn len(&self) -> usize;
+    fn len(&self) -> u64;
     fn read_bytes(&mut self, buffer: &mut [u8]) -> Result<usize, String>;
+    /// Moves the cursor to the given offset from the start.
+    fn seek(&mut self, offset: u64) -> Result<(), String>;
 }
 
 pub trait FileContentStorer: Sized {
-    fn for_name(name: &str, size: usize) -> Result<Self, String>;
+    fn for_name(name: &str, size: u64) -> Result<Self, String>;
+    /// Opens a file that already exists to carry on storing it after its
+    /// first `offset` bytes, dropping anything past them.
+    fn resume(name: &str, offset: u64) -> Result<Self, String>;
     fn push_bytes(&mut self, buffer: &[u8]) -> Result<usize, String>;
+    /// Deletes content that could not be stored completely or correctly,
+    /// keeping only the part a resumed file started with.
+    fn remove(self) -> Result<(), String>;
 }
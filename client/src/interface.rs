@@ -1,8 +1,305 @@
-use nxusb::prefixes::{Prefixes};
+use commands::{client_handshake, ClientCommandState, FileContentStorer, FileRetriever, FsInfoState, HandshakeState, HashState, ListState, MakeDirState, MoveState, ReadDeltaState, ReadState, RemoveState, SignatureState, StatState};
+use nxusb::checksum::ChecksumKind;
+use nxusb::delta::Signature;
+use nxusb::frame::{AbortPolicy, Frame, FrameKind};
+use nxusb::fsinfo::FsInfo;
+use nxusb::hashing::HashRecord;
+use nxusb::listing::{ListEntry, DEFAULT_PAGE_ENTRIES};
+use nxusb::prefixes::{CommandPrefix, FsInfoPrefix, HandshakePrefix, HashPrefix, ListPrefix, MakeDirPrefix, MovePrefix, PrefixFlags, Prefixes, ReadDeltaPrefix, ReadPrefix, RemovePrefix, SignaturePrefix, StatPrefix};
+use nxusb::removal::RemovalRecord;
+use nxusb::response::ResponseCode;
 
 pub trait ClientDevice {
-    fn push_prefix(&mut self, prefix : Prefixes) -> Result<usize, String>;
-    fn block_size(&self) -> usize;
-    fn pull_block(&mut self, buffer: &mut [u8]) -> Result<usize, String>;
-    fn push_block(&mut self, bytes: &[u8]) -> Result<usize, String>;
-}
\ No newline at end of file
+    /// Sends a frame to the server, returning the number of bytes sent.
+    fn push_frame(&mut self, frame: Frame) -> Result<usize, String>;
+
+    /// Receives the next whole frame from the server, however many transfers
+    /// it spans.
+    fn pull_frame(&mut self) -> Result<Frame, String>;
+
+    /// The largest frame payload to send to the server.
+    fn frame_payload(&self) -> usize;
+
+    /// Checks whether the user asked to stop the command in progress, and
+    /// what to do with the partial file if so.
+    fn abort_requested(&self) -> Option<AbortPolicy> {
+        None
+    }
+
+    fn push_prefix(&mut self, prefix : Prefixes) -> Result<usize, String> {
+        self.push_frame(Frame::prefix(&prefix))
+    }
+
+    /// Exchanges handshakes with the server, returning the server's handshake
+    /// or an error if the two sides cannot work together.
+    fn handshake(&mut self) -> Result<HandshakePrefix, String> {
+        let mut state = HandshakeState::new_handshake(client_handshake());
+        self.push_prefix(Prefixes::Handshake(state.prefix()))?;
+        while state.needs_pull() {
+            let frame = self.pull_frame().map_err(|e| {
+                format!(
+                    "Did not get a handshake reply from the server ({}); is nxusb_server.nro running and up to date?",
+                    e
+                )
+            })?;
+            state.pull_frame(frame)?;
+        }
+        state
+            .server
+            .ok_or("Handshake finished without a server reply.".to_owned())
+    }
+
+    /// Sends the command's prefix and then runs it until it has nothing left
+    /// to send or receive.
+    fn run_command<P: CommandPrefix, C: ClientCommandState<P>>(&mut self, prefix: Prefixes, state: &mut C) -> Result<(), String>
+    where
+        Self: Sized,
+    {
+        self.push_prefix(prefix)?;
+        loop {
+            if let Some(policy) = self.abort_requested() {
+                if state.needs_pull() || state.needs_push() {
+                    return self.abort(policy, state);
+                }
+            }
+            if state.needs_pull() {
+                let frame = self.pull_frame()?;
+                state.pull_frame(frame)?;
+            } else if state.needs_push() {
+                let frame = state.push_frame(self.frame_payload())?;
+                self.push_frame(frame)?;
+            } else {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells the server to stop the command in progress and waits for it to
+    /// acknowledge, dropping anything else it sends first, then stops the
+    /// command on this side too. Always returns an error, since the command
+    /// did not finish.
+    fn abort<P: CommandPrefix, C: ClientCommandState<P>>(&mut self, policy: AbortPolicy, state: &mut C) -> Result<(), String>
+    where
+        Self: Sized,
+    {
+        println!("Aborting the transfer; waiting for the server to stop.");
+        self.push_frame(Frame::abort(policy))?;
+        loop {
+            let frame = self.pull_frame()?;
+            if frame.kind == FrameKind::Response && frame.parse_response()?.code == ResponseCode::Aborted {
+                break;
+            }
+        }
+        state.abort(policy)?;
+        Err("Transfer aborted.".to_owned())
+    }
+
+    /// Reads at most `length` bytes of the file on the Switch, starting at
+    /// `offset`, into a new local file. Returns the number of bytes read,
+    /// which is less than `length` if the file ends first.
+    fn read_range<StoreType: FileContentStorer>(
+        &mut self,
+        switch_path: &str,
+        output_name: &str,
+        offset: u64,
+        length: u64,
+        checksum: ChecksumKind,
+    ) -> Result<u64, String>
+    where
+        Self: Sized,
+    {
+        let prefix = ReadPrefix {
+            flags: (PrefixFlags::OFFSET | PrefixFlags::LIMIT).with_checksum(checksum),
+            file_name_length: switch_path.len() as u16,
+        };
+        let mut state = ReadState::<StoreType>::new_ranged_read(prefix, switch_path, output_name, offset, length)?;
+        self.run_command(Prefixes::Read(prefix), &mut state)?;
+        Ok(state.file_size)
+    }
+
+    /// Reads a file on the Switch into `output_name` as a delta against the
+    /// given signature of the old local copy at `basis_name`. Returns the
+    /// state of the finished read, which says how much of the file came over
+    /// the line.
+    fn read_delta<StoreType: FileContentStorer, FileType: FileRetriever>(
+        &mut self,
+        switch_path: &str,
+        output_name: &str,
+        basis_name: &str,
+        signature: &Signature,
+    ) -> Result<ReadDeltaState<StoreType, FileType>, String>
+    where
+        Self: Sized,
+    {
+        let prefix = ReadDeltaPrefix {
+            flags: PrefixFlags::empty().with_checksum(signature.kind),
+            file_name_length: switch_path.len() as u16,
+            signature_length: signature.serialize().len() as u64,
+        };
+        let mut state = ReadDeltaState::new_read_delta(prefix, switch_path, output_name, basis_name, signature)?;
+        self.run_command(Prefixes::ReadDelta(prefix), &mut state)?;
+        Ok(state)
+    }
+
+    /// Gets the signature of a file on the Switch in blocks of `block_size`,
+    /// or `None` if there is no such file.
+    fn signature(&mut self, switch_path: &str, block_size: u32, checksum: ChecksumKind) -> Result<Option<Signature>, String>
+    where
+        Self: Sized,
+    {
+        let prefix = SignaturePrefix {
+            flags: PrefixFlags::empty().with_checksum(checksum),
+            file_name_length: switch_path.len() as u16,
+            block_size,
+        };
+        let mut state = SignatureState::new_signature(prefix, switch_path)?;
+        self.run_command(Prefixes::Signature(prefix), &mut state)?;
+        state.into_signature()
+    }
+
+    /// Lists every entry in a directory on the Switch, sorted by name,
+    /// fetching as many pages as it takes. With `recursive`, everything under
+    /// its subdirectories is listed too, named like `sub/file`.
+    fn list_dir(&mut self, switch_path: &str, recursive: bool, checksum: ChecksumKind) -> Result<Vec<ListEntry>, String>
+    where
+        Self: Sized,
+    {
+        let flags = if recursive { PrefixFlags::RECURSIVE } else { PrefixFlags::empty() };
+        let mut entries: Vec<ListEntry> = Vec::new();
+        loop {
+            let prefix = ListPrefix {
+                flags: flags.with_checksum(checksum),
+                file_name_length: switch_path.len() as u16,
+                start: entries.len() as u32,
+                max_entries: DEFAULT_PAGE_ENTRIES,
+            };
+            let mut state = ListState::new_list(prefix, switch_path)?;
+            self.run_command(Prefixes::List(prefix), &mut state)?;
+            let page = state.into_page()?;
+            let done = page.entries.is_empty() || entries.len() + page.entries.len() >= page.total as usize;
+            entries.extend(page.entries);
+            if done {
+                return Ok(entries);
+            }
+        }
+    }
+
+    /// Describes whatever is at a path on the Switch, or returns `None` if
+    /// nothing is there. With the `FOLLOW_LINKS` flag a symbolic link is
+    /// described by what it points to.
+    fn stat(&mut self, switch_path: &str, flags: PrefixFlags) -> Result<Option<ListEntry>, String>
+    where
+        Self: Sized,
+    {
+        let prefix = StatPrefix {
+            flags,
+            file_name_length: switch_path.len() as u16,
+        };
+        let mut state = StatState::new_stat(prefix, switch_path)?;
+        self.run_command(Prefixes::Stat(prefix), &mut state)?;
+        state.into_entry()
+    }
+
+    /// Describes the filesystem a path on the Switch is on.
+    fn fs_info(&mut self, switch_path: &str) -> Result<FsInfo, String>
+    where
+        Self: Sized,
+    {
+        let prefix = FsInfoPrefix {
+            flags: PrefixFlags::empty(),
+            file_name_length: switch_path.len() as u16,
+        };
+        let mut state = FsInfoState::new_fs_info(prefix, switch_path)?;
+        self.run_command(Prefixes::FsInfo(prefix), &mut state)?;
+        state.into_info()
+    }
+
+    /// Creates a directory on the Switch. With `parents`, any missing parent
+    /// directories are created too and the directory may already exist, like
+    /// `mkdir -p`.
+    fn make_dir(&mut self, switch_path: &str, parents: bool) -> Result<(), String>
+    where
+        Self: Sized,
+    {
+        let prefix = MakeDirPrefix {
+            flags: if parents { PrefixFlags::CREATE_PARENTS } else { PrefixFlags::empty() },
+            file_name_length: switch_path.len() as u16,
+        };
+        let mut state = MakeDirState::new_make_dir(prefix, switch_path)?;
+        self.run_command(Prefixes::MakeDir(prefix), &mut state)?;
+        match state.response {
+            Some(response) => response.into_result(),
+            None => Err(format!("Making directory {} finished without a response.", switch_path)),
+        }
+    }
+
+    /// Deletes a file or link on the Switch, or with `recursive` a directory
+    /// and everything in it, like `rm -r`. Returns what happened to each
+    /// entry the server tried to delete; some of them may have failed.
+    fn remove(&mut self, switch_path: &str, recursive: bool, checksum: ChecksumKind) -> Result<Vec<RemovalRecord>, String>
+    where
+        Self: Sized,
+    {
+        let flags = if recursive { PrefixFlags::RECURSIVE } else { PrefixFlags::empty() };
+        let prefix = RemovePrefix {
+            flags: flags.with_checksum(checksum),
+            file_name_length: switch_path.len() as u16,
+            dir: false,
+        };
+        let mut state = RemoveState::new_remove(prefix, switch_path)?;
+        self.run_command(Prefixes::Remove(prefix), &mut state)?;
+        state.into_records()
+    }
+
+    /// Deletes an empty directory on the Switch, like `rmdir`.
+    fn remove_dir(&mut self, switch_path: &str, checksum: ChecksumKind) -> Result<Vec<RemovalRecord>, String>
+    where
+        Self: Sized,
+    {
+        let prefix = RemovePrefix {
+            flags: PrefixFlags::empty().with_checksum(checksum),
+            file_name_length: switch_path.len() as u16,
+            dir: true,
+        };
+        let mut state = RemoveState::new_remove(prefix, switch_path)?;
+        self.run_command(Prefixes::Remove(prefix), &mut state)?;
+        state.into_records()
+    }
+
+    /// Hashes a file on the Switch, or with `recursive` every file under a
+    /// directory, without copying the content. Returns the digest of each
+    /// file; some of them may have failed.
+    fn hash(&mut self, switch_path: &str, recursive: bool, checksum: ChecksumKind) -> Result<Vec<HashRecord>, String>
+    where
+        Self: Sized,
+    {
+        let flags = if recursive { PrefixFlags::RECURSIVE } else { PrefixFlags::empty() };
+        let prefix = HashPrefix {
+            flags: flags.with_checksum(checksum),
+            file_name_length: switch_path.len() as u16,
+        };
+        let mut state = HashState::new_hash(prefix, switch_path)?;
+        self.run_command(Prefixes::Hash(prefix), &mut state)?;
+        state.into_records()
+    }
+
+    /// Moves or renames a path on the Switch. Fails if something is already
+    /// at the destination, unless `overwrite` is set.
+    fn move_path(&mut self, from: &str, to: &str, overwrite: bool) -> Result<(), String>
+    where
+        Self: Sized,
+    {
+        let prefix = MovePrefix {
+            flags: if overwrite { PrefixFlags::OVERWRITE } else { PrefixFlags::empty() },
+            file_name_length: from.len() as u16,
+            dest_name_length: to.len() as u16,
+        };
+        let mut state = MoveState::new_move(prefix, from, to)?;
+        self.run_command(Prefixes::Move(prefix), &mut state)?;
+        match state.response {
+            Some(response) => response.into_result(),
+            None => Err(format!("Moving {} finished without a response.", from)),
+        }
+    }
+}
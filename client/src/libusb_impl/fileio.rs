@@ -1,25 +1,50 @@
-
 use commands::{FileContentStorer, FileRetriever};
-use std::fs::File;
-use std::io::{Read, Write};
+use libc;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
 
 pub struct StdFile {
     path : String,
     file : File,
+    /// The length a resumed file started with, which is all that is kept if
+    /// storing it fails.
+    kept : Option<u64>,
 }
 impl FileContentStorer for StdFile {
-    fn for_name(name : &str, _size : usize) -> Result<Self, String>  {
+    fn for_name(name : &str, _size : u64) -> Result<Self, String>  {
         println!("Creating new file store: {}", name);
         let file = File::create(name).map_err(|e| format!("Error creating file: {:?}", e))?;
 
         Ok(StdFile {
             path : name.to_owned(),
-            file
+            file,
+            kept : None,
         })
 
     }
+    fn resume(name : &str, offset : u64) -> Result<Self, String> {
+        println!("Resuming file store {} at offset {}", name, offset);
+        let mut file = OpenOptions::new().write(true).open(name).map_err(|e| format!("Error opening file: {:?}", e))?;
+        file.set_len(offset).map_err(|e| format!("File truncate err: {:?}", e))?;
+        file.seek(SeekFrom::Start(offset)).map_err(|e| format!("File seek err: {:?}", e))?;
+        Ok(StdFile {
+            path : name.to_owned(),
+            file,
+            kept : Some(offset),
+        })
+    }
     fn push_bytes(&mut self, buffer : &[u8]) -> Result<usize, String>  {
-        self.file.write(buffer).map_err(|e| format!("File write err: {:?}", e))
+        self.file.write_all(buffer).map(|_| buffer.len()).map_err(|e| format!("File write err: {:?}", e))
+    }
+    fn remove(self) -> Result<(), String> {
+        let StdFile { path, file, kept } = self;
+        if let Some(len) = kept {
+            return file.set_len(len).map_err(|e| format!("Error truncating file {}: {:?}", path, e));
+        }
+        drop(file);
+        std::fs::remove_file(&path).map_err(|e| format!("Error removing file {}: {:?}", path, e))
     }
 }
 
@@ -28,8 +53,9 @@ impl FileRetriever for StdFile {
         println!("Opening file {}", name);
         let file = File::open(name).map_err(|e| format!("Error opening file: {:?}", e))?;
         Ok(StdFile {
-            path : name.to_owned(), 
-            file
+            path : name.to_owned(),
+            file,
+            kept : None,
         })
     }
     fn name(&self) -> &str {
@@ -38,7 +64,54 @@ impl FileRetriever for StdFile {
     fn read_bytes(&mut self, buffer : &mut [u8]) -> Result<usize, String> {
         self.file.read(buffer).map_err(|e| format!("File read err: {:?}", e))
     }
-    fn len(&self) -> usize {
-        self.file.metadata().map(|mtd| mtd.len() as usize).unwrap_or(0)
+    fn seek(&mut self, offset : u64) -> Result<(), String> {
+        self.file.seek(SeekFrom::Start(offset)).map(|_| ()).map_err(|e| format!("File seek err: {:?}", e))
+    }
+    fn len(&self) -> u64 {
+        self.file.metadata().map(|mtd| mtd.len()).unwrap_or(0)
+    }
+}
+
+/// Lists everything under a local directory, as paths relative to it joined
+/// with `/` and whether each is a directory. Each directory comes before what
+/// is in it, and entries are sorted by name within a directory.
+pub fn walk_tree(root : &str) -> Result<Vec<(String, bool)>, String> {
+    let mut entries = Vec::new();
+    walk_into(Path::new(root), "", &mut entries)?;
+    Ok(entries)
+}
+
+fn walk_into(dir : &Path, prefix : &str, entries : &mut Vec<(String, bool)>) -> Result<(), String> {
+    let mut children = Vec::new();
+    for ent in dir.read_dir().map_err(|e| format!("Error reading directory {}: {:?}", dir.display(), e))? {
+        let ent = ent.map_err(|e| format!("Error reading directory {}: {:?}", dir.display(), e))?;
+        let name = ent.file_name().into_string().map_err(|name| format!("File name {:?} is not UTF-8.", name))?;
+        // Follows links, so a linked file is pushed with its content.
+        let is_dir = ent.path().is_dir();
+        children.push((name, is_dir));
+    }
+    children.sort();
+    for (name, is_dir) in children {
+        let relative = format!("{}{}", prefix, name);
+        entries.push((relative.clone(), is_dir));
+        if is_dir {
+            walk_into(&dir.join(&name), &format!("{}/", relative), entries)?;
+        }
     }
-}
\ No newline at end of file
+    Ok(())
+}
+
+/// Sets the modification time of a local file to the given seconds since the
+/// epoch. The access time is set to the same moment.
+pub fn set_modified(path : &str, modified : u64) -> Result<(), String> {
+    let c_path = CString::new(path).map_err(|_| format!("Path {} has a NUL byte in it.", path))?;
+    let time = libc::timeval {
+        tv_sec: modified as libc::time_t,
+        tv_usec: 0,
+    };
+    let times = [time, time];
+    if unsafe { libc::utimes(c_path.as_ptr(), times.as_ptr()) } != 0 {
+        return Err(format!("Error setting the modification time of {}: {:?}", path, std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
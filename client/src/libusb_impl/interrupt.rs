@@ -0,0 +1,25 @@
+use libc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        // The user pressed Ctrl-C again while the abort was in progress.
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// Catches Ctrl-C so that the transfer in progress can be aborted instead of
+/// leaving the server halfway through a command. A second Ctrl-C exits
+/// straight away.
+pub fn catch_interrupts() {
+    unsafe {
+        libc::signal(libc::SIGINT, on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+/// Checks whether Ctrl-C has been pressed since `catch_interrupts` was called.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
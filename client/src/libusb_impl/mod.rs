@@ -1,3 +1,5 @@
 pub mod fileio;
 
-pub mod usbcom;
\ No newline at end of file
+pub mod interrupt;
+
+pub mod usbcom;
@@ -1,6 +1,10 @@
+use commands::client_handshake;
 use interface::ClientDevice;
+use libusb_impl::interrupt;
 use libusb::{Context, Device, DeviceDescriptor, DeviceHandle, TransferType};
-use nxusb::prefixes::{CommandPrefix, Prefixes};
+use nxusb::frame::{AbortPolicy, Frame, FrameDecoder, FrameEncoder, MAX_FRAME_PAYLOAD};
+use nxusb::handshake;
+use nxusb::prefixes::HandshakePrefix;
 use std::time::Duration;
 
 #[derive(Debug, Copy, Clone)]
@@ -20,6 +24,13 @@ pub struct UsbClient<'a> {
     device_handle: DeviceHandle<'a>,
     read_endpoint: ReadEndpoint,
     write_endpoint: WriteEndpoint,
+    /// The handshake the server answered with when the client connected.
+    pub server: Option<HandshakePrefix>,
+    /// What the server does with a partial file when Ctrl-C aborts the
+    /// transfer.
+    pub abort_policy: AbortPolicy,
+    encoder: FrameEncoder,
+    decoder: FrameDecoder,
 }
 
 impl<'a> UsbClient<'a> {
@@ -29,28 +40,57 @@ impl<'a> UsbClient<'a> {
         device_handle.reset().map_err(|e| format!("Found reset err: {:?}", e))?;
         device_handle.set_active_configuration(read_endpoint.0.config).map_err(|e| format!("Could not set active config: {:?}", e))?;
         device_handle.claim_interface(read_endpoint.0.iface).map_err(|e| format!("Could not claim iface {}: {:?}", read_endpoint.0.iface, e))?;
-        Ok(UsbClient {
+        let mut client = UsbClient {
             device_handle, 
             read_endpoint, 
-            write_endpoint
-        })
+            write_endpoint,
+            server: None,
+            abort_policy: AbortPolicy::Delete,
+            encoder: FrameEncoder::new(),
+            decoder: FrameDecoder::new(),
+        };
+        let server = client.handshake()?;
+        client.server = Some(server);
+        println!(
+            "Connected to server build {} (protocol version {}).",
+            handshake::format_build_version(server.build_version),
+            server.protocol_version
+        );
+        Ok(client)
     }
 }
 
 const CLIENT_BLOCK_SIZE : usize = 1024 ;
 impl<'a> ClientDevice for UsbClient<'a> {
-    fn push_prefix(&mut self, prefix: Prefixes) -> Result<usize, String> {
-        let bts = prefix.serialize();
-        push_bytes(&mut self.device_handle, &self.write_endpoint, &bts)
+    fn push_frame(&mut self, frame: Frame) -> Result<usize, String> {
+        let bts = self.encoder.encode(frame);
+        for chunk in bts.chunks(CLIENT_BLOCK_SIZE) {
+            push_bytes(&mut self.device_handle, &self.write_endpoint, chunk)?;
+        }
+        Ok(bts.len())
     }
-    fn block_size(&self) -> usize {
-        CLIENT_BLOCK_SIZE
+    fn pull_frame(&mut self) -> Result<Frame, String> {
+        let mut buffer = [0u8; CLIENT_BLOCK_SIZE];
+        loop {
+            if let Some(frame) = self.decoder.next_frame()? {
+                return Ok(frame);
+            }
+            let read = pull_bytes(&mut self.device_handle, &self.read_endpoint, &mut buffer)?;
+            self.decoder.push_bytes(&buffer[0..read]);
+        }
     }
-    fn pull_block(&mut self, buffer: &mut [u8]) -> Result<usize, String> {
-        pull_bytes(&mut self.device_handle, &self.read_endpoint, buffer)
+    fn frame_payload(&self) -> usize {
+        match self.server {
+            Some(ref server) => handshake::negotiate_frame_payload(&client_handshake(), server),
+            None => MAX_FRAME_PAYLOAD,
+        }
     }
-    fn push_block(&mut self, bytes: &[u8]) -> Result<usize, String> {
-        push_bytes(&mut self.device_handle, &self.write_endpoint, &bytes)
+    fn abort_requested(&self) -> Option<AbortPolicy> {
+        if interrupt::interrupted() {
+            Some(self.abort_policy)
+        } else {
+            None
+        }
     }
 }
 
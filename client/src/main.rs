@@ -1,16 +1,30 @@
+extern crate libc;
 extern crate libusb;
 extern crate nxusb;
 
-use nxusb::prefixes::{Prefixes, ReadPrefix, WritePrefix};
+use nxusb::checksum::ChecksumKind;
+use nxusb::delta;
+use nxusb::frame::AbortPolicy;
+use nxusb::hashing;
+use nxusb::listing::{EntryKind, ListEntry};
+use nxusb::handshake::{self, FEATURE_ABORT, FEATURE_DELTA, FEATURE_FS_INFO, FEATURE_HASH, FEATURE_LIST, FEATURE_MKDIR, FEATURE_MOVE, FEATURE_OVERWRITE_POLICY, FEATURE_RANGE, FEATURE_REMOVE, FEATURE_RESUME, FEATURE_STAT, SUPPORTED_FEATURES};
+use nxusb::prefixes::{OverwritePolicy, PrefixFlags, Prefixes, ProbePrefix, ReadPrefix, WritePrefix};
+use nxusb::response::ResponseCode;
+use std::collections::HashSet;
+use std::path::Path;
 
 pub mod interface;
 use interface::ClientDevice;
 
 pub mod commands;
-use commands::{ClientCommandState, ReadState, WriteState, FileContentStorer, FileRetriever};
+use commands::{ProbeState, ReadState, WriteState, FileRetriever};
+
+pub mod sync;
+use sync::{FileState, Side, SyncAction};
 
 pub mod libusb_impl;
-use libusb_impl::fileio::StdFile;
+use libusb_impl::fileio::{self, StdFile};
+use libusb_impl::interrupt;
 use libusb_impl::usbcom::UsbClient;
 
 pub mod test_impl;
@@ -18,17 +32,132 @@ pub mod test_impl;
 const SWITCH_VENDOR_ID: u16 = 1406;
 const SWITCH_PRODUCT_ID: u16 = 12288;
 
-const USAGE: &str = "Usage: nxusb_client [--push | --pull] [PATH ON SWITCH] [PATH ON COMPUTER]";
+/// The smallest existing file a transfer tries to update with a delta rather
+/// than by sending all of it again.
+const DELTA_MIN_LENGTH: u64 = 1024 * 1024;
+
+const USAGE: &str = "Usage: nxusb_client [--resume] [--push [--parents] [--overwrite=POLICY] | --pull] [PATH ON SWITCH] [PATH ON COMPUTER]
+       nxusb_client [--resume] --push -r [--overwrite=POLICY] [DIRECTORY ON SWITCH] [DIRECTORY ON COMPUTER]
+       nxusb_client [--resume] --pull -r [DIRECTORY ON SWITCH] [DIRECTORY ON COMPUTER]
+       nxusb_client --pull [--offset N] [--length M] [PATH ON SWITCH] [PATH ON COMPUTER]
+       nxusb_client ls [DIRECTORY ON SWITCH]
+       nxusb_client stat [PATH ON SWITCH]
+       nxusb_client df [PATH ON SWITCH]
+       nxusb_client mkdir [-p] [DIRECTORY ON SWITCH]
+       nxusb_client rm [-r] [PATH ON SWITCH]
+       nxusb_client rmdir [DIRECTORY ON SWITCH]
+       nxusb_client mv [-f] [PATH ON SWITCH] [NEW PATH ON SWITCH]
+       nxusb_client hash [-r] [--crc32] [PATH ON SWITCH] [MANIFEST]
+       nxusb_client verify [MANIFEST] [DIRECTORY ON SWITCH]
+       nxusb_client sync [--delete] [--checksum] [DIRECTORY ON COMPUTER] [DIRECTORY ON SWITCH]
+       nxusb_client sync --two-way [--prefer local|remote] [DIRECTORY ON COMPUTER] [DIRECTORY ON SWITCH]
+
+POLICY is what a push does when the file is already on the Switch: fail (the
+default), overwrite, skip (if it has the same size and content) or rename (to
+a free name with a numbered suffix).
+
+sync pushes the files that are missing on the Switch or older there, or with
+--checksum those whose content differs. --delete also removes what is only on
+the Switch. sync --two-way copies changes both ways; files changed on both
+sides are left alone unless --prefer says which side to keep.
+
+hash prints the SHA-256 (or with --crc32 the CRC-32) of files on the Switch
+without copying them, in the format of sha256sum, and writes the lines to
+MANIFEST if one is given. verify checks the files in such a manifest, with
+names relative to DIRECTORY ON SWITCH unless they are full Switch paths.";
 
 fn main() -> Result<(), String> {
-    let mut args = std::env::args();
-    if args.len() != 4 {
+    let all_args: Vec<String> = std::env::args().skip(1).collect();
+    let mut resume = false;
+    let mut parents = false;
+    let mut recursive = false;
+    let mut force = false;
+    let mut delete = false;
+    let mut by_hash = false;
+    let mut two_way = false;
+    let mut crc32 = false;
+    let mut prefer = None;
+    let mut policy = None;
+    let mut offset = None;
+    let mut length = None;
+    let mut args = Vec::new();
+    let mut arg_iter = all_args.iter();
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--resume" => resume = true,
+            "-p" | "--parents" => parents = true,
+            "-r" | "--recursive" => recursive = true,
+            "-f" | "--force" => force = true,
+            "--delete" => delete = true,
+            "-c" | "--checksum" => by_hash = true,
+            "--two-way" => two_way = true,
+            "--crc32" => crc32 = true,
+            "--prefer" => {
+                let name = arg_iter.next().map(|name| name.as_str()).unwrap_or("");
+                prefer = Some(Side::parse(name).ok_or(format!("--prefer takes local or remote, not {:?}.", name))?);
+            }
+            _ if arg.starts_with("--overwrite=") => {
+                let name = &arg["--overwrite=".len()..];
+                policy = Some(OverwritePolicy::parse(name).ok_or(format!("Unknown overwrite policy {}.", name))?);
+            }
+            "--offset" => offset = Some(parse_number(arg_iter.next())?),
+            "--length" => length = Some(parse_number(arg_iter.next())?),
+            _ => args.push(arg),
+        }
+    }
+    if args.len() == 2 && args[0] == "ls" {
+        return list(args[1]);
+    }
+    if args.len() == 2 && args[0] == "stat" {
+        return stat(args[1]);
+    }
+    if args.len() == 2 && args[0] == "df" {
+        return fs_info(args[1]);
+    }
+    if args.len() == 2 && args[0] == "mkdir" {
+        return make_dir(args[1], parents);
+    }
+    if args.len() == 2 && (args[0] == "rm" || args[0] == "rmdir") {
+        return remove(args[1], recursive, args[0] == "rmdir");
+    }
+    if args.len() == 3 && args[0] == "mv" {
+        return move_path(args[1], args[2], force);
+    }
+    if (args.len() == 2 || args.len() == 3) && args[0] == "hash" {
+        let kind = if crc32 { ChecksumKind::Crc32 } else { ChecksumKind::Sha256 };
+        return hash(args[1], recursive, kind, args.get(2).cloned());
+    }
+    if (args.len() == 2 || args.len() == 3) && args[0] == "verify" {
+        return verify(args[1], args.get(2).cloned());
+    }
+    if crc32 {
         println!("{}", USAGE);
-        return Err(format!("Could not parse args: {:?}", args));
+        return Err("--crc32 only works with hash.".to_owned());
+    }
+    if args.len() == 3 && args[0] == "sync" {
+        if two_way {
+            if delete || by_hash {
+                println!("{}", USAGE);
+                return Err("--delete and --checksum do not work with --two-way.".to_owned());
+            }
+            return sync_both(args[1], args[2], prefer);
+        }
+        if prefer.is_some() {
+            println!("{}", USAGE);
+            return Err("--prefer only works with --two-way.".to_owned());
+        }
+        return sync(args[1], args[2], delete, by_hash);
+    }
+    if delete || by_hash || two_way || prefer.is_some() {
+        println!("{}", USAGE);
+        return Err("--delete, --checksum, --two-way and --prefer only work with sync.".to_owned());
+    }
+    if args.len() != 3 {
+        println!("{}", USAGE);
+        return Err(format!("Could not parse args: {:?}", all_args));
     }
 
-    let _ = args.next();
-    let push_string = parse_arg(&mut args)?;
+    let push_string = args[0];
 
     let should_push = if push_string == "--pull" {
         false
@@ -36,86 +165,936 @@ fn main() -> Result<(), String> {
         true
     } else {
         println!("{}", USAGE);
-        return Err(format!("Could not parse args: {:?}", args));
+        return Err(format!("Could not parse args: {:?}", all_args));
     };
-    let switch_path = parse_arg(&mut args)?;
-    let computer_path = parse_arg(&mut args)?;
+    let ranged = offset.is_some() || length.is_some();
+    if ranged && (should_push || resume) {
+        println!("{}", USAGE);
+        return Err("--offset and --length only work with --pull, and not with --resume.".to_owned());
+    }
+    if parents && !should_push {
+        println!("{}", USAGE);
+        return Err("--parents only works with --push and mkdir.".to_owned());
+    }
+    if policy.is_some() && !should_push {
+        println!("{}", USAGE);
+        return Err("--overwrite only works with --push.".to_owned());
+    }
+    let policy = policy.unwrap_or(OverwritePolicy::Fail);
+    if recursive && ranged {
+        println!("{}", USAGE);
+        return Err("--recursive does not work with --offset and --length.".to_owned());
+    }
+    if force {
+        println!("{}", USAGE);
+        return Err("--force only works with mv.".to_owned());
+    }
+    let switch_path = args[1];
+    let computer_path = args[2];
 
     let mut usb_ctx: libusb::Context =
         libusb::Context::new().map_err(|e| format!("Usb context create err: {:?}", e))?;
 
     let mut nx_device =
         UsbClient::from_vendor_product(&mut usb_ctx, SWITCH_VENDOR_ID, SWITCH_PRODUCT_ID)?;
-    if should_push {
-        copy_to_switch(&mut nx_device, &switch_path, &computer_path).map(|_| ())
+    let server_features = nx_device.server.map(|h| h.features).unwrap_or(0);
+    let checksum = handshake::negotiate_checksum(SUPPORTED_FEATURES, server_features);
+    let resume = if resume && server_features & FEATURE_RESUME == 0 {
+        println!("The server cannot resume transfers; sending the whole file instead.");
+        false
+    } else {
+        resume
+    };
+    if server_features & FEATURE_ABORT != 0 {
+        // A resumable transfer keeps what it has so far, so that it can be
+        // picked up again.
+        nx_device.abort_policy = if resume { AbortPolicy::Keep } else { AbortPolicy::Delete };
+        interrupt::catch_interrupts();
+    }
+    if ranged {
+        if server_features & FEATURE_RANGE == 0 {
+            return Err("The server cannot read part of a file; please update it.".to_owned());
+        }
+        let offset = offset.unwrap_or(0);
+        let read = nx_device.read_range::<StdFile>(switch_path, computer_path, offset, length.unwrap_or(u64::MAX), checksum)?;
+        println!("Read {} bytes of {} starting at byte {}.", read, switch_path, offset);
+        Ok(())
+    } else if should_push {
+        let needs_feature = policy == OverwritePolicy::SkipIdentical || policy == OverwritePolicy::Rename;
+        if needs_feature && server_features & FEATURE_OVERWRITE_POLICY == 0 {
+            return Err("The server cannot skip or rename existing files; please update it.".to_owned());
+        }
+        // A resumed push only sends what is missing, and a skipping one may
+        // send nothing, so only a push of everything is checked for room.
+        if !resume && policy != OverwritePolicy::SkipIdentical && server_features & FEATURE_FS_INFO != 0 {
+            let sizes = if recursive {
+                fileio::walk_tree(computer_path)?
+                    .into_iter()
+                    .filter(|&(_, is_dir)| !is_dir)
+                    .map(|(relative, _)| std::fs::metadata(Path::new(&computer_path).join(relative)).map(|mtd| mtd.len()).unwrap_or(0))
+                    .collect()
+            } else {
+                vec![StdFile::open_file(computer_path)?.len()]
+            };
+            check_room(&mut nx_device, switch_path, &sizes)?;
+        }
+        if recursive {
+            push_tree(&mut nx_device, switch_path, computer_path, checksum, resume, policy)
+        } else {
+            copy_to_switch(&mut nx_device, switch_path, computer_path, checksum, resume, parents, policy).map(|_| ())
+        }
+    } else if recursive {
+        if server_features & FEATURE_LIST == 0 {
+            return Err("The server cannot list directories; please update it.".to_owned());
+        }
+        pull_tree(&mut nx_device, switch_path, computer_path, checksum, resume)
     } else {
-        copy_from_switch(&mut nx_device, &switch_path, &computer_path).map(|_| ())
+        copy_from_switch(&mut nx_device, switch_path, computer_path, checksum, resume).map(|_| ())
     }
 }
 
-#[inline]
-fn parse_arg(args: &mut std::env::Args) -> Result<String, String> {
-    match args.next() {
-        Some(c) => Ok(c),
-        _ => {
-            println!("{}", USAGE);
-            return Err(format!("Could not parse args: {:?}", args));
+/// Connects to the Switch for a command that needs the given feature,
+/// failing with a message naming what the server cannot do otherwise.
+fn connect<'a>(usb_ctx: &'a mut libusb::Context, feature: u16, missing: &str) -> Result<UsbClient<'a>, String> {
+    let nx_device = UsbClient::from_vendor_product(usb_ctx, SWITCH_VENDOR_ID, SWITCH_PRODUCT_ID)?;
+    let server_features = nx_device.server.map(|h| h.features).unwrap_or(0);
+    if server_features & feature != feature {
+        return Err(format!("The server cannot {}; please update it.", missing));
+    }
+    Ok(nx_device)
+}
+
+/// Prints the entries of a directory on the Switch like `ls -l`.
+fn list(switch_path: &str) -> Result<(), String> {
+    let mut usb_ctx: libusb::Context =
+        libusb::Context::new().map_err(|e| format!("Usb context create err: {:?}", e))?;
+    let mut nx_device = connect(&mut usb_ctx, FEATURE_LIST, "list directories")?;
+    let server_features = nx_device.server.map(|h| h.features).unwrap_or(0);
+    let checksum = handshake::negotiate_checksum(SUPPORTED_FEATURES, server_features);
+    let entries = nx_device.list_dir(switch_path, false, checksum)?;
+    println!("total {}", entries.len());
+    for entry in &entries {
+        println!("{}", commands::format_list_entry(entry));
+    }
+    Ok(())
+}
+
+/// Prints what is at a path on the Switch.
+fn stat(switch_path: &str) -> Result<(), String> {
+    let mut usb_ctx: libusb::Context =
+        libusb::Context::new().map_err(|e| format!("Usb context create err: {:?}", e))?;
+    let mut nx_device = connect(&mut usb_ctx, FEATURE_STAT, "describe paths")?;
+    match nx_device.stat(switch_path, PrefixFlags::FOLLOW_LINKS)? {
+        Some(entry) => {
+            let entry = ListEntry {
+                name: switch_path.to_owned(),
+                ..entry
+            };
+            println!("{}", commands::format_list_entry(&entry));
+            Ok(())
         }
+        None => Err(format!("Nothing exists at {} on the Switch.", switch_path)),
     }
 }
 
-fn copy_from_switch(
+/// Prints the size and free space of the filesystem a path on the Switch is
+/// on.
+fn fs_info(switch_path: &str) -> Result<(), String> {
+    let mut usb_ctx: libusb::Context =
+        libusb::Context::new().map_err(|e| format!("Usb context create err: {:?}", e))?;
+    let mut nx_device = connect(&mut usb_ctx, FEATURE_FS_INFO, "describe filesystems")?;
+    let info = nx_device.fs_info(switch_path)?;
+    println!("{}", commands::format_fs_info(switch_path, &info));
+    Ok(())
+}
+
+/// Creates a directory on the Switch.
+fn make_dir(switch_path: &str, parents: bool) -> Result<(), String> {
+    let mut usb_ctx: libusb::Context =
+        libusb::Context::new().map_err(|e| format!("Usb context create err: {:?}", e))?;
+    let mut nx_device = connect(&mut usb_ctx, FEATURE_MKDIR, "create directories")?;
+    nx_device.make_dir(switch_path, parents)?;
+    println!("Created directory {}.", switch_path);
+    Ok(())
+}
+
+/// Deletes a path on the Switch, printing what happened to each entry.
+fn remove(switch_path: &str, recursive: bool, dir: bool) -> Result<(), String> {
+    if recursive && dir {
+        println!("{}", USAGE);
+        return Err("rmdir only removes empty directories; use rm -r instead.".to_owned());
+    }
+    let mut usb_ctx: libusb::Context =
+        libusb::Context::new().map_err(|e| format!("Usb context create err: {:?}", e))?;
+    let mut nx_device = connect(&mut usb_ctx, FEATURE_REMOVE, "delete files")?;
+    let server_features = nx_device.server.map(|h| h.features).unwrap_or(0);
+    let checksum = handshake::negotiate_checksum(SUPPORTED_FEATURES, server_features);
+    let records = if dir {
+        nx_device.remove_dir(switch_path, checksum)?
+    } else {
+        nx_device.remove(switch_path, recursive, checksum)?
+    };
+    let mut failed = 0;
+    for record in &records {
+        if record.is_removed() {
+            println!("Removed {}", record.path);
+        } else {
+            failed += 1;
+            println!("Could not remove {}: {}", record.path, record.message);
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} entries could not be removed.", failed, records.len()));
+    }
+    Ok(())
+}
+
+/// Moves or renames a path on the Switch.
+fn move_path(from: &str, to: &str, force: bool) -> Result<(), String> {
+    let mut usb_ctx: libusb::Context =
+        libusb::Context::new().map_err(|e| format!("Usb context create err: {:?}", e))?;
+    let mut nx_device = connect(&mut usb_ctx, FEATURE_MOVE, "move files")?;
+    nx_device.move_path(from, to, force)?;
+    println!("Moved {} to {}.", from, to);
+    Ok(())
+}
+
+/// Prints the digest of a file on the Switch, or with `recursive` of every
+/// file under a directory, in the format of `sha256sum`. Files in a tree are
+/// named relative to it. With a manifest path the lines are written there too,
+/// so that `verify` or `sha256sum -c` can check copies against them later.
+fn hash(switch_path: &str, recursive: bool, kind: ChecksumKind, manifest: Option<&String>) -> Result<(), String> {
+    let mut usb_ctx: libusb::Context =
+        libusb::Context::new().map_err(|e| format!("Usb context create err: {:?}", e))?;
+    let mut nx_device = connect(&mut usb_ctx, FEATURE_HASH, "hash files")?;
+    let records = nx_device.hash(switch_path, recursive, kind)?;
+    let base = format!("{}/", switch_path.trim_end_matches('/'));
+    let mut lines = String::new();
+    let mut failed = 0;
+    for record in &records {
+        if !record.is_hashed() {
+            failed += 1;
+            eprintln!("Could not hash {}: {}", record.name, record.message);
+            continue;
+        }
+        let name = if recursive && record.name.starts_with(&base) {
+            &record.name[base.len()..]
+        } else {
+            record.name.as_str()
+        };
+        let line = hashing::manifest_line(&record.digest, name);
+        println!("{}", line);
+        lines.push_str(&line);
+        lines.push('\n');
+    }
+    if let Some(manifest) = manifest {
+        std::fs::write(manifest, lines).map_err(|e| format!("Could not write manifest {}: {:?}", manifest, e))?;
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} files could not be hashed.", failed, records.len()));
+    }
+    Ok(())
+}
+
+/// Checks the files named in a `sha256sum` manifest against their digests on
+/// the Switch, printing `OK` or `FAILED` for each like `sha256sum -c`. Names
+/// that are not full Switch paths are taken as relative to `switch_dir`. A
+/// manifest of 8 digit CRC-32 digests is checked with CRC-32.
+fn verify(manifest: &str, switch_dir: Option<&String>) -> Result<(), String> {
+    let content = std::fs::read_to_string(manifest).map_err(|e| format!("Could not read manifest {}: {:?}", manifest, e))?;
+    let mut entries = Vec::new();
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let (digest, name) = hashing::parse_manifest_line(line)?;
+        let kind = match digest.len() {
+            32 => ChecksumKind::Sha256,
+            4 => ChecksumKind::Crc32,
+            len => return Err(format!("Digests of {} bytes in {} are not SHA-256 or CRC-32.", len, manifest)),
+        };
+        let path = if name.contains(":/") {
+            name.clone()
+        } else {
+            match switch_dir {
+                Some(dir) => format!("{}/{}", dir.trim_end_matches('/'), name.trim_start_matches("./")),
+                None => return Err(format!("{} is not a full Switch path; give the directory the manifest is for.", name)),
+            }
+        };
+        entries.push((name, path, kind, digest));
+    }
+    let mut usb_ctx: libusb::Context =
+        libusb::Context::new().map_err(|e| format!("Usb context create err: {:?}", e))?;
+    let mut nx_device = connect(&mut usb_ctx, FEATURE_HASH, "hash files")?;
+    let mut failed = 0;
+    for (name, path, kind, digest) in &entries {
+        let matched = match nx_device.hash(path, false, *kind) {
+            Ok(records) => match records.first() {
+                Some(record) if record.is_hashed() => record.digest == *digest,
+                Some(record) => {
+                    eprintln!("Could not hash {}: {}", path, record.message);
+                    false
+                }
+                None => false,
+            },
+            Err(e) => {
+                eprintln!("Could not hash {}: {}", path, e);
+                false
+            }
+        };
+        if matched {
+            println!("{}: OK", name);
+        } else {
+            failed += 1;
+            println!("{}: FAILED", name);
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} files did not match.", failed, entries.len()));
+    }
+    Ok(())
+}
+
+/// Makes a directory on the Switch match a local one, pushing only the files
+/// that are new or changed. With `delete`, also removes whatever is only on
+/// the Switch. Carries on past files that fail, and prints a summary at the
+/// end.
+fn sync(computer_dir: &str, switch_dir: &str, delete: bool, by_hash: bool) -> Result<(), String> {
+    let mut usb_ctx: libusb::Context =
+        libusb::Context::new().map_err(|e| format!("Usb context create err: {:?}", e))?;
+    let mut nx_device = connect(&mut usb_ctx, FEATURE_LIST | FEATURE_STAT | FEATURE_MKDIR, "sync directories")?;
+    let server_features = nx_device.server.map(|h| h.features).unwrap_or(0);
+    let checksum = handshake::negotiate_checksum(SUPPORTED_FEATURES, server_features);
+    if by_hash && (checksum == ChecksumKind::None || server_features & FEATURE_RESUME == 0) {
+        return Err("The server cannot checksum files on request; please update it.".to_owned());
+    }
+    if delete && server_features & FEATURE_REMOVE == 0 {
+        return Err("The server cannot delete files; please update it.".to_owned());
+    }
+    if server_features & FEATURE_ABORT != 0 {
+        nx_device.abort_policy = AbortPolicy::Delete;
+        interrupt::catch_interrupts();
+    }
+    let switch_dir = switch_dir.trim_end_matches('/');
+    let local = sync::local_tree(computer_dir)?;
+    let remote = remote_tree(&mut nx_device, switch_dir, checksum)?;
+    let plan = sync::plan_push(&local, &remote, by_hash);
+    let mut unchanged = plan.unchanged;
+    let mut dirs = 0;
+    let mut files = 0;
+    let mut bytes = 0;
+    let mut deleted = 0;
+    let mut failures = Vec::new();
+    for relative in &plan.dirs {
+        let switch_path = format!("{}/{}", switch_dir, relative);
+        match nx_device.make_dir(&switch_path, true) {
+            Ok(()) => dirs += 1,
+            Err(e) => {
+                println!("Could not create {}: {}", switch_path, e);
+                failures.push(switch_path);
+            }
+        }
+    }
+    for &(ref relative, state) in &plan.files {
+        if interrupt::interrupted() {
+            break;
+        }
+        let switch_path = format!("{}/{}", switch_dir, relative);
+        let computer_path = Path::new(computer_dir).join(relative);
+        let computer_path = computer_path.to_string_lossy();
+        if state == FileState::CheckHash {
+            match same_content(&mut nx_device, &switch_path, &computer_path, checksum) {
+                Ok(true) => {
+                    unchanged += 1;
+                    continue;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    println!("Could not compare {}: {}", computer_path, e);
+                    failures.push(switch_path);
+                    continue;
+                }
+            }
+        }
+        match copy_to_switch(&mut nx_device, &switch_path, &computer_path, checksum, false, true, OverwritePolicy::Overwrite) {
+            Ok(len) => {
+                files += 1;
+                bytes += len;
+            }
+            Err(e) => {
+                println!("Could not push {}: {}", computer_path, e);
+                failures.push(switch_path);
+            }
+        }
+    }
+    if delete && !interrupt::interrupted() {
+        for relative in &plan.extras {
+            let switch_path = format!("{}/{}", switch_dir, relative);
+            match nx_device.remove(&switch_path, true, checksum) {
+                Ok(records) => {
+                    for record in &records {
+                        if record.is_removed() {
+                            deleted += 1;
+                        } else {
+                            println!("Could not remove {}: {}", record.path, record.message);
+                            failures.push(record.path.clone());
+                        }
+                    }
+                }
+                Err(e) => {
+                    println!("Could not remove {}: {}", switch_path, e);
+                    failures.push(switch_path);
+                }
+            }
+        }
+    } else if !plan.extras.is_empty() {
+        println!("{} entries are only on the Switch; --delete would remove them.", plan.extras.len());
+    }
+    println!(
+        "Synced {} to {}: pushed {} files ({} bytes), created {} directories, deleted {} entries, {} unchanged; {} failed.",
+        computer_dir,
+        switch_dir,
+        files,
+        bytes,
+        dirs,
+        deleted,
+        unchanged,
+        failures.len()
+    );
+    for failed in &failures {
+        println!("  failed: {}", failed);
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{} entries could not be synced.", failures.len()))
+    }
+}
+
+/// Copies what changed on either side of a pair of directories to the other
+/// side, using the state kept from the last sync to tell which side changed.
+/// Entries changed on both sides are reported and left alone, unless
+/// `prefer` says which side wins. Carries on past entries that fail, and
+/// prints a summary at the end.
+fn sync_both(computer_dir: &str, switch_dir: &str, prefer: Option<Side>) -> Result<(), String> {
+    let mut usb_ctx: libusb::Context =
+        libusb::Context::new().map_err(|e| format!("Usb context create err: {:?}", e))?;
+    let mut nx_device = connect(&mut usb_ctx, FEATURE_LIST | FEATURE_STAT | FEATURE_MKDIR | FEATURE_REMOVE, "sync directories both ways")?;
+    let server_features = nx_device.server.map(|h| h.features).unwrap_or(0);
+    let checksum = handshake::negotiate_checksum(SUPPORTED_FEATURES, server_features);
+    if server_features & FEATURE_ABORT != 0 {
+        nx_device.abort_policy = AbortPolicy::Delete;
+        interrupt::catch_interrupts();
+    }
+    let switch_dir = switch_dir.trim_end_matches('/');
+    std::fs::create_dir_all(computer_dir).map_err(|e| format!("Error creating directory {}: {:?}", computer_dir, e))?;
+    let base = sync::load_state(computer_dir, switch_dir)?;
+    let local = sync::local_tree(computer_dir)?;
+    let remote = remote_tree(&mut nx_device, switch_dir, checksum)?;
+    let steps = sync::plan_sync(&local, &remote, &base, prefer);
+    let mut unsettled: HashSet<String> = steps.iter().map(|step| step.name.clone()).collect();
+    let mut pushed = 0;
+    let mut pulled = 0;
+    let mut removed = 0;
+    let mut conflicts = Vec::new();
+    let mut failures = Vec::new();
+    let is_removal = |step: &&sync::SyncStep| step.action == SyncAction::RemoveRemote || step.action == SyncAction::RemoveLocal;
+    // Copies go in name order so that directories are made before what is in
+    // them, and removals the other way round so that they are emptied first.
+    let copies = steps.iter().filter(|step| !is_removal(step));
+    let removals = steps.iter().rev().filter(|step| is_removal(step));
+    for step in copies.chain(removals) {
+        if interrupt::interrupted() {
+            break;
+        }
+        let switch_path = format!("{}/{}", switch_dir, step.name);
+        let computer_path = Path::new(computer_dir).join(&step.name);
+        let computer_path = computer_path.to_string_lossy();
+        let remote_entry = remote.iter().find(|entry| entry.name == step.name);
+        let is_dir = match step.action {
+            SyncAction::Pull | SyncAction::RemoveLocal => remote_entry.map(|entry| entry.kind == EntryKind::Dir),
+            _ => local.iter().find(|entry| entry.name == step.name).map(|entry| entry.is_dir),
+        };
+        let is_dir = is_dir.unwrap_or_else(|| base.get(&step.name).map(|&(snapshot, _)| snapshot.is_dir).unwrap_or(false));
+        if step.conflict {
+            if step.action == SyncAction::Conflict {
+                println!("{} changed on both the computer and the Switch; leaving both alone.", step.name);
+                conflicts.push(step.name.clone());
+                continue;
+            }
+            let kept = if step.action == SyncAction::Push || step.action == SyncAction::RemoveRemote { "computer's" } else { "Switch's" };
+            println!("{} changed on both the computer and the Switch; keeping the {} version.", step.name, kept);
+        }
+        let result = match step.action {
+            SyncAction::Push if is_dir => nx_device.make_dir(&switch_path, true),
+            SyncAction::Push => {
+                copy_to_switch(&mut nx_device, &switch_path, &computer_path, checksum, false, true, OverwritePolicy::Overwrite).map(|_| pushed += 1)
+            }
+            SyncAction::Pull if is_dir => {
+                std::fs::create_dir_all(&*computer_path).map_err(|e| format!("Error creating directory {}: {:?}", computer_path, e))
+            }
+            SyncAction::Pull => pull_into(&mut nx_device, &switch_path, &computer_path, remote_entry, checksum).map(|_| pulled += 1),
+            SyncAction::RemoveRemote => {
+                let records = if is_dir {
+                    nx_device.remove_dir(&switch_path, checksum)
+                } else {
+                    nx_device.remove(&switch_path, false, checksum)
+                };
+                match records?.into_iter().find(|record| !record.is_removed()) {
+                    Some(record) => Err(record.message),
+                    None => {
+                        removed += 1;
+                        Ok(())
+                    }
+                }
+            }
+            SyncAction::RemoveLocal => {
+                let result = if is_dir {
+                    std::fs::remove_dir(&*computer_path)
+                } else {
+                    std::fs::remove_file(&*computer_path)
+                };
+                result
+                    .map(|_| removed += 1)
+                    .map_err(|e| format!("Error removing {}: {:?}", computer_path, e))
+            }
+            SyncAction::Conflict => continue,
+        };
+        match result {
+            Ok(()) => {
+                unsettled.remove(&step.name);
+            }
+            Err(e) => {
+                println!("Could not sync {}: {}", step.name, e);
+                failures.push(step.name.clone());
+            }
+        }
+    }
+    let local = sync::local_tree(computer_dir)?;
+    let remote = remote_tree(&mut nx_device, switch_dir, checksum)?;
+    sync::save_state(computer_dir, switch_dir, &sync::settle(&local, &remote, &base, &unsettled))?;
+    println!(
+        "Synced {} and {}: pushed {} files, pulled {} files, removed {} entries; {} conflicts, {} failed.",
+        computer_dir,
+        switch_dir,
+        pushed,
+        pulled,
+        removed,
+        conflicts.len(),
+        failures.len()
+    );
+    for failed in &failures {
+        println!("  failed: {}", failed);
+    }
+    for conflict in &conflicts {
+        println!("  conflict: {}", conflict);
+    }
+    if !failures.is_empty() {
+        Err(format!("{} entries could not be synced.", failures.len()))
+    } else if !conflicts.is_empty() {
+        Err(format!(
+            "{} entries changed on both sides; sync again with --prefer local or --prefer remote to pick which to keep.",
+            conflicts.len()
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Lists everything under a directory on the Switch for a sync, creating the
+/// directory first if it is not there yet.
+fn remote_tree(client: &mut UsbClient, switch_dir: &str, checksum: ChecksumKind) -> Result<Vec<ListEntry>, String> {
+    match client.stat(switch_dir, PrefixFlags::FOLLOW_LINKS)? {
+        Some(ref entry) if entry.kind == EntryKind::Dir => client.list_dir(switch_dir, true, checksum),
+        Some(_) => Err(format!("{} on the Switch is not a directory.", switch_dir)),
+        None => client.make_dir(switch_dir, true).map(|_| Vec::new()),
+    }
+}
+
+/// Pulls one file of a sync, making its local directory if need be and
+/// giving it the modification time it has on the Switch.
+fn pull_into(
     client: &mut UsbClient,
     switch_path: &str,
     computer_path: &str,
-) -> Result<usize, String> {
-    let prefix = ReadPrefix {
-        flags: 0,
+    entry: Option<&ListEntry>,
+    checksum: ChecksumKind,
+) -> Result<(), String> {
+    if let Some(parent) = Path::new(computer_path).parent() {
+        std::fs::create_dir_all(parent).map_err(|e| format!("Error creating directory {}: {:?}", parent.display(), e))?;
+    }
+    copy_from_switch(client, switch_path, computer_path, checksum, false)?;
+    match entry {
+        Some(entry) if entry.modified != 0 => fileio::set_modified(computer_path, entry.modified),
+        _ => Ok(()),
+    }
+}
+
+/// Whether the file on the Switch has the same length and checksum as the
+/// local one.
+fn same_content(client: &mut UsbClient, switch_path: &str, computer_path: &str, checksum: ChecksumKind) -> Result<bool, String> {
+    let mut fl = StdFile::open_file(computer_path)?;
+    let local_len = fl.len();
+    match probe(client, switch_path, local_len, checksum)? {
+        Some((remote_len, digest)) => Ok(remote_len == local_len && commands::digest_start(&mut fl, local_len, checksum)? == digest),
+        None => Ok(false),
+    }
+}
+
+/// Parses the value given to a numeric option.
+fn parse_number(arg: Option<&String>) -> Result<u64, String> {
+    match arg {
+        Some(value) => value.parse().map_err(|_| format!("Expected a number of bytes but got {}.", value)),
+        None => {
+            println!("{}", USAGE);
+            Err("Missing the value of an option.".to_owned())
+        }
+    }
+}
+
+/// Asks the server how long the file at `switch_path` is and for the checksum
+/// of its first `length` bytes, or `None` if there is no such file.
+fn probe(
+    client: &mut UsbClient,
+    switch_path: &str,
+    length: u64,
+    checksum: ChecksumKind,
+) -> Result<Option<(u64, Vec<u8>)>, String> {
+    let prefix = ProbePrefix {
+        flags: PrefixFlags::empty().with_checksum(checksum),
         file_name_length: switch_path.len() as u16,
+        length,
     };
-    client.push_prefix(Prefixes::Read(prefix))?;
-    let mut command_state = ReadState::<StdFile>::new_read(prefix, switch_path, computer_path)?;
-    let mut buffer: Vec<u8> = Vec::with_capacity(client.block_size());
-    buffer.resize(client.block_size(), 0);
-    loop {
-        if command_state.needs_pull() {
-            client.pull_block(&mut buffer)?;
-            command_state.pull_block(&buffer)?;
-        } else if command_state.needs_push() {
-            command_state.push_block(&mut buffer)?;
-            client.push_block(&buffer)?;
+    let mut command_state = ProbeState::new_probe(prefix, switch_path)?;
+    client.run_command(Prefixes::Probe(prefix), &mut command_state)?;
+    match (command_state.response, command_state.file_size) {
+        (Some(ref response), _) if response.code == ResponseCode::NotFound => Ok(None),
+        (Some(response), Some(file_size)) => {
+            let digest = response.checksum.clone();
+            response.into_result()?;
+            Ok(Some((file_size, digest)))
+        }
+        (Some(response), None) => response.into_result().map(|_| None),
+        (None, _) => Err(format!("Probe of {} finished without a response.", switch_path)),
+    }
+}
+
+/// Checks that files of the given sizes fit on the filesystem a push is
+/// going to, before any of them is sent.
+fn check_room(client: &mut UsbClient, switch_path: &str, sizes: &[u64]) -> Result<(), String> {
+    let mount = commands::mount_root(switch_path);
+    let info = client.fs_info(mount)?;
+    let needed = sizes.iter().map(|&len| info.space_for(len)).sum::<u64>();
+    if needed > info.free_bytes {
+        return Err(format!(
+            "Not enough room on {}: the push needs {} bytes but only {} are free.",
+            mount, needed, info.free_bytes
+        ));
+    }
+    Ok(())
+}
+
+/// Pushes everything under a local directory into a directory on the Switch
+/// in one session, creating the directories it needs. Carries on past files
+/// that fail, and prints a summary at the end.
+fn push_tree(
+    client: &mut UsbClient,
+    switch_dir: &str,
+    computer_dir: &str,
+    checksum: ChecksumKind,
+    resume: bool,
+    policy: OverwritePolicy,
+) -> Result<(), String> {
+    let entries = fileio::walk_tree(computer_dir)?;
+    let server_features = client.server.map(|h| h.features).unwrap_or(0);
+    let switch_dir = switch_dir.trim_end_matches('/');
+    let mut files = 0;
+    let mut bytes = 0;
+    let mut failures = Vec::new();
+    if server_features & FEATURE_MKDIR != 0 {
+        client.make_dir(switch_dir, true)?;
+    }
+    for (relative, is_dir) in entries {
+        let switch_path = format!("{}/{}", switch_dir, relative);
+        let computer_path = Path::new(computer_dir).join(&relative);
+        let computer_path = computer_path.to_string_lossy();
+        let pushed = if is_dir {
+            if server_features & FEATURE_MKDIR == 0 {
+                // Files are pushed with their parents created, so only empty
+                // directories are missed.
+                continue;
+            }
+            client.make_dir(&switch_path, true).map(|_| None)
         } else {
-            break;
+            copy_to_switch(client, &switch_path, &computer_path, checksum, resume, true, policy).map(Some)
+        };
+        match pushed {
+            Ok(Some(len)) => {
+                files += 1;
+                bytes += len;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                println!("Could not push {}: {}", computer_path, e);
+                failures.push(switch_path);
+                if interrupt::interrupted() {
+                    break;
+                }
+            }
         }
     }
-    Ok(command_state.file_size)
+    println!(
+        "Pushed {} files ({} bytes) from {} to {}; {} failed.",
+        files,
+        bytes,
+        computer_dir,
+        switch_dir,
+        failures.len()
+    );
+    for failed in &failures {
+        println!("  failed: {}", failed);
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{} entries could not be pushed.", failures.len()))
+    }
+}
+
+/// Pulls everything under a directory on the Switch into a local directory
+/// in one session, rebuilding the tree and keeping modification times where
+/// the Switch has them. Carries on past files that fail, and prints a
+/// summary at the end.
+fn pull_tree(
+    client: &mut UsbClient,
+    switch_dir: &str,
+    computer_dir: &str,
+    checksum: ChecksumKind,
+    resume: bool,
+) -> Result<(), String> {
+    let switch_dir = switch_dir.trim_end_matches('/');
+    let entries = client.list_dir(switch_dir, true, checksum)?;
+    std::fs::create_dir_all(computer_dir).map_err(|e| format!("Error creating directory {}: {:?}", computer_dir, e))?;
+    let mut files = 0;
+    let mut bytes = 0;
+    let mut failures = Vec::new();
+    for entry in &entries {
+        let switch_path = format!("{}/{}", switch_dir, entry.name);
+        let computer_path = Path::new(computer_dir).join(&entry.name);
+        let computer_path = computer_path.to_string_lossy();
+        let pulled = match entry.kind {
+            EntryKind::Dir => std::fs::create_dir_all(&*computer_path)
+                .map(|_| None)
+                .map_err(|e| format!("Error creating directory {}: {:?}", computer_path, e)),
+            EntryKind::File => copy_from_switch(client, &switch_path, &computer_path, checksum, resume).map(Some),
+            EntryKind::Other => {
+                println!("Skipping {}, which is neither a file nor a directory.", switch_path);
+                continue;
+            }
+        };
+        match pulled {
+            Ok(len) => {
+                if let Some(len) = len {
+                    files += 1;
+                    bytes += len;
+                }
+                if entry.modified != 0 && entry.kind == EntryKind::File {
+                    if let Err(e) = fileio::set_modified(&computer_path, entry.modified) {
+                        println!("{}", e);
+                    }
+                }
+            }
+            Err(e) => {
+                println!("Could not pull {}: {}", switch_path, e);
+                failures.push(switch_path);
+                if interrupt::interrupted() {
+                    break;
+                }
+            }
+        }
+    }
+    // A directory's time changes as its contents are pulled, so directories
+    // get theirs once everything is in place, deepest first.
+    for entry in entries.iter().rev().filter(|entry| entry.kind == EntryKind::Dir && entry.modified != 0) {
+        let computer_path = Path::new(computer_dir).join(&entry.name);
+        if let Err(e) = fileio::set_modified(&computer_path.to_string_lossy(), entry.modified) {
+            println!("{}", e);
+        }
+    }
+    println!(
+        "Pulled {} files ({} bytes) from {} to {}; {} failed.",
+        files,
+        bytes,
+        switch_dir,
+        computer_dir,
+        failures.len()
+    );
+    for failed in &failures {
+        println!("  failed: {}", failed);
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{} entries could not be pulled.", failures.len()))
+    }
+}
+
+fn copy_from_switch(
+    client: &mut UsbClient,
+    switch_path: &str,
+    computer_path: &str,
+    checksum: ChecksumKind,
+    resume: bool,
+) -> Result<u64, String> {
+    let mut flags = PrefixFlags::empty().with_checksum(checksum);
+    let local_len = std::fs::metadata(computer_path).map(|mtd| mtd.len()).unwrap_or(0);
+    let mut offset = 0;
+    if resume && local_len > 0 {
+        match probe(client, switch_path, local_len, checksum)? {
+            Some((remote_len, ref digest)) if remote_len >= local_len => {
+                let mut local = StdFile::open_file(computer_path)?;
+                if checksum == ChecksumKind::None || commands::digest_start(&mut local, local_len, checksum)? == *digest {
+                    println!("Resuming pull of {} at byte {} of {}.", switch_path, local_len, remote_len);
+                    offset = local_len;
+                    flags = flags | PrefixFlags::OFFSET;
+                } else {
+                    println!("{} does not match the start of {} on the Switch; pulling all of it again.", computer_path, switch_path);
+                }
+            }
+            _ => println!("{} is not a shorter copy of {} on the Switch; pulling all of it again.", computer_path, switch_path),
+        }
+    }
+    let server_features = client.server.map(|h| h.features).unwrap_or(0);
+    if offset == 0 && checksum != ChecksumKind::None && local_len >= DELTA_MIN_LENGTH && server_features & FEATURE_DELTA != 0 {
+        return pull_delta(client, switch_path, computer_path, checksum);
+    }
+    let prefix = ReadPrefix {
+        flags,
+        file_name_length: switch_path.len() as u16,
+    };
+    let mut command_state = ReadState::<StdFile>::new_resumed_read(prefix, switch_path, computer_path, offset)?;
+    client.run_command(Prefixes::Read(prefix), &mut command_state)?;
+    Ok(offset + command_state.file_size)
 }
 
 fn copy_to_switch(
     client: &mut UsbClient,
     switch_path: &str,
     computer_path: &str,
-) -> Result<usize, String> { 
-    let fl = StdFile::open_file(computer_path)?;
+    checksum: ChecksumKind,
+    resume: bool,
+    parents: bool,
+    policy: OverwritePolicy,
+) -> Result<u64, String> { 
+    let mut fl = StdFile::open_file(computer_path)?;
+    let local_len = fl.len();
+    let mut flags = PrefixFlags::empty().with_checksum(checksum);
+    if parents {
+        flags = flags | PrefixFlags::CREATE_PARENTS;
+    }
+    let mut policy = policy;
+    let mut offset = 0;
+    if resume {
+        if let Some((remote_len, digest)) = probe(client, switch_path, local_len, checksum)? {
+            if remote_len <= local_len && (checksum == ChecksumKind::None || commands::digest_start(&mut fl, remote_len, checksum)? == digest) {
+                println!("Resuming push of {} at byte {} of {}.", computer_path, remote_len, local_len);
+                offset = remote_len;
+                flags = flags | PrefixFlags::OFFSET;
+                policy = OverwritePolicy::Fail;
+            } else {
+                println!("{} on the Switch does not match the start of {}; pushing all of it again.", switch_path, computer_path);
+                policy = OverwritePolicy::Overwrite;
+            }
+        }
+    } else if policy == OverwritePolicy::SkipIdentical && checksum != ChecksumKind::None {
+        // The server would leave an identical file alone anyway, but checking
+        // first saves sending it.
+        let server_features = client.server.map(|h| h.features).unwrap_or(0);
+        if server_features & FEATURE_RESUME != 0 {
+            if let Some((remote_len, digest)) = probe(client, switch_path, local_len, checksum)? {
+                if remote_len == local_len && commands::digest_start(&mut fl, local_len, checksum)? == digest {
+                    println!("{} is already on the Switch; skipping it.", switch_path);
+                    return Ok(local_len);
+                }
+            }
+        }
+    }
+    let server_features = client.server.map(|h| h.features).unwrap_or(0);
+    let replaces = policy == OverwritePolicy::Overwrite || policy == OverwritePolicy::SkipIdentical;
+    if offset == 0 && replaces && checksum != ChecksumKind::None && local_len >= DELTA_MIN_LENGTH && server_features & FEATURE_DELTA != 0
+        && push_delta(client, switch_path, computer_path, &mut fl, checksum, flags)? {
+        return Ok(local_len);
+    }
+    flags = flags | policy.flags();
     let prefix = WritePrefix {
-        flags: u16::max_value(),
+        flags,
         file_name_length: switch_path.len() as u16,
-        file_length : fl.len() as u32
+        file_length : local_len - offset,
     };
-    client.push_prefix(Prefixes::Write(prefix))?;
-    let mut command_state = WriteState::<StdFile>::new_write(prefix, switch_path, computer_path)?;
-    let mut buffer: Vec<u8> = Vec::with_capacity(client.block_size());
-    buffer.resize(client.block_size(), 0);
-    loop {
-        if command_state.needs_pull() {
-            client.pull_block(&mut buffer)?;
-            command_state.pull_block(&buffer)?;
-        } else if command_state.needs_push() {
-            command_state.push_block(&mut buffer)?;
-            client.push_block(&buffer)?;
-        } else {
-            break;
-        }
+    let mut command_state = WriteState::<StdFile>::new_resumed_write(prefix, switch_path, computer_path, offset)?;
+    client.run_command(Prefixes::Write(prefix), &mut command_state)?;
+    if let Some(response) = command_state.response.as_ref().filter(|response| !response.message.is_empty()) {
+        println!("{}", response.message);
     }
-    Ok(command_state.prefix().file_length as usize)
+    Ok(local_len)
+}
+
+/// Pushes a file as a delta against the old copy on the Switch, sending only
+/// the parts of it that changed. Returns false without sending anything if
+/// there is no old copy, or so little of it matches that the whole file is
+/// cheaper to send.
+fn push_delta(
+    client: &mut UsbClient,
+    switch_path: &str,
+    computer_path: &str,
+    fl: &mut StdFile,
+    checksum: ChecksumKind,
+    flags: PrefixFlags,
+) -> Result<bool, String> {
+    let local_len = fl.len();
+    let signature = match client.signature(switch_path, delta::block_size_for(local_len), checksum)? {
+        Some(signature) => signature,
+        None => return Ok(false),
+    };
+    let (ops, digest) = commands::plan_delta(fl, &signature)?;
+    fl.seek(0)?;
+    let stream_len = delta::encoded_len(&ops);
+    if stream_len >= local_len {
+        println!("Too little of {} on the Switch matches {}; pushing all of it.", switch_path, computer_path);
+        return Ok(false);
+    }
+    println!(
+        "Sending {} of the {} bytes of {} as a delta of {} bytes.",
+        delta::literal_len(&ops),
+        local_len,
+        computer_path,
+        stream_len
+    );
+    let prefix = WritePrefix {
+        flags: flags | PrefixFlags::OVERWRITE | PrefixFlags::DELTA,
+        file_name_length: switch_path.len() as u16,
+        file_length: stream_len,
+    };
+    let mut command_state = WriteState::<StdFile>::new_delta_write(prefix, switch_path, computer_path, ops, digest)?;
+    client.run_command(Prefixes::Write(prefix), &mut command_state)?;
+    Ok(true)
+}
+
+/// Pulls a file as a delta against the local copy, which is only replaced
+/// once the new version has been rebuilt beside it and checked.
+fn pull_delta(client: &mut UsbClient, switch_path: &str, computer_path: &str, checksum: ChecksumKind) -> Result<u64, String> {
+    let mut local = StdFile::open_file(computer_path)?;
+    let block_size = delta::block_size_for(local.len());
+    let signature = commands::signature_of(&mut local, block_size, checksum)?;
+    let temp = format!("{}.nxusb-part", computer_path);
+    // The command removes the file it was rebuilding if the delta goes wrong,
+    // but not if the line itself fails partway.
+    let command_state = client
+        .read_delta::<StdFile, StdFile>(switch_path, &temp, computer_path, &signature)
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(&temp);
+        })?;
+    std::fs::rename(&temp, computer_path).map_err(|e| format!("Error replacing {} with {}: {:?}", computer_path, temp, e))?;
+    println!(
+        "Pulled {} of the {} bytes of {} as a delta.",
+        command_state.literal_bytes, command_state.file_size, switch_path
+    );
+    Ok(command_state.file_size)
 }
@@ -0,0 +1,429 @@
+use libusb_impl::fileio;
+use nxusb::listing::{EntryKind, ListEntry};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// How far apart two modification times can be and still count as the same.
+/// FAT only keeps times to the nearest 2 seconds.
+pub const MTIME_SLACK: u64 = 2;
+
+/// The file in the local directory where a two-way sync keeps what both
+/// sides looked like after the last sync. It is never synced itself.
+pub const STATE_FILE_NAME: &str = ".nxusb_sync";
+
+/// A file or directory on the computer, named by its path inside the tree
+/// being synced, like the entries of a recursive listing.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct LocalEntry {
+    pub name: String,
+    pub is_dir: bool,
+    /// The length in bytes of a file, and 0 for a directory.
+    pub size: u64,
+    /// The last modification time in seconds since the Unix epoch, or 0 if it
+    /// is not known.
+    pub modified: u64,
+}
+
+/// Lists everything under a local directory, each directory before what is
+/// in it.
+pub fn local_tree(root: &str) -> Result<Vec<LocalEntry>, String> {
+    let mut entries = Vec::new();
+    for (name, is_dir) in fileio::walk_tree(root)? {
+        if name == STATE_FILE_NAME {
+            continue;
+        }
+        let path = Path::new(root).join(&name);
+        let meta = std::fs::metadata(&path).map_err(|e| format!("Error reading {}: {:?}", path.display(), e))?;
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_secs())
+            .unwrap_or(0);
+        entries.push(LocalEntry {
+            name,
+            is_dir,
+            size: if is_dir { 0 } else { meta.len() },
+            modified,
+        });
+    }
+    Ok(entries)
+}
+
+/// Whether two modification times are the same, allowing for FAT's
+/// granularity.
+pub fn same_time(a: u64, b: u64) -> bool {
+    a.max(b) - a.min(b) <= MTIME_SLACK
+}
+
+/// How a local file compares to the copy on the Switch.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum FileState {
+    /// Nothing of that name is on the Switch.
+    New,
+    /// The copy on the Switch is out of date.
+    Changed,
+    Same,
+    /// The sizes match, so only comparing the content can tell.
+    CheckHash,
+}
+
+/// Compares a local file with what has its name on the Switch. A push stamps
+/// the copy on the Switch with the time it was made, so the file has changed
+/// if it was modified after that. With `by_hash`, files of the same size are
+/// compared by content instead of by time.
+pub fn compare(local: &LocalEntry, remote: Option<&ListEntry>, by_hash: bool) -> FileState {
+    let remote = match remote {
+        Some(remote) => remote,
+        None => return FileState::New,
+    };
+    if remote.kind != EntryKind::File || remote.size != local.size {
+        FileState::Changed
+    } else if by_hash {
+        FileState::CheckHash
+    } else if local.modified > remote.modified && !same_time(local.modified, remote.modified) {
+        FileState::Changed
+    } else {
+        FileState::Same
+    }
+}
+
+/// What a one-way sync has to do to make the Switch match the computer.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct PushPlan {
+    /// Directories to create on the Switch, each before those inside it.
+    pub dirs: Vec<String>,
+    /// Files to push, with what is known about each.
+    pub files: Vec<(String, FileState)>,
+    /// Entries on the Switch with no local counterpart, outermost only, so
+    /// that removing each recursively removes the rest.
+    pub extras: Vec<String>,
+    /// How many local files are already up to date.
+    pub unchanged: usize,
+}
+
+/// Works out what a one-way sync from the local tree to the listing of the
+/// Switch's tree has to do. A state file on the Switch is left alone.
+pub fn plan_push(local: &[LocalEntry], remote: &[ListEntry], by_hash: bool) -> PushPlan {
+    let remote: Vec<&ListEntry> = remote.iter().filter(|ent| ent.name != STATE_FILE_NAME).collect();
+    let remote_by_name: HashMap<&str, &ListEntry> = remote.iter().map(|ent| (ent.name.as_str(), *ent)).collect();
+    let mut plan = PushPlan::default();
+    for entry in local {
+        let remote = remote_by_name.get(entry.name.as_str()).cloned();
+        if entry.is_dir {
+            if remote.map(|ent| ent.kind != EntryKind::Dir).unwrap_or(true) {
+                plan.dirs.push(entry.name.clone());
+            }
+            continue;
+        }
+        match compare(entry, remote, by_hash) {
+            FileState::Same => plan.unchanged += 1,
+            state => plan.files.push((entry.name.clone(), state)),
+        }
+    }
+    let local_names: HashSet<&str> = local.iter().map(|ent| ent.name.as_str()).collect();
+    for entry in remote {
+        if local_names.contains(entry.name.as_str()) {
+            continue;
+        }
+        let inside_extra = plan
+            .extras
+            .iter()
+            .any(|extra| entry.name.starts_with(extra.as_str()) && entry.name[extra.len()..].starts_with('/'));
+        if !inside_extra {
+            plan.extras.push(entry.name.clone());
+        }
+    }
+    plan
+}
+
+/// What an entry looked like on one side.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct Snapshot {
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: u64,
+}
+
+impl Snapshot {
+    pub fn of_local(entry: &LocalEntry) -> Snapshot {
+        Snapshot {
+            is_dir: entry.is_dir,
+            size: entry.size,
+            modified: entry.modified,
+        }
+    }
+
+    /// The snapshot of a listed file or directory, or `None` for anything
+    /// else, which a sync leaves alone.
+    pub fn of_remote(entry: &ListEntry) -> Option<Snapshot> {
+        match entry.kind {
+            EntryKind::File | EntryKind::Dir => Some(Snapshot {
+                is_dir: entry.kind == EntryKind::Dir,
+                size: entry.size,
+                modified: entry.modified,
+            }),
+            EntryKind::Other => None,
+        }
+    }
+
+    /// Whether two snapshots show the same content, as far as can be told
+    /// without reading it.
+    pub fn agrees_with(&self, other: &Snapshot) -> bool {
+        self.is_dir == other.is_dir && (self.is_dir || (self.size == other.size && same_time(self.modified, other.modified)))
+    }
+}
+
+/// The local and remote snapshot of every entry that was on both sides after
+/// the last two-way sync of a pair of directories, by name.
+pub type SyncState = BTreeMap<String, (Snapshot, Snapshot)>;
+
+/// Reads the state of the pair of `local_root` and `remote_root`, which is
+/// empty if they have not been synced before.
+pub fn load_state(local_root: &str, remote_root: &str) -> Result<SyncState, String> {
+    let path = Path::new(local_root).join(STATE_FILE_NAME);
+    match std::fs::read_to_string(&path) {
+        Ok(text) => parse_state(&text, remote_root).map_err(|e| format!("{} in {}; delete it to start afresh.", e, path.display())),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(SyncState::new()),
+        Err(e) => Err(format!("Error reading {}: {:?}", path.display(), e)),
+    }
+}
+
+/// Replaces the state of the pair of `local_root` and `remote_root`, keeping
+/// that of any other directory on the Switch.
+pub fn save_state(local_root: &str, remote_root: &str, state: &SyncState) -> Result<(), String> {
+    let path = Path::new(local_root).join(STATE_FILE_NAME);
+    let old = std::fs::read_to_string(&path).unwrap_or_default();
+    std::fs::write(&path, serialize_state(&old, remote_root, state)).map_err(|e| format!("Error writing {}: {:?}", path.display(), e))
+}
+
+/// Escapes the backslashes, tabs and line breaks in a name so that it fits in
+/// one field of a state file.
+fn escape_field(name: &str) -> String {
+    name.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// Undoes `escape_field`, or returns `None` for an escape it never makes.
+fn unescape_field(field: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            _ => return None,
+        }
+    }
+    Some(unescaped)
+}
+
+/// Picks out the state of one directory on the Switch from a state file. Each
+/// line holds the directory on the Switch, the name, `d` or `f`, and the
+/// size and modification time on each side, separated by tabs. Backslashes,
+/// tabs and line breaks in the directory and the name are escaped.
+pub fn parse_state(text: &str, remote_root: &str) -> Result<SyncState, String> {
+    let mut state = SyncState::new();
+    for (idx, line) in text.lines().enumerate() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 7 || (fields[2] != "d" && fields[2] != "f") {
+            return Err(format!("Line {} of the sync state is damaged", idx + 1));
+        }
+        let (root, name) = match (unescape_field(fields[0]), unescape_field(fields[1])) {
+            (Some(root), Some(name)) => (root, name),
+            _ => return Err(format!("Line {} of the sync state is damaged", idx + 1)),
+        };
+        if root != remote_root {
+            continue;
+        }
+        let mut numbers = [0u64; 4];
+        for (number, field) in numbers.iter_mut().zip(&fields[3..]) {
+            *number = field.parse().map_err(|_| format!("Line {} of the sync state is damaged", idx + 1))?;
+        }
+        let is_dir = fields[2] == "d";
+        let local = Snapshot {
+            is_dir,
+            size: numbers[0],
+            modified: numbers[1],
+        };
+        let remote = Snapshot {
+            is_dir,
+            size: numbers[2],
+            modified: numbers[3],
+        };
+        state.insert(name, (local, remote));
+    }
+    Ok(state)
+}
+
+/// Rewrites a state file with `state` as the state of `remote_root`.
+pub fn serialize_state(old: &str, remote_root: &str, state: &SyncState) -> String {
+    let root = escape_field(remote_root);
+    let mut text = String::new();
+    for line in old.lines().filter(|line| line.split('\t').next() != Some(root.as_str())) {
+        text.push_str(line);
+        text.push('\n');
+    }
+    for (name, &(local, remote)) in state {
+        text.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            root,
+            escape_field(name),
+            if local.is_dir { "d" } else { "f" },
+            local.size,
+            local.modified,
+            remote.size,
+            remote.modified
+        ));
+    }
+    text
+}
+
+/// One side of a two-way sync.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Side {
+    Local,
+    Remote,
+}
+
+impl Side {
+    pub fn parse(name: &str) -> Option<Side> {
+        match name {
+            "local" => Some(Side::Local),
+            "remote" => Some(Side::Remote),
+            _ => None,
+        }
+    }
+}
+
+/// What a two-way sync does with one entry.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum SyncAction {
+    /// Copies the local entry to the Switch.
+    Push,
+    /// Copies the entry on the Switch to the computer.
+    Pull,
+    RemoveRemote,
+    RemoveLocal,
+    /// Both sides changed and nothing says which one to keep, so both are
+    /// left as they are.
+    Conflict,
+}
+
+/// An entry that a two-way sync has to do something with.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct SyncStep {
+    pub name: String,
+    pub action: SyncAction,
+    /// Whether both sides changed, so that `action` only comes from the
+    /// preferred side.
+    pub conflict: bool,
+}
+
+fn changed(base: Option<&Snapshot>, now: Option<&Snapshot>) -> bool {
+    match (base, now) {
+        (Some(base), Some(now)) => !base.agrees_with(now),
+        (None, None) => false,
+        _ => true,
+    }
+}
+
+fn local_and_remote(local: &[LocalEntry], remote: &[ListEntry]) -> BTreeMap<String, (Option<Snapshot>, Option<Snapshot>)> {
+    let mut both: BTreeMap<String, (Option<Snapshot>, Option<Snapshot>)> = BTreeMap::new();
+    for entry in local {
+        both.entry(entry.name.clone()).or_insert((None, None)).0 = Some(Snapshot::of_local(entry));
+    }
+    for entry in remote.iter().filter(|ent| ent.name != STATE_FILE_NAME) {
+        if let Some(snapshot) = Snapshot::of_remote(entry) {
+            both.entry(entry.name.clone()).or_insert((None, None)).1 = Some(snapshot);
+        }
+    }
+    both
+}
+
+/// Works out what a two-way sync has to do, by comparing each side with what
+/// it looked like after the last sync. An entry that changed on one side is
+/// copied or removed on the other. One that changed differently on both is a
+/// conflict, which `prefer` settles by taking that side's version; an entry
+/// that is a file on one side and a directory on the other always stays a
+/// conflict. Steps are in name order, so a directory comes before what is in
+/// it.
+pub fn plan_sync(local: &[LocalEntry], remote: &[ListEntry], base: &SyncState, prefer: Option<Side>) -> Vec<SyncStep> {
+    let both = local_and_remote(local, remote);
+    let mut steps = Vec::new();
+    for (name, &(local, remote)) in &both {
+        let (base_local, base_remote) = match base.get(name) {
+            Some(&(base_local, base_remote)) => (Some(base_local), Some(base_remote)),
+            None => (None, None),
+        };
+        let local_changed = changed(base_local.as_ref(), local.as_ref());
+        let remote_changed = changed(base_remote.as_ref(), remote.as_ref());
+        let towards_remote = if local.is_some() { SyncAction::Push } else { SyncAction::RemoveRemote };
+        let towards_local = if remote.is_some() { SyncAction::Pull } else { SyncAction::RemoveLocal };
+        let (action, conflict) = match (local, remote) {
+            (Some(l), Some(r)) if l.is_dir != r.is_dir => (SyncAction::Conflict, true),
+            (Some(l), Some(r)) if l.agrees_with(&r) => continue,
+            _ if !local_changed && !remote_changed => continue,
+            _ if !remote_changed => (towards_remote, false),
+            _ if !local_changed => (towards_local, false),
+            _ => match prefer {
+                Some(Side::Local) => (towards_remote, true),
+                Some(Side::Remote) => (towards_local, true),
+                None => (SyncAction::Conflict, true),
+            },
+        };
+        steps.push(SyncStep {
+            name: name.clone(),
+            action,
+            conflict,
+        });
+    }
+    // A directory removed on one side is kept if something new is going into
+    // it on the other.
+    let kept: Vec<String> = steps
+        .iter()
+        .filter(|step| step.action == SyncAction::RemoveRemote || step.action == SyncAction::RemoveLocal)
+        .filter(|step| {
+            let inside = format!("{}/", step.name);
+            steps
+                .iter()
+                .any(|other| other.name.starts_with(&inside) && other.action != step.action)
+        })
+        .map(|step| step.name.clone())
+        .collect();
+    steps.retain(|step| !kept.contains(&step.name));
+    steps
+}
+
+/// Works out the state to keep after a two-way sync from the listings taken
+/// once it has finished. Entries whose step did not happen keep their old
+/// state, so that the next sync sees the same change again.
+pub fn settle(local: &[LocalEntry], remote: &[ListEntry], base: &SyncState, unsettled: &HashSet<String>) -> SyncState {
+    let mut state = SyncState::new();
+    for (name, &(local, remote)) in &local_and_remote(local, remote) {
+        if unsettled.contains(name) {
+            continue;
+        }
+        if let (Some(local), Some(remote)) = (local, remote) {
+            if local.is_dir == remote.is_dir {
+                state.insert(name.clone(), (local, remote));
+            }
+        }
+    }
+    for name in unsettled {
+        if let Some(&entry) = base.get(name) {
+            state.insert(name.clone(), entry);
+        }
+    }
+    state
+}
@@ -1,13 +1,28 @@
 #![cfg(test)]
-use commands::{ClientCommandState, FileContentStorer, FileRetriever, ReadState};
+use commands::{self, ClientCommandState, FileContentStorer, FileRetriever, ProbeState, ReadState, WriteState};
 use interface::ClientDevice;
-use nxusb::prefixes::{CommandPrefix, Prefixes, ReadPrefix, WritePrefix, PREFIX_LENGTH};
+use libusb_impl::fileio;
+use nxusb::frame::{AbortPolicy, Frame, FrameDecoder, FrameEncoder, FrameKind};
+use nxusb::handshake::{PROTOCOL_VERSION, SUPPORTED_FEATURES};
+use nxusb::listing::{EntryKind, ListEntry, ListPage};
+use nxusb::prefixes::{self, CommandPrefix, HandshakePrefix, ListPrefix, MakeDirPrefix, MovePrefix, OverwritePolicy, PrefixFlags, Prefixes, ProbePrefix, ReadPrefix, RemovePrefix, StatPrefix, WritePrefix, PREFIX_LENGTH, READ_HEADER_LENGTH};
+use nxusb::checksum::{Checksum, ChecksumKind};
+use nxusb::removal::RemovalRecord;
+use nxusb::response::{Response, ResponseCode};
 use std::collections::HashMap;
 use std::sync::{Once, ONCE_INIT};
 use std::vec::Vec;
 
 struct TestFileContext {
     files: HashMap<String, Vec<u8>>,
+    /// Files too large to hold in memory, mapped to their length. Their
+    /// content is generated by `fake_byte`.
+    fake_files: HashMap<String, u64>,
+}
+
+/// The content of a fake file at the given offset.
+fn fake_byte(offset: u64) -> u8 {
+    (offset % 251) as u8
 }
 
 static mut CONTEXT: Option<TestFileContext> = None;
@@ -18,6 +33,7 @@ impl TestFileContext {
         INIT.call_once(|| {
             CONTEXT = Some(TestFileContext {
                 files: HashMap::new(),
+                fake_files: HashMap::new(),
             })
         });
         CONTEXT.as_mut().unwrap()
@@ -25,7 +41,7 @@ impl TestFileContext {
 }
 pub struct TestFile {
     name: String,
-    read_idx: usize,
+    read_idx: u64,
 }
 impl FileRetriever for TestFile {
     fn name(&self) -> &str {
@@ -37,18 +53,24 @@ impl FileRetriever for TestFile {
             read_idx :0
         })
     }
-    fn len(&self) -> usize {
-        let bts: Vec<u8> = unsafe {
-            TestFileContext::get_context()
-                .files
-                .get(&self.name)
-                .unwrap_or(&Vec::new())
-                .to_vec()
-        };
-        bts.len()
+    fn len(&self) -> u64 {
+        let ctx = unsafe { TestFileContext::get_context() };
+        if let Some(fake_len) = ctx.fake_files.get(&self.name) {
+            return *fake_len;
+        }
+        let bts: Vec<u8> = ctx.files.get(&self.name).unwrap_or(&Vec::new()).to_vec();
+        bts.len() as u64
 
     }
     fn read_bytes(&mut self, buffer: &mut [u8]) -> Result<usize, String> {
+        if let Some(fake_len) = unsafe { TestFileContext::get_context().fake_files.get(&self.name) } {
+            let num_bytes = (buffer.len() as u64).min(fake_len - self.read_idx) as usize;
+            for idx in 0..num_bytes {
+                buffer[idx] = fake_byte(self.read_idx + idx as u64);
+            }
+            self.read_idx += num_bytes as u64;
+            return Ok(num_bytes);
+        }
         let bts: Vec<u8> = unsafe {
             TestFileContext::get_context()
                 .files
@@ -58,29 +80,46 @@ impl FileRetriever for TestFile {
         };
 
         let mut bts_read = 0;
-        while bts_read < buffer.len() && self.read_idx + bts_read < bts.len() {
-            buffer[bts_read] = bts[self.read_idx + bts_read];
+        let read_idx = self.read_idx as usize;
+        while bts_read < buffer.len() && read_idx + bts_read < bts.len() {
+            buffer[bts_read] = bts[read_idx + bts_read];
             bts_read += 1;
         }
-        self.read_idx += bts_read;
+        self.read_idx += bts_read as u64;
         Ok(bts_read)
     }
+    fn seek(&mut self, offset: u64) -> Result<(), String> {
+        self.read_idx = offset;
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 struct TestFileStorer {
     name: String,
+    /// The length a resumed file started with.
+    kept: Option<usize>,
 }
 
 impl FileContentStorer for TestFileStorer {
-    fn for_name(name: &str, _size: usize) -> Result<Self, String> {
+    fn for_name(name: &str, _size: u64) -> Result<Self, String> {
         let name = name.to_owned();
         unsafe {
             TestFileContext::get_context()
                 .files
                 .insert(name.clone(), Vec::new());
         }
-        Ok(TestFileStorer { name: name })
+        Ok(TestFileStorer { name: name, kept: None })
+    }
+
+    fn resume(name: &str, offset: u64) -> Result<Self, String> {
+        let fl = unsafe { TestFileContext::get_context().files.get_mut(name) }
+            .ok_or(format!("Err: no file named {} to resume.", name))?;
+        fl.truncate(offset as usize);
+        Ok(TestFileStorer {
+            name: name.to_owned(),
+            kept: Some(offset as usize),
+        })
     }
 
     fn push_bytes(&mut self, buffer: &[u8]) -> Result<usize, String> {
@@ -96,12 +135,36 @@ impl FileContentStorer for TestFileStorer {
         fl.extend_from_slice(buffer);
         Ok(buffer.len())
     }
+
+    fn remove(self) -> Result<(), String> {
+        let ctx = unsafe { TestFileContext::get_context() };
+        match self.kept {
+            Some(len) => ctx.files.get_mut(&self.name).unwrap().truncate(len),
+            None => {
+                ctx.files.remove(&self.name);
+            }
+        }
+        Ok(())
+    }
 }
 
 const TEST_BLOCK_SIZE: usize = 100;
+
+/// The frame payload used by the tests; larger than a block so that frames
+/// span several reads, and small enough that files span several frames.
+const TEST_FRAME_PAYLOAD: usize = 150;
+
 pub struct TestUsbDevice {
     input_buf: Vec<u8>,
     output_buf: Vec<u8>,
+    /// Numbers the frames the tests feed in, standing in for the server.
+    input_encoder: FrameEncoder,
+    decoder: FrameDecoder,
+    encoder: FrameEncoder,
+    /// Reads back the frames the device sent, standing in for the server.
+    output_decoder: FrameDecoder,
+    /// Stands in for the user pressing Ctrl-C.
+    abort: Option<AbortPolicy>,
 }
 
 impl TestUsbDevice {
@@ -109,6 +172,11 @@ impl TestUsbDevice {
         TestUsbDevice {
             input_buf: Vec::new(),
             output_buf: Vec::new(),
+            input_encoder: FrameEncoder::new(),
+            decoder: FrameDecoder::new(),
+            encoder: FrameEncoder::new(),
+            output_decoder: FrameDecoder::new(),
+            abort: None,
         }
     }
 
@@ -116,6 +184,15 @@ impl TestUsbDevice {
         self.input_buf.extend_from_slice(input_bytes);
     }
 
+    pub fn push_input_frame(&mut self, frame: Frame) {
+        let bytes = self.input_encoder.encode(frame);
+        self.push_input(&bytes);
+    }
+
+    pub fn push_input_data(&mut self, data: &[u8]) {
+        self.push_input_frame(Frame::data(data.to_vec()));
+    }
+
     pub fn pull_input(&mut self, count: usize) -> Vec<u8> {
         self.input_buf.drain(0..count).collect()
     }
@@ -124,163 +201,1032 @@ impl TestUsbDevice {
         self.output_buf.extend_from_slice(output_bytes);
     }
 
-    pub fn pull_output(&mut self, count: usize) -> Vec<u8> {
-        self.output_buf.drain(0..count).collect()
+    /// Decodes the next frame the device sent.
+    pub fn pull_output_frame(&mut self) -> Frame {
+        let bytes: Vec<u8> = self.output_buf.drain(..).collect();
+        self.output_decoder.push_bytes(&bytes);
+        self.output_decoder
+            .next_frame()
+            .unwrap()
+            .expect("Device did not send a whole frame.")
+    }
+
+    /// Decodes all of the data frames the device sent, returning their
+    /// combined payload.
+    pub fn pull_output_data(&mut self) -> Vec<u8> {
+        let bytes: Vec<u8> = self.output_buf.drain(..).collect();
+        self.output_decoder.push_bytes(&bytes);
+        let mut data = Vec::new();
+        while let Some(frame) = self.output_decoder.next_frame().unwrap() {
+            assert_eq!(frame.kind, FrameKind::Data);
+            assert!(frame.payload.len() <= TEST_FRAME_PAYLOAD);
+            data.extend_from_slice(&frame.payload);
+        }
+        data
     }
 }
 
 impl ClientDevice for TestUsbDevice {
-    fn block_size(&self) -> usize {
-        TEST_BLOCK_SIZE
-    }
-
-    fn pull_block(&mut self, buffer: &mut [u8]) -> Result<usize, String> {
-        if buffer.len() < TEST_BLOCK_SIZE {
-            Err(format!(
-                "Dont have large enough output buf to read block! Need {} but was passed {}.",
-                TEST_BLOCK_SIZE,
-                buffer.len()
-            ))
-        } else if self.input_buf.len() < TEST_BLOCK_SIZE {
-            Err(format!(
-                "Dont have large enough input buf to read block! Need {} but have {}.",
-                TEST_BLOCK_SIZE,
-                self.input_buf.len()
-            ))
-        } else {
-            let bts = self.pull_input(TEST_BLOCK_SIZE);
-            for idx in 0..TEST_BLOCK_SIZE {
-                buffer[idx] = bts[idx];
+    fn push_frame(&mut self, frame: Frame) -> Result<usize, String> {
+        let bytes = self.encoder.encode(frame);
+        self.push_output(&bytes);
+        Ok(bytes.len())
+    }
+
+    fn pull_frame(&mut self) -> Result<Frame, String> {
+        loop {
+            if let Some(frame) = self.decoder.next_frame()? {
+                return Ok(frame);
             }
-            Ok(TEST_BLOCK_SIZE)
+            if self.input_buf.is_empty() {
+                return Err("Dont have large enough input buf to read a frame!".to_owned());
+            }
+            let count = self.input_buf.len().min(TEST_BLOCK_SIZE);
+            let bts = self.pull_input(count);
+            self.decoder.push_bytes(&bts);
         }
     }
 
-    fn push_block(&mut self, bytes: &[u8]) -> Result<usize, String> {
-        if bytes.len() != self.block_size() {
-            Err(format!(
-                "Got less then a block size of bytes to output: {} but expected {}",
-                bytes.len(),
-                TEST_BLOCK_SIZE
-            ))
-        } else {
-            self.push_output(bytes);
-            Ok(bytes.len())
-        }
+    fn frame_payload(&self) -> usize {
+        TEST_FRAME_PAYLOAD
     }
 
-    fn push_prefix(&mut self, prefix: Prefixes) -> Result<usize, String> {
-        let bts = prefix.serialize();
-        self.output_buf.extend_from_slice(&bts);
-        Ok(PREFIX_LENGTH)
+    fn abort_requested(&self) -> Option<AbortPolicy> {
+        self.abort
     }
 }
 
+/// Runs a command to completion against the test device.
+fn run_command<P: CommandPrefix, C: ClientCommandState<P>>(
+    state: &mut C,
+    usb_ctx: &mut TestUsbDevice,
+) -> Result<(), String> {
+    while state.needs_push() || state.needs_pull() {
+        if state.needs_push() {
+            let frame = state.push_frame(usb_ctx.frame_payload())?;
+            usb_ctx.push_frame(frame)?;
+        } else {
+            let frame = usb_ctx.pull_frame()?;
+            state.pull_frame(frame)?;
+        }
+    }
+    Ok(())
+}
+
 #[test]
 fn test_read_prefix_pushing() {
-    let expected: [u8; PREFIX_LENGTH] = [0x0, 0x0, 0x0, 0x10, 0x0, 0x0, 0x0, 0x0];
+    let expected: [u8; PREFIX_LENGTH] = [
+        0x1, 0x0, 0x0, 0x0, 0x0, 0x10, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
+    ];
     let prefix = ReadPrefix {
-        flags: 0,
+        flags: PrefixFlags::empty(),
         file_name_length: 16,
     };
     let mut usb_ctx = TestUsbDevice::empty();
     usb_ctx.push_prefix(Prefixes::Read(prefix)).unwrap();
-    let actual = &usb_ctx.output_buf[0..PREFIX_LENGTH];
-    assert_eq!(&expected, &actual);
+    let actual = usb_ctx.pull_output_frame();
+    assert_eq!(actual.kind, FrameKind::Prefix);
+    assert_eq!(&expected, &actual.payload[..]);
 }
 
 #[test]
 fn test_write_prefix_pushing() {
     let prefix = WritePrefix {
-        flags: 0b1010101010101010,
+        flags: PrefixFlags::from_bits(0b0010101010101010),
         file_name_length: 16,
         file_length: 4096,
     };
-    let expected: [u8; PREFIX_LENGTH] =
-        [0b10101010, 0b10101010, 0x00, 0x10, 0x00, 0x00, 0x10, 0x00];
+    let expected: [u8; PREFIX_LENGTH] = [
+        0x02, 0x00, 0b00101010, 0b10101010, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x10, 0x00,
+    ];
     let mut usb_ctx = TestUsbDevice::empty();
     usb_ctx.push_prefix(Prefixes::Write(prefix)).unwrap();
-    let actual = &usb_ctx.output_buf[0..PREFIX_LENGTH];
-    assert_eq!(&expected, actual);
+    let actual = usb_ctx.pull_output_frame();
+    assert_eq!(actual.kind, FrameKind::Prefix);
+    assert_eq!(&expected, &actual.payload[..]);
+}
+
+#[test]
+fn test_handshake() {
+    let server = HandshakePrefix {
+        protocol_version: PROTOCOL_VERSION,
+        build_version: 5,
+        max_frame_payload: TEST_FRAME_PAYLOAD as u16,
+        features: SUPPORTED_FEATURES,
+    };
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_frame(Frame::prefix(&server));
+    let actual = usb_ctx.handshake().unwrap();
+    assert_eq!(server, actual);
+    match usb_ctx.pull_output_frame().parse_prefix() {
+        Ok(Prefixes::Handshake(h)) => assert_eq!(h.protocol_version, PROTOCOL_VERSION),
+        other => panic!("Client sent {:?} instead of a handshake.", other),
+    }
+}
+
+#[test]
+fn test_handshake_rejects_old_server() {
+    let server = HandshakePrefix {
+        protocol_version: PROTOCOL_VERSION - 1,
+        build_version: 5,
+        max_frame_payload: TEST_FRAME_PAYLOAD as u16,
+        features: SUPPORTED_FEATURES,
+    };
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_frame(Frame::prefix(&server));
+    let err = usb_ctx.handshake().unwrap_err();
+    assert!(err.contains("Protocol version mismatch"));
+
+    // A server from before framing answers with a bare handshake.
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input(&server.serialize());
+    let err = usb_ctx.handshake().unwrap_err();
+    assert!(err.contains("needs to be updated"), "Unexpected error {}", err);
+
+    let mut usb_ctx = TestUsbDevice::empty();
+    assert!(usb_ctx.handshake().is_err());
 }
 
 #[test]
 fn test_read_file() {
-    let mut test_read_buffer = [0; TEST_BLOCK_SIZE];
-    let mut test_write_buffer = [0; TEST_BLOCK_SIZE];
     let mut usb_ctx = TestUsbDevice::empty();
     let read_prefix = ReadPrefix {
-        flags: 0,
+        flags: PrefixFlags::empty(),
         file_name_length: 3,
     };
     usb_ctx.push_prefix(Prefixes::Read(read_prefix)).unwrap();
     let mut read_state =
         ReadState::<TestFileStorer>::new_read(read_prefix, "fla", "fla_out").unwrap();
-    usb_ctx
-        .input_buf
-        .extend_from_slice(&[0, 0, 0, 5, b'H', b'e', b'l', b'l', b'o']);
-    assert_eq!(9, usb_ctx.input_buf.len());
-    usb_ctx.input_buf.resize(10000, 0);
-    assert_eq!(10000, usb_ctx.input_buf.len());
-    while read_state.needs_push() || read_state.needs_pull() {
-        if read_state.needs_pull() {
-            let _blk = usb_ctx.pull_block(&mut test_read_buffer).unwrap();
-            let _write = read_state.pull_block(&test_read_buffer).unwrap();
-        }
-        if read_state.needs_push() {
-            let _written = read_state.push_block(&mut test_write_buffer).unwrap();
-            let _blk = usb_ctx.push_block(&test_write_buffer).unwrap();
-        }
-    }
+    usb_ctx.push_input_data(&[0, 0, 0, 0, 0, 0, 0, 5, b'H', b'e']);
+    usb_ctx.push_input_data(&[b'l', b'l', b'o']);
+    usb_ctx.push_input_frame(Frame::response(&Response::ok()));
+    run_command(&mut read_state, &mut usb_ctx).unwrap();
+
+    assert!(usb_ctx.input_buf.is_empty());
+    assert_eq!(usb_ctx.pull_output_frame().kind, FrameKind::Prefix);
+    assert_eq!(usb_ctx.pull_output_data(), b"fla".to_vec());
     let read_content = unsafe { TestFileContext::get_context().files.get("fla_out").unwrap() };
     assert_eq!(read_content, &vec![b'H', b'e', b'l', b'l', b'o']);
+    assert_eq!(read_state.response, Some(Response::ok()));
+}
+
+#[test]
+fn test_read_server_error() {
+    let read_prefix = ReadPrefix {
+        flags: PrefixFlags::empty(),
+        file_name_length: 7,
+    };
+    let mut read_state =
+        ReadState::<TestFileStorer>::new_read(read_prefix, "missing", "missing_out").unwrap();
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_frame(Frame::response(&Response::error(
+        ResponseCode::NotFound,
+        "File open error".to_owned(),
+    )));
+
+    let err = run_command(&mut read_state, &mut usb_ctx).unwrap_err();
+    assert!(err.contains("not found"), "Unexpected error {}", err);
+    assert!(unsafe { TestFileContext::get_context().files.get("missing_out").is_none() });
+}
+
+#[test]
+fn test_read_truncated_file() {
+    let read_prefix = ReadPrefix {
+        flags: PrefixFlags::empty(),
+        file_name_length: 3,
+    };
+    let mut read_state =
+        ReadState::<TestFileStorer>::new_read(read_prefix, "fla", "short_out").unwrap();
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_data(&[0, 0, 0, 0, 0, 0, 0, 5, b'H', b'e']);
+    usb_ctx.push_input_frame(Frame::response(&Response::ok()));
+
+    let err = run_command(&mut read_state, &mut usb_ctx).unwrap_err();
+    assert!(err.contains("after 2 of 5 bytes"), "Unexpected error {}", err);
+    assert!(unsafe { TestFileContext::get_context().files.get("short_out").is_none() });
+}
+
+#[test]
+fn test_write_server_error() {
+    unsafe {
+        TestFileContext::get_context()
+            .files
+            .insert("exists_in".to_owned(), b"Hello".to_vec());
+    }
+    let write_prefix = WritePrefix {
+        flags: PrefixFlags::empty(),
+        file_name_length: 6,
+        file_length: 5,
+    };
+    let mut write_state =
+        WriteState::<TestFile>::new_write(write_prefix, "exists", "exists_in").unwrap();
+    let frame = write_state.push_frame(TEST_FRAME_PAYLOAD).unwrap();
+    assert_eq!(frame.payload, b"existsHello".to_vec());
+    assert!(!write_state.needs_push());
+    assert!(write_state.needs_pull());
+
+    let response = Response::error(ResponseCode::Exists, "File with name exists already exists!".to_owned());
+    let err = write_state.pull_frame(Frame::response(&response)).unwrap_err();
+    assert!(err.contains("already exists"), "Unexpected error {}", err);
+    assert_eq!(write_state.response.unwrap().code, ResponseCode::Exists);
+}
+
+#[test]
+fn test_read_large_file_header() {
+    let file_length: u64 = 5 * 1024 * 1024 * 1024 + 7;
+    let read_prefix = ReadPrefix {
+        flags: PrefixFlags::empty(),
+        file_name_length: 5,
+    };
+    let mut read_state =
+        ReadState::<TestFileStorer>::new_read(read_prefix, "large", "large_out").unwrap();
+    let _pushed = read_state.push_frame(TEST_FRAME_PAYLOAD).unwrap();
+    assert!(!read_state.needs_push());
+
+    let mut payload = prefixes::extract_bytes_u64(file_length).to_vec();
+    payload.resize(TEST_FRAME_PAYLOAD, 1);
+    read_state.pull_frame(Frame::data(payload)).unwrap();
+    assert_eq!(read_state.file_size, file_length);
+    assert!(read_state.needs_pull());
+    let read_content = unsafe { TestFileContext::get_context().files.get("large_out").unwrap() };
+    assert_eq!(read_content.len(), TEST_FRAME_PAYLOAD - READ_HEADER_LENGTH);
+}
+
+#[test]
+fn test_write_large_file_prefix() {
+    let file_length: u64 = 5 * 1024 * 1024 * 1024 + 7;
+    unsafe {
+        TestFileContext::get_context()
+            .fake_files
+            .insert("large_in".to_owned(), file_length);
+    }
+    let fl = TestFile::open_file("large_in").unwrap();
+    let write_prefix = WritePrefix {
+        flags: PrefixFlags::empty(),
+        file_name_length: 3,
+        file_length: fl.len(),
+    };
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_prefix(Prefixes::Write(write_prefix)).unwrap();
+    let sent = usb_ctx.pull_output_frame();
+    assert_eq!(Ok(Prefixes::Write(write_prefix)), sent.parse_prefix());
+
+    let mut write_state =
+        WriteState::<TestFile>::new_write(write_prefix, "fla", "large_in").unwrap();
+    let mut frame = Frame::data(Vec::new());
+    for _ in 0..3 {
+        assert!(write_state.needs_push());
+        frame = write_state.push_frame(TEST_FRAME_PAYLOAD).unwrap();
+        assert_eq!(frame.payload.len(), TEST_FRAME_PAYLOAD);
+    }
+    assert!(write_state.needs_push());
+    assert_eq!(frame.payload[0], fake_byte((2 * TEST_FRAME_PAYLOAD - 3) as u64));
 }
 
 #[test]
 fn test_write_file() {
-    //TODO: This
-    /*
-    let name = vec![b'f', b'l', b'a'];
-    let file = vec![b'H', b'e', b'l', b'l', b'o'];
+    let file = b"Hello, Switch! This file is long enough to need a few frames.".repeat(5);
+    unsafe {
+        TestFileContext::get_context()
+            .files
+            .insert("write_in".to_owned(), file.clone());
+    }
+    let write_prefix = WritePrefix {
+        flags: PrefixFlags::empty(),
+        file_name_length: 3,
+        file_length: file.len() as u64,
+    };
+    let mut write_state =
+        WriteState::<TestFile>::new_write(write_prefix, "fla", "write_in").unwrap();
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_frame(Frame::response(&Response::ok()));
+    run_command(&mut write_state, &mut usb_ctx).unwrap();
+
+    let sent = usb_ctx.pull_output_data();
+    assert_eq!(&sent[0..3], b"fla");
+    assert_eq!(&sent[3..], &file[..]);
+    assert!(usb_ctx.input_buf.is_empty());
+    assert_eq!(write_state.response, Some(Response::ok()));
+}
+
+#[test]
+fn test_write_file_checksum() {
+    let content = [b'w'; 150];
+    unsafe {
+        TestFileContext::get_context()
+            .files
+            .insert("cksum_in".to_owned(), content.to_vec());
+    }
+    let write_prefix = WritePrefix {
+        flags: PrefixFlags::empty().with_checksum(ChecksumKind::Sha256),
+        file_name_length: 3,
+        file_length: content.len() as u64,
+    };
+    let mut write_state =
+        WriteState::<TestFile>::new_write(write_prefix, "fla", "cksum_in").unwrap();
+    let mut usb_ctx = TestUsbDevice::empty();
+    while write_state.needs_push() {
+        let frame = write_state.push_frame(TEST_FRAME_PAYLOAD).unwrap();
+        usb_ctx.push_frame(frame).unwrap();
+    }
+
+    let mut sha = Checksum::new(ChecksumKind::Sha256);
+    sha.update(&content);
+    let digest = sha.finish();
+    let sent = usb_ctx.pull_output_data();
+    assert_eq!(sent.len(), 3 + content.len() + digest.len());
+    assert_eq!(&sent[3..3 + content.len()], &content[..]);
+    assert_eq!(&sent[3 + content.len()..], &digest[..]);
+
+    let response = Response::ok().with_checksum(digest);
+    write_state.pull_frame(Frame::response(&response)).unwrap();
+    assert!(!write_state.needs_pull());
+}
+
+#[test]
+fn test_read_file_checksum_mismatch() {
+    let read_prefix = ReadPrefix {
+        flags: PrefixFlags::empty().with_checksum(ChecksumKind::Crc32c),
+        file_name_length: 3,
+    };
+    let mut read_state =
+        ReadState::<TestFileStorer>::new_read(read_prefix, "fla", "corrupt_out").unwrap();
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_data(&[0, 0, 0, 0, 0, 0, 0, 5, b'H', b'e', b'l', b'l', b'o']);
+    let response = Response::ok().with_checksum(vec![0xde, 0xad, 0xbe, 0xef]);
+    usb_ctx.push_input_frame(Frame::response(&response));
+
+    let err = run_command(&mut read_state, &mut usb_ctx).unwrap_err();
+    assert!(err.contains("Checksum mismatch"), "Unexpected error {}", err);
+    assert!(unsafe { TestFileContext::get_context().files.get("corrupt_out").is_none() });
+}
+
+#[test]
+fn test_probe_file() {
+    let prefix = ProbePrefix {
+        flags: PrefixFlags::empty().with_checksum(ChecksumKind::Crc32c),
+        file_name_length: 6,
+        length: 5,
+    };
+    let mut probe_state = ProbeState::new_probe(prefix, "probed").unwrap();
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_data(&prefixes::extract_bytes_u64(14));
+    usb_ctx.push_input_frame(Frame::response(&Response::ok().with_checksum(vec![1, 2, 3, 4])));
+    run_command(&mut probe_state, &mut usb_ctx).unwrap();
+    assert_eq!(usb_ctx.pull_output_data(), b"probed".to_vec());
+    assert_eq!(probe_state.file_size, Some(14));
+    assert_eq!(probe_state.response.unwrap().checksum, vec![1, 2, 3, 4]);
+
+    let mut probe_state = ProbeState::new_probe(prefix, "probed").unwrap();
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_frame(Frame::response(&Response::ok()));
+    let err = run_command(&mut probe_state, &mut usb_ctx).unwrap_err();
+    assert!(err.contains("without sending its length"), "Unexpected error {}", err);
+}
+
+#[test]
+fn test_digest_start() {
+    unsafe {
+        TestFileContext::get_context()
+            .files
+            .insert("digest_in".to_owned(), b"Hello, Switch!".to_vec());
+    }
+    let mut fl = TestFile::open_file("digest_in").unwrap();
+    let mut check = Checksum::new(ChecksumKind::Sha256);
+    check.update(b"Hello");
+    assert_eq!(commands::digest_start(&mut fl, 5, ChecksumKind::Sha256), Ok(check.finish()));
+    let mut fl = TestFile::open_file("digest_in").unwrap();
+    assert!(commands::digest_start(&mut fl, 15, ChecksumKind::Sha256).is_err());
+}
 
+#[test]
+fn test_read_file_resume() {
+    unsafe {
+        TestFileContext::get_context()
+            .files
+            .insert("resume_out".to_owned(), b"Hello, Wo??".to_vec());
+    }
+    let read_prefix = ReadPrefix {
+        flags: PrefixFlags::OFFSET,
+        file_name_length: 3,
+    };
+    let mut read_state =
+        ReadState::<TestFileStorer>::new_resumed_read(read_prefix, "fla", "resume_out", 9).unwrap();
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_data(&[0, 0, 0, 0, 0, 0, 0, 3, b'r', b'l', b'd']);
+    usb_ctx.push_input_frame(Frame::response(&Response::ok()));
+    run_command(&mut read_state, &mut usb_ctx).unwrap();
+
+    let mut expected_request = b"fla".to_vec();
+    expected_request.extend_from_slice(&prefixes::extract_bytes_u64(9));
+    assert_eq!(usb_ctx.pull_output_data(), expected_request);
+    let read_content = unsafe { TestFileContext::get_context().files.get("resume_out").unwrap() };
+    assert_eq!(read_content, &b"Hello, World".to_vec());
+
+    // A failed resumed read keeps what the file started with.
+    let mut read_state =
+        ReadState::<TestFileStorer>::new_resumed_read(read_prefix, "fla", "resume_out", 5).unwrap();
     let mut usb_ctx = TestUsbDevice::empty();
-    usb_ctx.push_input(&name);
-    usb_ctx.push_input(&file);
-    usb_ctx.push_input(&[0; 100]);
-    usb_ctx.push_input(&[0; 100]);
-    usb_ctx.push_input(&[0; 100]);
-
-    let mut test_read_buffer: [u8; TEST_BLOCK_SIZE] = [0; TEST_BLOCK_SIZE];
-    let mut test_write_buffer: [u8; TEST_BLOCK_SIZE] = [0; TEST_BLOCK_SIZE];
+    usb_ctx.push_input_data(&[0, 0, 0, 0, 0, 0, 0, 7, b',', b' ']);
+    usb_ctx.push_input_frame(Frame::response(&Response::ok()));
+    assert!(run_command(&mut read_state, &mut usb_ctx).is_err());
+    let read_content = unsafe { TestFileContext::get_context().files.get("resume_out").unwrap() };
+    assert_eq!(read_content, &b"Hello".to_vec());
+
+    assert!(ReadState::<TestFileStorer>::new_resumed_read(
+        ReadPrefix { flags: PrefixFlags::empty(), file_name_length: 3 },
+        "fla",
+        "resume_out",
+        5
+    ).is_err());
+}
+
+#[test]
+fn test_write_file_resume() {
+    unsafe {
+        TestFileContext::get_context()
+            .files
+            .insert("resume_in".to_owned(), b"Hello, World".to_vec());
+    }
     let write_prefix = WritePrefix {
-        flags: 0b1000000000000000,
+        flags: PrefixFlags::OFFSET,
         file_name_length: 3,
-        file_length: 5,
+        file_length: 3,
     };
-    let mut write_command = WriteCommandState::<TestFileWriter>::from_prefix(write_prefix);
+    let mut write_state =
+        WriteState::<TestFile>::new_resumed_write(write_prefix, "fla", "resume_in", 9).unwrap();
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_frame(Frame::response(&Response::ok()));
+    run_command(&mut write_state, &mut usb_ctx).unwrap();
+
+    let mut expected = b"fla".to_vec();
+    expected.extend_from_slice(&prefixes::extract_bytes_u64(9));
+    expected.extend_from_slice(b"rld");
+    assert_eq!(usb_ctx.pull_output_data(), expected);
+}
+
+#[test]
+fn test_read_range() {
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_data(&[0, 0, 0, 0, 0, 0, 0, 5, b'l', b'l', b'o', b',', b' ']);
+    usb_ctx.push_input_frame(Frame::response(&Response::ok()));
+    let read = usb_ctx
+        .read_range::<TestFileStorer>("fla", "range_out", 2, 5, ChecksumKind::None)
+        .unwrap();
+    assert_eq!(read, 5);
+
+    match usb_ctx.pull_output_frame().parse_prefix() {
+        Ok(Prefixes::Read(prefix)) => assert!(prefix.flags.contains(PrefixFlags::OFFSET | PrefixFlags::LIMIT)),
+        other => panic!("Client sent {:?} instead of a read.", other),
+    }
+    let mut expected_request = b"fla".to_vec();
+    expected_request.extend_from_slice(&prefixes::extract_bytes_u64(2));
+    expected_request.extend_from_slice(&prefixes::extract_bytes_u64(5));
+    assert_eq!(usb_ctx.pull_output_data(), expected_request);
+    let read_content = unsafe { TestFileContext::get_context().files.get("range_out").unwrap() };
+    assert_eq!(read_content, &b"llo, ".to_vec());
+
+    let read_prefix = ReadPrefix {
+        flags: PrefixFlags::OFFSET,
+        file_name_length: 3,
+    };
+    assert!(ReadState::<TestFileStorer>::new_ranged_read(read_prefix, "fla", "range_out", 2, 5).is_err());
+}
 
-    while write_command.needs_input() || write_command.needs_output() {
-        if write_command.needs_input() {
-            let _blk = usb_ctx.read_block(&mut test_read_buffer).unwrap();
-            let _write = write_command.input_block(&test_read_buffer).unwrap();
+#[test]
+fn test_read_file_abort() {
+    let abort = |output_name: &str, policy: AbortPolicy| {
+        let mut usb_ctx = TestUsbDevice::empty();
+        let read_prefix = ReadPrefix {
+            flags: PrefixFlags::empty(),
+            file_name_length: 3,
+        };
+        let mut read_state = ReadState::<TestFileStorer>::new_read(read_prefix, "fla", output_name).unwrap();
+        usb_ctx.push_prefix(Prefixes::Read(read_prefix)).unwrap();
+        let frame = read_state.push_frame(TEST_FRAME_PAYLOAD).unwrap();
+        usb_ctx.push_frame(frame).unwrap();
+        usb_ctx.push_input_data(&[0, 0, 0, 0, 0, 0, 0, 5, b'H', b'e']);
+        let frame = usb_ctx.pull_frame().unwrap();
+        read_state.pull_frame(frame).unwrap();
+
+        // The server finishes sending the file before it sees the abort.
+        usb_ctx.push_input_data(&[b'l', b'l', b'o']);
+        usb_ctx.push_input_frame(Frame::response(&Response::ok()));
+        usb_ctx.push_input_frame(Frame::response(&Response::error(ResponseCode::Aborted, "Command aborted.".to_owned())));
+        let err = usb_ctx.abort(policy, &mut read_state).unwrap_err();
+        assert!(err.contains("aborted"), "Unexpected error {}", err);
+        assert!(usb_ctx.input_buf.is_empty());
+        assert_eq!(usb_ctx.pull_output_frame().kind, FrameKind::Prefix);
+        assert_eq!(usb_ctx.pull_output_frame().payload, b"fla".to_vec());
+        assert_eq!(usb_ctx.pull_output_frame().parse_abort().unwrap(), policy);
+    };
+
+    let ctx = unsafe { TestFileContext::get_context() };
+    abort("aborted_deleted", AbortPolicy::Delete);
+    assert!(!ctx.files.contains_key("aborted_deleted"));
+    abort("aborted_kept", AbortPolicy::Keep);
+    assert_eq!(ctx.files.get("aborted_kept").unwrap(), &b"He".to_vec());
+
+    // An abort asked for before the command gets going is sent straight
+    // after the prefix.
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.abort = Some(AbortPolicy::Delete);
+    usb_ctx.push_input_frame(Frame::response(&Response::error(ResponseCode::Aborted, "Command aborted.".to_owned())));
+    let read_prefix = ReadPrefix {
+        flags: PrefixFlags::empty(),
+        file_name_length: 3,
+    };
+    let mut read_state = ReadState::<TestFileStorer>::new_read(read_prefix, "fla", "never_read").unwrap();
+    assert!(usb_ctx.run_command(Prefixes::Read(read_prefix), &mut read_state).is_err());
+    assert_eq!(usb_ctx.pull_output_frame().kind, FrameKind::Prefix);
+    assert_eq!(usb_ctx.pull_output_frame().kind, FrameKind::Abort);
+    assert!(!ctx.files.contains_key("never_read"));
+}
+
+#[test]
+fn test_list_dir() {
+    let entry = |name: &str, kind: EntryKind, size: u64| ListEntry {
+        name: name.to_owned(),
+        kind,
+        size,
+        modified: 1_500_000_000,
+    };
+    let pages = vec![
+        ListPage {
+            total: 3,
+            entries: vec![entry("a.bin", EntryKind::File, 0x1_0000_0000), entry("b.txt", EntryKind::File, 5)],
+        },
+        ListPage {
+            total: 3,
+            entries: vec![entry("sub", EntryKind::Dir, 0)],
+        },
+    ];
+    let mut usb_ctx = TestUsbDevice::empty();
+    for page in &pages {
+        let bytes = page.serialize();
+        for chunk in bytes.chunks(TEST_FRAME_PAYLOAD) {
+            usb_ctx.push_input_data(chunk);
         }
-        if write_command.needs_output() {
-            let _written = write_command.output_block(&mut test_write_buffer).unwrap();
-            let _blk = usb_ctx.write_block(&test_write_buffer).unwrap();
+        let mut check = Checksum::new(ChecksumKind::Crc32c);
+        check.update(&bytes);
+        usb_ctx.push_input_frame(Frame::response(&Response::ok().with_checksum(check.finish())));
+    }
+    let entries = usb_ctx.list_dir("listed", true, ChecksumKind::Crc32c).unwrap();
+    assert!(usb_ctx.input_buf.is_empty());
+    let names: Vec<&str> = entries.iter().map(|ent| ent.name.as_str()).collect();
+    assert_eq!(names, vec!["a.bin", "b.txt", "sub"]);
+    assert_eq!(entries[0], pages[0].entries[0]);
+
+    // The second page starts after the entries of the first.
+    for start in 0..2 {
+        let prefix = usb_ctx.pull_output_frame().parse_prefix::<ListPrefix>().unwrap();
+        assert_eq!(prefix.start, start * 2);
+        assert!(prefix.flags.contains(PrefixFlags::RECURSIVE));
+        assert_eq!(usb_ctx.pull_output_frame().payload, b"listed".to_vec());
+    }
+
+    assert_eq!(
+        commands::format_list_entry(&entries[1]),
+        "-            5 2017-07-14 02:40 b.txt"
+    );
+    assert_eq!(commands::format_time(0), "1970-01-01 00:00");
+    assert_eq!(commands::format_time(951_827_696), "2000-02-29 12:34");
+}
+
+#[test]
+fn test_stat() {
+    let entry = ListEntry {
+        name: "file.txt".to_owned(),
+        kind: EntryKind::File,
+        size: 5,
+        modified: 1_500_000_000,
+    };
+    let mut usb_ctx = TestUsbDevice::empty();
+    let mut bytes = Vec::new();
+    entry.serialize_into(&mut bytes);
+    usb_ctx.push_input_data(&bytes);
+    usb_ctx.push_input_frame(Frame::response(&Response::ok()));
+    usb_ctx.push_input_frame(Frame::response(&Response::error(ResponseCode::NotFound, "Nothing here.".to_owned())));
+
+    assert_eq!(usb_ctx.stat("dir/file.txt", PrefixFlags::FOLLOW_LINKS), Ok(Some(entry)));
+    assert_eq!(usb_ctx.stat("dir/missing", PrefixFlags::empty()), Ok(None));
+    assert!(usb_ctx.input_buf.is_empty());
+    let prefix = usb_ctx.pull_output_frame().parse_prefix::<StatPrefix>().unwrap();
+    assert_eq!(prefix.flags, PrefixFlags::FOLLOW_LINKS);
+    assert_eq!(usb_ctx.pull_output_frame().payload, b"dir/file.txt".to_vec());
+}
+
+#[test]
+fn test_fs_info() {
+    let info = nxusb::fsinfo::FsInfo {
+        total_bytes: 64 << 30,
+        free_bytes: 3 << 30,
+        cluster_size: 0x8000,
+        kind: nxusb::fsinfo::FsKind::ExFat,
+    };
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_data(&info.serialize());
+    usb_ctx.push_input_frame(Frame::response(&Response::ok()));
+    usb_ctx.push_input_frame(Frame::response(&Response::error(ResponseCode::NotFound, "No such device.".to_owned())));
+
+    assert_eq!(usb_ctx.fs_info("sdmc:/"), Ok(info));
+    let err = usb_ctx.fs_info("usb:/").unwrap_err();
+    assert!(err.contains("No such device."), "Unexpected error {}", err);
+    assert!(usb_ctx.input_buf.is_empty());
+    let prefix = usb_ctx.pull_output_frame().parse_prefix::<prefixes::FsInfoPrefix>().unwrap();
+    assert_eq!(prefix.flags, PrefixFlags::empty());
+    assert_eq!(usb_ctx.pull_output_frame().payload, b"sdmc:/".to_vec());
+
+    assert_eq!(commands::mount_root("sdmc:/switch/tool.nro"), "sdmc:/");
+    assert_eq!(commands::mount_root("/switch/tool.nro"), "/");
+    assert_eq!(
+        commands::format_fs_info("sdmc:/", &info),
+        "sdmc:/ exFAT    68719476736 total     3221225472 free    32768 per cluster"
+    );
+}
+
+#[test]
+fn test_make_dir() {
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_frame(Frame::response(&Response::ok()));
+    usb_ctx.push_input_frame(Frame::response(&Response::error(ResponseCode::Exists, "Already there.".to_owned())));
+    assert!(usb_ctx.make_dir("sdmc:/switch/newtool", true).is_ok());
+    let err = usb_ctx.make_dir("sdmc:/switch/newtool", false).unwrap_err();
+    assert!(err.contains("Already there."), "Unexpected error {}", err);
+
+    let prefix = usb_ctx.pull_output_frame().parse_prefix::<MakeDirPrefix>().unwrap();
+    assert_eq!(prefix.flags, PrefixFlags::CREATE_PARENTS);
+    assert_eq!(usb_ctx.pull_output_frame().payload, b"sdmc:/switch/newtool".to_vec());
+    let prefix = usb_ctx.pull_output_frame().parse_prefix::<MakeDirPrefix>().unwrap();
+    assert_eq!(prefix.flags, PrefixFlags::empty());
+}
+
+#[test]
+fn test_remove() {
+    let mut usb_ctx = TestUsbDevice::empty();
+    let mut report = Vec::new();
+    RemovalRecord::removed("sdmc:/old/a.nro".to_owned(), EntryKind::File).serialize_into(&mut report);
+    RemovalRecord::failed("sdmc:/old".to_owned(), EntryKind::Dir, ResponseCode::Io, "Directory not empty.".to_owned()).serialize_into(&mut report);
+    let mut check = Checksum::new(ChecksumKind::Crc32c);
+    check.update(&report);
+    usb_ctx.push_input_frame(Frame::data(report[0..10].to_vec()));
+    usb_ctx.push_input_frame(Frame::data(report[10..].to_vec()));
+    usb_ctx.push_input_frame(Frame::response(
+        &Response::error(ResponseCode::Io, "1 of 2 entries could not be removed.".to_owned()).with_checksum(check.finish()),
+    ));
+    usb_ctx.push_input_frame(Frame::response(&Response::error(ResponseCode::InvalidInput, "Flags 0x0020 do not apply.".to_owned())));
+
+    let records = usb_ctx.remove("sdmc:/old", true, ChecksumKind::Crc32c).unwrap();
+    assert_eq!(records.len(), 2);
+    assert!(records[0].is_removed());
+    assert_eq!((records[1].path.as_str(), records[1].message.as_str()), ("sdmc:/old", "Directory not empty."));
+    // A command the server refuses outright has no records to report.
+    let err = usb_ctx.remove_dir("sdmc:/old", ChecksumKind::Crc32c).unwrap_err();
+    assert!(err.contains("do not apply"), "Unexpected error {}", err);
+
+    let prefix = usb_ctx.pull_output_frame().parse_prefix::<RemovePrefix>().unwrap();
+    assert_eq!(prefix.flags, PrefixFlags::RECURSIVE.with_checksum(ChecksumKind::Crc32c));
+    assert!(!prefix.dir);
+    assert_eq!(usb_ctx.pull_output_frame().payload, b"sdmc:/old".to_vec());
+    let prefix = usb_ctx.pull_output_frame().parse_prefix::<RemovePrefix>().unwrap();
+    assert!(prefix.dir);
+}
+
+#[test]
+fn test_move() {
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_frame(Frame::response(&Response::error(ResponseCode::Exists, "sdmc:/new.nro already exists.".to_owned())));
+    usb_ctx.push_input_frame(Frame::response(&Response::ok()));
+    let err = usb_ctx.move_path("sdmc:/old.nro", "sdmc:/new.nro", false).unwrap_err();
+    assert!(err.contains("already exists"), "Unexpected error {}", err);
+    assert!(usb_ctx.move_path("sdmc:/old.nro", "sdmc:/new.nro", true).is_ok());
+
+    let prefix = usb_ctx.pull_output_frame().parse_prefix::<MovePrefix>().unwrap();
+    assert_eq!(prefix.flags, PrefixFlags::empty());
+    assert_eq!((prefix.file_name_length, prefix.dest_name_length), (13, 13));
+    assert_eq!(usb_ctx.pull_output_frame().payload, b"sdmc:/old.nrosdmc:/new.nro".to_vec());
+    let prefix = usb_ctx.pull_output_frame().parse_prefix::<MovePrefix>().unwrap();
+    assert_eq!(prefix.flags, PrefixFlags::OVERWRITE);
+}
+
+#[test]
+fn test_overwrite_policy_flags() {
+    for name in &["fail", "overwrite", "skip", "rename"] {
+        let policy = OverwritePolicy::parse(name).unwrap();
+        let flags = (policy.flags() | PrefixFlags::CREATE_PARENTS).with_checksum(ChecksumKind::Crc32c);
+        assert_eq!(OverwritePolicy::from_flags(flags), Some(policy));
+    }
+    assert_eq!(OverwritePolicy::parse("sometimes"), None);
+    assert_eq!(OverwritePolicy::from_flags(PrefixFlags::OVERWRITE | PrefixFlags::SKIP_IDENTICAL), None);
+}
+
+#[test]
+fn test_walk_tree() {
+    let root = std::env::temp_dir().join(format!("nxusb_walk_tree_{}", std::process::id()));
+    std::fs::create_dir_all(root.join("romfs/empty")).unwrap();
+    std::fs::write(root.join("app.nro"), b"NRO0").unwrap();
+    std::fs::write(root.join("romfs/b.bin"), b"B").unwrap();
+    std::fs::write(root.join("romfs/a.bin"), b"A").unwrap();
+    let entries = fileio::walk_tree(&root.to_string_lossy()).unwrap();
+    std::fs::remove_dir_all(&root).unwrap();
+    let expected = vec![
+        ("app.nro", false),
+        ("romfs", true),
+        ("romfs/a.bin", false),
+        ("romfs/b.bin", false),
+        ("romfs/empty", true),
+    ];
+    assert_eq!(
+        entries,
+        expected.into_iter().map(|(name, is_dir)| (name.to_owned(), is_dir)).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_set_modified() {
+    let path = std::env::temp_dir().join(format!("nxusb_set_modified_{}", std::process::id()));
+    std::fs::write(&path, b"NRO0").unwrap();
+    fileio::set_modified(&path.to_string_lossy(), 1_500_000_000).unwrap();
+    let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(modified.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs(), 1_500_000_000);
+    assert!(fileio::set_modified("/nxusb/not/a/real/path", 0).is_err());
+}
+
+#[test]
+fn test_plan_push() {
+    let local_entry = |name: &str, is_dir: bool, size: u64, modified: u64| ::sync::LocalEntry {
+        name: name.to_owned(),
+        is_dir,
+        size,
+        modified,
+    };
+    let remote_entry = |name: &str, kind: EntryKind, size: u64, modified: u64| ListEntry {
+        name: name.to_owned(),
+        kind,
+        size,
+        modified,
+    };
+    let local = vec![
+        local_entry("app.nro", false, 4, 1000),
+        local_entry("romfs", true, 0, 1000),
+        local_entry("romfs/a.bin", false, 1, 1001),
+        local_entry("romfs/b.bin", false, 1, 1010),
+        local_entry("romfs/c.bin", false, 2, 900),
+        local_entry("saves", true, 0, 1000),
+        local_entry("saves/new.sav", false, 8, 1000),
+    ];
+    let remote = vec![
+        remote_entry("app.nro", EntryKind::File, 4, 1000),
+        remote_entry("old", EntryKind::Dir, 0, 1000),
+        remote_entry("old/stale.bin", EntryKind::File, 1, 1000),
+        remote_entry("romfs", EntryKind::Dir, 0, 1000),
+        remote_entry("romfs/a.bin", EntryKind::File, 1, 1000),
+        remote_entry("romfs/b.bin", EntryKind::File, 1, 1000),
+        remote_entry("romfs/c.bin", EntryKind::File, 3, 1000),
+        remote_entry("romfs/gone.bin", EntryKind::File, 1, 1000),
+    ];
+    let plan = ::sync::plan_push(&local, &remote, false);
+    assert_eq!(plan.dirs, vec!["saves".to_owned()]);
+    assert_eq!(
+        plan.files,
+        vec![
+            ("romfs/b.bin".to_owned(), ::sync::FileState::Changed),
+            ("romfs/c.bin".to_owned(), ::sync::FileState::Changed),
+            ("saves/new.sav".to_owned(), ::sync::FileState::New),
+        ]
+    );
+    assert_eq!(plan.extras, vec!["old".to_owned(), "romfs/gone.bin".to_owned()]);
+    assert_eq!(plan.unchanged, 2);
+
+    let plan = ::sync::plan_push(&local, &remote, true);
+    assert_eq!(plan.files[0], ("app.nro".to_owned(), ::sync::FileState::CheckHash));
+    assert_eq!(plan.unchanged, 0);
+}
+
+#[test]
+fn test_plan_sync() {
+    use sync::{Side, Snapshot, SyncAction, SyncState};
+    let local_entry = |name: &str, size: u64, modified: u64| ::sync::LocalEntry {
+        name: name.to_owned(),
+        is_dir: false,
+        size,
+        modified,
+    };
+    let remote_entry = |name: &str, size: u64, modified: u64| ListEntry {
+        name: name.to_owned(),
+        kind: EntryKind::File,
+        size,
+        modified,
+    };
+    let snapshot = |size: u64, modified: u64| Snapshot {
+        is_dir: false,
+        size,
+        modified,
+    };
+    let mut base = SyncState::new();
+    for name in &["config.ini", "deleted.sav", "edited.sav", "same.bin", "both.sav"] {
+        base.insert(name.to_string(), (snapshot(4, 1000), snapshot(4, 2000)));
+    }
+    let local = vec![
+        local_entry("both.sav", 5, 3000),
+        local_entry("config.ini", 4, 1001),
+        local_entry("edited.sav", 4, 1000),
+        local_entry("new.bin", 1, 3000),
+        local_entry("same.bin", 4, 1000),
+    ];
+    let remote = vec![
+        remote_entry("both.sav", 6, 3000),
+        remote_entry("config.ini", 4, 2000),
+        remote_entry("deleted.sav", 4, 2000),
+        remote_entry("edited.sav", 8, 2500),
+        remote_entry("same.bin", 4, 2000),
+        remote_entry(::sync::STATE_FILE_NAME, 300, 2000),
+    ];
+    let actions = |prefer| {
+        ::sync::plan_sync(&local, &remote, &base, prefer)
+            .into_iter()
+            .map(|step| (step.name, step.action, step.conflict))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        actions(None),
+        vec![
+            ("both.sav".to_owned(), SyncAction::Conflict, true),
+            ("deleted.sav".to_owned(), SyncAction::RemoveRemote, false),
+            ("edited.sav".to_owned(), SyncAction::Pull, false),
+            ("new.bin".to_owned(), SyncAction::Push, false),
+        ]
+    );
+    assert_eq!(actions(Some(Side::Local))[0], ("both.sav".to_owned(), SyncAction::Push, true));
+    assert_eq!(actions(Some(Side::Remote))[0], ("both.sav".to_owned(), SyncAction::Pull, true));
+    // The state file on the Switch is neither pulled nor removed by a push.
+    let extras = ::sync::plan_push(&local, &remote, false).extras;
+    assert_eq!(extras, vec!["deleted.sav".to_owned()]);
+
+    let unsettled = vec!["both.sav".to_owned()].into_iter().collect();
+    let state = ::sync::settle(&local, &remote, &base, &unsettled);
+    assert_eq!(state.get("both.sav"), base.get("both.sav"));
+    assert_eq!(state.get("same.bin"), Some(&(snapshot(4, 1000), snapshot(4, 2000))));
+    assert_eq!(state.get("deleted.sav"), None);
+
+    let text = ::sync::serialize_state("sdmc:/other\tx\tf\t1\t2\t3\t4\n", "sdmc:/switch", &state);
+    assert!(text.starts_with("sdmc:/other\tx\tf\t1\t2\t3\t4\n"));
+    assert_eq!(::sync::parse_state(&text, "sdmc:/switch").unwrap(), state);
+    assert_eq!(::sync::parse_state(&text, "sdmc:/other").unwrap().len(), 1);
+    assert!(::sync::parse_state("sdmc:/switch\tx\tf\t1\n", "sdmc:/switch").is_err());
+
+    // Tabs, line breaks and backslashes in names are escaped.
+    let mut odd = SyncState::new();
+    odd.insert("tab\there".to_owned(), (snapshot(1, 2), snapshot(3, 4)));
+    odd.insert("line\nbreak\\".to_owned(), (snapshot(5, 6), snapshot(7, 8)));
+    let text = ::sync::serialize_state("", "sdmc:/odd\tdir", &odd);
+    assert_eq!(text.lines().count(), 2);
+    assert_eq!(::sync::parse_state(&text, "sdmc:/odd\tdir").unwrap(), odd);
+    assert_eq!(::sync::serialize_state(&text, "sdmc:/odd\tdir", &SyncState::new()), "");
+    assert!(::sync::parse_state("sdmc:/switch\tbad\\q\tf\t1\t2\t3\t4\n", "sdmc:/switch").is_err());
+}
+
+#[test]
+fn test_delta_transfers() {
+    use commands::ReadDeltaState;
+    use nxusb::delta::{self, Decoded, DeltaDecoder, SignatureBuilder};
+    use nxusb::prefixes::ReadDeltaPrefix;
+    let old: Vec<u8> = (0..20_000u64).map(|idx| (idx * 7919 % 251) as u8).collect();
+    let mut new = old.clone();
+    new.splice(3000..3500, vec![0x11; 200]);
+    new[15_000..15_010].copy_from_slice(&[0x22; 10]);
+    let files = unsafe { &mut TestFileContext::get_context().files };
+    files.insert("delta_old".to_owned(), old.clone());
+    files.insert("delta_new".to_owned(), new.clone());
+    let kind = ChecksumKind::Crc32c;
+
+    let signature = commands::signature_of(&mut TestFile::open_file("delta_old").unwrap(), 2048, kind).unwrap();
+    let mut builder = SignatureBuilder::new(2048, kind);
+    builder.input(&old);
+    assert_eq!(signature, builder.finish());
+    let (ops, digest) = commands::plan_delta(&mut TestFile::open_file("delta_new").unwrap(), &signature).unwrap();
+    let mut check = Checksum::new(kind);
+    check.update(&new);
+    assert_eq!(digest, check.finish());
+    let literal_len = delta::literal_len(&ops);
+    assert!(literal_len < 3 * 2048);
+
+    // A delta write sends the stream and then the digest of the whole file,
+    // and the stream rebuilds the new version from the old one.
+    let stream_len = delta::encoded_len(&ops);
+    let write_prefix = WritePrefix {
+        flags: (PrefixFlags::OVERWRITE | PrefixFlags::DELTA).with_checksum(kind),
+        file_name_length: 3,
+        file_length: stream_len,
+    };
+    let mut write_state = WriteState::<TestFile>::new_delta_write(write_prefix, "fla", "delta_new", ops.clone(), digest.clone()).unwrap();
+    let mut usb_ctx = TestUsbDevice::empty();
+    while write_state.needs_push() {
+        let frame = write_state.push_frame(TEST_FRAME_PAYLOAD).unwrap();
+        usb_ctx.push_frame(frame).unwrap();
+    }
+    let sent = usb_ctx.pull_output_data();
+    assert_eq!(sent.len() as u64, 3 + stream_len + digest.len() as u64);
+    assert_eq!(&sent[sent.len() - digest.len()..], &digest[..]);
+    let stream = sent[3..sent.len() - digest.len()].to_vec();
+    let mut rebuilt = Vec::new();
+    let mut decoder = DeltaDecoder::new();
+    let mut rest = &stream[..];
+    while !rest.is_empty() {
+        let (used, piece) = decoder.input(rest).unwrap();
+        match piece {
+            Some(Decoded::Literal(bytes)) => rebuilt.extend_from_slice(bytes),
+            Some(Decoded::Copy { offset, length }) => rebuilt.extend_from_slice(&old[offset as usize..(offset + length) as usize]),
+            None => {}
         }
+        rest = &rest[used..];
     }
+    assert_eq!(rebuilt, new);
+    write_state.pull_frame(Frame::response(&Response::ok().with_checksum(digest.clone()))).unwrap();
 
-    let fl_ctx = unsafe { TestFileContext::get_context() };
-    let written_fl = if let Some(f) = fl_ctx.files.get("fla") {
-        f
-    } else {
-        panic!(
-            "Couldn't find the file \"fla\" in the context map {:?}",
-            fl_ctx.files
-        );
+    // A delta read rebuilds the file from the same stream and the local copy,
+    // and removes it if the result does not hash to what the server sent.
+    let read_prefix = ReadDeltaPrefix {
+        flags: PrefixFlags::empty().with_checksum(kind),
+        file_name_length: 3,
+        signature_length: signature.serialize().len() as u64,
     };
-    assert_eq!(written_fl, &file);
-    assert!(usb_ctx.input_buf.into_iter().all(|a| a == 0));
-    assert!(usb_ctx.output_buf.is_empty());
-    */
+    for good in [true, false].iter() {
+        let mut read_state =
+            ReadDeltaState::<TestFileStorer, TestFile>::new_read_delta(read_prefix, "fla", "delta_out", "delta_old", &signature).unwrap();
+        let mut usb_ctx = TestUsbDevice::empty();
+        let mut output = prefixes::extract_bytes_u64(new.len() as u64).to_vec();
+        output.extend_from_slice(&stream);
+        for chunk in output.chunks(TEST_FRAME_PAYLOAD) {
+            usb_ctx.push_input_data(chunk);
+        }
+        let checksum = if *good { digest.clone() } else { vec![0; digest.len()] };
+        usb_ctx.push_input_frame(Frame::response(&Response::ok().with_checksum(checksum)));
+        let result = run_command(&mut read_state, &mut usb_ctx);
+        let mut expected_request = b"fla".to_vec();
+        expected_request.extend_from_slice(&signature.serialize());
+        assert_eq!(usb_ctx.pull_output_data(), expected_request);
+        if *good {
+            assert_eq!(result, Ok(()));
+            assert_eq!(read_state.literal_bytes, literal_len);
+            assert_eq!(files["delta_out"], new);
+        } else {
+            assert!(result.is_err());
+            assert!(!files.contains_key("delta_out"));
+        }
+    }
+
+    // A delta that goes wrong partway leaves no partial file behind either.
+    let mut read_state =
+        ReadDeltaState::<TestFileStorer, TestFile>::new_read_delta(read_prefix, "fla", "delta_out", "delta_old", &signature).unwrap();
+    let mut usb_ctx = TestUsbDevice::empty();
+    let mut output = prefixes::extract_bytes_u64(1).to_vec();
+    output.extend_from_slice(&stream);
+    for chunk in output.chunks(TEST_FRAME_PAYLOAD) {
+        usb_ctx.push_input_data(chunk);
+    }
+    let err = run_command(&mut read_state, &mut usb_ctx).unwrap_err();
+    assert!(err.contains("rebuilds more than"), "Unexpected error {}", err);
+    assert!(!files.contains_key("delta_out"));
+}
+
+#[test]
+fn test_hash() {
+    use nxusb::hashing::{self, HashRecord};
+    use nxusb::prefixes::HashPrefix;
+    let mut usb_ctx = TestUsbDevice::empty();
+    let mut report = Vec::new();
+    HashRecord::hashed("sdmc:/roms/a.bin".to_owned(), 9, vec![0xcb, 0xf4, 0x39, 0x26]).serialize_into(&mut report);
+    HashRecord::failed("sdmc:/roms/b.bin".to_owned(), ResponseCode::Io, "Read failed.".to_owned()).serialize_into(&mut report);
+    let mut check = Checksum::new(ChecksumKind::Crc32);
+    check.update(&report);
+    // The server sends empty frames while it is still hashing.
+    usb_ctx.push_input_frame(Frame::data(Vec::new()));
+    usb_ctx.push_input_frame(Frame::data(report[0..20].to_vec()));
+    usb_ctx.push_input_frame(Frame::data(report[20..].to_vec()));
+    usb_ctx.push_input_frame(Frame::response(
+        &Response::error(ResponseCode::Io, "1 of 2 files could not be hashed.".to_owned()).with_checksum(check.finish()),
+    ));
+    usb_ctx.push_input_frame(Frame::response(&Response::error(ResponseCode::NotFound, "Nothing named sdmc:/gone.".to_owned())));
+
+    let records = usb_ctx.hash("sdmc:/roms", true, ChecksumKind::Crc32).unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!((records[0].size, records[0].digest.clone()), (9, vec![0xcb, 0xf4, 0x39, 0x26]));
+    assert!(!records[1].is_hashed());
+    let err = usb_ctx.hash("sdmc:/gone", false, ChecksumKind::Sha256).unwrap_err();
+    assert!(err.contains("Nothing named"), "Unexpected error {}", err);
+    let prefix = usb_ctx.pull_output_frame().parse_prefix::<HashPrefix>().unwrap();
+    assert_eq!(prefix.flags, PrefixFlags::RECURSIVE.with_checksum(ChecksumKind::Crc32));
+
+    // Manifest lines read back the way sha256sum writes them, escapes and all.
+    let line = hashing::manifest_line(&records[0].digest, "a.bin");
+    assert_eq!(line, "cbf43926  a.bin");
+    assert_eq!(hashing::parse_manifest_line(&line), Ok((records[0].digest.clone(), "a.bin".to_owned())));
+    let line = hashing::manifest_line(&[0xab], "odd\\name\n.bin");
+    assert_eq!(line, "\\ab  odd\\\\name\\n.bin");
+    assert_eq!(hashing::parse_manifest_line(&line), Ok((vec![0xab], "odd\\name\n.bin".to_owned())));
+    assert_eq!(hashing::parse_manifest_line("ab *binary.bin"), Ok((vec![0xab], "binary.bin".to_owned())));
+    assert!(hashing::parse_manifest_line("zz  bad.bin").is_err());
 }
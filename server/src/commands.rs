@@ -1,4 +1,14 @@
-use nxusb::prefixes::{CommandPrefix, ReadPrefix, WritePrefix, Prefixes};
+use nxusb::checksum::{self, Checksum, ChecksumKind};
+use nxusb::delta::{self, BlockSignature, Decoded, DeltaDecoder, DeltaEncoder, DeltaOp, DeltaPlanner, Signature};
+use nxusb::frame::{AbortPolicy, Frame, FrameKind, MAX_FRAME_PAYLOAD};
+use nxusb::fsinfo::FsInfo;
+use nxusb::handshake::{self, PROTOCOL_VERSION, SUPPORTED_FEATURES};
+use nxusb::hashing::HashRecord;
+use nxusb::listing::{EntryKind, ListEntry, ListPage};
+use nxusb::prefixes::{self, CommandPrefix, FsInfoPrefix, HandshakePrefix, HashPrefix, ListPrefix, MakeDirPrefix, MovePrefix, OverwritePolicy, PrefixFlags, ProbePrefix, ReadDeltaPrefix, ReadPrefix, RemovePrefix, SignaturePrefix, StatPrefix, WritePrefix, Prefixes, OFFSET_LENGTH, PREFIX_LENGTH};
+use nxusb::removal::RemovalRecord;
+use std::marker::PhantomData;
+use nxusb::response::{Response, ResponseCode};
 
 macro_rules! dprintln {
     () => ({
@@ -18,45 +28,328 @@ pub trait ServerCommandState<T: CommandPrefix> {
     /// communication line.
     fn needs_input(&self) -> bool;
 
-    /// Passes a block from the communication line to the command, and returns
-    /// either the number of bytes read from the block or an error message.
-    fn input_block(&mut self, block: &[u8]) -> Result<usize, String>;
+    /// Passes a frame from the communication line to the command, or returns
+    /// an error message if the command cannot use it.
+    fn input_frame(&mut self, frame: Frame) -> Result<(), String>;
 
     /// Checks or not the command being run needs to pass output to the
     /// communication line.
     fn needs_output(&self) -> bool;
 
-    /// Passes a block to the communication line from the command, and returns
-    /// either the number of bytes writen to the block or an error message.
-    fn output_block(&mut self, buffer: &mut [u8]) -> Result<usize, String>;
+    /// Gets the next frame to pass to the communication line from the
+    /// command, with a payload of at most `max_payload` bytes.
+    fn output_frame(&mut self, max_payload: usize) -> Result<Frame, String>;
+
+    /// Stops the command before it finishes, as an abort frame asks, deleting
+    /// or keeping anything it wrote as the policy says. Returns the response
+    /// acknowledging the abort.
+    fn abort(&mut self, _policy: AbortPolicy) -> Response {
+        Response::error(ResponseCode::Aborted, "Command aborted.".to_owned())
+    }
+}
+
+/// Takes the payload out of a data frame, or fails if a command was sent some
+/// other kind of frame in the middle of its input.
+fn data_payload(frame: Frame) -> Result<Vec<u8>, String> {
+    match frame.kind {
+        FrameKind::Data => Ok(frame.payload),
+        kind => Err(format!(
+            "Expected a data frame but got a {:?} frame (sequence {}).",
+            kind, frame.sequence
+        )),
+    }
+}
+
+/// Collects the file name that starts a command's input, and the offset and
+/// byte count after it if the flags ask for them.
+#[derive(Debug)]
+struct NameInput {
+    name_length: usize,
+    offset_length: usize,
+    limit_length: usize,
+    bytes: Vec<u8>,
+}
+
+impl NameInput {
+    fn new(name_length: u16, flags: PrefixFlags) -> NameInput {
+        let offset_length = if flags.contains(PrefixFlags::OFFSET) { OFFSET_LENGTH } else { 0 };
+        let limit_length = if flags.contains(PrefixFlags::LIMIT) { OFFSET_LENGTH } else { 0 };
+        NameInput {
+            name_length: name_length as usize,
+            offset_length,
+            limit_length,
+            bytes: Vec::with_capacity(name_length as usize + offset_length + limit_length),
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.name_length + self.offset_length + self.limit_length - self.bytes.len()
+    }
+
+    fn is_complete(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// Takes bytes from the start of the block, returning the number used.
+    fn input(&mut self, block: &[u8]) -> usize {
+        let taken = self.remaining().min(block.len());
+        self.bytes.extend_from_slice(&block[0..taken]);
+        taken
+    }
+
+    /// Takes a frame of a command whose input is only the name and what
+    /// follows it, refusing any bytes past them. Returns the name once all
+    /// of it has come; `what` says what the name is in the error.
+    fn input_frame(&mut self, frame: Frame, what: &str) -> Result<Option<String>, String> {
+        let payload = data_payload(frame)?;
+        if payload.len() > self.remaining() {
+            return Err(format!(
+                "Got {} bytes of {} when only {} were left.",
+                payload.len(),
+                what,
+                self.remaining()
+            ));
+        }
+        self.input(&payload);
+        if self.is_complete() {
+            self.name().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn name(&self) -> Result<String, String> {
+        String::from_utf8(self.bytes[0..self.name_length].to_vec()).map_err(|e| format!("UTF8 Error: {:?}", e).to_owned())
+    }
+
+    /// The offset sent after the name, or 0 if none was.
+    fn offset(&self) -> u64 {
+        if self.offset_length == 0 {
+            0
+        } else {
+            prefixes::combine_bytes_u64(&self.bytes[self.name_length..])
+        }
+    }
+
+    /// The byte count sent after the name and offset, if one was.
+    fn limit(&self) -> Option<u64> {
+        if self.limit_length == 0 {
+            None
+        } else {
+            Some(prefixes::combine_bytes_u64(&self.bytes[self.name_length + self.offset_length..]))
+        }
+    }
 }
 
 /// A command to read a file from the device and return its contents to the
 /// communication line.
 ///
+/// The input is the file name in data frames, followed by the offset to start
+/// from if the flags have `OFFSET` and the most bytes to send if they have
+/// `LIMIT`. The output is data frames holding the number of bytes that will be
+/// sent as a big-endian `u64` followed by that many bytes of file content, and
+/// finally a response frame carrying the checksum of the content that was
+/// sent.
+/// If the file cannot be opened or reading fails partway, the response frame
+/// is sent straight away with the error.
+///
 /// The parameter `FileReaderType` is the type to be used to find the files and
 /// read their content.
 #[derive(Debug)]
 pub struct ReadCommandState<FileReaderType: FileReader> {
     prefix: ReadPrefix,
     file_name: String,
+    input: NameInput,
+    offset: u64,
+    limit: Option<u64>,
     file: Option<FileReaderType>,
-    finished: bool,
+    header_sent: bool,
+    file_len: u64,
+    sent_idx: u64,
+    checksum: Option<Checksum>,
+    response: Option<Response>,
+    responded: bool,
+}
+
+/// Checks that a command only asked for flags it honours, and reads the
+/// checksum it asked for out of them. Builds the error response otherwise.
+fn checksum_from_flags(flags: PrefixFlags, honoured: PrefixFlags) -> Result<Checksum, Response> {
+    if flags.unknown_bits() != 0 {
+        return Err(Response::error(
+            ResponseCode::Protocol,
+            format!("Unknown bits {:#06x} in flags.", flags.unknown_bits()),
+        ));
+    }
+    let unsupported = flags.bits() & !(honoured.bits() | checksum::CHECKSUM_FLAG_MASK);
+    if unsupported != 0 {
+        return Err(Response::error(
+            ResponseCode::InvalidInput,
+            format!("Flags {:#06x} do not apply to this command.", unsupported),
+        ));
+    }
+    let kind = flags.checksum().ok_or(Response::error(
+        ResponseCode::Protocol,
+        format!("Unknown checksum kind in flags {:#06x}.", flags.bits()),
+    ))?;
+    if flags.contains(PrefixFlags::VERIFY) && kind == ChecksumKind::None {
+        return Err(Response::error(
+            ResponseCode::InvalidInput,
+            "Verifying a transfer needs a checksum.".to_owned(),
+        ));
+    }
+    Ok(Checksum::new(kind))
 }
 
 /// A trait to abstract over a cursor-based approach for reading an object from a
 /// name.
 pub trait FileReader: Sized {
-    /// Creates a handle to the object to be read
-    fn new(file_name: &str) -> Result<Self, String>;
+    /// Creates a handle to the object to be read. Honours the
+    /// `RECURSIVE` and `FOLLOW_LINKS` flags.
+    fn new(file_name: &str, flags: PrefixFlags) -> Result<Self, Response>;
 
     /// Gets the number of bytes in this File.
-    fn len(&self) -> usize;
+    fn len(&self) -> u64;
 
     /// Reads the next bytes to the given buffer, returning the number of bytes read.
     /// This function either fills up the buffer if it can or short-circuits if it reaches
     /// the end of the file's content before the buffer is filled.
-    fn read_bytes(&mut self, buffer: &mut [u8]) -> Result<usize, String>;
+    fn read_bytes(&mut self, buffer: &mut [u8]) -> Result<usize, Response>;
+
+    /// Moves the cursor to the given offset from the start, which is at most
+    /// `len()`.
+    fn seek(&mut self, offset: u64) -> Result<(), Response>;
+
+    /// Lists the entries directly inside a directory, in any order. Honours
+    /// the `FOLLOW_LINKS` flag, both for the directory itself and for the
+    /// kind and size given for links inside it. With the `RECURSIVE` flag
+    /// everything in its subdirectories is listed too, named by its path
+    /// relative to the directory, like `sub/file`.
+    fn list_dir(dir_name: &str, flags: PrefixFlags) -> Result<Vec<ListEntry>, Response>;
+
+    /// Describes whatever is at the path, named after its last component.
+    /// Honours the `FOLLOW_LINKS` flag.
+    fn stat(file_name: &str, flags: PrefixFlags) -> Result<ListEntry, Response>;
+
+    /// Describes the filesystem the path is on: its size, free space, cluster
+    /// size and kind.
+    fn fs_info(path: &str) -> Result<FsInfo, Response>;
+}
+
+/// Opens a file for reading with its cursor at the given offset, failing if
+/// the file is shorter than that.
+fn open_at<FileReaderType: FileReader>(file_name: &str, flags: PrefixFlags, offset: u64) -> Result<FileReaderType, Response> {
+    let mut fl = FileReaderType::new(file_name, flags)?;
+    if offset > fl.len() {
+        return Err(Response::error(
+            ResponseCode::InvalidInput,
+            format!("Offset {} is past the end of {}, which has {} bytes.", offset, file_name, fl.len()),
+        ));
+    }
+    if offset > 0 {
+        fl.seek(offset)?;
+    }
+    Ok(fl)
+}
+
+impl<FileReaderType: FileReader> ReadCommandState<FileReaderType> {
+    /// Fills the buffer with file content, stopping early at the first error
+    /// and recording it in the response. Returns the number of bytes filled.
+    fn read_content(&mut self, buffer: &mut [u8]) -> usize {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            let rd = match (&mut self.file, &self.response) {
+                (Some(fl), None) => fl.read_bytes(&mut buffer[filled..]),
+                _ => break,
+            };
+            match rd {
+                Ok(0) => {
+                    self.response = Some(Response::error(
+                        ResponseCode::Io,
+                        format!(
+                            "File {} ended after {} of {} bytes.",
+                            self.file_name,
+                            self.sent_idx + filled as u64,
+                            self.file_len
+                        ),
+                    ));
+                }
+                Ok(n) => filled += n,
+                Err(e) => self.response = Some(e),
+            }
+        }
+        if let Some(ck) = &mut self.checksum {
+            ck.update(&buffer[0..filled]);
+        }
+        filled
+    }
+
+    /// Opens the file named by the input at the offset asked for, recording
+    /// the error if it can't be.
+    fn open(&mut self) {
+        match open_at::<FileReaderType>(&self.file_name, self.prefix.flags, self.offset) {
+            Ok(fl) => {
+                let after_offset = fl.len() - self.offset;
+                self.file_len = self.limit.map_or(after_offset, |limit| limit.min(after_offset));
+                self.file = Some(fl);
+            }
+            Err(e) => {
+                dprintln!("Could not open file {}: {}", self.file_name, e);
+                self.response = Some(e);
+            }
+        }
+    }
+
+    /// Reads the sent range of the file a second time, checking that it still
+    /// has the digest of the content that was sent.
+    fn verify(&self, digest: &[u8]) -> Response {
+        let kind = self.prefix.flags.checksum().unwrap_or(ChecksumKind::None);
+        let mut fl = match open_at::<FileReaderType>(&self.file_name, self.prefix.flags, self.offset) {
+            Ok(fl) => fl,
+            Err(e) => return e,
+        };
+        let mut check = Checksum::new(kind);
+        let mut buffer = vec![0u8; 4096];
+        let mut remaining = self.file_len;
+        while remaining > 0 {
+            let want = remaining.min(buffer.len() as u64) as usize;
+            match fl.read_bytes(&mut buffer[0..want]) {
+                Ok(0) => break,
+                Ok(n) => {
+                    check.update(&buffer[0..n]);
+                    remaining -= n as u64;
+                }
+                Err(e) => return e,
+            }
+        }
+        let reread = check.finish();
+        if reread.as_slice() == digest {
+            Response::ok()
+        } else {
+            Response::error(
+                ResponseCode::ChecksumMismatch,
+                format!(
+                    "File {} read back with checksum {} instead of {}; it may have changed during the read.",
+                    self.file_name,
+                    checksum::to_hex(&reread),
+                    checksum::to_hex(digest)
+                ),
+            )
+        }
+    }
+
+    /// Builds the response frame that ends the command.
+    fn respond(&mut self) -> Frame {
+        let digest = checksum::take_digest(&mut self.checksum);
+        let response = match self.response.take() {
+            Some(err) => err,
+            None if self.prefix.flags.contains(PrefixFlags::VERIFY) => self.verify(&digest),
+            None => Response::ok(),
+        };
+        let response = response.with_checksum(digest);
+        dprintln!("Finished output of file {}: {}", self.file_name, response);
+        self.responded = true;
+        Frame::response(&response)
+    }
 }
 
 impl<FileReaderType: FileReader> ServerCommandState<ReadPrefix>
@@ -64,210 +357,2109 @@ impl<FileReaderType: FileReader> ServerCommandState<ReadPrefix>
 {
     fn from_prefix(prefix: ReadPrefix) -> Self {
         let ln = prefix.file_name_length as usize;
+        let honoured = PrefixFlags::RECURSIVE
+            | PrefixFlags::FOLLOW_LINKS
+            | PrefixFlags::VERIFY
+            | PrefixFlags::OFFSET
+            | PrefixFlags::LIMIT;
+        let (checksum, response) = match checksum_from_flags(prefix.flags, honoured) {
+            Ok(ck) => (Some(ck), None),
+            Err(e) => (None, Some(e)),
+        };
         ReadCommandState {
             prefix,
             file_name: String::with_capacity(ln),
+            input: NameInput::new(prefix.file_name_length, prefix.flags),
+            offset: 0,
+            limit: None,
             file: None,
-            finished: false,
+            header_sent: false,
+            file_len: 0,
+            sent_idx: 0,
+            checksum,
+            response,
+            responded: false,
         }
     }
 
     fn needs_input(&self) -> bool {
-        self.file_name.len() < self.prefix.file_name_length as usize
-    }
-
-    fn input_block(&mut self, block: &[u8]) -> Result<usize, String> {
-        let start_len = self.file_name.len();
-        let need_bytes = self.prefix.file_name_length as usize - start_len;
-        let block_size = block.len();
-        if need_bytes > block_size {
-            let part = String::from_utf8(block.to_vec())
-                .map_err(|e| format!("UTF8 Error: {:?}", e).to_owned())?;
-            self.file_name.push_str(&part);
-            Ok(block_size)
-        } else {
-            let bytes = block[0..need_bytes].to_vec();
-            let part =
-                String::from_utf8(bytes).map_err(|e| format!("UTF8 Error: {:?}", e).to_owned())?;
-            self.file_name.push_str(&part);
-            Ok(need_bytes)
+        !self.input.is_complete()
+    }
+
+    fn input_frame(&mut self, frame: Frame) -> Result<(), String> {
+        if let Some(name) = self.input.input_frame(frame, "file name")? {
+            self.file_name = name;
+            self.offset = self.input.offset();
+            self.limit = self.input.limit();
         }
+        Ok(())
     }
 
     fn needs_output(&self) -> bool {
-        !self.finished && !self.needs_input()
-    }
-
-    fn output_block(&mut self, buffer: &mut [u8]) -> Result<usize, String> {
-        let buflen = buffer.len();
-        let buffer_idx_begin = if self.file.is_none() {
-            let fl = FileReaderType::new(&self.file_name)?;
-            let fl_len = fl.len();
-            dprintln!("Now starting output of file {} with size {}.", self.file_name, fl_len);
-            buffer[0] = ((fl_len & 0xFF000000) >> 24) as u8;
-            buffer[1] = ((fl_len & 0xFF0000) >> 16) as u8;
-            buffer[2] = ((fl_len & 0xFF00) >> 8) as u8;
-            buffer[3] = (fl_len & 0xFF) as u8;
-            self.file = Some(fl);
-            4
-        } else {
-            0
-        };
-        let fl = if let Some(f) = &mut self.file {
-            f
-        } else {
-            return Ok(0);
-        };
-        let read_bytes = fl.read_bytes(&mut buffer[buffer_idx_begin..])?;
-        if read_bytes < buflen - buffer_idx_begin {
-            self.finished = true;
+        !self.responded && !self.needs_input()
+    }
+
+    fn output_frame(&mut self, max_payload: usize) -> Result<Frame, String> {
+        if !self.header_sent && self.file.is_none() && self.response.is_none() {
+            self.open();
         }
-        Ok(read_bytes)
+        if self.response.is_some() || (self.header_sent && self.sent_idx >= self.file_len) {
+            return Ok(self.respond());
+        }
+        let mut payload = Vec::with_capacity(max_payload);
+        if !self.header_sent {
+            dprintln!("Now starting output of file {} with size {}.", self.file_name, self.file_len);
+            payload.extend_from_slice(&prefixes::extract_bytes_u64(self.file_len));
+            self.header_sent = true;
+        }
+        let content_begin = payload.len();
+        let remaining = self.file_len - self.sent_idx;
+        let content_len = remaining.min(max_payload.saturating_sub(content_begin) as u64) as usize;
+        payload.resize(content_begin + content_len, 0);
+        let read_bytes = self.read_content(&mut payload[content_begin..]);
+        payload.truncate(content_begin + read_bytes);
+        self.sent_idx += read_bytes as u64;
+        if payload.is_empty() {
+            return Ok(self.respond());
+        }
+        Ok(Frame::data(payload))
+    }
+
+    fn abort(&mut self, _policy: AbortPolicy) -> Response {
+        self.responded = true;
+        let message = format!("Read of {} aborted after {} of {} bytes.", self.file_name, self.sent_idx, self.file_len);
+        dprintln!("{}", message);
+        Response::error(ResponseCode::Aborted, message)
     }
 }
 
 /// A trait to abstract over a cursor-based approach for writing files to a given
 /// file name.
 pub trait FileWriter: Sized {
-    /// Creates a handle to the object to be written to
-    fn new(file_name: &str) -> Result<Self, String>;
+    /// Creates a handle to the object to be written to. Honours the
+    /// `OVERWRITE`, `NEW_NAME`, `APPEND`, `CREATE_PARENTS` and `FOLLOW_LINKS`
+    /// flags. Unless appending, the content goes into a temporary file next
+    /// to the target until `commit`, so a failed write leaves any old file as
+    /// it was.
+    fn new(file_name: &str, flags: PrefixFlags) -> Result<Self, Response>;
+
+    /// Opens a file for the `SKIP_IDENTICAL` policy, which will be sent
+    /// `length` bytes. If the file already has that length, writes that match
+    /// its content leave it untouched, and the first one that differs cuts the
+    /// file there and carries on as a normal write. Otherwise it acts like
+    /// `new` with `OVERWRITE`. Honours the `CREATE_PARENTS` and
+    /// `FOLLOW_LINKS` flags.
+    fn replace_if_changed(file_name: &str, flags: PrefixFlags, length: u64) -> Result<Self, Response>;
+
+    /// Whether the file is still exactly as it was before this handle was
+    /// opened, which is only ever true for `replace_if_changed`.
+    fn unchanged(&self) -> bool;
+
+    /// The name the file is written under, which has a numbered suffix if
+    /// `NEW_NAME` had to pick a free one.
+    fn name(&self) -> &str;
+
+    /// Opens a file that already exists to carry on writing it, keeping its
+    /// first `offset` bytes and dropping anything after them. Honours the
+    /// `FOLLOW_LINKS` flag.
+    fn resume(file_name: &str, flags: PrefixFlags, offset: u64) -> Result<Self, Response>;
 
     /// Writes to the file using bytes from the given buffer, returning the number of bytes written.
-    fn write_bytes(&mut self, buffer: &[u8]) -> Result<usize, String>;
+    fn write_bytes(&mut self, buffer: &[u8]) -> Result<usize, Response>;
+
+    /// Closes and deletes a file that could not be written completely, or
+    /// cuts a file that was being appended to or resumed back to its old
+    /// length.
+    fn remove(self) -> Result<(), Response>;
+
+    /// Makes a finished write visible under the file's name: syncs the
+    /// content to storage and renames the temporary file over the target, if
+    /// there is one.
+    fn commit(&mut self) -> Result<(), Response>;
+
+    /// Whether `commit` would rename the temporary file over a file that is
+    /// already at the target.
+    fn replaces_existing(&self) -> bool;
+
+    /// Flushes everything written so far to storage and reads it back,
+    /// returning the digest of the bytes written through this handle.
+    fn read_back_digest(&mut self, kind: ChecksumKind) -> Result<Vec<u8>, Response>;
+
+    /// Reads the file as it was before this handle was opened, starting at
+    /// `offset`, for a `DELTA` write to copy from. Returns 0 at its end, or
+    /// straight away if there was no such file. Only used on handles from
+    /// `new` with `OVERWRITE`, whose content goes into a temporary file.
+    fn read_old(&mut self, offset: u64, buffer: &mut [u8]) -> Result<usize, Response>;
+
+    /// Creates a directory, failing if it already exists. Honours the
+    /// `CREATE_PARENTS` flag, which also lets the directory already exist.
+    fn make_dir(dir_name: &str, flags: PrefixFlags) -> Result<(), Response>;
+
+    /// Deletes what is at a path without following links, returning a record
+    /// of every entry it tried to delete. With `dir_only` the path must be an
+    /// empty directory; otherwise it must not be a directory unless the flags
+    /// have `RECURSIVE`, in which case everything inside it is deleted first.
+    fn remove_path(path: &str, flags: PrefixFlags, dir_only: bool) -> Vec<RemovalRecord>;
+
+    /// Moves or renames a path, copying it and deleting the original if it
+    /// cannot be renamed across file systems. Fails if something is already at
+    /// the destination, unless the flags have `OVERWRITE`.
+    fn move_path(from: &str, to: &str, flags: PrefixFlags) -> Result<(), Response>;
+}
+
+/// Adds a numbered suffix to the last component of a file name, before its
+/// extension, so that `sdmc:/a/tool.nro` becomes `sdmc:/a/tool (1).nro`.
+pub fn suffixed_name(file_name: &str, number: u32) -> String {
+    let base_start = file_name.rfind('/').map(|idx| idx + 1).unwrap_or(0);
+    match file_name[base_start..].rfind('.') {
+        Some(dot) if dot > 0 => {
+            let dot = base_start + dot;
+            format!("{} ({}){}", &file_name[0..dot], number, &file_name[dot..])
+        }
+        _ => format!("{} ({})", file_name, number),
+    }
+}
+
+/// The temporary file a write puts its content in before it is renamed over
+/// `file_name`: a hidden sibling, so that `sdmc:/a/tool.nro` is written as
+/// `sdmc:/a/.tool.nro.nxusb-part`.
+pub fn temp_name(file_name: &str) -> String {
+    let base_start = file_name.rfind('/').map(|idx| idx + 1).unwrap_or(0);
+    format!("{}.{}.nxusb-part", &file_name[0..base_start], &file_name[base_start..])
 }
 
+/// The most a delta write reads from the old file at a time.
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A command to write a file sent over the communication line to the device.
+///
+/// The input is data frames holding the file name, the offset to write from if
+/// the flags have `OFFSET`, `file_length` bytes of content and then the
+/// client's digest of the content, if the flags ask for a checksum. The output
+/// is a single response frame.
+/// If the file cannot be written the rest of the content is still consumed so
+/// the line stays in sync, and the error is reported in the closing
+/// `Response`. A file whose content fails to write or whose checksum does not
+/// match is removed.
+/// The content is written to a temporary file that only replaces the target
+/// once all of it has arrived and been checked, so a failed or aborted write
+/// never leaves a truncated file in place of an old one. Appends and resumed
+/// writes go straight into the existing file instead.
+/// With the `DELTA` flag the content is a delta stream, and the file is
+/// rebuilt from ranges of the old file and the new bytes in the stream.
 #[derive(Debug)]
 pub struct WriteCommandState<FileWriterType: FileWriter> {
     prefix: WritePrefix,
     file_name: String,
+    input: NameInput,
     file: Option<FileWriterType>,
-    finished: bool,
-    write_idx: usize,
+    /// How many bytes of content, or of the delta stream, have arrived.
+    write_idx: u64,
+    delta: Option<DeltaDecoder>,
+    checksum: Option<Checksum>,
+    digest_len: usize,
+    client_digest: Vec<u8>,
+    response: Option<Response>,
+    responded: bool,
+}
+
+impl<WriterType: FileWriter> WriteCommandState<WriterType> {
+    /// Takes the bytes of the client's digest from the start of the block,
+    /// returning the number of bytes used.
+    fn input_digest(&mut self, block: &[u8]) -> usize {
+        let taken = (self.digest_len - self.client_digest.len()).min(block.len());
+        self.client_digest.extend_from_slice(&block[0..taken]);
+        taken
+    }
+
+    /// Compares the client's digest against our own, producing the final
+    /// response and removing the file if anything went wrong.
+    fn finish(&mut self) -> Response {
+        let digest = checksum::take_digest(&mut self.checksum);
+        let response = match self.response.take() {
+            Some(err) => err,
+            None if !self.delta.as_ref().is_none_or(|decoder| decoder.is_between_ops()) => Response::error(
+                ResponseCode::Protocol,
+                format!("The delta for {} stopped partway through an instruction.", self.file_name),
+            ),
+            None if digest != self.client_digest => Response::error(
+                ResponseCode::ChecksumMismatch,
+                format!(
+                    "Got checksum {} for {} but the client sent {}.",
+                    checksum::to_hex(&digest),
+                    self.file_name,
+                    checksum::to_hex(&self.client_digest)
+                ),
+            ),
+            None if self.prefix.flags.contains(PrefixFlags::VERIFY) => self.verify(&digest),
+            None => self.success(),
+        };
+        let response = match (&mut self.file, response.is_ok()) {
+            (Some(fl), true) => fl.commit().err().unwrap_or(response),
+            _ => response,
+        };
+        let fl = self.file.take();
+        if let (Some(fl), false) = (fl, response.is_ok()) {
+            dprintln!("Removing partial file {}.", self.file_name);
+            if let Err(e) = fl.remove() {
+                dprintln!("Could not remove partial file {}: {}", self.file_name, e);
+            }
+        }
+        response.with_checksum(digest)
+    }
+    /// The response to a write that went through, saying so if the file was
+    /// left alone or written under another name.
+    fn success(&self) -> Response {
+        let mut response = Response::ok();
+        if let Some(fl) = &self.file {
+            if fl.unchanged() {
+                response.message = format!("{} is identical; left it unchanged.", self.file_name);
+            } else if fl.name() != self.file_name {
+                response.message = format!("{} already exists; wrote {} instead.", self.file_name, fl.name());
+            }
+        }
+        response
+    }
+
+    /// Reads the written file back from storage, checking that it has the
+    /// digest of the content that was received.
+    fn verify(&mut self, digest: &[u8]) -> Response {
+        let kind = self.prefix.flags.checksum().unwrap_or(ChecksumKind::None);
+        let reread = match self.file.as_mut().map(|fl| fl.read_back_digest(kind)) {
+            Some(Ok(reread)) => reread,
+            Some(Err(e)) => return e,
+            None => return Response::ok(),
+        };
+        if reread.as_slice() == digest {
+            self.success()
+        } else {
+            Response::error(
+                ResponseCode::ChecksumMismatch,
+                format!(
+                    "File {} read back with checksum {} instead of {}.",
+                    self.file_name,
+                    checksum::to_hex(&reread),
+                    checksum::to_hex(digest)
+                ),
+            )
+        }
+    }
+
+    /// Takes the bytes of the file name and offset from the start of the
+    /// block, opening the file once they are complete. Returns the number of
+    /// bytes used.
+    fn input_name(&mut self, block: &[u8]) -> Result<usize, String> {
+        if self.input.is_complete() {
+            return Ok(0);
+        }
+        let taken = self.input.input(block);
+        if self.input.is_complete() {
+            self.file_name = self.input.name()?;
+            if self.response.is_some() {
+                return Ok(taken);
+            }
+            let opened = if self.prefix.flags.contains(PrefixFlags::OFFSET) {
+                WriterType::resume(&self.file_name, self.prefix.flags, self.input.offset())
+            } else if self.prefix.flags.contains(PrefixFlags::SKIP_IDENTICAL) {
+                WriterType::replace_if_changed(&self.file_name, self.prefix.flags, self.prefix.file_length)
+            } else {
+                WriterType::new(&self.file_name, self.prefix.flags)
+            };
+            match opened {
+                Ok(fl) => self.file = Some(fl),
+                Err(e) => {
+                    dprintln!("Could not open file {} for writing: {}", self.file_name, e);
+                    self.response = Some(e);
+                }
+            }
+        }
+        Ok(taken)
+    }
+
+    /// Writes file content to the file, or rebuilds it from a piece of a
+    /// delta stream, or drops it if an error has already happened.
+    fn input_content(&mut self, bytes: &[u8]) {
+        self.write_idx += bytes.len() as u64;
+        if self.response.is_some() {
+            return;
+        }
+        if self.delta.is_some() {
+            self.input_delta(bytes);
+        } else {
+            self.store(bytes);
+        }
+    }
+
+    /// Hashes content and writes it to the file.
+    fn store(&mut self, bytes: &[u8]) {
+        if let Some(ck) = &mut self.checksum {
+            ck.update(bytes);
+        }
+        let fl = match &mut self.file {
+            Some(fl) => fl,
+            None => return,
+        };
+        let mut written = 0;
+        while written < bytes.len() {
+            match fl.write_bytes(&bytes[written..]) {
+                Ok(0) => {
+                    self.response = Some(Response::error(
+                        ResponseCode::Io,
+                        format!("Could not write to file {}.", self.file_name),
+                    ));
+                    return;
+                }
+                Ok(n) => written += n,
+                Err(e) => {
+                    self.response = Some(e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Carries out the instructions in a piece of a delta stream.
+    fn input_delta(&mut self, bytes: &[u8]) {
+        let mut used = 0;
+        while used < bytes.len() && self.response.is_none() {
+            let decoded = match &mut self.delta {
+                Some(decoder) => decoder.input(&bytes[used..]),
+                None => return,
+            };
+            match decoded {
+                Ok((taken, piece)) => {
+                    used += taken;
+                    match piece {
+                        Some(Decoded::Literal(literal)) => self.store(literal),
+                        Some(Decoded::Copy { offset, length }) => self.copy_old(offset, length),
+                        None => {}
+                    }
+                }
+                Err(e) => self.response = Some(Response::error(ResponseCode::Protocol, e)),
+            }
+        }
+    }
+
+    /// Copies a range of the old file into the new one.
+    fn copy_old(&mut self, offset: u64, length: u64) {
+        let mut buffer = vec![0u8; length.min(COPY_CHUNK_SIZE as u64) as usize];
+        let mut copied = 0;
+        while copied < length && self.response.is_none() {
+            let want = (length - copied).min(buffer.len() as u64) as usize;
+            let read = match &mut self.file {
+                Some(fl) => fl.read_old(offset + copied, &mut buffer[0..want]),
+                None => return,
+            };
+            match read {
+                Ok(0) => {
+                    self.response = Some(Response::error(
+                        ResponseCode::InvalidInput,
+                        format!("The delta copies byte {} of {}, past the end of the old file.", offset + copied, self.file_name),
+                    ))
+                }
+                Ok(n) => {
+                    self.store(&buffer[0..n]);
+                    copied += n as u64;
+                }
+                Err(e) => self.response = Some(e),
+            }
+        }
+    }
 }
 
 impl<WriterType: FileWriter> ServerCommandState<WritePrefix> for WriteCommandState<WriterType> {
     fn from_prefix(prefix: WritePrefix) -> Self {
         let ln = prefix.file_name_length as usize;
+        let honoured = PrefixFlags::OVERWRITE
+            | PrefixFlags::SKIP_IDENTICAL
+            | PrefixFlags::NEW_NAME
+            | PrefixFlags::APPEND
+            | PrefixFlags::CREATE_PARENTS
+            | PrefixFlags::FOLLOW_LINKS
+            | PrefixFlags::VERIFY
+            | PrefixFlags::OFFSET
+            | PrefixFlags::DELTA;
+        let policy = OverwritePolicy::from_flags(prefix.flags);
+        let replaces = policy != Some(OverwritePolicy::Fail);
+        let appends = prefix.flags.contains(PrefixFlags::APPEND);
+        let delta = prefix.flags.contains(PrefixFlags::DELTA);
+        let (checksum, response) = match checksum_from_flags(prefix.flags, honoured) {
+            Ok(_) if policy.is_none() => (
+                None,
+                Some(Response::error(
+                    ResponseCode::InvalidInput,
+                    "A write can only have one overwrite policy.".to_owned(),
+                )),
+            ),
+            Ok(_) if replaces && appends => (
+                None,
+                Some(Response::error(
+                    ResponseCode::InvalidInput,
+                    "A write cannot both overwrite and append.".to_owned(),
+                )),
+            ),
+            Ok(_) if prefix.flags.contains(PrefixFlags::OFFSET) && (replaces || appends) => (
+                None,
+                Some(Response::error(
+                    ResponseCode::InvalidInput,
+                    "A write at an offset cannot also overwrite or append.".to_owned(),
+                )),
+            ),
+            Ok(_) if delta && (policy != Some(OverwritePolicy::Overwrite) || appends || prefix.flags.contains(PrefixFlags::OFFSET)) => (
+                None,
+                Some(Response::error(
+                    ResponseCode::InvalidInput,
+                    "A delta write can only overwrite a whole file.".to_owned(),
+                )),
+            ),
+            Ok(ref ck) if delta && ck.kind() == ChecksumKind::None => (
+                None,
+                Some(Response::error(
+                    ResponseCode::InvalidInput,
+                    "A delta write needs a checksum of the rebuilt file.".to_owned(),
+                )),
+            ),
+            Ok(ck) => (Some(ck), None),
+            Err(e) => (None, Some(e)),
+        };
+        // The client sends its digest even if the command is refused, so
+        // take its length from the flags as sent.
+        let digest_len = prefix.flags.checksum().map(|kind| kind.digest_len()).unwrap_or(0);
         WriteCommandState {
             prefix,
             file_name: String::with_capacity(ln),
+            input: NameInput::new(prefix.file_name_length, prefix.flags),
             file: None,
-            finished: false,
             write_idx: 0,
+            delta: if delta { Some(DeltaDecoder::new()) } else { None },
+            checksum,
+            digest_len,
+            client_digest: Vec::with_capacity(digest_len),
+            response,
+            responded: false,
         }
     }
     fn needs_input(&self) -> bool {
-        !self.finished
-    }
-
-    fn input_block(&mut self, block: &[u8]) -> Result<usize, String> {
-        let block_size = block.len();
-        let name_bytes_to_get = self.prefix.file_name_length as usize - self.file_name.len();
-        let file_bytes_to_get = self.prefix.file_length as usize - self.write_idx;
-
-        //Already finished: do nothing.
-        if self.finished || block_size == 0 {
-            Ok(0)
-        }
-        //Already finished but don't know it: set the flag.
-        else if name_bytes_to_get == 0 && file_bytes_to_get == 0 {
-            self.finished = true;
-            Ok(0)
-        }
-        //The entire block is for the file name.
-        else if name_bytes_to_get > block_size {
-            let part = String::from_utf8(block.to_vec())
-                .map_err(|e| format!("UTF8 Error: {:?}", e).to_owned())?;
-            self.file_name.push_str(&part);
-            Ok(block_size)
-        }
-        //The entire block is for the file content.
-        else if name_bytes_to_get == 0 && file_bytes_to_get > 0 {
-            //TODO: This is just a series of borrow checker manipulations to
-            // short-circuit set self.file and then use it.
-            if self.file.is_none() {
-                let fl = WriterType::new(&self.file_name)?;
-                self.file = Some(fl);
-            }
-            let bytes_to_write = &block[0..file_bytes_to_get.min(block_size)];
-            if let Some(fl) = &mut self.file {
-                let rval = fl.write_bytes(bytes_to_write);
-                if let Ok(n) = rval {
-                    self.write_idx += n;
+        !self.responded
+            && (!self.input.is_complete()
+                || self.write_idx < self.prefix.file_length
+                || self.client_digest.len() < self.digest_len)
+    }
+
+    fn input_frame(&mut self, frame: Frame) -> Result<(), String> {
+        let block = data_payload(frame)?;
+        let name_bytes = self.input_name(&block)?;
+        let file_bytes_to_get = self.prefix.file_length - self.write_idx;
+        let available = (block.len() - name_bytes) as u64;
+        let content_end = name_bytes + file_bytes_to_get.min(available) as usize;
+        self.input_content(&block[name_bytes..content_end]);
+        let digest_bytes = if self.write_idx == self.prefix.file_length {
+            self.input_digest(&block[content_end..])
+        } else {
+            0
+        };
+        if content_end + digest_bytes < block.len() {
+            return Err(format!(
+                "Got {} bytes past the end of the write to {}.",
+                block.len() - content_end - digest_bytes,
+                self.file_name
+            ));
+        }
+        Ok(())
+    }
+
+    fn needs_output(&self) -> bool {
+        !self.responded && !self.needs_input()
+    }
+
+    fn output_frame(&mut self, _max_payload: usize) -> Result<Frame, String> {
+        let response = self.finish();
+        dprintln!("Finished writing file {}: {}", self.file_name, response);
+        self.responded = true;
+        Ok(Frame::response(&response))
+    }
+
+    fn abort(&mut self, policy: AbortPolicy) -> Response {
+        self.responded = true;
+        let mut message = format!("Write to {} aborted after {} bytes", self.file_name, self.write_idx);
+        match (self.file.take(), policy) {
+            (Some(fl), AbortPolicy::Delete) => match fl.remove() {
+                Ok(()) => message.push_str("; the partial file was removed."),
+                Err(e) => message.push_str(&format!("; the partial file could not be removed: {}", e)),
+            },
+            // Keeping a partial file must never put it in place of an old
+            // one, so the old file stays and the partial one is dropped.
+            (Some(fl), AbortPolicy::Keep) if fl.replaces_existing() => match fl.remove() {
+                Ok(()) => message.push_str("; the old file was left as it was."),
+                Err(e) => message.push_str(&format!("; the partial file could not be removed: {}", e)),
+            },
+            (Some(mut fl), AbortPolicy::Keep) => match fl.commit() {
+                Ok(()) => message.push_str("; the partial file was kept."),
+                Err(e) => message.push_str(&format!("; the partial file could not be kept: {}", e)),
+            },
+            (None, _) => message.push('.'),
+        }
+        dprintln!("{}", message);
+        Response::error(ResponseCode::Aborted, message)
+    }
+}
+
+/// A command telling the client how much of a file exists, so that it can
+/// resume a transfer.
+///
+/// The input is the file name in data frames. The output is a data frame
+/// holding the file length as a big-endian `u64`, and then a response frame
+/// carrying the checksum of the first `length` bytes of the file. If the file
+/// cannot be opened, only the response frame is sent.
+#[derive(Debug)]
+pub struct ProbeCommandState<FileReaderType: FileReader> {
+    prefix: ProbePrefix,
+    file_name: String,
+    input: NameInput,
+    file: Option<FileReaderType>,
+    checksum: Option<Checksum>,
+    response: Option<Response>,
+    responded: bool,
+}
+
+impl<FileReaderType: FileReader> ProbeCommandState<FileReaderType> {
+    /// Hashes the start of the file, returning the response that ends the
+    /// command.
+    fn hash_start(&mut self) -> Response {
+        let (fl, mut check) = match (self.file.as_mut(), self.checksum.take()) {
+            (Some(fl), Some(check)) => (fl, check),
+            _ => return self.response.take().unwrap_or(Response::ok()),
+        };
+        let mut remaining = self.prefix.length.min(fl.len());
+        let mut buffer = vec![0u8; 4096];
+        while remaining > 0 {
+            let want = remaining.min(buffer.len() as u64) as usize;
+            match fl.read_bytes(&mut buffer[0..want]) {
+                Ok(0) => {
+                    return Response::error(
+                        ResponseCode::Io,
+                        format!("File {} ended while hashing it.", self.file_name),
+                    )
                 }
-                rval
-            }
-            // Since we previously set self.file in the None case and short-circuited
-            // if it didn't work, this branch should be impossible to reach.
-            else {
-                Err("File is somehow None after the branch!".to_owned())
+                Ok(n) => {
+                    check.update(&buffer[0..n]);
+                    remaining -= n as u64;
+                }
+                Err(e) => return e,
             }
         }
-        //Need to both finish the name and start the file
-        else {
-            let name_bytes = &block[0..name_bytes_to_get];
-            let name_part = String::from_utf8(name_bytes.to_vec())
-                .map_err(|e| format!("UTF8 Error: {:?}", e).to_owned())?;
-            self.file_name.push_str(&name_part);
-            let mut fl = WriterType::new(&self.file_name)?;
-            let not_name = &block[name_bytes_to_get..];
-            let file_bytes = &not_name[0..file_bytes_to_get.min(block_size - name_bytes_to_get)];
-            let rval = fl.write_bytes(file_bytes);
-            if let Ok(n) = rval {
-                self.write_idx = n;
-            }
-            self.file = Some(fl);
-            rval
+        Response::ok().with_checksum(check.finish())
+    }
+}
+
+impl<FileReaderType: FileReader> ServerCommandState<ProbePrefix> for ProbeCommandState<FileReaderType> {
+    fn from_prefix(prefix: ProbePrefix) -> Self {
+        let (checksum, response) = match checksum_from_flags(prefix.flags, PrefixFlags::FOLLOW_LINKS) {
+            Ok(ck) => (Some(ck), None),
+            Err(e) => (None, Some(e)),
+        };
+        ProbeCommandState {
+            prefix,
+            file_name: String::with_capacity(prefix.file_name_length as usize),
+            input: NameInput::new(prefix.file_name_length, prefix.flags),
+            file: None,
+            checksum,
+            response,
+            responded: false,
+        }
+    }
+
+    fn needs_input(&self) -> bool {
+        !self.input.is_complete()
+    }
+
+    fn input_frame(&mut self, frame: Frame) -> Result<(), String> {
+        if let Some(name) = self.input.input_frame(frame, "file name")? {
+            self.file_name = name;
         }
+        Ok(())
     }
 
     fn needs_output(&self) -> bool {
-        false
+        !self.responded && !self.needs_input()
     }
 
-    fn output_block(&mut self, _buffer: &mut [u8]) -> Result<usize, String> {
-        Ok(0)
+    fn output_frame(&mut self, _max_payload: usize) -> Result<Frame, String> {
+        if self.file.is_none() && self.response.is_none() {
+            match FileReaderType::new(&self.file_name, self.prefix.flags) {
+                Ok(fl) => {
+                    let len = fl.len();
+                    self.file = Some(fl);
+                    return Ok(Frame::data(prefixes::extract_bytes_u64(len).to_vec()));
+                }
+                Err(e) => self.response = Some(e),
+            }
+        }
+        let response = self.hash_start();
+        dprintln!("Probed file {}: {}", self.file_name, response);
+        self.responded = true;
+        Ok(Frame::response(&response))
     }
 }
 
-pub enum CommandStates<T : FileReader, U : FileWriter> {
-    Read(ReadCommandState<T>), 
-    Write(WriteCommandState<U>),
+/// Where a paged listing got to, which the server keeps between list
+/// commands so that the page after it is taken from here instead of listing
+/// the directory again.
+#[derive(Debug)]
+pub struct ListCursor {
+    dir_name: String,
+    flags: PrefixFlags,
+    total: u32,
+    /// The number of the next entry in the listing.
+    next: u32,
+    /// The entries not sent yet, sorted by name.
+    rest: std::vec::IntoIter<ListEntry>,
 }
 
-impl <T : FileReader, U : FileWriter> ServerCommandState<Prefixes> for CommandStates<T, U> {
-    fn from_prefix(prefix: Prefixes) -> Self {
-        match prefix {
-            Prefixes::Read(r) => CommandStates::Read(ReadCommandState::from_prefix(r)), 
-            Prefixes::Write(w) => CommandStates::Write(WriteCommandState::from_prefix(w))
+/// A command listing the entries in a directory, a page at a time.
+///
+/// The input is the directory name in data frames. The output is data frames
+/// holding a `ListPage` of the entries asked for by the prefix, sorted by
+/// name, and then a response frame carrying the checksum of the page. If the
+/// directory cannot be listed, only the response frame is sent. With the
+/// `RECURSIVE` flag the pages cover the whole tree under the directory.
+///
+/// A page that starts where the previous list command of the same directory
+/// stopped is taken from that command's `ListCursor`, so that paging through
+/// a directory lists it only once.
+#[derive(Debug)]
+pub struct ListCommandState<FileReaderType: FileReader> {
+    prefix: ListPrefix,
+    dir_name: String,
+    input: NameInput,
+    cursor: Option<ListCursor>,
+    page: Option<Vec<u8>>,
+    sent_idx: usize,
+    checksum: Option<Checksum>,
+    response: Option<Response>,
+    responded: bool,
+    reader: PhantomData<FileReaderType>,
+}
+
+impl<FileReaderType: FileReader> ListCommandState<FileReaderType> {
+    /// Hands the command the cursor the previous list command left, which it
+    /// carries on from if it asks for the page after it.
+    pub fn resume(&mut self, cursor: ListCursor) {
+        self.cursor = Some(cursor);
+    }
+
+    /// Takes the cursor for the next page, if any of the listing is left.
+    pub fn take_cursor(&mut self) -> Option<ListCursor> {
+        self.cursor.take().filter(|cursor| cursor.rest.len() > 0)
+    }
+
+    /// Whether the cursor left by the previous list command is where this
+    /// one's page starts.
+    fn cursor_fits(&self) -> bool {
+        match &self.cursor {
+            Some(cursor) => cursor.dir_name == self.dir_name && cursor.flags == self.prefix.flags && cursor.next == self.prefix.start,
+            None => false,
         }
     }
 
-    fn needs_input(&self) -> bool {
-        match self {
-            &CommandStates::Read(ref r) => r.needs_input(), 
-            &CommandStates::Write(ref w) => w.needs_input()
+    /// Lists the directory and sorts it into a cursor at its first entry.
+    fn start_listing(&self) -> Result<ListCursor, Response> {
+        let mut entries = FileReaderType::list_dir(&self.dir_name, self.prefix.flags)?;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(ListCursor {
+            dir_name: self.dir_name.clone(),
+            flags: self.prefix.flags,
+            total: entries.len() as u32,
+            next: 0,
+            rest: entries.into_iter(),
+        })
+    }
+
+    /// Encodes the page that was asked for, listing the directory unless the
+    /// cursor is already there, and records the error if it can't be listed.
+    fn build_page(&mut self) {
+        if !self.cursor_fits() {
+            match self.start_listing() {
+                Ok(cursor) => self.cursor = Some(cursor),
+                Err(e) => {
+                    dprintln!("Could not list directory {}: {}", self.dir_name, e);
+                    self.cursor = None;
+                    self.response = Some(e);
+                    return;
+                }
+            }
+        }
+        let cursor = match &mut self.cursor {
+            Some(cursor) => cursor,
+            None => return,
+        };
+        let skipped = cursor.rest.by_ref().take(self.prefix.start.saturating_sub(cursor.next) as usize).count();
+        let entries: Vec<ListEntry> = cursor.rest.by_ref().take(self.prefix.max_entries as usize).collect();
+        cursor.next += (skipped + entries.len()) as u32;
+        let page = ListPage {
+            total: cursor.total,
+            entries,
+        };
+        dprintln!(
+            "Listing {} entries of {} starting at entry {}.",
+            page.entries.len(),
+            self.dir_name,
+            self.prefix.start
+        );
+        let bytes = page.serialize();
+        if let Some(ck) = &mut self.checksum {
+            ck.update(&bytes);
+        }
+        self.page = Some(bytes);
+    }
+}
+
+impl<FileReaderType: FileReader> ServerCommandState<ListPrefix> for ListCommandState<FileReaderType> {
+    fn from_prefix(prefix: ListPrefix) -> Self {
+        let (checksum, response) = match checksum_from_flags(prefix.flags, PrefixFlags::RECURSIVE | PrefixFlags::FOLLOW_LINKS) {
+            Ok(ck) => (Some(ck), None),
+            Err(e) => (None, Some(e)),
+        };
+        ListCommandState {
+            prefix,
+            dir_name: String::with_capacity(prefix.file_name_length as usize),
+            input: NameInput::new(prefix.file_name_length, prefix.flags),
+            cursor: None,
+            page: None,
+            sent_idx: 0,
+            checksum,
+            response,
+            responded: false,
+            reader: PhantomData,
         }
     }
 
-    fn input_block(&mut self, block: &[u8]) -> Result<usize, String> {
-        match self {
-            &mut CommandStates::Read(ref mut r) => r.input_block( block), 
-            &mut CommandStates::Write(ref mut w) => w.input_block(block)
+    fn needs_input(&self) -> bool {
+        !self.input.is_complete()
+    }
+
+    fn input_frame(&mut self, frame: Frame) -> Result<(), String> {
+        if let Some(name) = self.input.input_frame(frame, "directory name")? {
+            self.dir_name = name;
         }
+        Ok(())
     }
 
     fn needs_output(&self) -> bool {
-        match self {
-            &CommandStates::Read(ref r) => r.needs_output(), 
-            &CommandStates::Write(ref w) => w.needs_output()
+        !self.responded && !self.needs_input()
+    }
+
+    fn output_frame(&mut self, max_payload: usize) -> Result<Frame, String> {
+        if self.page.is_none() && self.response.is_none() {
+            self.build_page();
+        }
+        if let (Some(page), None) = (&self.page, &self.response) {
+            if self.sent_idx < page.len() {
+                let end = (self.sent_idx + max_payload).min(page.len());
+                let payload = page[self.sent_idx..end].to_vec();
+                self.sent_idx = end;
+                return Ok(Frame::data(payload));
+            }
+        }
+        let digest = checksum::take_digest(&mut self.checksum);
+        let response = self.response.take().unwrap_or(Response::ok()).with_checksum(digest);
+        dprintln!("Finished listing {}: {}", self.dir_name, response);
+        self.responded = true;
+        Ok(Frame::response(&response))
+    }
+}
+
+/// A command describing a single path on the device.
+///
+/// The input is the path in data frames. The output is a data frame holding
+/// the path's `ListEntry`, and then a response frame. If nothing is at the
+/// path, only the response frame is sent.
+#[derive(Debug)]
+pub struct StatCommandState<FileReaderType: FileReader> {
+    prefix: StatPrefix,
+    file_name: String,
+    input: NameInput,
+    entry_sent: bool,
+    response: Option<Response>,
+    responded: bool,
+    reader: PhantomData<FileReaderType>,
+}
+
+impl<FileReaderType: FileReader> ServerCommandState<StatPrefix> for StatCommandState<FileReaderType> {
+    fn from_prefix(prefix: StatPrefix) -> Self {
+        let response = checksum_from_flags(prefix.flags, PrefixFlags::FOLLOW_LINKS).err();
+        StatCommandState {
+            prefix,
+            file_name: String::with_capacity(prefix.file_name_length as usize),
+            input: NameInput::new(prefix.file_name_length, prefix.flags),
+            entry_sent: false,
+            response,
+            responded: false,
+            reader: PhantomData,
+        }
+    }
+
+    fn needs_input(&self) -> bool {
+        !self.input.is_complete()
+    }
+
+    fn input_frame(&mut self, frame: Frame) -> Result<(), String> {
+        if let Some(name) = self.input.input_frame(frame, "file name")? {
+            self.file_name = name;
+        }
+        Ok(())
+    }
+
+    fn needs_output(&self) -> bool {
+        !self.responded && !self.needs_input()
+    }
+
+    fn output_frame(&mut self, _max_payload: usize) -> Result<Frame, String> {
+        if !self.entry_sent && self.response.is_none() {
+            match FileReaderType::stat(&self.file_name, self.prefix.flags) {
+                Ok(entry) => {
+                    self.entry_sent = true;
+                    let mut payload = Vec::new();
+                    entry.serialize_into(&mut payload);
+                    return Ok(Frame::data(payload));
+                }
+                Err(e) => self.response = Some(e),
+            }
+        }
+        let response = self.response.take().unwrap_or(Response::ok());
+        dprintln!("Described {}: {}", self.file_name, response);
+        self.responded = true;
+        Ok(Frame::response(&response))
+    }
+}
+
+/// A command describing the filesystem a path is on.
+///
+/// The input is the path in data frames. The output is a data frame holding
+/// the filesystem's `FsInfo`, and then a response frame. If the path cannot
+/// be looked up, only the response frame is sent.
+#[derive(Debug)]
+pub struct FsInfoCommandState<FileReaderType: FileReader> {
+    file_name: String,
+    input: NameInput,
+    info_sent: bool,
+    response: Option<Response>,
+    responded: bool,
+    reader: PhantomData<FileReaderType>,
+}
+
+impl<FileReaderType: FileReader> ServerCommandState<FsInfoPrefix> for FsInfoCommandState<FileReaderType> {
+    fn from_prefix(prefix: FsInfoPrefix) -> Self {
+        let response = checksum_from_flags(prefix.flags, PrefixFlags::empty()).err();
+        FsInfoCommandState {
+            file_name: String::with_capacity(prefix.file_name_length as usize),
+            input: NameInput::new(prefix.file_name_length, prefix.flags),
+            info_sent: false,
+            response,
+            responded: false,
+            reader: PhantomData,
+        }
+    }
+
+    fn needs_input(&self) -> bool {
+        !self.input.is_complete()
+    }
+
+    fn input_frame(&mut self, frame: Frame) -> Result<(), String> {
+        if let Some(name) = self.input.input_frame(frame, "file name")? {
+            self.file_name = name;
+        }
+        Ok(())
+    }
+
+    fn needs_output(&self) -> bool {
+        !self.responded && !self.needs_input()
+    }
+
+    fn output_frame(&mut self, _max_payload: usize) -> Result<Frame, String> {
+        if !self.info_sent && self.response.is_none() {
+            match FileReaderType::fs_info(&self.file_name) {
+                Ok(info) => {
+                    self.info_sent = true;
+                    return Ok(Frame::data(info.serialize()));
+                }
+                Err(e) => self.response = Some(e),
+            }
+        }
+        let response = self.response.take().unwrap_or(Response::ok());
+        dprintln!("Described the filesystem of {}: {}", self.file_name, response);
+        self.responded = true;
+        Ok(Frame::response(&response))
+    }
+}
+
+/// The most file bytes a hash command reads before sending a frame, so that
+/// the client hears from it regularly while a large file is hashed.
+const HASH_STEP: u64 = 16 * 1024 * 1024;
+
+/// A command hashing a file, or every file in a tree, without sending the
+/// content.
+///
+/// The input is the path in data frames. The output is data frames holding a
+/// `HashRecord` for every file, in the order of their names, and then a
+/// response frame carrying the checksum of the records. The response is an
+/// error if any file could not be hashed. A data frame is sent after every
+/// `HASH_STEP` bytes read, which is empty if no record was finished, so that
+/// the client knows the command is still running.
+#[derive(Debug)]
+pub struct HashCommandState<FileReaderType: FileReader> {
+    prefix: HashPrefix,
+    path: String,
+    input: NameInput,
+    files: Option<Vec<String>>,
+    next_file: usize,
+    /// The file being hashed, and the bytes of it hashed so far.
+    current: Option<(FileReaderType, Checksum, u64)>,
+    block: Vec<u8>,
+    report: Vec<u8>,
+    hashed: usize,
+    first_failure: Option<HashRecord>,
+    failures: usize,
+    checksum: Option<Checksum>,
+    response: Option<Response>,
+    responded: bool,
+}
+
+impl<FileReaderType: FileReader> HashCommandState<FileReaderType> {
+    /// Works out which files to hash, recording the error if the path cannot
+    /// be looked up or is a directory without the `RECURSIVE` flag.
+    fn find_files(&mut self) {
+        let entry = match FileReaderType::stat(&self.path, self.prefix.flags) {
+            Ok(entry) => entry,
+            Err(e) => {
+                self.response = Some(e);
+                return;
+            }
+        };
+        let files = match entry.kind {
+            EntryKind::File => vec![self.path.clone()],
+            EntryKind::Dir if self.prefix.flags.contains(PrefixFlags::RECURSIVE) => {
+                let mut entries = match FileReaderType::list_dir(&self.path, self.prefix.flags) {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        self.response = Some(e);
+                        return;
+                    }
+                };
+                entries.retain(|entry| entry.kind == EntryKind::File);
+                entries.sort_by(|a, b| a.name.cmp(&b.name));
+                let base = self.path.trim_end_matches('/');
+                entries
+                    .into_iter()
+                    .map(|entry| format!("{}/{}", base, entry.name))
+                    .collect()
+            }
+            EntryKind::Dir => {
+                self.response = Some(Response::error(
+                    ResponseCode::InvalidInput,
+                    format!("{} is a directory; hashing it needs the recursive flag.", self.path),
+                ));
+                return;
+            }
+            EntryKind::Other => {
+                self.response = Some(Response::error(
+                    ResponseCode::InvalidInput,
+                    format!("{} is not a file or directory.", self.path),
+                ));
+                return;
+            }
+        };
+        dprintln!("Hashing {} files under {}.", files.len(), self.path);
+        self.files = Some(files);
+    }
+
+    fn kind(&self) -> ChecksumKind {
+        self.prefix.flags.checksum().unwrap_or(ChecksumKind::None)
+    }
+
+    fn add_record(&mut self, record: HashRecord) {
+        if record.is_hashed() {
+            self.hashed += 1;
+        } else {
+            self.failures += 1;
+            if self.first_failure.is_none() {
+                self.first_failure = Some(record.clone());
+            }
+        }
+        record.serialize_into(&mut self.report);
+    }
+
+    /// Hashes files until either `HASH_STEP` bytes have been read or a frame's
+    /// worth of records is waiting. Returns whether every file is done.
+    fn hash_step(&mut self, max_payload: usize) -> bool {
+        let mut budget = HASH_STEP;
+        while budget > 0 && self.report.len() < max_payload {
+            if self.current.is_none() {
+                let name = match &self.files {
+                    Some(files) if self.next_file < files.len() => files[self.next_file].clone(),
+                    _ => return true,
+                };
+                match FileReaderType::new(&name, self.prefix.flags) {
+                    Ok(fl) => self.current = Some((fl, Checksum::new(self.kind()), 0)),
+                    Err(e) => {
+                        self.next_file += 1;
+                        self.add_record(HashRecord::failed(name, e.code, e.message));
+                        continue;
+                    }
+                }
+            }
+            let (done, failure) = match &mut self.current {
+                Some((fl, ck, read_idx)) => {
+                    let want = (fl.len() - *read_idx).min(self.block.len() as u64).min(budget) as usize;
+                    if want == 0 {
+                        (true, None)
+                    } else {
+                        match fl.read_bytes(&mut self.block[0..want]) {
+                            Ok(0) => (true, Some(Response::error(ResponseCode::Io, "File ended while it was hashed.".to_owned()))),
+                            Ok(n) => {
+                                ck.update(&self.block[0..n]);
+                                *read_idx += n as u64;
+                                budget -= n as u64;
+                                (false, None)
+                            }
+                            Err(e) => (true, Some(e)),
+                        }
+                    }
+                }
+                None => continue,
+            };
+            if !done {
+                continue;
+            }
+            let name = self.files.as_ref().map(|files| files[self.next_file].clone()).unwrap_or(String::new());
+            self.next_file += 1;
+            let (_, ck, read_idx) = match self.current.take() {
+                Some(current) => current,
+                None => continue,
+            };
+            let record = match failure {
+                Some(e) => HashRecord::failed(name, e.code, e.message),
+                None => HashRecord::hashed(name, read_idx, ck.finish()),
+            };
+            self.add_record(record);
+        }
+        false
+    }
+
+    fn respond(&mut self) -> Frame {
+        let digest = checksum::take_digest(&mut self.checksum);
+        let response = match (self.response.take(), self.first_failure.take()) {
+            (Some(err), _) => err,
+            (None, Some(first)) => Response::error(
+                first.code,
+                format!(
+                    "{} of {} files could not be hashed; {}: {}",
+                    self.failures,
+                    self.failures + self.hashed,
+                    first.name,
+                    first.message
+                ),
+            ),
+            (None, None) => Response::ok(),
+        }
+        .with_checksum(digest);
+        dprintln!("Finished hashing {}: {}", self.path, response);
+        self.responded = true;
+        Frame::response(&response)
+    }
+}
+
+impl<FileReaderType: FileReader> ServerCommandState<HashPrefix> for HashCommandState<FileReaderType> {
+    fn from_prefix(prefix: HashPrefix) -> Self {
+        let (checksum, response) = match checksum_from_flags(prefix.flags, PrefixFlags::RECURSIVE | PrefixFlags::FOLLOW_LINKS) {
+            Ok(ref ck) if ck.kind() == ChecksumKind::None => (
+                None,
+                Some(Response::error(
+                    ResponseCode::InvalidInput,
+                    "Hashing needs a checksum kind.".to_owned(),
+                )),
+            ),
+            Ok(ck) => (Some(ck), None),
+            Err(e) => (None, Some(e)),
+        };
+        HashCommandState {
+            prefix,
+            path: String::with_capacity(prefix.file_name_length as usize),
+            input: NameInput::new(prefix.file_name_length, prefix.flags),
+            files: None,
+            next_file: 0,
+            current: None,
+            block: vec![0; COPY_CHUNK_SIZE],
+            report: Vec::new(),
+            hashed: 0,
+            first_failure: None,
+            failures: 0,
+            checksum,
+            response,
+            responded: false,
+        }
+    }
+
+    fn needs_input(&self) -> bool {
+        !self.input.is_complete()
+    }
+
+    fn input_frame(&mut self, frame: Frame) -> Result<(), String> {
+        if let Some(name) = self.input.input_frame(frame, "path")? {
+            self.path = name;
+        }
+        Ok(())
+    }
+
+    fn needs_output(&self) -> bool {
+        !self.responded && !self.needs_input()
+    }
+
+    fn output_frame(&mut self, max_payload: usize) -> Result<Frame, String> {
+        if self.files.is_none() && self.response.is_none() {
+            self.find_files();
+        }
+        if self.response.is_some() {
+            return Ok(self.respond());
+        }
+        let finished = self.hash_step(max_payload);
+        if finished && self.report.is_empty() {
+            return Ok(self.respond());
+        }
+        let end = self.report.len().min(max_payload);
+        let payload: Vec<u8> = self.report.drain(0..end).collect();
+        if let Some(ck) = &mut self.checksum {
+            ck.update(&payload);
+        }
+        Ok(Frame::data(payload))
+    }
+}
+
+/// A command sending the block signature of a file, so that the client can
+/// send a delta against it.
+///
+/// The input is the file name in data frames. The output is data frames
+/// holding the file's `delta::Signature`, and then a response frame carrying
+/// the checksum of the signature. If the file cannot be read, or reading
+/// fails partway, the response frame is sent straight away with the error.
+#[derive(Debug)]
+pub struct SignatureCommandState<FileReaderType: FileReader> {
+    prefix: SignaturePrefix,
+    file_name: String,
+    input: NameInput,
+    file: Option<FileReaderType>,
+    header_sent: bool,
+    read_idx: u64,
+    block: Vec<u8>,
+    checksum: Option<Checksum>,
+    response: Option<Response>,
+    responded: bool,
+}
+
+impl<FileReaderType: FileReader> SignatureCommandState<FileReaderType> {
+    /// Reads the next block of the file and works out its checksums.
+    fn next_block(&mut self) -> Result<BlockSignature, Response> {
+        let fl = match &mut self.file {
+            Some(fl) => fl,
+            None => return Err(Response::error(ResponseCode::Io, format!("File {} is not open.", self.file_name))),
+        };
+        let want = (fl.len() - self.read_idx).min(self.prefix.block_size as u64) as usize;
+        self.block.resize(want, 0);
+        let mut filled = 0;
+        while filled < want {
+            match fl.read_bytes(&mut self.block[filled..]) {
+                Ok(0) => {
+                    return Err(Response::error(
+                        ResponseCode::Io,
+                        format!("File {} ended while making its signature.", self.file_name),
+                    ))
+                }
+                Ok(n) => filled += n,
+                Err(e) => return Err(e),
+            }
+        }
+        self.read_idx += want as u64;
+        let kind = self.prefix.flags.checksum().unwrap_or(ChecksumKind::None);
+        Ok(BlockSignature::of(&self.block, kind))
+    }
+
+    fn respond(&mut self) -> Frame {
+        let response = match (self.response.take(), self.checksum.take()) {
+            (Some(err), _) => err,
+            (None, Some(ck)) => Response::ok().with_checksum(ck.finish()),
+            (None, None) => Response::ok(),
+        };
+        dprintln!("Sent the signature of {}: {}", self.file_name, response);
+        self.responded = true;
+        Frame::response(&response)
+    }
+}
+
+impl<FileReaderType: FileReader> ServerCommandState<SignaturePrefix> for SignatureCommandState<FileReaderType> {
+    fn from_prefix(prefix: SignaturePrefix) -> Self {
+        let (checksum, response) = match checksum_from_flags(prefix.flags, PrefixFlags::FOLLOW_LINKS) {
+            Ok(ref ck) if ck.kind() == ChecksumKind::None => (
+                None,
+                Some(Response::error(
+                    ResponseCode::InvalidInput,
+                    "A signature needs a checksum kind for its blocks.".to_owned(),
+                )),
+            ),
+            Ok(_) if prefix.block_size == 0 || prefix.block_size > delta::MAX_BLOCK_SIZE => (
+                None,
+                Some(Response::error(
+                    ResponseCode::InvalidInput,
+                    format!("Block size {} is not between 1 and {}.", prefix.block_size, delta::MAX_BLOCK_SIZE),
+                )),
+            ),
+            Ok(ck) => (Some(ck), None),
+            Err(e) => (None, Some(e)),
+        };
+        SignatureCommandState {
+            prefix,
+            file_name: String::with_capacity(prefix.file_name_length as usize),
+            input: NameInput::new(prefix.file_name_length, prefix.flags),
+            file: None,
+            header_sent: false,
+            read_idx: 0,
+            block: Vec::new(),
+            checksum,
+            response,
+            responded: false,
+        }
+    }
+
+    fn needs_input(&self) -> bool {
+        !self.input.is_complete()
+    }
+
+    fn input_frame(&mut self, frame: Frame) -> Result<(), String> {
+        if let Some(name) = self.input.input_frame(frame, "file name")? {
+            self.file_name = name;
+        }
+        Ok(())
+    }
+
+    fn needs_output(&self) -> bool {
+        !self.responded && !self.needs_input()
+    }
+
+    fn output_frame(&mut self, max_payload: usize) -> Result<Frame, String> {
+        if !self.header_sent && self.response.is_none() {
+            match FileReaderType::new(&self.file_name, self.prefix.flags) {
+                Ok(fl) => self.file = Some(fl),
+                Err(e) => self.response = Some(e),
+            }
+        }
+        let file_len = match (&self.file, &self.response) {
+            (Some(fl), None) => fl.len(),
+            _ => return Ok(self.respond()),
+        };
+        if self.header_sent && self.read_idx >= file_len {
+            return Ok(self.respond());
+        }
+        let mut payload = Vec::with_capacity(max_payload);
+        if !self.header_sent {
+            dprintln!("Making the signature of {} in blocks of {} bytes.", self.file_name, self.prefix.block_size);
+            payload.extend_from_slice(&Signature::header(file_len, self.prefix.block_size));
+            self.header_sent = true;
+        }
+        let kind = self.prefix.flags.checksum().unwrap_or(ChecksumKind::None);
+        let entry_len = 4 + kind.digest_len();
+        while self.read_idx < file_len && payload.len() + entry_len <= max_payload {
+            match self.next_block() {
+                Ok(block) => block.serialize_into(&mut payload),
+                Err(e) => {
+                    self.response = Some(e);
+                    return Ok(self.respond());
+                }
+            }
+        }
+        if let Some(ck) = &mut self.checksum {
+            ck.update(&payload);
+        }
+        Ok(Frame::data(payload))
+    }
+}
+
+/// A command reading a file as a delta against the signature of the client's
+/// old copy of it.
+///
+/// The input is the file name and then the signature in data frames. The
+/// output is data frames holding the `delta::DELTA_HEADER_LENGTH` header and
+/// then the delta stream, and finally a response frame carrying the checksum
+/// of the whole file. The file is read twice, through two handles: one plans
+/// the delta, and the other reads the new bytes the stream carries.
+/// Instructions are sent as soon as they are planned, and a data frame is sent
+/// after every `HASH_STEP` bytes planned, which is empty if no instruction was
+/// found, so that the client knows the command is still running.
+/// If the file cannot be read or the signature makes no sense, the response
+/// frame is sent straight away with the error.
+#[derive(Debug)]
+pub struct ReadDeltaCommandState<FileReaderType: FileReader> {
+    prefix: ReadDeltaPrefix,
+    file_name: String,
+    input: NameInput,
+    signature: Vec<u8>,
+    /// The handle the delta is planned from, and how far it has got.
+    plan_file: Option<FileReaderType>,
+    planned: u64,
+    planner: Option<DeltaPlanner>,
+    block: Vec<u8>,
+    /// The handle the bytes of literals are read from.
+    file: Option<FileReaderType>,
+    /// Where the file's cursor is, so that it is only moved when a literal
+    /// starts somewhere else.
+    read_pos: u64,
+    encoder: DeltaEncoder,
+    header_sent: bool,
+    file_len: u64,
+    literal_bytes: u64,
+    checksum: Option<Checksum>,
+    digest: Vec<u8>,
+    response: Option<Response>,
+    responded: bool,
+    /// Whether the signature is too long to buffer, so that the command is
+    /// refused before any of its input is read.
+    refused: bool,
+}
+
+impl<FileReaderType: FileReader> ReadDeltaCommandState<FileReaderType> {
+    /// Opens the file twice and parses the client's signature, ready to plan
+    /// the delta against it.
+    fn start(&mut self) -> Result<(), Response> {
+        let kind = self.prefix.flags.checksum().unwrap_or(ChecksumKind::None);
+        let signature = Signature::parse(&self.signature, kind).map_err(|e| Response::error(ResponseCode::InvalidInput, e))?;
+        let plan_file = FileReaderType::new(&self.file_name, self.prefix.flags)?;
+        self.file = Some(FileReaderType::new(&self.file_name, self.prefix.flags)?);
+        self.file_len = plan_file.len();
+        self.plan_file = Some(plan_file);
+        self.planner = Some(DeltaPlanner::new(signature));
+        Ok(())
+    }
+
+    /// Plans the delta until either `HASH_STEP` bytes have been read or some
+    /// instructions are ready to send, and finishes the plan at the end of
+    /// the file.
+    fn plan_step(&mut self) -> Result<(), Response> {
+        let mut budget = HASH_STEP;
+        while budget > 0 && self.encoder.is_idle() {
+            let (planner, fl) = match (&mut self.planner, &mut self.plan_file) {
+                (Some(planner), Some(fl)) => (planner, fl),
+                _ => return Ok(()),
+            };
+            let want = (self.file_len - self.planned).min(self.block.len() as u64).min(budget) as usize;
+            if want == 0 {
+                self.finish_plan();
+                return Ok(());
+            }
+            let read = fl.read_bytes(&mut self.block[0..want])?;
+            if read == 0 {
+                return Err(Response::error(
+                    ResponseCode::Io,
+                    format!("{} ended after {} of its {} bytes.", self.file_name, self.planned, self.file_len),
+                ));
+            }
+            planner.input(&self.block[0..read]);
+            if let Some(ck) = &mut self.checksum {
+                ck.update(&self.block[0..read]);
+            }
+            self.planned += read as u64;
+            budget -= read as u64;
+            let ops = planner.take_ops();
+            for op in ops {
+                self.push_op(op);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish_plan(&mut self) {
+        self.plan_file = None;
+        if let Some(planner) = self.planner.take() {
+            for op in planner.finish() {
+                self.push_op(op);
+            }
+        }
+        self.digest = checksum::take_digest(&mut self.checksum);
+        dprintln!(
+            "Planned the delta of {} with {} of its {} bytes in full.",
+            self.file_name,
+            self.literal_bytes,
+            self.file_len
+        );
+    }
+
+    fn push_op(&mut self, op: DeltaOp) {
+        if let DeltaOp::Literal { length, .. } = op {
+            self.literal_bytes += length;
+        }
+        self.encoder.push(op);
+    }
+
+    fn respond(&mut self) -> Frame {
+        let response = match self.response.take() {
+            Some(err) => err,
+            None => Response::ok().with_checksum(self.digest.clone()),
+        };
+        dprintln!("Finished the delta of {}: {}", self.file_name, response);
+        self.responded = true;
+        Frame::response(&response)
+    }
+}
+
+impl<FileReaderType: FileReader> ServerCommandState<ReadDeltaPrefix> for ReadDeltaCommandState<FileReaderType> {
+    fn from_prefix(prefix: ReadDeltaPrefix) -> Self {
+        let (checksum, response) = match checksum_from_flags(prefix.flags, PrefixFlags::FOLLOW_LINKS) {
+            Ok(ref ck) if ck.kind() == ChecksumKind::None => (
+                None,
+                Some(Response::error(
+                    ResponseCode::InvalidInput,
+                    "A delta read needs a checksum kind for the signature's blocks.".to_owned(),
+                )),
+            ),
+            Ok(ck) => (Some(ck), None),
+            Err(e) => (None, Some(e)),
+        };
+        let max_signature = Signature::max_len(prefix.flags.checksum().unwrap_or(ChecksumKind::None));
+        let refused = prefix.signature_length > max_signature;
+        let response = if refused {
+            Some(Response::error(
+                ResponseCode::Protocol,
+                format!("A signature of {} bytes is longer than the {} allowed.", prefix.signature_length, max_signature),
+            ))
+        } else {
+            response
+        };
+        ReadDeltaCommandState {
+            prefix,
+            file_name: String::with_capacity(prefix.file_name_length as usize),
+            input: NameInput::new(prefix.file_name_length, prefix.flags),
+            signature: Vec::new(),
+            plan_file: None,
+            planned: 0,
+            planner: None,
+            block: vec![0; COPY_CHUNK_SIZE],
+            file: None,
+            read_pos: 0,
+            encoder: DeltaEncoder::new(Vec::new()),
+            header_sent: false,
+            file_len: 0,
+            literal_bytes: 0,
+            checksum,
+            digest: Vec::new(),
+            response,
+            responded: false,
+            refused,
+        }
+    }
+
+    fn needs_input(&self) -> bool {
+        !self.responded && !self.refused && (!self.input.is_complete() || (self.signature.len() as u64) < self.prefix.signature_length)
+    }
+
+    fn input_frame(&mut self, frame: Frame) -> Result<(), String> {
+        let block = data_payload(frame)?;
+        let mut taken = 0;
+        if !self.input.is_complete() {
+            taken = self.input.input(&block);
+            if self.input.is_complete() {
+                self.file_name = self.input.name()?;
+            }
+        }
+        let wanted = self.prefix.signature_length - self.signature.len() as u64;
+        if (block.len() - taken) as u64 > wanted {
+            return Err(format!(
+                "Got {} bytes of signature for {} when only {} were left.",
+                block.len() - taken,
+                self.file_name,
+                wanted
+            ));
+        }
+        self.signature.extend_from_slice(&block[taken..]);
+        Ok(())
+    }
+
+    fn needs_output(&self) -> bool {
+        !self.responded && !self.needs_input()
+    }
+
+    fn output_frame(&mut self, max_payload: usize) -> Result<Frame, String> {
+        if !self.header_sent && self.response.is_none() {
+            if let Err(e) = self.start() {
+                self.response = Some(e);
+            }
+        }
+        if self.response.is_some() {
+            return Ok(self.respond());
+        }
+        let mut payload = Vec::with_capacity(max_payload);
+        if !self.header_sent {
+            payload.extend_from_slice(&prefixes::extract_bytes_u64(self.file_len));
+            self.header_sent = true;
+        }
+        if self.encoder.is_idle() {
+            if let Err(e) = self.plan_step() {
+                self.response = Some(e);
+                return Ok(self.respond());
+            }
+        }
+        let stream_begin = payload.len();
+        payload.resize(max_payload.max(stream_begin), 0);
+        let read_pos = &mut self.read_pos;
+        let filled = match &mut self.file {
+            Some(fl) => self.encoder.fill(&mut payload[stream_begin..], |offset, buffer| {
+                if offset != *read_pos {
+                    fl.seek(offset).map_err(|e| e.message)?;
+                    *read_pos = offset;
+                }
+                let read = fl.read_bytes(buffer).map_err(|e| e.message)?;
+                *read_pos += read as u64;
+                Ok(read)
+            }),
+            None => Ok(0),
+        };
+        match filled {
+            Ok(n) => payload.truncate(stream_begin + n),
+            Err(e) => {
+                self.response = Some(Response::error(ResponseCode::Io, e));
+                return Ok(self.respond());
+            }
+        }
+        if payload.is_empty() && self.planner.is_none() && self.encoder.is_idle() {
+            return Ok(self.respond());
+        }
+        Ok(Frame::data(payload))
+    }
+}
+
+/// A command creating a directory on the device.
+///
+/// The input is the directory name in data frames. The output is a single
+/// response frame.
+#[derive(Debug)]
+pub struct MakeDirCommandState<FileWriterType: FileWriter> {
+    prefix: MakeDirPrefix,
+    dir_name: String,
+    input: NameInput,
+    response: Option<Response>,
+    responded: bool,
+    writer: PhantomData<FileWriterType>,
+}
+
+impl<FileWriterType: FileWriter> ServerCommandState<MakeDirPrefix> for MakeDirCommandState<FileWriterType> {
+    fn from_prefix(prefix: MakeDirPrefix) -> Self {
+        let response = checksum_from_flags(prefix.flags, PrefixFlags::CREATE_PARENTS).err();
+        MakeDirCommandState {
+            prefix,
+            dir_name: String::with_capacity(prefix.file_name_length as usize),
+            input: NameInput::new(prefix.file_name_length, prefix.flags),
+            response,
+            responded: false,
+            writer: PhantomData,
+        }
+    }
+
+    fn needs_input(&self) -> bool {
+        !self.input.is_complete()
+    }
+
+    fn input_frame(&mut self, frame: Frame) -> Result<(), String> {
+        if let Some(name) = self.input.input_frame(frame, "directory name")? {
+            self.dir_name = name;
+        }
+        Ok(())
+    }
+
+    fn needs_output(&self) -> bool {
+        !self.responded && !self.needs_input()
+    }
+
+    fn output_frame(&mut self, _max_payload: usize) -> Result<Frame, String> {
+        let response = match self.response.take() {
+            Some(err) => err,
+            None => match FileWriterType::make_dir(&self.dir_name, self.prefix.flags) {
+                Ok(()) => Response::ok(),
+                Err(e) => e,
+            },
+        };
+        dprintln!("Made directory {}: {}", self.dir_name, response);
+        self.responded = true;
+        Ok(Frame::response(&response))
+    }
+}
+
+/// A command deleting a file, link or directory on the device.
+///
+/// The input is the path in data frames. The output is data frames holding a
+/// `RemovalRecord` for every entry the server tried to delete, and then a
+/// response frame carrying the checksum of the records. The response is an
+/// error if any entry could not be deleted.
+#[derive(Debug)]
+pub struct RemoveCommandState<FileWriterType: FileWriter> {
+    prefix: RemovePrefix,
+    path: String,
+    input: NameInput,
+    report: Option<Vec<u8>>,
+    sent_idx: usize,
+    checksum: Option<Checksum>,
+    response: Option<Response>,
+    responded: bool,
+    writer: PhantomData<FileWriterType>,
+}
+
+impl<FileWriterType: FileWriter> RemoveCommandState<FileWriterType> {
+    /// Deletes the path and encodes what happened to each entry, recording
+    /// the first failure as the command's response.
+    fn build_report(&mut self) {
+        let records = FileWriterType::remove_path(&self.path, self.prefix.flags, self.prefix.dir);
+        let failed: Vec<&RemovalRecord> = records.iter().filter(|r| !r.is_removed()).collect();
+        dprintln!(
+            "Removed {} of {} entries under {}.",
+            records.len() - failed.len(),
+            records.len(),
+            self.path
+        );
+        if let Some(first) = failed.first() {
+            self.response = Some(Response::error(
+                first.code,
+                format!(
+                    "{} of {} entries could not be removed; {}: {}",
+                    failed.len(),
+                    records.len(),
+                    first.path,
+                    first.message
+                ),
+            ));
+        }
+        let mut bytes = Vec::new();
+        for record in records.iter() {
+            record.serialize_into(&mut bytes);
+        }
+        if let Some(ck) = &mut self.checksum {
+            ck.update(&bytes);
+        }
+        self.report = Some(bytes);
+    }
+}
+
+impl<FileWriterType: FileWriter> ServerCommandState<RemovePrefix> for RemoveCommandState<FileWriterType> {
+    fn from_prefix(prefix: RemovePrefix) -> Self {
+        let honoured = if prefix.dir {
+            PrefixFlags::empty()
+        } else {
+            PrefixFlags::RECURSIVE
+        };
+        let (checksum, response) = match checksum_from_flags(prefix.flags, honoured) {
+            Ok(ck) => (Some(ck), None),
+            Err(e) => (None, Some(e)),
+        };
+        RemoveCommandState {
+            prefix,
+            path: String::with_capacity(prefix.file_name_length as usize),
+            input: NameInput::new(prefix.file_name_length, prefix.flags),
+            report: None,
+            sent_idx: 0,
+            checksum,
+            response,
+            responded: false,
+            writer: PhantomData,
+        }
+    }
+
+    fn needs_input(&self) -> bool {
+        !self.input.is_complete()
+    }
+
+    fn input_frame(&mut self, frame: Frame) -> Result<(), String> {
+        if let Some(name) = self.input.input_frame(frame, "path")? {
+            self.path = name;
+        }
+        Ok(())
+    }
+
+    fn needs_output(&self) -> bool {
+        !self.responded && !self.needs_input()
+    }
+
+    fn output_frame(&mut self, max_payload: usize) -> Result<Frame, String> {
+        if self.report.is_none() && self.response.is_none() {
+            self.build_report();
+        }
+        if let Some(report) = &self.report {
+            if self.sent_idx < report.len() {
+                let end = (self.sent_idx + max_payload).min(report.len());
+                let payload = report[self.sent_idx..end].to_vec();
+                self.sent_idx = end;
+                return Ok(Frame::data(payload));
+            }
+        }
+        let digest = checksum::take_digest(&mut self.checksum);
+        let response = self.response.take().unwrap_or(Response::ok()).with_checksum(digest);
+        dprintln!("Finished removing {}: {}", self.path, response);
+        self.responded = true;
+        Ok(Frame::response(&response))
+    }
+}
+
+/// A command moving or renaming a path on the device.
+///
+/// The input is the source path and then the destination path in data frames.
+/// The output is a single response frame.
+#[derive(Debug)]
+pub struct MoveCommandState<FileWriterType: FileWriter> {
+    prefix: MovePrefix,
+    from: String,
+    to: String,
+    input: NameInput,
+    response: Option<Response>,
+    responded: bool,
+    writer: PhantomData<FileWriterType>,
+}
+
+impl<FileWriterType: FileWriter> ServerCommandState<MovePrefix> for MoveCommandState<FileWriterType> {
+    fn from_prefix(prefix: MovePrefix) -> Self {
+        let response = checksum_from_flags(prefix.flags, PrefixFlags::OVERWRITE).err();
+        // Both names are collected as one, and split once they are in.
+        let names_length = prefix.file_name_length.checked_add(prefix.dest_name_length);
+        let response = response.or(match names_length {
+            Some(_) => None,
+            None => Some(Response::error(
+                ResponseCode::InvalidInput,
+                "The source and destination names are too long.".to_owned(),
+            )),
+        });
+        MoveCommandState {
+            prefix,
+            from: String::new(),
+            to: String::new(),
+            input: NameInput::new(names_length.unwrap_or(0), prefix.flags),
+            response,
+            responded: false,
+            writer: PhantomData,
+        }
+    }
+
+    fn needs_input(&self) -> bool {
+        !self.input.is_complete()
+    }
+
+    fn input_frame(&mut self, frame: Frame) -> Result<(), String> {
+        if let Some(names) = self.input.input_frame(frame, "path names")? {
+            let split = self.prefix.file_name_length as usize;
+            if !names.is_char_boundary(split) {
+                return Err(format!("The source name {:?} does not end on a character boundary.", names));
+            }
+            let (from, to) = names.split_at(split);
+            self.from = from.to_owned();
+            self.to = to.to_owned();
+        }
+        Ok(())
+    }
+
+    fn needs_output(&self) -> bool {
+        !self.responded && !self.needs_input()
+    }
+
+    fn output_frame(&mut self, _max_payload: usize) -> Result<Frame, String> {
+        let response = match self.response.take() {
+            Some(err) => err,
+            None => match FileWriterType::move_path(&self.from, &self.to, self.prefix.flags) {
+                Ok(()) => Response::ok(),
+                Err(e) => e,
+            },
+        };
+        dprintln!("Moved {} to {}: {}", self.from, self.to, response);
+        self.responded = true;
+        Ok(Frame::response(&response))
+    }
+}
+
+/// Builds the handshake this server answers with.
+pub fn server_handshake() -> HandshakePrefix {
+    HandshakePrefix {
+        protocol_version: PROTOCOL_VERSION,
+        build_version: handshake::parse_build_version(
+            env!("CARGO_PKG_VERSION_MAJOR"),
+            env!("CARGO_PKG_VERSION_MINOR"),
+            env!("CARGO_PKG_VERSION_PATCH"),
+        ),
+        max_frame_payload: MAX_FRAME_PAYLOAD as u16,
+        features: SUPPORTED_FEATURES,
+    }
+}
+
+/// A command answering the client's handshake with the server's own version
+/// information.
+#[derive(Debug)]
+pub struct HandshakeCommandState {
+    client: HandshakePrefix,
+    sent: Option<HandshakePrefix>,
+}
+
+impl HandshakeCommandState {
+    /// Checks whether the client that started this handshake can talk to this
+    /// server. Only meaningful once the server's reply has been sent.
+    pub fn check_compatible(&self) -> Result<(), String> {
+        match self.sent {
+            Some(ref server) => handshake::check_compatible(server, &self.client),
+            None => Err("Handshake reply was never sent.".to_owned()),
+        }
+    }
+
+    /// The largest frame payload both sides accept, as agreed in the
+    /// handshake.
+    pub fn frame_payload(&self) -> usize {
+        let server = self.sent.unwrap_or(server_handshake());
+        handshake::negotiate_frame_payload(&server, &self.client)
+    }
+
+    /// The protocol version the rest of the connection is spoken in, as
+    /// agreed in the handshake.
+    pub fn protocol_version(&self) -> u8 {
+        handshake::negotiate_protocol_version(PROTOCOL_VERSION, self.client.protocol_version)
+    }
+}
+
+impl ServerCommandState<HandshakePrefix> for HandshakeCommandState {
+    fn from_prefix(prefix: HandshakePrefix) -> Self {
+        HandshakeCommandState {
+            client: prefix,
+            sent: None,
+        }
+    }
+
+    fn needs_input(&self) -> bool {
+        false
+    }
+
+    fn input_frame(&mut self, frame: Frame) -> Result<(), String> {
+        Err(format!("Handshake takes no input but got a {:?} frame.", frame.kind))
+    }
+
+    fn needs_output(&self) -> bool {
+        self.sent.is_none()
+    }
+
+    fn output_frame(&mut self, _max_payload: usize) -> Result<Frame, String> {
+        let mut reply = server_handshake();
+        reply.protocol_version = handshake::negotiate_protocol_version(PROTOCOL_VERSION, self.client.protocol_version);
+        self.sent = Some(reply);
+        Ok(Frame::prefix(&reply))
+    }
+}
+
+/// A command standing in for a prefix the server could not make sense of,
+/// such as one with an opcode from a newer client. It takes no input and
+/// answers with a single protocol error response.
+#[derive(Debug)]
+pub struct RejectedCommandState {
+    response: Response,
+    responded: bool,
+}
+
+impl RejectedCommandState {
+    pub fn new(message: String) -> Self {
+        RejectedCommandState {
+            response: Response::error(ResponseCode::Protocol, message),
+            responded: false,
+        }
+    }
+
+    pub fn needs_input(&self) -> bool {
+        false
+    }
+
+    pub fn input_frame(&mut self, frame: Frame) -> Result<(), String> {
+        Err(format!("A refused command takes no input but got a {:?} frame.", frame.kind))
+    }
+
+    pub fn needs_output(&self) -> bool {
+        !self.responded
+    }
+
+    pub fn output_frame(&mut self, _max_payload: usize) -> Result<Frame, String> {
+        dprintln!("Refusing command: {}", self.response);
+        self.responded = true;
+        Ok(Frame::response(&self.response))
+    }
+}
+
+pub enum CommandStates<T : FileReader, U : FileWriter> {
+    Handshake(HandshakeCommandState),
+    Read(ReadCommandState<T>), 
+    Write(WriteCommandState<U>),
+    Probe(ProbeCommandState<T>),
+    List(ListCommandState<T>),
+    Stat(StatCommandState<T>),
+    MakeDir(MakeDirCommandState<U>),
+    Remove(RemoveCommandState<U>),
+    Move(MoveCommandState<U>),
+    FsInfo(FsInfoCommandState<T>),
+    Signature(SignatureCommandState<T>),
+    ReadDelta(ReadDeltaCommandState<T>),
+    Hash(HashCommandState<T>),
+    Rejected(RejectedCommandState),
+}
+
+impl <T : FileReader, U : FileWriter> CommandStates<T, U> {
+    /// Starts the command that a prefix frame asks for, or one refusing it if
+    /// the prefix cannot be parsed in the layout of the negotiated
+    /// `protocol_version`. Returns `None` for any other kind of frame, which
+    /// is input left over from a refused command and can be dropped.
+    pub fn from_frame(frame: &Frame, protocol_version: u8) -> Option<Self> {
+        if frame.kind != FrameKind::Prefix {
+            return None;
+        }
+        if frame.payload.len() != PREFIX_LENGTH {
+            return Some(CommandStates::Rejected(RejectedCommandState::new(format!(
+                "Prefix frame has {} bytes instead of {}.",
+                frame.payload.len(),
+                PREFIX_LENGTH
+            ))));
+        }
+        let mut prefix_bytes = [0u8; PREFIX_LENGTH];
+        prefix_bytes.copy_from_slice(&frame.payload);
+        let decoded = if handshake::uses_legacy_layout(protocol_version) {
+            dprintln!("Reading a prefix in the legacy layout; the client should be updated.");
+            Prefixes::decode_legacy(prefix_bytes)
+        } else {
+            Prefixes::decode(prefix_bytes)
+        };
+        match decoded {
+            Ok(prefix) => Some(CommandStates::from_prefix(prefix)),
+            Err(e) => Some(CommandStates::Rejected(RejectedCommandState::new(e))),
+        }
+    }
+
+    /// Hands a list command the cursor the previous command left. Any other
+    /// command drops it, since it may change what the listing would be.
+    pub fn resume_listing(&mut self, cursor: Option<ListCursor>) {
+        if let (CommandStates::List(list), Some(cursor)) = (self, cursor) {
+            list.resume(cursor);
+        }
+    }
+
+    /// Takes the cursor a finished list command left for the next page.
+    pub fn take_list_cursor(&mut self) -> Option<ListCursor> {
+        match self {
+            CommandStates::List(list) => list.take_cursor(),
+            _ => None,
+        }
+    }
+}
+
+impl <T : FileReader, U : FileWriter> ServerCommandState<Prefixes> for CommandStates<T, U> {
+    fn from_prefix(prefix: Prefixes) -> Self {
+        match prefix {
+            Prefixes::Handshake(h) => CommandStates::Handshake(HandshakeCommandState::from_prefix(h)),
+            Prefixes::Read(r) => CommandStates::Read(ReadCommandState::from_prefix(r)), 
+            Prefixes::Write(w) => CommandStates::Write(WriteCommandState::from_prefix(w)),
+            Prefixes::Probe(p) => CommandStates::Probe(ProbeCommandState::from_prefix(p)),
+            Prefixes::List(l) => CommandStates::List(ListCommandState::from_prefix(l)),
+            Prefixes::Stat(s) => CommandStates::Stat(StatCommandState::from_prefix(s)),
+            Prefixes::MakeDir(m) => CommandStates::MakeDir(MakeDirCommandState::from_prefix(m)),
+            Prefixes::Remove(r) => CommandStates::Remove(RemoveCommandState::from_prefix(r)),
+            Prefixes::Move(m) => CommandStates::Move(MoveCommandState::from_prefix(m)),
+            Prefixes::FsInfo(f) => CommandStates::FsInfo(FsInfoCommandState::from_prefix(f)),
+            Prefixes::Signature(s) => CommandStates::Signature(SignatureCommandState::from_prefix(s)),
+            Prefixes::ReadDelta(r) => CommandStates::ReadDelta(ReadDeltaCommandState::from_prefix(r)),
+            Prefixes::Hash(h) => CommandStates::Hash(HashCommandState::from_prefix(h)),
+        }
+    }
+
+    fn needs_input(&self) -> bool {
+        match self {
+            &CommandStates::Handshake(ref h) => h.needs_input(),
+            &CommandStates::Read(ref r) => r.needs_input(), 
+            &CommandStates::Write(ref w) => w.needs_input(),
+            &CommandStates::Probe(ref p) => p.needs_input(),
+            &CommandStates::List(ref l) => l.needs_input(),
+            &CommandStates::Stat(ref s) => s.needs_input(),
+            &CommandStates::MakeDir(ref m) => m.needs_input(),
+            &CommandStates::Remove(ref r) => r.needs_input(),
+            &CommandStates::Move(ref m) => m.needs_input(),
+            &CommandStates::FsInfo(ref f) => f.needs_input(),
+            &CommandStates::Signature(ref s) => s.needs_input(),
+            &CommandStates::ReadDelta(ref r) => r.needs_input(),
+            &CommandStates::Hash(ref h) => h.needs_input(),
+            &CommandStates::Rejected(ref c) => c.needs_input(),
+        }
+    }
+
+    fn input_frame(&mut self, frame: Frame) -> Result<(), String> {
+        match self {
+            &mut CommandStates::Handshake(ref mut h) => h.input_frame(frame),
+            &mut CommandStates::Read(ref mut r) => r.input_frame(frame), 
+            &mut CommandStates::Write(ref mut w) => w.input_frame(frame),
+            &mut CommandStates::Probe(ref mut p) => p.input_frame(frame),
+            &mut CommandStates::List(ref mut l) => l.input_frame(frame),
+            &mut CommandStates::Stat(ref mut s) => s.input_frame(frame),
+            &mut CommandStates::MakeDir(ref mut m) => m.input_frame(frame),
+            &mut CommandStates::Remove(ref mut r) => r.input_frame(frame),
+            &mut CommandStates::Move(ref mut m) => m.input_frame(frame),
+            &mut CommandStates::FsInfo(ref mut f) => f.input_frame(frame),
+            &mut CommandStates::Signature(ref mut s) => s.input_frame(frame),
+            &mut CommandStates::ReadDelta(ref mut r) => r.input_frame(frame),
+            &mut CommandStates::Hash(ref mut h) => h.input_frame(frame),
+            &mut CommandStates::Rejected(ref mut c) => c.input_frame(frame),
+        }
+    }
+
+    fn needs_output(&self) -> bool {
+        match self {
+            &CommandStates::Handshake(ref h) => h.needs_output(),
+            &CommandStates::Read(ref r) => r.needs_output(), 
+            &CommandStates::Write(ref w) => w.needs_output(),
+            &CommandStates::Probe(ref p) => p.needs_output(),
+            &CommandStates::List(ref l) => l.needs_output(),
+            &CommandStates::Stat(ref s) => s.needs_output(),
+            &CommandStates::MakeDir(ref m) => m.needs_output(),
+            &CommandStates::Remove(ref r) => r.needs_output(),
+            &CommandStates::Move(ref m) => m.needs_output(),
+            &CommandStates::FsInfo(ref f) => f.needs_output(),
+            &CommandStates::Signature(ref s) => s.needs_output(),
+            &CommandStates::ReadDelta(ref r) => r.needs_output(),
+            &CommandStates::Hash(ref h) => h.needs_output(),
+            &CommandStates::Rejected(ref c) => c.needs_output(),
+        }
+    }
+
+    fn output_frame(&mut self, max_payload: usize) -> Result<Frame, String> {
+        match self {
+            &mut CommandStates::Handshake(ref mut h) => h.output_frame(max_payload),
+            &mut CommandStates::Read(ref mut r) => r.output_frame(max_payload), 
+            &mut CommandStates::Write(ref mut w) => w.output_frame(max_payload),
+            &mut CommandStates::Probe(ref mut p) => p.output_frame(max_payload),
+            &mut CommandStates::List(ref mut l) => l.output_frame(max_payload),
+            &mut CommandStates::Stat(ref mut s) => s.output_frame(max_payload),
+            &mut CommandStates::MakeDir(ref mut m) => m.output_frame(max_payload),
+            &mut CommandStates::Remove(ref mut r) => r.output_frame(max_payload),
+            &mut CommandStates::Move(ref mut m) => m.output_frame(max_payload),
+            &mut CommandStates::FsInfo(ref mut f) => f.output_frame(max_payload),
+            &mut CommandStates::Signature(ref mut s) => s.output_frame(max_payload),
+            &mut CommandStates::ReadDelta(ref mut r) => r.output_frame(max_payload),
+            &mut CommandStates::Hash(ref mut h) => h.output_frame(max_payload),
+            &mut CommandStates::Rejected(ref mut c) => c.output_frame(max_payload),
         }
     }
 
-    fn output_block(&mut self, buffer: &mut [u8]) -> Result<usize, String> {
+    fn abort(&mut self, policy: AbortPolicy) -> Response {
         match self {
-            &mut CommandStates::Read(ref mut r) => r.output_block(buffer), 
-            &mut CommandStates::Write(ref mut w) => w.output_block(buffer)
+            &mut CommandStates::Handshake(ref mut h) => h.abort(policy),
+            &mut CommandStates::Read(ref mut r) => r.abort(policy),
+            &mut CommandStates::Write(ref mut w) => w.abort(policy),
+            &mut CommandStates::Probe(ref mut p) => p.abort(policy),
+            &mut CommandStates::List(ref mut l) => l.abort(policy),
+            &mut CommandStates::Stat(ref mut s) => s.abort(policy),
+            &mut CommandStates::MakeDir(ref mut m) => m.abort(policy),
+            &mut CommandStates::Remove(ref mut r) => r.abort(policy),
+            &mut CommandStates::Move(ref mut m) => m.abort(policy),
+            &mut CommandStates::FsInfo(ref mut f) => f.abort(policy),
+            &mut CommandStates::Signature(ref mut s) => s.abort(policy),
+            &mut CommandStates::ReadDelta(ref mut r) => r.abort(policy),
+            &mut CommandStates::Hash(ref mut h) => h.abort(policy),
+            &mut CommandStates::Rejected(_) => Response::error(ResponseCode::Aborted, "Command aborted.".to_owned()),
         }
 
     }
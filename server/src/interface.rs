@@ -1,8 +1,64 @@
-use nxusb::prefixes::Prefixes;
+use commands::ServerCommandState;
+use nxusb::frame::{AbortPolicy, Frame, FrameKind};
+use nxusb::prefixes::{CommandPrefix, Prefixes};
+use nxusb::response::{Response, ResponseCode};
 
 pub trait ServerDevice {
-    fn read_prefix(&mut self) -> Result<Prefixes, String>;
-    fn block_size(&self) -> usize;
-    fn read_block(&mut self, buffer: &mut [u8]) -> Result<usize, String>;
-    fn write_block(&mut self, bytes: &[u8]) -> Result<usize, String>;
+    /// Reads the next whole frame from the communication line, however many
+    /// transfers it spans.
+    fn read_frame(&mut self) -> Result<Frame, String>;
+
+    /// Returns the next whole frame if the client has already sent one,
+    /// without waiting for it.
+    fn poll_frame(&mut self) -> Result<Option<Frame>, String>;
+
+    /// Writes a frame to the communication line, returning the number of
+    /// bytes sent.
+    fn write_frame(&mut self, frame: Frame) -> Result<usize, String>;
+
+    /// Numbers the frames sent from here on from 0 again, for a client that
+    /// has started a new session.
+    fn start_session(&mut self);
+
+    /// Reads the prefix frame that starts the next command.
+    fn read_prefix(&mut self) -> Result<Prefixes, String> {
+        self.read_frame()?.parse_prefix()
+    }
+
+    /// Passes one frame of input to a command that needs it, or sends one
+    /// frame of its output. Before each frame of output the line is checked
+    /// for an abort frame, so that a command streaming to the client can be
+    /// stopped partway. Returns whether the command was aborted or refused
+    /// its input, in which case its response has been sent and the command is
+    /// over; whatever else was sent for it is dropped as stray input.
+    fn step_command<P: CommandPrefix, C: ServerCommandState<P>>(&mut self, command: &mut C, max_payload: usize) -> Result<bool, String>
+    where
+        Self: Sized,
+    {
+        if command.needs_input() {
+            let frame = self.read_frame()?;
+            if frame.kind != FrameKind::Abort {
+                if let Err(e) = command.input_frame(frame) {
+                    command.abort(AbortPolicy::Delete);
+                    self.write_frame(Frame::response(&Response::error(ResponseCode::Protocol, e)))?;
+                    return Ok(true);
+                }
+                return Ok(false);
+            }
+            let response = command.abort(frame.parse_abort()?);
+            self.write_frame(Frame::response(&response))?;
+            return Ok(true);
+        }
+        // Anything else that arrives now is input the command did not take,
+        // such as the rest of a refused command, and is dropped.
+        if let Some(frame) = self.poll_frame()? {
+            if frame.kind == FrameKind::Abort {
+                let response = command.abort(frame.parse_abort()?);
+                self.write_frame(Frame::response(&response))?;
+                return Ok(true);
+            }
+        }
+        self.write_frame(command.output_frame(max_payload)?)?;
+        Ok(false)
+    }
 }
@@ -1,5 +1,7 @@
 use commands::FileReader;
-use commands::FileWriter;
+use commands::{suffixed_name, temp_name, FileWriter};
+use super::splitfile::{self, SplitFile};
+use std::ffi::{CStr, CString};
 use std::fs::File;
 use std::io::Read;
 use std::io::Write;
@@ -7,7 +9,15 @@ use std::path::Path;
 use std::fs::OpenOptions;
 use std::os::unix::fs::MetadataExt;
 use std::io::Seek;
+use std::time::UNIX_EPOCH;
+use libc;
 use libnx_rs::fs::{FileSystem};
+use nxusb::checksum::{Checksum, ChecksumKind};
+use nxusb::fsinfo::{FsInfo, FsKind};
+use nxusb::listing::{EntryKind, ListEntry};
+use nxusb::prefixes::PrefixFlags;
+use nxusb::removal::RemovalRecord;
+use nxusb::response::{Response, ResponseCode};
 macro_rules! dprintln {
     () => ({
         println!();
@@ -18,124 +28,983 @@ macro_rules! dprintln {
         eprintln!($($arg)*);
     })
 }
+/// Refuses a path that is a symbolic link unless the command asked to
+/// follow links.
+fn check_link(pt: &Path, flags: PrefixFlags) -> Result<(), Response> {
+    let is_link = std::fs::symlink_metadata(pt)
+        .map(|meta| meta.file_type().is_symlink())
+        .unwrap_or(false);
+    if is_link && !flags.contains(PrefixFlags::FOLLOW_LINKS) {
+        Err(Response::error(
+            ResponseCode::InvalidInput,
+            format!("{} is a symbolic link.", pt.display()),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Where the content of a file is kept: in one piece, or split into parts
+/// for a file system that cannot hold it whole.
+enum Storage {
+    Plain(File),
+    Split(SplitFile),
+}
+
+impl Storage {
+    /// Opens a file, or the split file in a concatenation directory, for
+    /// reading.
+    fn open_read(pt: &Path) -> std::io::Result<Storage> {
+        if pt.is_dir() {
+            SplitFile::open(pt, false).map(Storage::Split)
+        } else {
+            File::open(pt).map(Storage::Plain)
+        }
+    }
+
+    /// Opens a file, or the split file in a concatenation directory, for
+    /// writing at its end.
+    fn open_append(pt: &Path) -> std::io::Result<Storage> {
+        if pt.is_dir() {
+            let mut split = SplitFile::open(pt, true)?;
+            split.seek(std::io::SeekFrom::End(0))?;
+            Ok(Storage::Split(split))
+        } else {
+            OpenOptions::new().create(true).append(true).open(pt).map(Storage::Plain)
+        }
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        match self {
+            Storage::Plain(fl) => fl.metadata().map(|meta| meta.len()),
+            Storage::Split(split) => Ok(split.len()),
+        }
+    }
+
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        match self {
+            Storage::Plain(fl) => fl.set_len(len),
+            Storage::Split(split) => split.set_len(len),
+        }
+    }
+
+    fn sync_all(&self) -> std::io::Result<()> {
+        match self {
+            Storage::Plain(fl) => fl.sync_all(),
+            Storage::Split(split) => split.sync_all(),
+        }
+    }
+
+    fn is_split(&self) -> bool {
+        match self {
+            Storage::Plain(_) => false,
+            Storage::Split(_) => true,
+        }
+    }
+}
+
+impl Read for Storage {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Storage::Plain(fl) => fl.read(buf),
+            Storage::Split(split) => split.read(buf),
+        }
+    }
+}
+
+impl Write for Storage {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Storage::Plain(fl) => fl.write(buf),
+            Storage::Split(split) => split.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Storage::Plain(fl) => fl.flush(),
+            Storage::Split(split) => split.flush(),
+        }
+    }
+}
+
+impl Seek for Storage {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Storage::Plain(fl) => fl.seek(pos),
+            Storage::Split(split) => split.seek(pos),
+        }
+    }
+}
+
+/// The length of a file, counting a concatenation directory as the file it
+/// holds, or `None` if there is no such file.
+fn file_len(pt: &Path) -> Option<u64> {
+    match std::fs::metadata(pt) {
+        Ok(ref meta) if meta.is_file() => Some(meta.len()),
+        Ok(ref meta) if meta.is_dir() => splitfile::split_len(pt),
+        _ => None,
+    }
+}
+
+/// Whether a file on the same file system as the path has to be split once
+/// it grows past `splitfile::PART_SIZE`.
+fn splits_large_files(path: &str) -> bool {
+    match mount_kind(path) {
+        FsKind::Fat32 => true,
+        FsKind::ExFat => false,
+        // Horizon cannot say which its SD card uses, and reads split files
+        // back either way.
+        FsKind::Unknown => cfg!(target_os = "horizon"),
+    }
+}
+
+/// The kind of file system the path is on.
+#[cfg(not(test))]
+fn mount_kind(path: &str) -> FsKind {
+    CString::new(path).map(|c_path| fs_kind(&c_path)).unwrap_or(FsKind::Unknown)
+}
+
+#[cfg(test)]
+thread_local! {
+    /// The kind of file system tests make every path look like it is on, so
+    /// that splitting can be tried in any directory.
+    static TEST_MOUNT_KIND: std::cell::Cell<FsKind> = const { std::cell::Cell::new(FsKind::Unknown) };
+}
+
+#[cfg(test)]
+fn mount_kind(_path: &str) -> FsKind {
+    TEST_MOUNT_KIND.with(|kind| kind.get())
+}
+
+/// Deletes a file or the concatenation directory holding one.
+fn remove_file_or_split(pt: &Path) -> std::io::Result<()> {
+    if pt.is_dir() && splitfile::split_len(pt).is_some() {
+        std::fs::remove_dir_all(pt)
+    } else {
+        std::fs::remove_file(pt)
+    }
+}
+
 pub struct StdFileWriter {
-    file: File,
+    path: String,
+    /// The sibling file the content goes into until it is committed, for
+    /// writes that replace the whole file.
+    temp: Option<String>,
+    file: Storage,
+    /// The old length of a file being appended to or resumed, which is all
+    /// that is kept if the write fails.
+    appended_to: Option<u64>,
+    /// Whether every write so far has matched the existing content, for the
+    /// `SKIP_IDENTICAL` policy. The existing file is only read while this
+    /// holds.
+    matching: bool,
+    /// The file being replaced, opened the first time a delta write copies
+    /// from it.
+    old: Option<Storage>,
+    /// Whether the old file was removed but the temporary file could not be
+    /// renamed over it, which leaves the temporary file as the only copy of
+    /// the content, so it must not be removed.
+    stranded: bool,
+}
+
+/// Creates a fresh temporary file to write the content of the target into.
+fn create_temp(file_name: &str) -> Result<(String, Storage), Response> {
+    let temp = temp_name(file_name);
+    if Path::new(&temp).is_dir() {
+        // Left over from a split write that never finished.
+        std::fs::remove_dir_all(&temp).map_err(|e| Response::from_io_error("Temp file create err", &e))?;
+    }
+    let fl = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&temp)
+        .map_err(|e| Response::from_io_error("Temp file create err", &e))?;
+    Ok((temp, Storage::Plain(fl)))
+}
+
+impl StdFileWriter {
+    /// The path the content is being written to right now.
+    fn written_path(&self) -> &str {
+        self.temp.as_ref().unwrap_or(&self.path)
+    }
+
+    /// Writes at the end of the file, first turning it into a split file if
+    /// this write would take it past `splitfile::PART_SIZE` on a file system
+    /// that needs that.
+    fn write_content(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+        if !self.file.is_split() {
+            let len = self.file.len()?;
+            if len <= splitfile::PART_SIZE && len + buffer.len() as u64 > splitfile::PART_SIZE && splits_large_files(self.written_path()) {
+                let path = self.written_path().to_owned();
+                self.file.sync_all()?;
+                self.file = Storage::Split(SplitFile::convert(Path::new(&path))?);
+            }
+        }
+        self.file.write(buffer)
+    }
 }
 
 impl FileWriter for StdFileWriter {
-    fn new(file_name: &str) -> Result<Self, String> {
+    fn new(file_name: &str, flags: PrefixFlags) -> Result<Self, Response> {
+        let mut path = file_name.to_owned();
+        if flags.contains(PrefixFlags::NEW_NAME) {
+            let mut number = 1;
+            while std::fs::symlink_metadata(&path).is_ok() {
+                path = suffixed_name(file_name, number);
+                number += 1;
+            }
+        }
+        let file_name = path.as_str();
+        let pt = Path::new(file_name);
+        check_link(pt, flags)?;
+        if flags.contains(PrefixFlags::CREATE_PARENTS) {
+            if let Some(parent) = pt.parent() {
+                std::fs::create_dir_all(parent).map_err(|e| Response::from_io_error("Parent create err", &e))?;
+            }
+        }
+        let existed = pt.exists();
+        let replace = flags.contains(PrefixFlags::OVERWRITE) || flags.contains(PrefixFlags::APPEND);
+        if existed && !replace {
+            return Err(Response::error(
+                ResponseCode::Exists,
+                format!("File with name {} already exists!", file_name),
+            ));
+        }
+        if !flags.contains(PrefixFlags::APPEND) {
+            let (temp, fl) = create_temp(file_name)?;
+            return Ok(StdFileWriter {
+                path: file_name.to_owned(),
+                temp: Some(temp),
+                file: fl,
+                appended_to: None,
+                matching: false,
+                old: None,
+                stranded: false,
+            });
+        }
+        let fl = Storage::open_append(pt).map_err(|e| Response::from_io_error("File create err", &e))?;
+        let appended_to = if existed {
+            Some(fl.len().map_err(|e| Response::from_io_error("File metadata err", &e))?)
+        } else {
+            None
+        };
+        Ok(StdFileWriter {
+            path: file_name.to_owned(),
+            temp: None,
+            file: fl,
+            appended_to,
+            matching: false,
+            old: None,
+            stranded: false,
+        })
+    }
+
+    fn replace_if_changed(file_name: &str, flags: PrefixFlags, length: u64) -> Result<Self, Response> {
+        let pt = Path::new(file_name);
+        check_link(pt, flags)?;
+        if file_len(pt) != Some(length) {
+            return StdFileWriter::new(file_name, flags | PrefixFlags::OVERWRITE);
+        }
+        let fl = Storage::open_read(pt).map_err(|e| Response::from_io_error("File open err", &e))?;
+        Ok(StdFileWriter {
+            path: file_name.to_owned(),
+            temp: None,
+            file: fl,
+            appended_to: None,
+            matching: true,
+            old: None,
+            stranded: false,
+        })
+    }
+
+    fn unchanged(&self) -> bool {
+        self.matching
+    }
+
+    fn name(&self) -> &str {
+        &self.path
+    }
+
+    fn resume(file_name: &str, flags: PrefixFlags, offset: u64) -> Result<Self, Response> {
         let pt = Path::new(file_name);
-        //For now we will error on file update b/c we're still not production-grade.
-        if pt.exists() {
-            Err(format!("File with name {} already exists!", file_name).to_owned())
+        check_link(pt, flags)?;
+        let opened = if pt.is_dir() {
+            SplitFile::open(pt, true).map(Storage::Split)
         } else {
-            let fl =
-                File::create(pt).map_err(|e| format!("File create err: {:?}", e).to_owned())?;
-            Ok(StdFileWriter { file: fl })
+            OpenOptions::new().write(true).open(pt).map(Storage::Plain)
+        };
+        let mut fl = opened.map_err(|e| Response::from_io_error("File open err", &e))?;
+        let len = fl.len().map_err(|e| Response::from_io_error("File metadata err", &e))?;
+        if offset > len {
+            return Err(Response::error(
+                ResponseCode::InvalidInput,
+                format!("Offset {} is past the end of {}, which has {} bytes.", offset, file_name, len),
+            ));
         }
+        fl.set_len(offset).map_err(|e| Response::from_io_error("File truncate error", &e))?;
+        fl.seek(std::io::SeekFrom::Start(offset))
+            .map_err(|e| Response::from_io_error("Seek err", &e))?;
+        Ok(StdFileWriter {
+            path: file_name.to_owned(),
+            temp: None,
+            file: fl,
+            appended_to: Some(offset),
+            matching: false,
+            old: None,
+            stranded: false,
+        })
     }
 
-    fn write_bytes(&mut self, buffer: &[u8]) -> Result<usize, String> {
+    fn write_bytes(&mut self, buffer: &[u8]) -> Result<usize, Response> {
+        if self.matching {
+            let start = self
+                .file
+                .stream_position()
+                .map_err(|e| Response::from_io_error("Seek err", &e))?;
+            let mut existing = vec![0u8; buffer.len()];
+            if self.file.read_exact(&mut existing).is_ok() && existing.as_slice() == buffer {
+                return Ok(buffer.len());
+            }
+            // The content differs from here on, so the part that matched is
+            // copied into a temporary file and the rest is written after it.
+            let (temp, fl) = create_temp(&self.path)?;
+            let mut existing = std::mem::replace(&mut self.file, fl);
+            self.temp = Some(temp);
+            self.matching = false;
+            existing
+                .seek(std::io::SeekFrom::Start(0))
+                .map_err(|e| Response::from_io_error("Seek err", &e))?;
+            let mut chunk = vec![0u8; LEN_BUFFER_SIZE];
+            let mut copied = 0;
+            while copied < start {
+                let want = (start - copied).min(LEN_BUFFER_SIZE as u64) as usize;
+                existing
+                    .read_exact(&mut chunk[0..want])
+                    .map_err(|e| Response::from_io_error("Temp file copy err", &e))?;
+                let mut done = 0;
+                while done < want {
+                    done += self
+                        .write_content(&chunk[done..want])
+                        .map_err(|e| Response::from_io_error("Temp file copy err", &e))?;
+                }
+                copied += want as u64;
+            }
+        }
+        self.write_content(buffer)
+            .map_err(|e| Response::from_io_error("File write error", &e))
+    }
+
+    fn remove(self) -> Result<(), Response> {
+        let StdFileWriter { path, temp, file, appended_to, matching, stranded, .. } = self;
+        if matching || stranded {
+            return Ok(());
+        }
+        if let Some(old_len) = appended_to {
+            let mut file = file;
+            file.set_len(old_len).map_err(|e| Response::from_io_error("File truncate error", &e))?;
+            if file.is_split() {
+                splitfile::mark_split(Path::new(&path)).map_err(|e| Response::from_io_error("Split file mark error", &e))?;
+            }
+            return Ok(());
+        }
+        drop(file);
+        remove_file_or_split(Path::new(temp.as_ref().unwrap_or(&path))).map_err(|e| Response::from_io_error("File remove error", &e))
+    }
+
+    fn commit(&mut self) -> Result<(), Response> {
         self.file
-            .write(buffer)
-            .map_err(|e| format!("File write error: {:?}", e).to_owned())
+            .sync_all()
+            .map_err(|e| Response::from_io_error("File sync error", &e))?;
+        if self.file.is_split() {
+            splitfile::mark_split(Path::new(self.written_path())).map_err(|e| Response::from_io_error("Split file mark error", &e))?;
+        }
+        self.old = None;
+        let temp = match &self.temp {
+            Some(temp) => temp.clone(),
+            None => return Ok(()),
+        };
+        if let Err(e) = std::fs::rename(&temp, &self.path) {
+            // Some file systems will not rename over an existing file, and
+            // nothing renames over a directory, so only then is the old one
+            // removed first. Any other error leaves the old file alone.
+            let target = Path::new(&self.path);
+            if e.kind() != std::io::ErrorKind::AlreadyExists && !target.is_dir() {
+                return Err(Response::from_io_error("Temp file rename err", &e));
+            }
+            remove_file_or_split(target).map_err(|e| Response::from_io_error("Old file remove err", &e))?;
+            if let Err(e) = std::fs::rename(&temp, &self.path) {
+                self.stranded = true;
+                return Err(Response::error(
+                    ResponseCode::from_io_error(&e),
+                    format!(
+                        "The old {} was removed but the new content could not be renamed over it, so it was left in {}: {}",
+                        self.path, temp, e
+                    ),
+                ));
+            }
+        }
+        self.temp = None;
+        Ok(())
     }
+
+    fn replaces_existing(&self) -> bool {
+        self.temp.is_some() && std::fs::symlink_metadata(&self.path).is_ok()
+    }
+
+    fn read_back_digest(&mut self, kind: ChecksumKind) -> Result<Vec<u8>, Response> {
+        self.file
+            .sync_all()
+            .map_err(|e| Response::from_io_error("File sync error", &e))?;
+        let mut fl = Storage::open_read(Path::new(self.written_path())).map_err(|e| Response::from_io_error("File reopen error", &e))?;
+        fl.seek(std::io::SeekFrom::Start(self.appended_to.unwrap_or(0)))
+            .map_err(|e| Response::from_io_error("Seek err", &e))?;
+        let mut check = Checksum::new(kind);
+        let mut buffer = vec![0u8; LEN_BUFFER_SIZE];
+        loop {
+            let rd = fl
+                .read(&mut buffer)
+                .map_err(|e| Response::from_io_error("File read back error", &e))?;
+            if rd == 0 {
+                break;
+            }
+            check.update(&buffer[0..rd]);
+        }
+        Ok(check.finish())
+    }
+
+    fn read_old(&mut self, offset: u64, buffer: &mut [u8]) -> Result<usize, Response> {
+        if self.old.is_none() {
+            match Storage::open_read(Path::new(&self.path)) {
+                Ok(fl) => self.old = Some(fl),
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+                Err(e) => return Err(Response::from_io_error("Old file open err", &e)),
+            }
+        }
+        let fl = match &mut self.old {
+            Some(fl) => fl,
+            None => return Ok(0),
+        };
+        fl.seek(std::io::SeekFrom::Start(offset))
+            .map_err(|e| Response::from_io_error("Seek err", &e))?;
+        fl.read(buffer).map_err(|e| Response::from_io_error("Old file read err", &e))
+    }
+
+    fn make_dir(dir_name: &str, flags: PrefixFlags) -> Result<(), Response> {
+        let pt = Path::new(dir_name);
+        if flags.contains(PrefixFlags::CREATE_PARENTS) {
+            std::fs::create_dir_all(pt).map_err(|e| Response::from_io_error("Dir create err", &e))
+        } else {
+            std::fs::create_dir(pt).map_err(|e| Response::from_io_error("Dir create err", &e))
+        }
+    }
+
+    fn remove_path(path: &str, flags: PrefixFlags, dir_only: bool) -> Vec<RemovalRecord> {
+        let pt = Path::new(path);
+        let mut report = Vec::new();
+        let kind = match std::fs::symlink_metadata(pt) {
+            Ok(ref meta) if meta.is_dir() && splitfile::split_len(pt).is_some() => {
+                report.push(if dir_only {
+                    RemovalRecord::failed(path.to_owned(), EntryKind::File, ResponseCode::InvalidInput, "Not a directory.".to_owned())
+                } else {
+                    remove_split(pt)
+                });
+                return report;
+            }
+            Ok(meta) => entry_kind(&meta),
+            Err(e) => {
+                report.push(RemovalRecord::failed(
+                    path.to_owned(),
+                    EntryKind::Other,
+                    ResponseCode::from_io_error(&e),
+                    format!("Entry metadata error: {}", e),
+                ));
+                return report;
+            }
+        };
+        if dir_only && kind != EntryKind::Dir {
+            report.push(RemovalRecord::failed(
+                path.to_owned(),
+                kind,
+                ResponseCode::InvalidInput,
+                "Not a directory.".to_owned(),
+            ));
+        } else if kind == EntryKind::Dir && !dir_only && !flags.contains(PrefixFlags::RECURSIVE) {
+            report.push(RemovalRecord::failed(
+                path.to_owned(),
+                kind,
+                ResponseCode::InvalidInput,
+                "Is a directory; removing it needs the recursive flag.".to_owned(),
+            ));
+        } else if kind == EntryKind::Dir && !dir_only {
+            remove_tree(pt, &mut report);
+        } else {
+            report.push(remove_entry(pt, kind));
+        }
+        report
+    }
+
+    fn move_path(from: &str, to: &str, flags: PrefixFlags) -> Result<(), Response> {
+        let from_pt = Path::new(from);
+        let to_pt = Path::new(to);
+        std::fs::symlink_metadata(from_pt).map_err(|e| Response::from_io_error("Move source err", &e))?;
+        if std::fs::symlink_metadata(to_pt).is_ok() && !flags.contains(PrefixFlags::OVERWRITE) {
+            return Err(Response::error(
+                ResponseCode::Exists,
+                format!("{} already exists.", to),
+            ));
+        }
+        match rename(from_pt, to_pt) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                // Another mount point, so the data has to be copied over. The
+                // copy is made beside the destination and only renamed into
+                // place once all of it is there.
+                let temp = temp_name(to);
+                let temp_pt = Path::new(&temp);
+                let _ = remove_copy(temp_pt);
+                if let Err(e) = copy_tree(from_pt, temp_pt).and_then(|()| std::fs::rename(temp_pt, to_pt)) {
+                    let _ = remove_copy(temp_pt);
+                    return Err(Response::from_io_error("Move copy err", &e));
+                }
+                remove_copy(from_pt).map_err(|e| Response::from_io_error("Move source remove err", &e))
+            }
+            Err(e) => Err(Response::from_io_error("Move err", &e)),
+        }
+    }
+}
+
+/// Renames a path, which fails with `EXDEV` if it would cross mount points.
+#[cfg(not(test))]
+fn rename(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::rename(from, to)
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Whether tests make every move look like it crosses mount points, so
+    /// that it has to copy.
+    static TEST_CROSS_MOUNT: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+#[cfg(test)]
+fn rename(from: &Path, to: &Path) -> std::io::Result<()> {
+    if TEST_CROSS_MOUNT.with(|cross| cross.get()) {
+        return Err(std::io::Error::from_raw_os_error(libc::EXDEV));
+    }
+    std::fs::rename(from, to)
+}
+
+/// Copies a file, a link, or a directory and everything in it. Links are
+/// copied as links, and a split file stays split.
+fn copy_tree(from: &Path, to: &Path) -> std::io::Result<()> {
+    let file_type = std::fs::symlink_metadata(from)?.file_type();
+    if file_type.is_symlink() {
+        return std::os::unix::fs::symlink(std::fs::read_link(from)?, to);
+    }
+    if !file_type.is_dir() {
+        return std::fs::copy(from, to).map(|_| ());
+    }
+    std::fs::create_dir(to)?;
+    for ent in from.read_dir()? {
+        let ent = ent?;
+        copy_tree(&ent.path(), &to.join(ent.file_name()))?;
+    }
+    if splitfile::split_len(from).is_some() {
+        splitfile::mark_split(to)?;
+    }
+    Ok(())
+}
+
+/// Deletes a file, a link, or a directory and everything in it, stopping at
+/// the first error.
+fn remove_copy(pt: &Path) -> std::io::Result<()> {
+    if std::fs::symlink_metadata(pt)?.is_dir() {
+        std::fs::remove_dir_all(pt)
+    } else {
+        std::fs::remove_file(pt)
+    }
+}
+
+/// Deletes a single file, link or empty directory.
+fn remove_entry(pt: &Path, kind: EntryKind) -> RemovalRecord {
+    let path = pt.to_string_lossy().into_owned();
+    let result = if kind == EntryKind::Dir {
+        std::fs::remove_dir(pt)
+    } else {
+        std::fs::remove_file(pt)
+    };
+    match result {
+        Ok(()) => RemovalRecord::removed(path, kind),
+        Err(e) => RemovalRecord::failed(path, kind, ResponseCode::from_io_error(&e), format!("Remove err: {}", e)),
+    }
+}
+
+/// Deletes a split file, which is a single file as far as the report goes.
+fn remove_split(pt: &Path) -> RemovalRecord {
+    let path = pt.to_string_lossy().into_owned();
+    match std::fs::remove_dir_all(pt) {
+        Ok(()) => RemovalRecord::removed(path, EntryKind::File),
+        Err(e) => RemovalRecord::failed(path, EntryKind::File, ResponseCode::from_io_error(&e), format!("Remove err: {}", e)),
+    }
+}
+
+/// Deletes everything inside a directory and then the directory itself,
+/// carrying on past entries that fail so the report covers the whole tree.
+fn remove_tree(dir: &Path, report: &mut Vec<RemovalRecord>) {
+    let ents = match dir.read_dir() {
+        Ok(ents) => ents,
+        Err(e) => {
+            report.push(RemovalRecord::failed(
+                dir.to_string_lossy().into_owned(),
+                EntryKind::Dir,
+                ResponseCode::from_io_error(&e),
+                format!("Read dir error: {}", e),
+            ));
+            return;
+        }
+    };
+    for ent in ents {
+        let pt = match ent {
+            Ok(ent) => ent.path(),
+            Err(e) => {
+                report.push(RemovalRecord::failed(
+                    dir.to_string_lossy().into_owned(),
+                    EntryKind::Dir,
+                    ResponseCode::from_io_error(&e),
+                    format!("Read entry error: {}", e),
+                ));
+                continue;
+            }
+        };
+        match std::fs::symlink_metadata(&pt).map(|meta| entry_kind(&meta)) {
+            Ok(EntryKind::Dir) if splitfile::split_len(&pt).is_some() => report.push(remove_split(&pt)),
+            Ok(EntryKind::Dir) => remove_tree(&pt, report),
+            Ok(kind) => report.push(remove_entry(&pt, kind)),
+            Err(e) => report.push(RemovalRecord::failed(
+                pt.to_string_lossy().into_owned(),
+                EntryKind::Other,
+                ResponseCode::from_io_error(&e),
+                format!("Entry metadata error: {}", e),
+            )),
+        }
+    }
+    report.push(remove_entry(dir, EntryKind::Dir));
 }
 
 pub struct StdFileReader {
-    path: String,
-    file: Option<File>,
-    idx: usize,
-    file_len : usize, 
-    finished: bool,
+    file: Option<Storage>,
+    /// The names of a directory's entries, each ended by a 0 byte, when
+    /// reading a directory.
+    listing: Vec<u8>,
+    listing_idx: usize,
+    file_len: u64,
+}
+
+/// Reads the directory, pairing each entry with its name after the prefix.
+fn named_entries(dir: &Path, name_prefix: &str) -> Result<Vec<(std::fs::DirEntry, String)>, Response> {
+    let ents = dir
+        .read_dir()
+        .map_err(|e| Response::from_io_error("Read dir error", &e))?;
+    let mut named = Vec::new();
+    for ent in ents {
+        let ent = ent.map_err(|e| Response::from_io_error("Read entry error", &e))?;
+        let raw_name = ent
+            .file_name()
+            .into_string()
+            .map_err(|_| Response::error(ResponseCode::InvalidInput, "Could not convert OsString.".to_owned()))?;
+        named.push((ent, format!("{}{}", name_prefix, raw_name)));
+    }
+    Ok(named)
+}
+
+/// Appends the names of everything in the directory to the listing, going
+/// into subdirectories if asked to.
+fn list_dir(dir: &Path, name_prefix: &str, flags: PrefixFlags, listing: &mut Vec<u8>) -> Result<(), Response> {
+    for (ent, name) in named_entries(dir, name_prefix)? {
+        listing.extend_from_slice(name.as_bytes());
+        listing.push(0);
+        if !flags.contains(PrefixFlags::RECURSIVE) {
+            continue;
+        }
+        let file_type = ent.file_type().map_err(|e| Response::from_io_error("Read entry error", &e))?;
+        let is_dir = if file_type.is_symlink() && flags.contains(PrefixFlags::FOLLOW_LINKS) {
+            ent.path().is_dir()
+        } else {
+            file_type.is_dir()
+        };
+        if is_dir {
+            list_dir(&ent.path(), &format!("{}/", name), flags, listing)?;
+        }
+    }
+    Ok(())
+}
+
+/// Describes everything in the directory, named relative to the directory
+/// being listed, going into subdirectories if asked to.
+fn tree_entries(dir: &Path, name_prefix: &str, flags: PrefixFlags, entries: &mut Vec<ListEntry>) -> Result<(), Response> {
+    for (ent, name) in named_entries(dir, name_prefix)? {
+        let entry = path_entry(&ent.path(), name, flags)?;
+        let descend = flags.contains(PrefixFlags::RECURSIVE) && entry.kind == EntryKind::Dir;
+        let name = entry.name.clone();
+        entries.push(entry);
+        if descend {
+            tree_entries(&ent.path(), &format!("{}/", name), flags, entries)?;
+        }
+    }
+    Ok(())
+}
+
+fn entry_kind(meta: &std::fs::Metadata) -> EntryKind {
+    if meta.is_file() {
+        EntryKind::File
+    } else if meta.is_dir() {
+        EntryKind::Dir
+    } else {
+        EntryKind::Other
+    }
+}
+
+/// Describes whatever is at the path, under the given name.
+fn path_entry(pt: &Path, name: String, flags: PrefixFlags) -> Result<ListEntry, Response> {
+    let meta = if flags.contains(PrefixFlags::FOLLOW_LINKS) {
+        std::fs::metadata(pt)
+    } else {
+        std::fs::symlink_metadata(pt)
+    };
+    let meta = meta.map_err(|e| Response::from_io_error("Entry metadata error", &e))?;
+    let split_len = if meta.is_dir() { splitfile::split_len(pt) } else { None };
+    let kind = if split_len.is_some() { EntryKind::File } else { entry_kind(&meta) };
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    Ok(ListEntry {
+        name,
+        kind,
+        size: match (kind, split_len) {
+            (_, Some(len)) => len,
+            (EntryKind::File, None) => meta.len(),
+            _ => 0,
+        },
+        modified,
+    })
+}
+
+/// Names the filesystem a path is on from the magic number `statfs` gives.
+#[cfg(target_os = "linux")]
+fn fs_kind(c_path: &CStr) -> FsKind {
+    let mut stats: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stats) } != 0 {
+        return FsKind::Unknown;
+    }
+    match stats.f_type as i64 {
+        0x4d44 => FsKind::Fat32,
+        0x2011_BAB0 => FsKind::ExFat,
+        _ => FsKind::Unknown,
+    }
+}
+
+/// `statvfs` does not say what the filesystem is, and nothing else here
+/// does either.
+#[cfg(not(target_os = "linux"))]
+fn fs_kind(_c_path: &CStr) -> FsKind {
+    FsKind::Unknown
 }
 
 const LEN_BUFFER_SIZE : usize = 4 * 1024 * 1024;
 impl FileReader for StdFileReader {
-    fn new(file_name: &str) -> Result<Self, String> {
+    fn new(file_name: &str, flags: PrefixFlags) -> Result<Self, Response> {
         let pt = Path::new(file_name);
         dprintln!("Creating StdFileReader for file {}.", file_name);
-        let (file, ln): (Option<File>, usize) = if !file_name.ends_with('/') {
+        check_link(pt, flags)?;
+        if !file_name.ends_with('/') {
             dprintln!("It's a file; now opening.");
-            let mut fl = File::open(pt).map_err(|e| format!("File open error: {:?}", e).to_owned())?;
+            let mut fl = Storage::open_read(pt).map_err(|e| Response::from_io_error("File open error", &e))?;
 
-            let mut ln : usize = 0; 
+            let mut ln : u64 = 0; 
             let mut garbage : Vec<u8> = Vec::with_capacity(LEN_BUFFER_SIZE);
             garbage.resize(LEN_BUFFER_SIZE, 0);
             let mut rd;
             loop {
-                rd = fl.read(&mut garbage).map_err(|e| format!("Fl.read error when calcing size: {:?}",e))?;
-                ln += rd;
+                rd = fl.read(&mut garbage).map_err(|e| Response::from_io_error("Fl.read error when calcing size", &e))?;
+                ln += rd as u64;
                 if rd == 0 {
                     break;
                 }
             }
-            fl.seek(std::io::SeekFrom::Start(0)).map_err(|e| format!("Seek err: {:?}", e))?;
-            (Some(fl), ln)
+            fl.seek(std::io::SeekFrom::Start(0)).map_err(|e| Response::from_io_error("Seek err", &e))?;
+            Ok(StdFileReader {
+                file: Some(fl),
+                listing: Vec::new(),
+                listing_idx: 0,
+                file_len: ln,
+            })
         } else {
-            dprintln!("Not a file.");
-            (None, 0)
-        };
-
-        Ok(StdFileReader {
-            path: file_name.to_owned(),
-            file,
-            idx: 0,
-            file_len : ln,  
-            finished: !pt.exists(),
-        })
+            dprintln!("Not a file; listing it instead.");
+            let mut listing = Vec::new();
+            list_dir(pt, "", flags, &mut listing)?;
+            Ok(StdFileReader {
+                file: None,
+                file_len: listing.len() as u64,
+                listing,
+                listing_idx: 0,
+            })
+        }
     }
 
-    fn len(&self) -> usize {
+    fn len(&self) -> u64 {
         self.file_len
     }
 
-    fn read_bytes(&mut self, buffer: &mut [u8]) -> Result<usize, String> {
-        let buflen = buffer.len();
-        if self.finished {
-            Ok(0)
-        } else if let Some(fl) = &mut self.file {
-            let rd = fl
-                .read(buffer)
-                .map_err(|e| format!("File read error: {:?}", e).to_owned())?;
-            self.idx += rd;
-            if rd < buflen {
-                self.finished = true;
-            }
-            Ok(rd)
+    fn read_bytes(&mut self, buffer: &mut [u8]) -> Result<usize, Response> {
+        if let Some(fl) = &mut self.file {
+            fl.read(buffer)
+                .map_err(|e| Response::from_io_error("File read error", &e))
         } else {
-            let dirpath = Path::new(&self.path);
-            let ents = dirpath
-                .read_dir()
-                .map_err(|e| format!("Read dir error: {:?}", e).to_owned())?;
-            let mut cur_offset = 0usize;
-            for ent in ents.into_iter() {
-                let ent = ent.map_err(|e| format!("Read entry error: {:?}", e).to_owned())?;
-                let raw_name = ent
-                    .file_name()
-                    .into_string()
-                    .map_err(|_| format!("Could not convert OsString.").to_owned())?;
-                let name_str = format!("{}\0", raw_name);
-                let name_bytes = name_str.as_bytes();
-
-                let bytes_to_skip = self.idx - cur_offset;
-                if bytes_to_skip > name_bytes.len() {
-                    cur_offset += name_bytes.len();
-                    continue;
-                }
-                let bytes_need_write = &name_bytes[bytes_to_skip..];
-                if buflen - cur_offset < bytes_need_write.len() {
-                    buffer[cur_offset..]
-                        .copy_from_slice(&bytes_need_write[0..(buflen - cur_offset)]);
-                    self.idx += buflen;
-                    return Ok(buflen);
-                }
-                buffer[cur_offset..bytes_need_write.len()].copy_from_slice(bytes_need_write);
-                cur_offset += bytes_need_write.len();
-            }
-            self.idx += cur_offset;
-            self.finished = true;
-            Ok(cur_offset)
+            let count = buffer.len().min(self.listing.len() - self.listing_idx);
+            buffer[0..count].copy_from_slice(&self.listing[self.listing_idx..self.listing_idx + count]);
+            self.listing_idx += count;
+            Ok(count)
+        }
+    }
+
+    fn seek(&mut self, offset: u64) -> Result<(), Response> {
+        if let Some(fl) = &mut self.file {
+            fl.seek(std::io::SeekFrom::Start(offset))
+                .map(|_| ())
+                .map_err(|e| Response::from_io_error("Seek err", &e))
+        } else {
+            self.listing_idx = offset as usize;
+            Ok(())
+        }
+    }
+
+    fn list_dir(dir_name: &str, flags: PrefixFlags) -> Result<Vec<ListEntry>, Response> {
+        let pt = Path::new(dir_name);
+        check_link(pt, flags)?;
+        let mut entries = Vec::new();
+        tree_entries(pt, "", flags, &mut entries)?;
+        Ok(entries)
+    }
+
+    fn stat(file_name: &str, flags: PrefixFlags) -> Result<ListEntry, Response> {
+        let pt = Path::new(file_name);
+        let name = pt
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(file_name)
+            .to_owned();
+        path_entry(pt, name, flags)
+    }
+
+    fn fs_info(path: &str) -> Result<FsInfo, Response> {
+        let c_path = CString::new(path)
+            .map_err(|_| Response::error(ResponseCode::InvalidInput, format!("Path {} has a NUL byte in it.", path)))?;
+        let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) } != 0 {
+            return Err(Response::from_io_error("Filesystem info error", &std::io::Error::last_os_error()));
+        }
+        let block = if stats.f_frsize != 0 { stats.f_frsize as u64 } else { stats.f_bsize as u64 };
+        Ok(FsInfo {
+            total_bytes: stats.f_blocks as u64 * block,
+            free_bytes: stats.f_bavail as u64 * block,
+            cluster_size: block as u32,
+            kind: fs_kind(&c_path),
+        })
+    }
+}
+
+#[test]
+fn test_std_writes_split_on_fat32() {
+    let dir = splitfile::test_dir("fileio-split");
+    let path = dir.join("big.bin");
+    let name = path.to_str().unwrap();
+    let content: Vec<u8> = (0..40u8).collect();
+    let write_all = |fl: &mut StdFileWriter, bytes: &[u8]| {
+        let mut done = 0;
+        while done < bytes.len() {
+            done += fl.write_bytes(&bytes[done..]).unwrap();
+        }
+    };
+
+    // Only the mount kind is faked; the parts are really written.
+    TEST_MOUNT_KIND.with(|kind| kind.set(FsKind::Fat32));
+    let mut fl = StdFileWriter::new(name, PrefixFlags::empty()).unwrap();
+    write_all(&mut fl, &content[0..10]);
+    write_all(&mut fl, &content[10..40]);
+    fl.commit().unwrap();
+    assert!(path.is_dir());
+    assert_eq!(splitfile::split_len(&path), Some(40));
+    assert_eq!(dir.read_dir().unwrap().count(), 1);
+    let mut reader = StdFileReader::new(name, PrefixFlags::empty()).unwrap();
+    let mut read_back = Vec::new();
+    let mut buffer = [0u8; 64];
+    loop {
+        match reader.read_bytes(&mut buffer).unwrap() {
+            0 => break,
+            n => read_back.extend_from_slice(&buffer[0..n]),
         }
     }
+    assert_eq!(reader.len(), 40);
+    assert_eq!(read_back, content);
+    assert_eq!(StdFileReader::stat(name, PrefixFlags::empty()).unwrap().kind, EntryKind::File);
+
+    // Appending carries on in the last part.
+    let mut fl = StdFileWriter::new(name, PrefixFlags::APPEND).unwrap();
+    write_all(&mut fl, &content[0..10]);
+    fl.commit().unwrap();
+    assert_eq!(splitfile::split_len(&path), Some(50));
+
+    // Replacing it with a small file swaps the directory for a plain file.
+    let mut fl = StdFileWriter::new(name, PrefixFlags::OVERWRITE).unwrap();
+    write_all(&mut fl, &content[0..5]);
+    fl.commit().unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), &content[0..5]);
+
+    // Other file systems hold the file in one piece.
+    TEST_MOUNT_KIND.with(|kind| kind.set(FsKind::ExFat));
+    let mut fl = StdFileWriter::new(name, PrefixFlags::OVERWRITE).unwrap();
+    write_all(&mut fl, &content);
+    fl.commit().unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), content);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_std_move_across_mounts() {
+    let dir = splitfile::test_dir("fileio-move");
+    let from = dir.join("from");
+    std::fs::create_dir(&from).unwrap();
+    std::fs::write(from.join("a.txt"), b"Hello").unwrap();
+    std::os::unix::fs::symlink("a.txt", from.join("link")).unwrap();
+    let split = from.join("split.bin");
+    std::fs::write(&split, [7u8; 10]).unwrap();
+    splitfile::SplitFile::convert(&split).unwrap();
+    let to = dir.join("to");
+    let name = |pt: &Path| pt.to_str().unwrap().to_owned();
+
+    // A move that cannot rename copies everything over, links as links and
+    // split files still split, and then removes the source.
+    TEST_CROSS_MOUNT.with(|cross| cross.set(true));
+    StdFileWriter::move_path(&name(&from), &name(&to), PrefixFlags::empty()).unwrap();
+    assert!(std::fs::symlink_metadata(&from).is_err());
+    assert_eq!(std::fs::read(to.join("a.txt")).unwrap(), b"Hello".to_vec());
+    assert_eq!(std::fs::read_link(to.join("link")).unwrap(), Path::new("a.txt"));
+    assert_eq!(splitfile::split_len(&to.join("split.bin")), Some(10));
+    assert_eq!(dir.read_dir().unwrap().count(), 1);
+
+    // A copy that fails partway leaves neither a half-copied destination nor
+    // a changed source behind.
+    let _socket = std::os::unix::net::UnixListener::bind(to.join("socket")).unwrap();
+    let back = dir.join("back");
+    assert!(StdFileWriter::move_path(&name(&to), &name(&back), PrefixFlags::empty()).is_err());
+    assert!(std::fs::symlink_metadata(&back).is_err());
+    assert_eq!(dir.read_dir().unwrap().count(), 1);
+    assert_eq!(std::fs::read(to.join("a.txt")).unwrap(), b"Hello".to_vec());
+    TEST_CROSS_MOUNT.with(|cross| cross.set(false));
+    std::fs::remove_dir_all(&dir).unwrap();
 }
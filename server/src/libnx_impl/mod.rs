@@ -3,3 +3,5 @@ pub use self::usb_comms::*;
 
 mod fileio;
 pub use self::fileio::*;
+
+mod splitfile;
@@ -0,0 +1,340 @@
+use nxusb::fsinfo::split_part_name;
+#[cfg(not(test))]
+use nxusb::fsinfo::SPLIT_PART_SIZE;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// The length of every part of a split file but the last.
+#[cfg(not(test))]
+pub const PART_SIZE: u64 = SPLIT_PART_SIZE;
+
+/// Tests use tiny parts so that a few bytes cross them.
+#[cfg(test)]
+pub const PART_SIZE: u64 = 16;
+
+/// A file kept as a concatenation directory, for file systems like FAT32
+/// that cannot hold it in one piece: parts named `00`, `01` and so on, each
+/// `PART_SIZE` bytes long except the last.
+///
+/// Horizon marks these directories with the archive bit and then shows them
+/// as plain files, so this is only needed to write one, or to read one back
+/// anywhere else.
+pub struct SplitFile {
+    dir: PathBuf,
+    len: u64,
+    pos: u64,
+    writable: bool,
+    /// The part under the cursor and its index, opened when first needed.
+    part: Option<(usize, File)>,
+}
+
+/// The total length of the split file in a directory, or `None` if the
+/// directory holds anything but a run of parts from `00`, all full but the
+/// last.
+pub fn split_len(dir: &Path) -> Option<u64> {
+    let mut parts = Vec::new();
+    for ent in dir.read_dir().ok()? {
+        let ent = ent.ok()?;
+        let meta = ent.metadata().ok()?;
+        if !meta.is_file() {
+            return None;
+        }
+        parts.push((ent.file_name().into_string().ok()?, meta.len()));
+    }
+    if parts.is_empty() {
+        return None;
+    }
+    parts.sort();
+    let last = parts.len() - 1;
+    for (idx, &(ref name, len)) in parts.iter().enumerate() {
+        if *name != split_part_name(idx) || (idx < last && len != PART_SIZE) {
+            return None;
+        }
+    }
+    Some(parts.iter().map(|&(_, len)| len).sum())
+}
+
+/// Marks a finished concatenation directory so that Horizon treats it as one
+/// file.
+#[cfg(target_os = "horizon")]
+pub fn mark_split(dir: &Path) -> io::Result<()> {
+    use libc;
+    use std::ffi::CString;
+    extern "C" {
+        fn fsdevSetArchiveBit(path: *const libc::c_char) -> u32;
+    }
+    let c_path = CString::new(dir.to_string_lossy().into_owned())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Path has a NUL byte in it."))?;
+    let rc = unsafe { fsdevSetArchiveBit(c_path.as_ptr()) };
+    if rc != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Could not set the archive bit on {}: {:#x}", dir.display(), rc),
+        ));
+    }
+    Ok(())
+}
+
+/// Nothing else has an archive bit, so the parts alone mark a split file.
+#[cfg(not(target_os = "horizon"))]
+pub fn mark_split(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+impl SplitFile {
+    /// Opens the split file in a directory.
+    pub fn open(dir: &Path, writable: bool) -> io::Result<SplitFile> {
+        let len = split_len(dir).ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a split file.", dir.display()),
+        ))?;
+        Ok(SplitFile {
+            dir: dir.to_owned(),
+            len,
+            pos: 0,
+            writable,
+            part: None,
+        })
+    }
+
+    /// Turns a plain file into the first part of a split file at the same
+    /// path, with the cursor at its end.
+    pub fn convert(path: &Path) -> io::Result<SplitFile> {
+        let base = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let staging = path.with_file_name(format!(".{}.{}", base, split_part_name(0)));
+        std::fs::rename(path, &staging)?;
+        std::fs::create_dir(path)?;
+        std::fs::rename(&staging, path.join(split_part_name(0)))?;
+        let mut split = SplitFile::open(path, true)?;
+        split.pos = split.len;
+        Ok(split)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn part_at(&mut self, idx: usize) -> io::Result<&mut File> {
+        if self.part.as_ref().map(|&(open_idx, _)| open_idx) != Some(idx) {
+            if let Some((_, old)) = self.part.take() {
+                if self.writable {
+                    old.sync_all()?;
+                }
+            }
+            let part_path = self.dir.join(split_part_name(idx));
+            let fl = if self.writable {
+                OpenOptions::new().read(true).write(true).create(true).truncate(false).open(part_path)?
+            } else {
+                File::open(part_path)?
+            };
+            self.part = Some((idx, fl));
+        }
+        Ok(&mut self.part.as_mut().unwrap().1)
+    }
+
+    /// Cuts or extends the file to the given length, dropping any parts past
+    /// its new end.
+    pub fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let parts_for = |len: u64| len.div_ceil(PART_SIZE).max(1) as usize;
+        let (old_parts, new_parts) = (parts_for(self.len), parts_for(len));
+        self.part = None;
+        for idx in new_parts..old_parts {
+            std::fs::remove_file(self.dir.join(split_part_name(idx)))?;
+        }
+        for idx in 0..new_parts {
+            let part_len = (len - idx as u64 * PART_SIZE).min(PART_SIZE);
+            self.part_at(idx)?.set_len(part_len)?;
+        }
+        self.len = len;
+        Ok(())
+    }
+
+    pub fn sync_all(&self) -> io::Result<()> {
+        match self.part {
+            Some((_, ref fl)) => fl.sync_all(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Read for SplitFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len {
+            return Ok(0);
+        }
+        let idx = (self.pos / PART_SIZE) as usize;
+        let within = self.pos % PART_SIZE;
+        let count = (buf.len() as u64).min(PART_SIZE - within).min(self.len - self.pos) as usize;
+        let fl = self.part_at(idx)?;
+        fl.seek(SeekFrom::Start(within))?;
+        let rd = fl.read(&mut buf[0..count])?;
+        self.pos += rd as u64;
+        Ok(rd)
+    }
+}
+
+impl Write for SplitFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let idx = (self.pos / PART_SIZE) as usize;
+        let within = self.pos % PART_SIZE;
+        let count = (buf.len() as u64).min(PART_SIZE - within) as usize;
+        let fl = self.part_at(idx)?;
+        fl.seek(SeekFrom::Start(within))?;
+        let wr = fl.write(&buf[0..count])?;
+        self.pos += wr as u64;
+        self.len = self.len.max(self.pos);
+        Ok(wr)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.part {
+            Some((_, ref mut fl)) => fl.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Seek for SplitFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => offset_by(self.pos, delta),
+            SeekFrom::End(delta) => offset_by(self.len, delta),
+        };
+        match new_pos {
+            Some(new_pos) => {
+                self.pos = new_pos;
+                Ok(new_pos)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek before the start of a split file.")),
+        }
+    }
+}
+
+fn offset_by(base: u64, delta: i64) -> Option<u64> {
+    if delta < 0 {
+        base.checked_sub(delta.wrapping_neg() as u64)
+    } else {
+        base.checked_add(delta as u64)
+    }
+}
+
+/// Makes an empty directory for a test to work in.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("nxusb-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_split_convert() {
+    let dir = test_dir("split-convert");
+    let path = dir.join("big.bin");
+    let content: Vec<u8> = (0..40u8).collect();
+    std::fs::write(&path, &content[0..10]).unwrap();
+    let mut split = SplitFile::convert(&path).unwrap();
+    assert_eq!(split.len(), 10);
+    split.write_all(&content[10..]).unwrap();
+    split.sync_all().unwrap();
+    drop(split);
+
+    // The file is now parts in a directory of the same name, and nothing is
+    // left beside it.
+    assert!(path.is_dir());
+    assert_eq!(std::fs::read(path.join("00")).unwrap(), &content[0..16]);
+    assert_eq!(std::fs::read(path.join("01")).unwrap(), &content[16..32]);
+    assert_eq!(std::fs::read(path.join("02")).unwrap(), &content[32..40]);
+    assert_eq!(dir.read_dir().unwrap().count(), 1);
+    assert_eq!(split_len(&path), Some(40));
+    let mut read_back = Vec::new();
+    SplitFile::open(&path, false).unwrap().read_to_end(&mut read_back).unwrap();
+    assert_eq!(read_back, content);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_split_seek_and_set_len() {
+    let dir = test_dir("split-seek");
+    let path = dir.join("big.bin");
+    let content: Vec<u8> = (0..40u8).collect();
+    std::fs::create_dir(&path).unwrap();
+    std::fs::write(path.join("00"), &content[0..16]).unwrap();
+    std::fs::write(path.join("01"), &content[16..32]).unwrap();
+    std::fs::write(path.join("02"), &content[32..40]).unwrap();
+    let mut split = SplitFile::open(&path, true).unwrap();
+
+    // Reads stop at the end of a part, and pick up in the next one.
+    let mut buffer = [0u8; 8];
+    assert_eq!(split.seek(SeekFrom::Start(12)).unwrap(), 12);
+    assert_eq!(split.read(&mut buffer).unwrap(), 4);
+    assert_eq!(split.read(&mut buffer).unwrap(), 8);
+    assert_eq!(buffer, content[16..24]);
+    assert_eq!(split.seek(SeekFrom::End(-2)).unwrap(), 38);
+    assert_eq!(split.read(&mut buffer).unwrap(), 2);
+    assert_eq!(split.read(&mut buffer).unwrap(), 0);
+    assert_eq!(split.seek(SeekFrom::Current(-30)).unwrap(), 10);
+    assert!(split.seek(SeekFrom::Current(-11)).is_err());
+
+    // A write across a boundary changes both parts.
+    split.seek(SeekFrom::Start(14)).unwrap();
+    split.write_all(&[0xFF; 4]).unwrap();
+    assert_eq!(std::fs::read(path.join("00")).unwrap()[14..], [0xFF; 2]);
+    assert_eq!(std::fs::read(path.join("01")).unwrap()[0..2], [0xFF; 2]);
+
+    // Cutting the file drops the parts past its end, and growing it fills
+    // whole parts with zeros.
+    split.set_len(20).unwrap();
+    assert!(!path.join("02").exists());
+    assert_eq!(split_len(&path), Some(20));
+    split.set_len(36).unwrap();
+    assert_eq!(std::fs::read(path.join("01")).unwrap().len(), 16);
+    assert_eq!(std::fs::read(path.join("02")).unwrap(), vec![0; 4]);
+    assert_eq!(split.len(), 36);
+    split.set_len(5).unwrap();
+    assert_eq!(split_len(&path), Some(5));
+    assert_eq!(path.read_dir().unwrap().count(), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_split_len() {
+    let dir = test_dir("split-len");
+    let split = dir.join("split");
+    std::fs::create_dir(&split).unwrap();
+    assert_eq!(split_len(&split), None);
+    std::fs::write(split.join("00"), [0u8; 16]).unwrap();
+    std::fs::write(split.join("01"), [0u8; 3]).unwrap();
+    assert_eq!(split_len(&split), Some(19));
+    assert!(SplitFile::open(&split, false).is_ok());
+
+    // Only a run of parts from `00`, all full but the last, is a split file.
+    std::fs::write(split.join("03"), [0u8; 3]).unwrap();
+    assert_eq!(split_len(&split), None);
+    std::fs::remove_file(split.join("03")).unwrap();
+    std::fs::write(split.join("00"), [0u8; 15]).unwrap();
+    assert_eq!(split_len(&split), None);
+    std::fs::write(split.join("00"), [0u8; 16]).unwrap();
+    std::fs::write(split.join("notes.txt"), b"not a part").unwrap();
+    assert_eq!(split_len(&split), None);
+    std::fs::remove_file(split.join("notes.txt")).unwrap();
+    std::fs::create_dir(split.join("02")).unwrap();
+    assert_eq!(split_len(&split), None);
+    assert!(SplitFile::open(&split, false).is_err());
+    std::fs::remove_dir(split.join("02")).unwrap();
+    assert_eq!(split_len(&split), Some(19));
+    assert_eq!(split_len(&split.join("00")), None);
+    assert_eq!(split_len(&dir.join("missing")), None);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_mark_split() {
+    let dir = test_dir("split-mark");
+    std::fs::write(dir.join("00"), b"abc").unwrap();
+    mark_split(&dir).unwrap();
+    assert_eq!(split_len(&dir), Some(3));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,68 +1,103 @@
 use interface::ServerDevice;
 use libnx_rs::usbcomms::UsbCommsInterface;
-use nxusb::prefixes::{CommandPrefix, Prefixes, PREFIX_LENGTH};
+use nxusb::frame::{Frame, FrameDecoder, FrameEncoder};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
 
 const TEST_BLOCK_SIZE: usize = 1024;
-impl ServerDevice for UsbCommsInterface {
-    fn read_prefix(&mut self) -> Result<Prefixes, String> {
-        let mut prefix_buff: [u8; PREFIX_LENGTH] = [0; PREFIX_LENGTH];
-        let mut read_idx = 0;
-        let mut read_count = 0;
-        while read_idx < PREFIX_LENGTH && read_count < 4{
-            let read_bytes = self.read_bytes(&mut prefix_buff[read_idx..]);
-            println!("ReadPrefix attempt {}: got {} bytes.", read_count, read_bytes);
-            read_idx += read_bytes;
-            read_count += 1;
-        }
-        if read_idx == PREFIX_LENGTH {
-            Prefixes::parse_prefix(prefix_buff)
-                .ok_or(format!("Could not parse bytes {:?}", prefix_buff).to_owned())
+
+/// A `UsbCommsInterface` that frames everything it sends and receives.
+///
+/// Reads only ever wait for the client, so a thread reads and decodes frames
+/// as they arrive and hands them over, which lets `poll_frame` check for one
+/// without waiting. libnx locks reads and writes separately, so the thread can
+/// read while frames are written here.
+pub struct UsbCommsDevice {
+    interface: &'static mut UsbCommsInterface,
+    encoder: FrameEncoder,
+    frames: Receiver<Result<Frame, String>>,
+}
+
+/// The interface the reading thread uses, which is only ever read from there.
+struct ReadHandle(*mut UsbCommsInterface);
+
+unsafe impl Send for ReadHandle {}
+
+/// Reads frames from the interface and sends them on, until either the
+/// interface fails or nothing is listening any more.
+fn read_frames(handle: ReadHandle, frames: Sender<Result<Frame, String>>) {
+    let mut decoder = FrameDecoder::new();
+    let mut buffer = [0u8; TEST_BLOCK_SIZE];
+    loop {
+        let frame = match decoder.next_frame() {
+            Ok(Some(frame)) => Ok(frame),
+            Ok(None) => {
+                let bt_read = unsafe { (*handle.0).read_bytes(&mut buffer) };
+                if bt_read == 0 {
+                    Err("Read 0 bytes. Is this interface initialized?".to_owned())
+                } else {
+                    decoder.push_bytes(&buffer[0..bt_read]);
+                    continue;
+                }
+            }
+            Err(e) => Err(e),
+        };
+        let failed = frame.is_err();
+        if frames.send(frame).is_err() || failed {
+            return;
         }
-        else {
-            Err(format!("Only retrieved {} bytes out of {}. Buffer {:?}", read_idx, PREFIX_LENGTH, prefix_buff))
+    }
+}
+
+impl UsbCommsDevice {
+    /// Starts reading frames from the interface in the background. The
+    /// interface has to live as long as the program, because the reading
+    /// thread is never stopped.
+    pub fn new(interface: &'static mut UsbCommsInterface) -> UsbCommsDevice {
+        let (sender, frames) = mpsc::channel();
+        let handle = ReadHandle(interface as *mut UsbCommsInterface);
+        thread::spawn(move || read_frames(handle, sender));
+        UsbCommsDevice {
+            interface,
+            encoder: FrameEncoder::new(),
+            frames,
         }
     }
+}
 
-    fn block_size(&self) -> usize {
-        TEST_BLOCK_SIZE
+impl ServerDevice for UsbCommsDevice {
+    fn read_frame(&mut self) -> Result<Frame, String> {
+        self.frames
+            .recv()
+            .map_err(|_| "The USB reading thread stopped.".to_owned())?
     }
-    fn read_block(&mut self, buffer: &mut [u8]) -> Result<usize, String> {
-        if buffer.len() != TEST_BLOCK_SIZE {
-            Err(format!(
-                "Bad read block size: expected {} but got block of size {}",
-                TEST_BLOCK_SIZE,
-                buffer.len()
-            ).to_owned())
-        } else {
-            let bt_read = self.read_bytes(buffer);
-            match bt_read {
-                TEST_BLOCK_SIZE => Ok(TEST_BLOCK_SIZE),
-                0 => Err("Read 0 bytes. Is this interface initialized?".to_owned()),
-                n => Err(format!(
-                    "Bad read block result: expected {} but read {} bytes instead.",
-                    TEST_BLOCK_SIZE, n
-                ).to_owned()),
-            }
+
+    fn poll_frame(&mut self) -> Result<Option<Frame>, String> {
+        match self.frames.try_recv() {
+            Ok(frame) => frame.map(Some),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err("The USB reading thread stopped.".to_owned()),
         }
     }
 
-    fn write_block(&mut self, bytes: &[u8]) -> Result<usize, String> {
-        if bytes.len() != TEST_BLOCK_SIZE {
-            return Err(format!(
-                "Bad write block size: expected {} but got block of size {}",
-                TEST_BLOCK_SIZE,
-                bytes.len()
-            ).to_owned());
-        } else {
-            let bt_written = self.write_bytes(bytes);
-            match bt_written {
-                TEST_BLOCK_SIZE => Ok(TEST_BLOCK_SIZE),
-                0 => Err("Wrote 0 bytes. Is this interface initialized?".to_owned()),
-                n => Err(format!(
-                    "Bad write block result: expected {} but wrote {} bytes instead.",
-                    TEST_BLOCK_SIZE, n
-                ).to_owned()),
+    fn write_frame(&mut self, frame: Frame) -> Result<usize, String> {
+        let bytes = self.encoder.encode(frame);
+        for chunk in bytes.chunks(TEST_BLOCK_SIZE) {
+            let bt_written = self.interface.write_bytes(chunk);
+            if bt_written == 0 {
+                return Err("Wrote 0 bytes. Is this interface initialized?".to_owned());
+            } else if bt_written != chunk.len() {
+                return Err(format!(
+                    "Bad write result: expected {} but wrote {} bytes instead.",
+                    chunk.len(),
+                    bt_written
+                ));
             }
         }
+        Ok(bytes.len())
+    }
+
+    fn start_session(&mut self) {
+        self.encoder.reset();
     }
 }
@@ -1,16 +1,19 @@
 extern crate libc;
 
 pub mod commands;
-use commands::{ServerCommandState, CommandStates};
+use commands::{ServerCommandState, CommandStates, ListCursor};
 
 pub mod interface;
 use interface::ServerDevice;
 
 extern crate nxusb;
 pub use nxusb::prefixes;
+use nxusb::frame::{Frame, FrameKind, MAX_FRAME_PAYLOAD};
+use nxusb::handshake::PROTOCOL_VERSION;
+use nxusb::response::{Response, ResponseCode};
 
 pub mod libnx_impl;
-use libnx_impl::{StdFileReader, StdFileWriter};
+use libnx_impl::{StdFileReader, StdFileWriter, UsbCommsDevice};
 
 pub mod test_impl;
 
@@ -83,17 +86,23 @@ pub fn test_runner() -> Result<(), String> {
 pub fn server_runner() -> Result<(), String> {
     dprintln!("Initing console.");
     let mut debug = console::ConsoleHandle::default();
-    let mut usb_interfaces = [usbcomms::UsbCommsInterface::default()];
+    // The interface lives as long as the program, since the thread reading
+    // it in the background is never stopped.
+    let usb_interfaces: &'static mut [usbcomms::UsbCommsInterface; 1] = Box::leak(Box::new([usbcomms::UsbCommsInterface::default()]));
     dprintln!("Initing interface array{:?}", usb_interfaces);
     dprintln!("Initing UsbCommsContext.");
-    let _usb_context = usbcomms::UsbCommsContext::initialize(&mut usb_interfaces)
+    let _usb_context = usbcomms::UsbCommsContext::initialize(usb_interfaces)
         .map_err(|e| format!("Libnx Error: {:?}", e))?;
 
-    let usb_interface = &mut usb_interfaces[0];
+    let mut usb_interface = UsbCommsDevice::new(&mut usb_interfaces[0]);
 
     let mut hid_handle = libnx_rs::hid::HidContext {};
     let controller_handle = hid_handle.get_controller(libnx_rs::hid::HidControllerID::CONTROLLER_P1_AUTO);
     let mut current_command : Option<CommandStates<StdFileReader, StdFileWriter>> = None; 
+    let mut handshake_done = false;
+    let mut frame_payload = MAX_FRAME_PAYLOAD;
+    let mut protocol_version = PROTOCOL_VERSION;
+    let mut list_cursor: Option<ListCursor> = None;
     loop {
         hid_handle.scan_input();
         if controller_handle.keys_down_raw() & 1024 != 0 {
@@ -105,37 +114,62 @@ pub fn server_runner() -> Result<(), String> {
         if current_command.is_none() {
             dprintln!("Waiting for command prefix.");
             debug.update();
-            let prefix = usb_interface.read_prefix()?;
-            dprintln!("Found command prefix {:?}", prefix);
-            debug.update();
-            let command = CommandStates::from_prefix(prefix);
+            let frame = usb_interface.read_frame()?;
+            if frame.kind == FrameKind::Abort {
+                dprintln!("Got an abort with no command running.");
+                let response = Response::error(ResponseCode::Aborted, "No command was running.".to_owned());
+                usb_interface.write_frame(Frame::response(&response))?;
+                continue;
+            }
+            let mut command = match CommandStates::from_frame(&frame, protocol_version) {
+                Some(command) => command,
+                None => {
+                    dprintln!("Dropping stray {:?} frame {}.", frame.kind, frame.sequence);
+                    continue;
+                }
+            };
+            match (handshake_done, &command) {
+                (false, CommandStates::Handshake(_)) => {}
+                (false, _) => {
+                    return Err("Client did not start with a handshake; it is probably older than this server and needs to be updated.".to_owned());
+                }
+                (true, CommandStates::Handshake(_)) if frame.starts_session() => {
+                    dprintln!("Client started a new session.");
+                    usb_interface.start_session();
+                }
+                (true, CommandStates::Handshake(_)) => {
+                    return Err("Client sent a second handshake on the same connection.".to_owned());
+                }
+                (true, _) => {}
+            }
+            command.resume_listing(list_cursor.take());
             current_command = Some(command);
         }
 
         let finished = {
             let command : &mut CommandStates<StdFileReader, StdFileWriter> = current_command.as_mut().ok_or("Error: current command shouldn't be None.")?;
-            if command.needs_input() {
-                let mut buffer : Vec<u8> = Vec::with_capacity(usb_interface.block_size());
-                buffer.resize(usb_interface.block_size(), 0);
-                usb_interface.read_block(&mut buffer)?;
-                command.input_block(&buffer)?;
-                false
-            }
-            else if command.needs_output() {
-                let mut buffer : Vec<u8> = Vec::with_capacity(usb_interface.block_size());
-                buffer.resize(usb_interface.block_size(), 0);
-                command.output_block(&mut buffer)?;
-                usb_interface.write_block(&mut buffer)?;
-                false
+            if command.needs_input() || command.needs_output() {
+                usb_interface.step_command(command, frame_payload)?
             }
             else {
+                if let CommandStates::Handshake(h) = command {
+                    if let Err(e) = h.check_compatible() {
+                        dprintln!("Refusing client: {}", e);
+                        debug.update();
+                        return Err(e);
+                    }
+                    frame_payload = h.frame_payload();
+                    protocol_version = h.protocol_version();
+                    dprintln!("Handshake with client succeeded; using frames of up to {} bytes.", frame_payload);
+                    handshake_done = true;
+                }
                 dprintln!("Finished command.");
                 true
             }
         };
 
         if finished {
-            current_command = None;
+            list_cursor = current_command.take().and_then(|mut command| command.take_list_cursor());
         }
 
     }
@@ -1,12 +1,48 @@
-use commands::{FileReader, FileWriter, ReadCommandState, ServerCommandState, WriteCommandState};
+use commands::{self, CommandStates, FileReader, FileWriter, HandshakeCommandState, ListCommandState, ProbeCommandState, ReadCommandState, ServerCommandState, WriteCommandState};
 use interface::ServerDevice;
-use prefixes::{CommandPrefix, Prefixes, ReadPrefix, WritePrefix, PREFIX_LENGTH};
-use std::collections::HashMap;
+use nxusb::fsinfo::{split_part_name, FsInfo, FsKind, SPLIT_PART_SIZE};
+use nxusb::frame::{AbortPolicy, Frame, FrameDecoder, FrameEncoder, FrameKind, FRAME_HEADER_LENGTH, MAX_FRAME_PAYLOAD};
+use nxusb::handshake::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, SUPPORTED_FEATURES};
+use prefixes::{self, CommandPrefix, FsInfoPrefix, HandshakePrefix, ListPrefix, MakeDirPrefix, MovePrefix, Opcode, OverwritePolicy, PrefixFlags, Prefixes, ProbePrefix, ReadPrefix, RemovePrefix, StatPrefix, WritePrefix, PREFIX_LENGTH, READ_HEADER_LENGTH};
+use nxusb::checksum::{self, Checksum, ChecksumKind};
+use nxusb::listing::{EntryKind, ListEntry, ListPage};
+use nxusb::removal::RemovalRecord;
+use nxusb::response::{Response, ResponseCode};
+use std::collections::{HashMap, HashSet};
 use std::sync::{Once, ONCE_INIT};
 use std::vec::Vec;
 
 struct TestFileContext {
     files: HashMap<String, Vec<u8>>,
+    /// Files too large to hold in memory, mapped to their length. Their
+    /// content is generated by `fake_byte`.
+    fake_files: HashMap<String, u64>,
+    /// Directories that exist, without a trailing slash. Files whose names
+    /// contain a slash can only be written inside one of these.
+    dirs: HashSet<String>,
+    /// Symbolic links, mapped to their targets.
+    links: HashMap<String, String>,
+    /// Files whose storage flips the bits of the first byte of each write.
+    corrupt_writes: HashSet<String>,
+    /// Modification times of files, in seconds since the Unix epoch. Files
+    /// not in here were last modified at time 0.
+    mtimes: HashMap<String, u64>,
+    /// Entries whose deletion fails as if they were read only.
+    locked: HashSet<String>,
+    /// Filesystems, mapped from the prefix every path on them starts with.
+    mounts: HashMap<String, FsInfo>,
+    /// Part sizes for split files on the FAT32 mounts, scaled down from
+    /// `SPLIT_PART_SIZE` so that tests can cross them. Files on a FAT32 mount
+    /// are split once they grow past this.
+    part_sizes: HashMap<String, u64>,
+    /// Directories holding the parts of a split file, as if they had the
+    /// archive bit set.
+    split_dirs: HashSet<String>,
+}
+
+/// The content of a fake file at the given offset.
+fn fake_byte(offset: u64) -> u8 {
+    (offset % 251) as u8
 }
 
 static mut CONTEXT: Option<TestFileContext> = None;
@@ -17,78 +53,613 @@ impl TestFileContext {
         INIT.call_once(|| {
             CONTEXT = Some(TestFileContext {
                 files: HashMap::new(),
+                fake_files: HashMap::new(),
+                dirs: HashSet::new(),
+                links: HashMap::new(),
+                corrupt_writes: HashSet::new(),
+                mtimes: HashMap::new(),
+                locked: HashSet::new(),
+                mounts: HashMap::new(),
+                part_sizes: HashMap::new(),
+                split_dirs: HashSet::new(),
             })
         });
         CONTEXT.as_mut().unwrap()
     }
+
+    /// Resolves a symbolic link if the flags allow it.
+    fn resolve(&self, name: &str, flags: PrefixFlags) -> Result<String, Response> {
+        match self.links.get(name) {
+            Some(target) if flags.contains(PrefixFlags::FOLLOW_LINKS) => Ok(target.clone()),
+            Some(_) => Err(Response::error(
+                ResponseCode::InvalidInput,
+                format!("{} is a symbolic link.", name),
+            )),
+            None => Ok(name.to_owned()),
+        }
+    }
+
+    /// Lists the files in a directory, each name ended by a 0 byte.
+    fn list(&self, dir: &str, flags: PrefixFlags) -> Result<Vec<u8>, Response> {
+        if !dir.ends_with('/') || !self.dirs.contains(&dir[0..dir.len() - 1]) {
+            return Err(Response::error(
+                ResponseCode::NotFound,
+                format!("No test directory named {}.", dir),
+            ));
+        }
+        let mut names: Vec<&str> = self
+            .files
+            .keys()
+            .filter(|name| name.starts_with(dir))
+            .map(|name| &name[dir.len()..])
+            .filter(|name| flags.contains(PrefixFlags::RECURSIVE) || !name.contains('/'))
+            .collect();
+        names.sort();
+        let mut listing = Vec::new();
+        for name in names {
+            listing.extend_from_slice(name.as_bytes());
+            listing.push(0);
+        }
+        Ok(listing)
+    }
+
+    /// The size of the parts a file is split into once it grows past it, if
+    /// the file is on a FAT32 mount.
+    fn split_part_size(&self, name: &str) -> Option<u64> {
+        self.mounts
+            .iter()
+            .filter(|&(mount, _)| name.starts_with(mount.as_str()))
+            .max_by_key(|&(mount, _)| mount.len())
+            .filter(|&(_, info)| info.kind == FsKind::Fat32)
+            .map(|(mount, _)| self.part_sizes.get(mount).cloned().unwrap_or(SPLIT_PART_SIZE))
+    }
+
+    /// The parts of a split file, in order.
+    fn split_parts(&self, name: &str) -> Vec<String> {
+        (0..)
+            .map(|idx| format!("{}/{}", name, split_part_name(idx)))
+            .take_while(|part| self.files.contains_key(part))
+            .collect()
+    }
+
+    /// The content of a split file, or `None` if the name is not one.
+    fn split_content(&self, name: &str) -> Option<Vec<u8>> {
+        if !self.split_dirs.contains(name) {
+            return None;
+        }
+        Some(self.split_parts(name).iter().flat_map(|part| self.files[part].iter().cloned()).collect())
+    }
+
+    /// Whether the name is a part inside a split file.
+    fn in_split_dir(&self, name: &str) -> bool {
+        name.rfind('/').map(|idx| self.split_dirs.contains(&name[0..idx])).unwrap_or(false)
+    }
+
+    /// Turns a split file back into a single file, so that it can be written
+    /// to like one.
+    fn join_split(&mut self, name: &str) {
+        if let Some(content) = self.split_content(name) {
+            for part in self.split_parts(name) {
+                self.files.remove(&part);
+            }
+            self.split_dirs.remove(name);
+            self.dirs.remove(name);
+            self.files.insert(name.to_owned(), content);
+        }
+    }
+
+    /// Splits a file into parts if it has grown past the part size of a FAT32
+    /// mount.
+    fn store(&mut self, name: &str) {
+        let part_size = match self.split_part_size(name) {
+            Some(part_size) => part_size as usize,
+            None => return,
+        };
+        if self.files.get(name).map(|fl| fl.len() <= part_size).unwrap_or(true) {
+            return;
+        }
+        let content = self.files.remove(name).unwrap();
+        for (idx, part) in content.chunks(part_size).enumerate() {
+            self.files.insert(format!("{}/{}", name, split_part_name(idx)), part.to_vec());
+        }
+        self.dirs.insert(name.to_owned());
+        self.split_dirs.insert(name.to_owned());
+    }
+
+    /// The names of every file, link and directory.
+    fn entry_names(&self) -> Vec<String> {
+        self.files
+            .keys()
+            .chain(self.fake_files.keys())
+            .chain(self.links.keys())
+            .chain(self.dirs.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// Describes whatever has the given name, if anything does.
+    fn entry(&self, name: &str, flags: PrefixFlags) -> Option<ListEntry> {
+        let base = name.rsplit('/').next().unwrap_or(name).to_owned();
+        let modified = self.mtimes.get(name).cloned().unwrap_or(0);
+        if let Some(content) = self.split_content(name) {
+            return Some(ListEntry { name: base, kind: EntryKind::File, size: content.len() as u64, modified });
+        }
+        if let Some(target) = self.links.get(name) {
+            return if flags.contains(PrefixFlags::FOLLOW_LINKS) {
+                self.entry(target, flags).map(|ent| ListEntry { name: base, ..ent })
+            } else {
+                Some(ListEntry { name: base, kind: EntryKind::Other, size: 0, modified })
+            };
+        }
+        let size = match (self.files.get(name), self.fake_files.get(name)) {
+            (Some(fl), _) => fl.len() as u64,
+            (None, Some(len)) => *len,
+            (None, None) if self.dirs.contains(name) => {
+                return Some(ListEntry { name: base, kind: EntryKind::Dir, size: 0, modified });
+            }
+            (None, None) => return None,
+        };
+        Some(ListEntry { name: base, kind: EntryKind::File, size, modified })
+    }
 }
 
 #[derive(Debug)]
 pub struct TestFileReader {
     bytes: Vec<u8>,
+    fake_len: Option<u64>,
+    read_idx: u64,
 }
 
 impl FileReader for TestFileReader {
-    fn new(name: &str) -> Result<Self, String> {
-        let bts: Vec<u8> = unsafe {
-            TestFileContext::get_context()
-                .files
-                .get(name)
-                .unwrap_or(&Vec::new())
-                .to_vec()
-        };
+    fn new(name: &str, flags: PrefixFlags) -> Result<Self, Response> {
+        let ctx = unsafe { TestFileContext::get_context() };
+        let name = &ctx.resolve(name, flags)?;
+        if name.ends_with('/') {
+            return Ok(TestFileReader {
+                bytes: ctx.list(name, flags)?,
+                fake_len: None,
+                read_idx: 0,
+            });
+        }
+        if let Some(bts) = ctx.split_content(name) {
+            return Ok(TestFileReader {
+                bytes: bts,
+                fake_len: None,
+                read_idx: 0,
+            });
+        }
+        if !ctx.files.contains_key(name) && !ctx.fake_files.contains_key(name) {
+            return Err(Response::error(
+                ResponseCode::NotFound,
+                format!("No test file named {}.", name),
+            ));
+        }
+        let bts: Vec<u8> = ctx.files.get(name).unwrap_or(&Vec::new()).to_vec();
 
-        Ok(TestFileReader { bytes: bts })
+        Ok(TestFileReader {
+            bytes: bts,
+            fake_len: ctx.fake_files.get(name).cloned(),
+            read_idx: 0,
+        })
     }
-    fn len(&self) -> usize {
-        self.bytes.len()
+    fn len(&self) -> u64 {
+        self.fake_len.unwrap_or(self.bytes.len() as u64)
     }
-    fn read_bytes(&mut self, buffer: &mut [u8]) -> Result<usize, String> {
+    fn read_bytes(&mut self, buffer: &mut [u8]) -> Result<usize, Response> {
         let buflen = buffer.len();
-        let num_bytes = buflen.min(self.bytes.len());
-        for (idx, bt) in self.bytes.drain(0..num_bytes).enumerate() {
-            buffer[idx] = bt;
+        if let Some(fake_len) = self.fake_len {
+            let num_bytes = (buflen as u64).min(fake_len - self.read_idx) as usize;
+            for idx in 0..num_bytes {
+                buffer[idx] = fake_byte(self.read_idx + idx as u64);
+            }
+            self.read_idx += num_bytes as u64;
+            return Ok(num_bytes);
         }
+        let start = self.read_idx as usize;
+        let num_bytes = buflen.min(self.bytes.len() - start);
+        buffer[0..num_bytes].copy_from_slice(&self.bytes[start..start + num_bytes]);
+        self.read_idx += num_bytes as u64;
         Ok(num_bytes)
     }
+    fn seek(&mut self, offset: u64) -> Result<(), Response> {
+        self.read_idx = offset;
+        Ok(())
+    }
+
+    fn list_dir(dir_name: &str, flags: PrefixFlags) -> Result<Vec<ListEntry>, Response> {
+        let ctx = unsafe { TestFileContext::get_context() };
+        let dir = ctx.resolve(dir_name.trim_end_matches('/'), flags)?;
+        if !ctx.dirs.contains(&dir) {
+            return Err(Response::error(
+                ResponseCode::NotFound,
+                format!("No test directory named {}.", dir_name),
+            ));
+        }
+        let prefix = format!("{}/", dir);
+        let recursive = flags.contains(PrefixFlags::RECURSIVE);
+        let names: HashSet<&String> = ctx
+            .files
+            .keys()
+            .chain(ctx.fake_files.keys())
+            .chain(ctx.dirs.iter())
+            .chain(ctx.links.keys())
+            .filter(|name| name.starts_with(&prefix) && (recursive || !name[prefix.len()..].contains('/')))
+            .filter(|name| !ctx.in_split_dir(name))
+            .collect();
+        Ok(names
+            .into_iter()
+            .filter_map(|name| {
+                ctx.entry(name, flags).map(|ent| ListEntry {
+                    name: name[prefix.len()..].to_owned(),
+                    ..ent
+                })
+            })
+            .collect())
+    }
+
+    fn stat(file_name: &str, flags: PrefixFlags) -> Result<ListEntry, Response> {
+        let ctx = unsafe { TestFileContext::get_context() };
+        ctx.entry(file_name, flags).ok_or(Response::error(
+            ResponseCode::NotFound,
+            format!("Nothing named {}.", file_name),
+        ))
+    }
+
+    fn fs_info(path: &str) -> Result<FsInfo, Response> {
+        let ctx = unsafe { TestFileContext::get_context() };
+        ctx.mounts
+            .iter()
+            .filter(|&(mount, _)| path.starts_with(mount.as_str()))
+            .max_by_key(|&(mount, _)| mount.len())
+            .map(|(_, info)| *info)
+            .ok_or(Response::error(
+                ResponseCode::NotFound,
+                format!("No test filesystem holds {}.", path),
+            ))
+    }
 }
 
 #[derive(Debug)]
 pub struct TestFileWriter {
     name: String,
+    /// The temporary file the content goes into until it is committed.
+    temp: Option<String>,
+    appended_to: Option<usize>,
+    /// How far the content written so far has matched the existing file, for
+    /// `replace_if_changed`, or `None` once it has differed.
+    matched: Option<usize>,
 }
 
 impl FileWriter for TestFileWriter {
-    fn new(file_name: &str) -> Result<Self, String> {
-        unsafe {
-            TestFileContext::get_context()
-                .files
-                .insert(file_name.to_string(), Vec::new());
+    fn new(file_name: &str, flags: PrefixFlags) -> Result<Self, Response> {
+        let ctx = unsafe { TestFileContext::get_context() };
+        let mut file_name = ctx.resolve(file_name, flags)?;
+        if flags.contains(PrefixFlags::NEW_NAME) {
+            let base = file_name.clone();
+            let mut number = 1;
+            while ctx.entry(&file_name, PrefixFlags::empty()).is_some() {
+                file_name = commands::suffixed_name(&base, number);
+                number += 1;
+            }
+        }
+        let file_name = &file_name;
+        ctx.join_split(file_name);
+        if let Some(idx) = file_name.rfind('/') {
+            let parent = &file_name[0..idx];
+            if flags.contains(PrefixFlags::CREATE_PARENTS) {
+                for (idx, _) in file_name.match_indices('/') {
+                    ctx.dirs.insert(file_name[0..idx].to_owned());
+                }
+            } else if !ctx.dirs.contains(parent) {
+                return Err(Response::error(
+                    ResponseCode::NotFound,
+                    format!("No test directory named {}.", parent),
+                ));
+            }
+        }
+        let existing = ctx.files.get(file_name).map(|fl| fl.len());
+        if existing.is_some() && !flags.contains(PrefixFlags::OVERWRITE) && !flags.contains(PrefixFlags::APPEND) {
+            return Err(Response::error(
+                ResponseCode::Exists,
+                format!("File with name {} already exists!", file_name),
+            ));
+        }
+        if !flags.contains(PrefixFlags::APPEND) {
+            let temp = commands::temp_name(file_name);
+            ctx.files.insert(temp.clone(), Vec::new());
+            return Ok(TestFileWriter {
+                name: file_name.to_owned(),
+                temp: Some(temp),
+                appended_to: None,
+                matched: None,
+            });
+        }
+        if existing.is_none() {
+            ctx.files.insert(file_name.to_string(), Vec::new());
         }
         Ok(TestFileWriter {
             name: file_name.to_owned(),
+            temp: None,
+            appended_to: existing,
+            matched: None,
         })
     }
 
-    fn write_bytes(&mut self, buffer: &[u8]) -> Result<usize, String> {
-        let fl: &mut Vec<u8> = unsafe {
-            TestFileContext::get_context()
-                .files
-                .get_mut(&self.name)
-                .ok_or(format!(
-                    "Err: could not find buffer for file named {}.",
-                    self.name
-                ))?
-        };
+    fn replace_if_changed(file_name: &str, flags: PrefixFlags, length: u64) -> Result<Self, Response> {
+        let ctx = unsafe { TestFileContext::get_context() };
+        let resolved = ctx.resolve(file_name, flags)?;
+        ctx.join_split(&resolved);
+        match ctx.files.get(&resolved) {
+            Some(fl) if fl.len() as u64 == length => Ok(TestFileWriter {
+                name: resolved,
+                temp: None,
+                appended_to: None,
+                matched: Some(0),
+            }),
+            _ => TestFileWriter::new(file_name, flags | PrefixFlags::OVERWRITE),
+        }
+    }
+
+    fn unchanged(&self) -> bool {
+        self.matched.is_some()
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn resume(file_name: &str, flags: PrefixFlags, offset: u64) -> Result<Self, Response> {
+        let ctx = unsafe { TestFileContext::get_context() };
+        let file_name = &ctx.resolve(file_name, flags)?;
+        ctx.join_split(file_name);
+        let fl = ctx.files.get_mut(file_name).ok_or(Response::error(
+            ResponseCode::NotFound,
+            format!("No test file named {}.", file_name),
+        ))?;
+        if offset as usize > fl.len() {
+            return Err(Response::error(
+                ResponseCode::InvalidInput,
+                format!("Offset {} is past the end of {}.", offset, file_name),
+            ));
+        }
+        fl.truncate(offset as usize);
+        Ok(TestFileWriter {
+            name: file_name.to_owned(),
+            temp: None,
+            appended_to: Some(offset as usize),
+            matched: None,
+        })
+    }
+
+    fn write_bytes(&mut self, buffer: &[u8]) -> Result<usize, Response> {
+        let ctx = unsafe { TestFileContext::get_context() };
+        if let Some(matched) = self.matched {
+            let existing = &ctx.files[&self.name];
+            let end = matched + buffer.len();
+            if end <= existing.len() && &existing[matched..end] == buffer {
+                self.matched = Some(end);
+                return Ok(buffer.len());
+            }
+            let temp = commands::temp_name(&self.name);
+            let start = existing[0..matched].to_vec();
+            ctx.files.insert(temp.clone(), start);
+            self.temp = Some(temp);
+            self.matched = None;
+        }
+        let written = self.temp.as_ref().unwrap_or(&self.name);
+        let fl: &mut Vec<u8> = ctx.files.get_mut(written).ok_or(format!(
+            "Err: could not find buffer for file named {}.",
+            written
+        ))?;
+        let start = fl.len();
         fl.extend_from_slice(buffer);
+        if unsafe { TestFileContext::get_context().corrupt_writes.contains(&self.name) } && !buffer.is_empty() {
+            fl[start] ^= 0xFF;
+        }
         Ok(buffer.len())
     }
+
+    fn remove(self) -> Result<(), Response> {
+        let ctx = unsafe { TestFileContext::get_context() };
+        match (self.matched, self.appended_to) {
+            (Some(_), _) => {}
+            (None, Some(old_len)) => ctx.files.get_mut(&self.name).unwrap().truncate(old_len),
+            (None, None) => {
+                ctx.files.remove(self.temp.as_ref().unwrap_or(&self.name));
+            }
+        }
+        ctx.store(&self.name);
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), Response> {
+        let ctx = unsafe { TestFileContext::get_context() };
+        if let Some(temp) = self.temp.take() {
+            let content = ctx.files.remove(&temp).unwrap_or(Vec::new());
+            ctx.files.insert(self.name.clone(), content);
+        }
+        ctx.store(&self.name);
+        Ok(())
+    }
+
+    fn replaces_existing(&self) -> bool {
+        let ctx = unsafe { TestFileContext::get_context() };
+        self.temp.is_some() && ctx.entry(&self.name, PrefixFlags::empty()).is_some()
+    }
+
+    fn read_back_digest(&mut self, kind: ChecksumKind) -> Result<Vec<u8>, Response> {
+        let ctx = unsafe { TestFileContext::get_context() };
+        let mut check = Checksum::new(kind);
+        let written = self.temp.as_ref().unwrap_or(&self.name);
+        check.update(&ctx.files[written][self.appended_to.unwrap_or(0)..]);
+        Ok(check.finish())
+    }
+
+    fn read_old(&mut self, offset: u64, buffer: &mut [u8]) -> Result<usize, Response> {
+        let ctx = unsafe { TestFileContext::get_context() };
+        let old = match ctx.files.get(&self.name) {
+            Some(old) => old,
+            None => return Ok(0),
+        };
+        let start = (offset as usize).min(old.len());
+        let read = (old.len() - start).min(buffer.len());
+        buffer[0..read].copy_from_slice(&old[start..start + read]);
+        Ok(read)
+    }
+
+    fn make_dir(dir_name: &str, flags: PrefixFlags) -> Result<(), Response> {
+        let ctx = unsafe { TestFileContext::get_context() };
+        let dir_name = dir_name.trim_end_matches('/');
+        let is_dir = ctx.dirs.contains(dir_name);
+        if ctx.files.contains_key(dir_name) || (is_dir && !flags.contains(PrefixFlags::CREATE_PARENTS)) {
+            return Err(Response::error(
+                ResponseCode::Exists,
+                format!("{} already exists.", dir_name),
+            ));
+        }
+        if flags.contains(PrefixFlags::CREATE_PARENTS) {
+            for (idx, _) in dir_name.match_indices('/') {
+                ctx.dirs.insert(dir_name[0..idx].to_owned());
+            }
+        } else if let Some(idx) = dir_name.rfind('/') {
+            if !ctx.dirs.contains(&dir_name[0..idx]) {
+                return Err(Response::error(
+                    ResponseCode::NotFound,
+                    format!("No test directory named {}.", &dir_name[0..idx]),
+                ));
+            }
+        }
+        ctx.dirs.insert(dir_name.to_owned());
+        Ok(())
+    }
+
+    fn remove_path(path: &str, flags: PrefixFlags, dir_only: bool) -> Vec<RemovalRecord> {
+        let ctx = unsafe { TestFileContext::get_context() };
+        let path = path.trim_end_matches('/');
+        let kind = match ctx.entry(path, PrefixFlags::empty()) {
+            Some(ent) => ent.kind,
+            None => {
+                return vec![RemovalRecord::failed(
+                    path.to_owned(),
+                    EntryKind::Other,
+                    ResponseCode::NotFound,
+                    format!("No test entry named {}.", path),
+                )]
+            }
+        };
+        let mut names = vec![path.to_owned()];
+        if dir_only && kind != EntryKind::Dir {
+            return vec![RemovalRecord::failed(path.to_owned(), kind, ResponseCode::InvalidInput, "Not a directory.".to_owned())];
+        } else if kind == EntryKind::Dir && !dir_only {
+            if !flags.contains(PrefixFlags::RECURSIVE) {
+                return vec![RemovalRecord::failed(
+                    path.to_owned(),
+                    kind,
+                    ResponseCode::InvalidInput,
+                    "Is a directory; removing it needs the recursive flag.".to_owned(),
+                )];
+            }
+            let inside = format!("{}/", path);
+            let inside_tree: Vec<String> = ctx
+                .entry_names()
+                .into_iter()
+                .filter(|name| name.starts_with(&inside) && !ctx.in_split_dir(name))
+                .collect();
+            names.extend(inside_tree);
+        }
+        // Deepest first, so each directory is emptied before it is removed.
+        names.sort_by(|a, b| b.cmp(a));
+        let mut report = Vec::new();
+        for name in names {
+            let kind = ctx.entry(&name, PrefixFlags::empty()).map(|ent| ent.kind).unwrap_or(EntryKind::Other);
+            let inside = format!("{}/", name);
+            let not_empty = kind == EntryKind::Dir && ctx.entry_names().iter().any(|other| other.starts_with(&inside));
+            if ctx.locked.contains(&name) {
+                report.push(RemovalRecord::failed(name, kind, ResponseCode::Permission, "Entry is locked.".to_owned()));
+            } else if not_empty {
+                report.push(RemovalRecord::failed(name, kind, ResponseCode::Io, "Directory not empty.".to_owned()));
+            } else {
+                ctx.join_split(&name);
+                ctx.files.remove(&name);
+                ctx.fake_files.remove(&name);
+                ctx.links.remove(&name);
+                ctx.dirs.remove(&name);
+                report.push(RemovalRecord::removed(name, kind));
+            }
+        }
+        report
+    }
+
+    fn move_path(from: &str, to: &str, flags: PrefixFlags) -> Result<(), Response> {
+        let ctx = unsafe { TestFileContext::get_context() };
+        let (from, to) = (from.trim_end_matches('/'), to.trim_end_matches('/'));
+        if ctx.entry(from, PrefixFlags::empty()).is_none() {
+            return Err(Response::error(
+                ResponseCode::NotFound,
+                format!("No test entry named {}.", from),
+            ));
+        }
+        if ctx.entry(to, PrefixFlags::empty()).is_some() && !flags.contains(PrefixFlags::OVERWRITE) {
+            return Err(Response::error(
+                ResponseCode::Exists,
+                format!("{} already exists.", to),
+            ));
+        }
+        if let Some(idx) = to.rfind('/') {
+            if !ctx.dirs.contains(&to[0..idx]) {
+                return Err(Response::error(
+                    ResponseCode::NotFound,
+                    format!("No test directory named {}.", &to[0..idx]),
+                ));
+            }
+        }
+        let inside = format!("{}/", from);
+        for name in ctx.entry_names() {
+            let new_name = if name == from {
+                to.to_owned()
+            } else if name.starts_with(&inside) {
+                format!("{}/{}", to, &name[inside.len()..])
+            } else {
+                continue;
+            };
+            if let Some(content) = ctx.files.remove(&name) {
+                ctx.files.insert(new_name.clone(), content);
+            }
+            if let Some(len) = ctx.fake_files.remove(&name) {
+                ctx.fake_files.insert(new_name.clone(), len);
+            }
+            if let Some(target) = ctx.links.remove(&name) {
+                ctx.links.insert(new_name.clone(), target);
+            }
+            if ctx.dirs.remove(&name) {
+                ctx.dirs.insert(new_name.clone());
+            }
+            if ctx.split_dirs.remove(&name) {
+                ctx.split_dirs.insert(new_name.clone());
+            }
+            if let Some(mtime) = ctx.mtimes.remove(&name) {
+                ctx.mtimes.insert(new_name, mtime);
+            }
+        }
+        Ok(())
+    }
 }
 
 const TEST_BLOCK_SIZE: usize = 100;
+
+/// The frame payload used by the tests; larger than a block so that frames
+/// span several reads, and small enough that files span several frames.
+const TEST_FRAME_PAYLOAD: usize = 150;
+
 pub struct TestUsbDevice {
     input_buf: Vec<u8>,
     output_buf: Vec<u8>,
+    /// Numbers the frames the tests feed in, standing in for the client.
+    input_encoder: FrameEncoder,
+    decoder: FrameDecoder,
+    encoder: FrameEncoder,
+    /// Reads back the frames the device sent, standing in for the client.
+    output_decoder: FrameDecoder,
 }
 
 impl TestUsbDevice {
@@ -96,6 +667,10 @@ impl TestUsbDevice {
         TestUsbDevice {
             input_buf: Vec::new(),
             output_buf: Vec::new(),
+            input_encoder: FrameEncoder::new(),
+            decoder: FrameDecoder::new(),
+            encoder: FrameEncoder::new(),
+            output_decoder: FrameDecoder::new(),
         }
     }
 
@@ -103,6 +678,15 @@ impl TestUsbDevice {
         self.input_buf.extend_from_slice(input_bytes);
     }
 
+    pub fn push_input_frame(&mut self, frame: Frame) {
+        let bytes = self.input_encoder.encode(frame);
+        self.push_input(&bytes);
+    }
+
+    pub fn push_input_data(&mut self, data: &[u8]) {
+        self.push_input_frame(Frame::data(data.to_vec()));
+    }
+
     pub fn pull_input(&mut self, count: usize) -> Vec<u8> {
         self.input_buf.drain(0..count).collect()
     }
@@ -114,145 +698,268 @@ impl TestUsbDevice {
     pub fn pull_output(&mut self, count: usize) -> Vec<u8> {
         self.output_buf.drain(0..count).collect()
     }
-}
 
-impl ServerDevice for TestUsbDevice {
-    fn block_size(&self) -> usize {
-        TEST_BLOCK_SIZE
+    /// Decodes the next frame the device sent.
+    pub fn pull_output_frame(&mut self) -> Frame {
+        let bytes: Vec<u8> = self.output_buf.drain(..).collect();
+        self.output_decoder.push_bytes(&bytes);
+        self.output_decoder
+            .next_frame()
+            .unwrap()
+            .expect("Device did not send a whole frame.")
     }
 
-    fn read_block(&mut self, buffer: &mut [u8]) -> Result<usize, String> {
-        if buffer.len() < TEST_BLOCK_SIZE {
-            Err(format!(
-                "Dont have large enough output buf to read block! Need {} but was passed {}.",
-                TEST_BLOCK_SIZE,
-                buffer.len()
-            ))
-        } else if self.input_buf.len() < TEST_BLOCK_SIZE {
-            Err(format!(
-                "Dont have large enough input buf to read block! Need {} but have {}.",
-                TEST_BLOCK_SIZE,
-                self.input_buf.len()
-            ))
-        } else {
-            let bts = self.pull_input(TEST_BLOCK_SIZE);
-            for idx in 0..TEST_BLOCK_SIZE {
-                buffer[idx] = bts[idx];
+    /// Decodes data frames until a non-data frame, returning their combined
+    /// payload and the frame that ended them.
+    pub fn pull_output_data(&mut self) -> (Vec<u8>, Frame) {
+        let mut data = Vec::new();
+        loop {
+            let frame = self.pull_output_frame();
+            if frame.kind != FrameKind::Data {
+                return (data, frame);
             }
-            Ok(TEST_BLOCK_SIZE)
+            assert!(frame.payload.len() <= TEST_FRAME_PAYLOAD);
+            data.extend_from_slice(&frame.payload);
         }
     }
+}
 
-    fn write_block(&mut self, bytes: &[u8]) -> Result<usize, String> {
-        if bytes.len() != self.block_size() {
-            Err(format!(
-                "Got less then a block size of bytes to output: {} but expected {}",
-                bytes.len(),
-                TEST_BLOCK_SIZE
-            ))
-        } else {
-            self.push_output(bytes);
-            Ok(bytes.len())
+impl ServerDevice for TestUsbDevice {
+    fn read_frame(&mut self) -> Result<Frame, String> {
+        loop {
+            if let Some(frame) = self.decoder.next_frame()? {
+                return Ok(frame);
+            }
+            if self.input_buf.is_empty() {
+                return Err("Dont have large enough input buf to read a frame!".to_owned());
+            }
+            let count = self.input_buf.len().min(TEST_BLOCK_SIZE);
+            let bts = self.pull_input(count);
+            self.decoder.push_bytes(&bts);
         }
     }
 
-    fn read_prefix(&mut self) -> Result<Prefixes, String> {
-        if self.input_buf.len() < PREFIX_LENGTH {
-            Err(format!(
-                "Don't have large enough input buf to read prefix! Need {} but have {}.",
-                PREFIX_LENGTH,
-                self.input_buf.len()
-            ))
+    fn poll_frame(&mut self) -> Result<Option<Frame>, String> {
+        if self.input_buf.is_empty() {
+            return self.decoder.next_frame();
+        }
+        self.read_frame().map(Some)
+    }
+
+    fn write_frame(&mut self, frame: Frame) -> Result<usize, String> {
+        let bytes = self.encoder.encode(frame);
+        self.push_output(&bytes);
+        Ok(bytes.len())
+    }
+
+    fn start_session(&mut self) {
+        self.encoder.reset();
+    }
+}
+
+/// Runs a command to completion against the test device.
+#[cfg(test)]
+fn run_command<P: CommandPrefix, C: ServerCommandState<P>>(
+    command: &mut C,
+    usb_ctx: &mut TestUsbDevice,
+) {
+    while command.needs_input() || command.needs_output() {
+        if command.needs_input() {
+            let frame = usb_ctx.read_frame().unwrap();
+            command.input_frame(frame).unwrap();
         } else {
-            let mut prefix_buffer: [u8; PREFIX_LENGTH] = [0; PREFIX_LENGTH];
-            let prefix_bytes = self.pull_input(PREFIX_LENGTH);
-            for idx in 0..PREFIX_LENGTH {
-                prefix_buffer[idx] = prefix_bytes[idx];
-            }
-            Prefixes::parse_prefix(prefix_buffer)
-                .ok_or("Err: found None when parsing prefix.".to_string())
+            let frame = command.output_frame(TEST_FRAME_PAYLOAD).unwrap();
+            usb_ctx.write_frame(frame).unwrap();
         }
     }
 }
 
+/// Reads of the legacy layout, which still decode during the deprecation
+/// window.
 #[test]
 fn test_read_prefix_parsing() {
     let expected = ReadPrefix {
-        flags: 0,
+        flags: PrefixFlags::empty(),
         file_name_length: 16,
     };
-    let bts: [u8; PREFIX_LENGTH] = [0x0, 0x0, 0x0, 0x10, 0x0, 0x0, 0x0, 0x0];
-    let mut usb_ctx = TestUsbDevice::empty();
-    usb_ctx.push_input(&bts);
-    let wrapped_actual = usb_ctx.read_prefix().unwrap();
-    match wrapped_actual {
-        Prefixes::Read(a) => {
-            assert_eq!(expected, a);
-        }
-        Prefixes::Write(a) => {
-            panic!(
-                "Got write prefix in read test: {:?} instead of expected {:?}.",
-                a, expected
-            );
-        }
-    }
+    let bts: [u8; PREFIX_LENGTH] = [
+        0x0, 0x0, 0x0, 0x10, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
+    ];
+    assert_eq!(Prefixes::decode_legacy(bts), Ok(Prefixes::Read(expected)));
+    // Outside the legacy layout the same bytes are an unknown opcode.
+    assert!(Prefixes::decode(bts).is_err());
 }
 
+/// Writes of the legacy layout, which still decode during the deprecation
+/// window.
 #[test]
 fn test_write_prefix_parsing() {
     let expected = WritePrefix {
-        flags: 0b1010101010101010,
+        flags: PrefixFlags::from_bits(0b1010101010101010),
         file_name_length: 16,
         file_length: 4096,
     };
-    let bts: [u8; PREFIX_LENGTH] = [0b10101010, 0b10101010, 0x00, 0x10, 0x00, 0x00, 0x10, 0x00];
+    let bts: [u8; PREFIX_LENGTH] = [
+        0b10101010, 0b10101010, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x10, 0x00,
+    ];
+    assert_eq!(Prefixes::decode_legacy(bts), Ok(Prefixes::Write(expected)));
+    assert!(Prefixes::decode(bts).is_err());
+}
+
+#[test]
+fn test_handshake_prefix_parsing() {
+    let expected = HandshakePrefix {
+        protocol_version: PROTOCOL_VERSION,
+        build_version: 0x1234,
+        max_frame_payload: TEST_FRAME_PAYLOAD as u16,
+        features: SUPPORTED_FEATURES,
+    };
     let mut usb_ctx = TestUsbDevice::empty();
-    usb_ctx.push_input(&bts);
-    let wrapped_actual = usb_ctx.read_prefix().unwrap();
-    match wrapped_actual {
-        Prefixes::Write(a) => {
+    usb_ctx.push_input_frame(Frame::prefix(&expected));
+    match usb_ctx.read_prefix().unwrap() {
+        Prefixes::Handshake(a) => {
             assert_eq!(expected, a);
         }
-        Prefixes::Read(a) => {
+        a => {
             panic!(
-                "Got read prefix in write test: {:?} instead of expected {:?}.",
+                "Got non-handshake prefix in handshake test: {:?} instead of expected {:?}.",
                 a, expected
             );
         }
     }
 }
 
+#[test]
+fn test_handshake_reply() {
+    let client = HandshakePrefix {
+        protocol_version: PROTOCOL_VERSION,
+        build_version: 0,
+        max_frame_payload: TEST_FRAME_PAYLOAD as u16,
+        features: SUPPORTED_FEATURES,
+    };
+    let mut usb_ctx = TestUsbDevice::empty();
+    let mut command = HandshakeCommandState::from_prefix(client);
+    assert!(command.check_compatible().is_err());
+    run_command(&mut command, &mut usb_ctx);
+    assert!(command.check_compatible().is_ok());
+    assert_eq!(command.frame_payload(), TEST_FRAME_PAYLOAD);
+
+    let reply: HandshakePrefix = usb_ctx.pull_output_frame().parse_prefix().unwrap();
+    assert_eq!(reply.protocol_version, PROTOCOL_VERSION);
+    assert_eq!(reply.max_frame_payload as usize, MAX_FRAME_PAYLOAD);
+}
+
+#[test]
+fn test_handshake_version_mismatch() {
+    let client = HandshakePrefix {
+        protocol_version: PROTOCOL_VERSION + 1,
+        build_version: 0,
+        max_frame_payload: TEST_FRAME_PAYLOAD as u16,
+        features: SUPPORTED_FEATURES,
+    };
+    let mut usb_ctx = TestUsbDevice::empty();
+    let mut command = HandshakeCommandState::from_prefix(client);
+    run_command(&mut command, &mut usb_ctx);
+    let err = command.check_compatible().unwrap_err();
+    assert!(err.contains("Protocol version mismatch"));
+}
+
+#[test]
+fn test_frames_span_blocks() {
+    let frames = vec![
+        Frame::data(vec![7; 3 * TEST_BLOCK_SIZE]),
+        Frame::data(Vec::new()),
+        Frame::response(&Response::ok()),
+    ];
+    let mut usb_ctx = TestUsbDevice::empty();
+    for frame in frames.iter() {
+        usb_ctx.push_input_frame(frame.clone());
+    }
+    for (idx, frame) in frames.into_iter().enumerate() {
+        let actual = usb_ctx.read_frame().unwrap();
+        assert_eq!(actual.sequence, idx as u32);
+        assert_eq!(actual.kind, frame.kind);
+        assert_eq!(actual.payload, frame.payload);
+    }
+    assert!(usb_ctx.input_buf.is_empty());
+    assert!(usb_ctx.read_frame().is_err());
+}
+
+#[test]
+fn test_frame_sequence_mismatch() {
+    let mut encoder = FrameEncoder::new();
+    let first = encoder.encode(Frame::data(b"first".to_vec()));
+    let _lost = encoder.encode(Frame::data(b"lost".to_vec()));
+    let third = encoder.encode(Frame::data(b"third".to_vec()));
+
+    let mut decoder = FrameDecoder::new();
+    decoder.push_bytes(&first);
+    decoder.push_bytes(&third);
+    assert_eq!(decoder.next_frame().unwrap().unwrap().payload, b"first".to_vec());
+    let err = decoder.next_frame().unwrap_err();
+    assert!(err.contains("sequence mismatch"), "Unexpected error {}", err);
+}
+
+#[test]
+fn test_unframed_handshake_rejected() {
+    let old_client = HandshakePrefix {
+        protocol_version: PROTOCOL_VERSION - 1,
+        build_version: 0,
+        max_frame_payload: TEST_BLOCK_SIZE as u16,
+        features: SUPPORTED_FEATURES,
+    };
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input(&old_client.serialize());
+    let err = usb_ctx.read_prefix().unwrap_err();
+    assert!(err.contains("needs to be updated"), "Unexpected error {}", err);
+}
+
 #[test]
 fn test_read_file() {
     let file = vec![b'H', b'e', b'l', b'l', b'o'];
     let fl_ctx = unsafe { TestFileContext::get_context() };
-    fl_ctx.files.insert("fla".to_string(), file.clone());
+    fl_ctx.files.insert("flr".to_string(), file.clone());
 
     let mut usb_ctx = TestUsbDevice::empty();
-    usb_ctx.push_input(&[b'f', b'l', b'a']);
-    usb_ctx.push_input(&[0; 100]);
+    usb_ctx.push_input_data(&[b'f', b'l', b'r']);
 
-    let mut test_read_buffer: [u8; TEST_BLOCK_SIZE] = [0; TEST_BLOCK_SIZE];
-    let mut test_write_buffer: [u8; TEST_BLOCK_SIZE] = [0; TEST_BLOCK_SIZE];
     let read_prefix = ReadPrefix {
-        flags: 0,
+        flags: PrefixFlags::empty(),
         file_name_length: 3,
     };
     let mut read_command = ReadCommandState::<TestFileReader>::from_prefix(read_prefix);
+    run_command(&mut read_command, &mut usb_ctx);
 
-    while read_command.needs_input() || read_command.needs_output() {
-        if read_command.needs_input() {
-            let _blk = usb_ctx.read_block(&mut test_read_buffer).unwrap();
-            let _read = read_command.input_block(&test_read_buffer).unwrap();
-        }
-        if read_command.needs_output() {
-            let _written = read_command.output_block(&mut test_write_buffer).unwrap();
-            let _blk = usb_ctx.write_block(&test_write_buffer).unwrap();
-        }
-    }
-    assert!(usb_ctx.input_buf.clone().into_iter().all(|a| a == 0));
-    assert_eq!(usb_ctx.pull_output(4), vec![0, 0, 0, 5]);
-    assert_eq!(usb_ctx.pull_output(5), file);
+    assert!(usb_ctx.input_buf.is_empty());
+    let (data, end) = usb_ctx.pull_output_data();
+    assert_eq!(&data[0..READ_HEADER_LENGTH], &[0, 0, 0, 0, 0, 0, 0, 5]);
+    assert_eq!(&data[READ_HEADER_LENGTH..], &file[..]);
+    assert_eq!(end.parse_response().unwrap(), Response::ok());
+    assert!(usb_ctx.output_buf.is_empty());
+}
+
+#[test]
+fn test_read_file_spans_frames() {
+    let file: Vec<u8> = (0..400u32).map(|idx| (idx % 256) as u8).collect();
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    fl_ctx.files.insert("multi".to_string(), file.clone());
+
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_data(b"mu");
+    usb_ctx.push_input_data(b"lti");
+
+    let read_prefix = ReadPrefix {
+        flags: PrefixFlags::empty(),
+        file_name_length: 5,
+    };
+    let mut read_command = ReadCommandState::<TestFileReader>::from_prefix(read_prefix);
+    run_command(&mut read_command, &mut usb_ctx);
+
+    let (data, end) = usb_ctx.pull_output_data();
+    assert_eq!(prefixes::combine_bytes_u64(&data), file.len() as u64);
+    assert_eq!(&data[READ_HEADER_LENGTH..], &file[..]);
+    assert_eq!(end.parse_response().unwrap(), Response::ok());
 }
 
 #[test]
@@ -261,31 +968,16 @@ fn test_write_file() {
     let file = vec![b'H', b'e', b'l', b'l', b'o'];
 
     let mut usb_ctx = TestUsbDevice::empty();
-    usb_ctx.push_input(&name);
-    usb_ctx.push_input(&file);
-    usb_ctx.push_input(&[0; 100]);
-    usb_ctx.push_input(&[0; 100]);
-    usb_ctx.push_input(&[0; 100]);
-
-    let mut test_read_buffer: [u8; TEST_BLOCK_SIZE] = [0; TEST_BLOCK_SIZE];
-    let mut test_write_buffer: [u8; TEST_BLOCK_SIZE] = [0; TEST_BLOCK_SIZE];
+    usb_ctx.push_input_data(&name);
+    usb_ctx.push_input_data(&file);
+
     let write_prefix = WritePrefix {
-        flags: 0b1000000000000000,
+        flags: PrefixFlags::empty(),
         file_name_length: 3,
         file_length: 5,
     };
     let mut write_command = WriteCommandState::<TestFileWriter>::from_prefix(write_prefix);
-
-    while write_command.needs_input() || write_command.needs_output() {
-        if write_command.needs_input() {
-            let _blk = usb_ctx.read_block(&mut test_read_buffer).unwrap();
-            let _write = write_command.input_block(&test_read_buffer).unwrap();
-        }
-        if write_command.needs_output() {
-            let _written = write_command.output_block(&mut test_write_buffer).unwrap();
-            let _blk = usb_ctx.write_block(&test_write_buffer).unwrap();
-        }
-    }
+    run_command(&mut write_command, &mut usb_ctx);
 
     let fl_ctx = unsafe { TestFileContext::get_context() };
     let written_fl = if let Some(f) = fl_ctx.files.get("fla") {
@@ -297,6 +989,1472 @@ fn test_write_file() {
         );
     };
     assert_eq!(written_fl, &file);
-    assert!(usb_ctx.input_buf.into_iter().all(|a| a == 0));
+    assert!(usb_ctx.input_buf.is_empty());
+    let response = usb_ctx.pull_output_frame().parse_response().unwrap();
+    assert_eq!(response, Response::ok());
+    assert!(usb_ctx.output_buf.is_empty());
+}
+
+#[test]
+fn test_write_rejects_extra_bytes() {
+    let write_prefix = WritePrefix {
+        flags: PrefixFlags::empty(),
+        file_name_length: 5,
+        file_length: 5,
+    };
+    let mut write_command = WriteCommandState::<TestFileWriter>::from_prefix(write_prefix);
+    let err = write_command
+        .input_frame(Frame::data(b"extraHello!".to_vec()))
+        .unwrap_err();
+    assert!(err.contains("past the end"), "Unexpected error {}", err);
+
+    let mut write_command = WriteCommandState::<TestFileWriter>::from_prefix(write_prefix);
+    let err = write_command
+        .input_frame(Frame::response(&Response::ok()))
+        .unwrap_err();
+    assert!(err.contains("Expected a data frame"), "Unexpected error {}", err);
+}
+
+#[test]
+fn test_large_write_prefix_parsing() {
+    let expected = WritePrefix {
+        flags: PrefixFlags::empty(),
+        file_name_length: 3,
+        file_length: 5 * 1024 * 1024 * 1024 + 7,
+    };
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_frame(Frame::prefix(&expected));
+    match usb_ctx.read_prefix().unwrap() {
+        Prefixes::Write(a) => {
+            assert_eq!(expected, a);
+        }
+        a => {
+            panic!(
+                "Got non-write prefix in write test: {:?} instead of expected {:?}.",
+                a, expected
+            );
+        }
+    }
+}
+
+#[test]
+fn test_read_large_file_header() {
+    let file_length: u64 = 5 * 1024 * 1024 * 1024 + 7;
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    fl_ctx.fake_files.insert("large".to_string(), file_length);
+
+    let read_prefix = ReadPrefix {
+        flags: PrefixFlags::empty(),
+        file_name_length: 5,
+    };
+    let mut read_command = ReadCommandState::<TestFileReader>::from_prefix(read_prefix);
+    read_command.input_frame(Frame::data(b"large".to_vec())).unwrap();
+
+    // Only stream the first few frames; the whole file would take far too long.
+    let mut data = Vec::new();
+    for _ in 0..3 {
+        assert!(read_command.needs_output());
+        let frame = read_command.output_frame(TEST_FRAME_PAYLOAD).unwrap();
+        assert_eq!(frame.kind, FrameKind::Data);
+        assert_eq!(frame.payload.len(), TEST_FRAME_PAYLOAD);
+        data.extend_from_slice(&frame.payload);
+    }
+    assert!(read_command.needs_output());
+
+    assert_eq!(prefixes::combine_bytes_u64(&data), file_length);
+    for (idx, bt) in data[READ_HEADER_LENGTH..].iter().enumerate() {
+        assert_eq!(*bt, fake_byte(idx as u64));
+    }
+}
+
+#[test]
+fn test_read_missing_file() {
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_data(b"missing");
+
+    let read_prefix = ReadPrefix {
+        flags: PrefixFlags::empty(),
+        file_name_length: 7,
+    };
+    let mut read_command = ReadCommandState::<TestFileReader>::from_prefix(read_prefix);
+    run_command(&mut read_command, &mut usb_ctx);
+
+    // No length or content is sent for a file that can't be opened.
+    let response = usb_ctx.pull_output_frame().parse_response().unwrap();
+    assert_eq!(response.code, ResponseCode::NotFound);
     assert!(usb_ctx.output_buf.is_empty());
 }
+
+#[test]
+fn test_write_existing_file() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    fl_ctx.files.insert("exists".to_string(), b"Old".to_vec());
+
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_data(b"exists");
+    usb_ctx.push_input_data(&[b'N'; 100]);
+    usb_ctx.push_input_data(&[b'N'; 50]);
+
+    let write_prefix = WritePrefix {
+        flags: PrefixFlags::empty(),
+        file_name_length: 6,
+        file_length: 150,
+    };
+    let mut write_command = WriteCommandState::<TestFileWriter>::from_prefix(write_prefix);
+    run_command(&mut write_command, &mut usb_ctx);
+
+    // The rest of the file content was consumed even though it was dropped.
+    assert!(usb_ctx.input_buf.is_empty());
+    assert_eq!(fl_ctx.files.get("exists").unwrap(), &b"Old".to_vec());
+    let response = usb_ctx.pull_output_frame().parse_response().unwrap();
+    assert_eq!(response.code, ResponseCode::Exists);
+}
+
+#[test]
+fn test_checksum_vectors() {
+    let mut crc = Checksum::new(ChecksumKind::Crc32c);
+    crc.update(b"1234");
+    crc.update(b"56789");
+    assert_eq!(checksum::to_hex(&crc.finish()), "e3069283");
+
+    let mut sha = Checksum::new(ChecksumKind::Sha256);
+    sha.update(b"abc");
+    assert_eq!(
+        checksum::to_hex(&sha.finish()),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+
+    let mut sha = Checksum::new(ChecksumKind::Sha256);
+    sha.update(&[b'a'; 1000]);
+    assert_eq!(
+        checksum::to_hex(&sha.finish()),
+        "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3"
+    );
+}
+
+/// Runs a write command to completion over the given input bytes, returning
+/// the server's response.
+#[cfg(test)]
+fn run_write_command(prefix: WritePrefix, input: &[u8]) -> Response {
+    let mut usb_ctx = TestUsbDevice::empty();
+    for chunk in input.chunks(TEST_FRAME_PAYLOAD) {
+        usb_ctx.push_input_data(chunk);
+    }
+    let mut write_command = WriteCommandState::<TestFileWriter>::from_prefix(prefix);
+    run_command(&mut write_command, &mut usb_ctx);
+    assert!(usb_ctx.input_buf.is_empty());
+    usb_ctx.pull_output_frame().parse_response().unwrap()
+}
+
+#[test]
+fn test_write_file_checksum() {
+    let content = [b'c'; 250];
+    let mut sha = Checksum::new(ChecksumKind::Sha256);
+    sha.update(&content);
+    let digest = sha.finish();
+
+    let mut input = b"cksum_ok".to_vec();
+    input.extend_from_slice(&content);
+    input.extend_from_slice(&digest);
+    let prefix = WritePrefix {
+        flags: PrefixFlags::empty().with_checksum(ChecksumKind::Sha256),
+        file_name_length: 8,
+        file_length: content.len() as u64,
+    };
+    let response = run_write_command(prefix, &input);
+    assert_eq!(response.code, ResponseCode::Ok);
+    assert_eq!(response.checksum, digest);
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    assert_eq!(fl_ctx.files.get("cksum_ok").unwrap(), &content.to_vec());
+}
+
+#[test]
+fn test_write_file_checksum_mismatch() {
+    let content = [b'c'; 250];
+    let mut input = b"cksum_bad".to_vec();
+    input.extend_from_slice(&content);
+    input.extend_from_slice(&[0, 1, 2, 3]);
+    let prefix = WritePrefix {
+        flags: PrefixFlags::empty().with_checksum(ChecksumKind::Crc32c),
+        file_name_length: 9,
+        file_length: content.len() as u64,
+    };
+    let response = run_write_command(prefix, &input);
+    assert_eq!(response.code, ResponseCode::ChecksumMismatch);
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    assert!(fl_ctx.files.get("cksum_bad").is_none());
+}
+
+#[test]
+fn test_read_file_checksum() {
+    let content = [b'r'; 150];
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    fl_ctx.files.insert("cksum_read".to_string(), content.to_vec());
+
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_data(b"cksum_read");
+
+    let read_prefix = ReadPrefix {
+        flags: PrefixFlags::empty().with_checksum(ChecksumKind::Crc32c),
+        file_name_length: 10,
+    };
+    let mut read_command = ReadCommandState::<TestFileReader>::from_prefix(read_prefix);
+    run_command(&mut read_command, &mut usb_ctx);
+
+    let (_data, end) = usb_ctx.pull_output_data();
+    let response = end.parse_response().unwrap();
+    let mut crc = Checksum::new(ChecksumKind::Crc32c);
+    crc.update(&content);
+    assert_eq!(response.code, ResponseCode::Ok);
+    assert_eq!(response.checksum, crc.finish());
+}
+
+/// Runs a read command to completion for the given name, returning the data
+/// sent after the length header and the server's response.
+#[cfg(test)]
+fn run_read_command(flags: PrefixFlags, name: &str) -> (Vec<u8>, Response) {
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_data(name.as_bytes());
+    let prefix = ReadPrefix {
+        flags,
+        file_name_length: name.len() as u16,
+    };
+    let mut read_command = ReadCommandState::<TestFileReader>::from_prefix(prefix);
+    run_command(&mut read_command, &mut usb_ctx);
+    let (data, end) = usb_ctx.pull_output_data();
+    let content = data.get(READ_HEADER_LENGTH..).unwrap_or(&[]).to_vec();
+    (content, end.parse_response().unwrap())
+}
+
+/// Runs a command that takes its path names as input to completion, returning
+/// the data it sent and the server's response.
+#[cfg(test)]
+fn run_named_command(prefix: Prefixes, input: &[u8]) -> (Vec<u8>, Response) {
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_data(input);
+    let mut command = CommandStates::<TestFileReader, TestFileWriter>::from_prefix(prefix);
+    run_command(&mut command, &mut usb_ctx);
+    assert!(usb_ctx.input_buf.is_empty());
+    let (data, end) = usb_ctx.pull_output_data();
+    (data, end.parse_response().unwrap())
+}
+
+/// Builds the input for a write of the given name and content.
+#[cfg(test)]
+fn write_input(flags: PrefixFlags, name: &str, content: &[u8]) -> (WritePrefix, Vec<u8>) {
+    let prefix = WritePrefix {
+        flags,
+        file_name_length: name.len() as u16,
+        file_length: content.len() as u64,
+    };
+    let mut input = name.as_bytes().to_vec();
+    input.extend_from_slice(content);
+    if let Some(kind) = flags.checksum() {
+        let mut check = Checksum::new(kind);
+        check.update(content);
+        input.extend_from_slice(&check.finish());
+    }
+    (prefix, input)
+}
+
+#[test]
+fn test_prefix_flags_round_trip() {
+    let flags = (PrefixFlags::OVERWRITE | PrefixFlags::VERIFY).with_checksum(ChecksumKind::Sha256);
+    let write = WritePrefix {
+        flags,
+        file_name_length: 4,
+        file_length: 10,
+    };
+    let bytes = write.serialize();
+    assert_eq!(bytes[0], Opcode::Write.to_byte());
+    assert_eq!(Prefixes::parse_prefix(bytes), Some(Prefixes::Write(write)));
+    assert!(flags.contains(PrefixFlags::VERIFY));
+    assert!(!flags.contains(PrefixFlags::APPEND));
+    assert_eq!(flags.checksum(), Some(ChecksumKind::Sha256));
+
+    let read = ReadPrefix {
+        flags: PrefixFlags::RECURSIVE | PrefixFlags::FOLLOW_LINKS,
+        file_name_length: 4,
+    };
+    assert_eq!(Prefixes::parse_prefix(read.serialize()), Some(Prefixes::Read(read)));
+}
+
+#[test]
+fn test_write_overwrite_and_append() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    fl_ctx.files.insert("replace_me".to_string(), b"Old".to_vec());
+    fl_ctx.files.insert("append_me".to_string(), b"Old".to_vec());
+
+    let (prefix, input) = write_input(PrefixFlags::OVERWRITE, "replace_me", b"New");
+    assert_eq!(run_write_command(prefix, &input), Response::ok());
+    assert_eq!(fl_ctx.files.get("replace_me").unwrap(), &b"New".to_vec());
+
+    let flags = (PrefixFlags::APPEND | PrefixFlags::VERIFY).with_checksum(ChecksumKind::Crc32c);
+    let (prefix, input) = write_input(flags, "append_me", b"New");
+    assert!(run_write_command(prefix, &input).is_ok());
+    assert_eq!(fl_ctx.files.get("append_me").unwrap(), &b"OldNew".to_vec());
+
+    // A failed append keeps what was there before.
+    let (prefix, mut input) = write_input(flags, "append_me", b"Bad");
+    let digest_start = input.len() - 4;
+    input[digest_start] ^= 0xFF;
+    let response = run_write_command(prefix, &input);
+    assert_eq!(response.code, ResponseCode::ChecksumMismatch);
+    assert_eq!(fl_ctx.files.get("append_me").unwrap(), &b"OldNew".to_vec());
+}
+
+#[test]
+fn test_write_create_parents() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    let (prefix, input) = write_input(PrefixFlags::empty(), "new/parents/fl", b"Hello");
+    assert_eq!(run_write_command(prefix, &input).code, ResponseCode::NotFound);
+    assert!(fl_ctx.files.get("new/parents/fl").is_none());
+
+    let (prefix, input) = write_input(PrefixFlags::CREATE_PARENTS, "new/parents/fl", b"Hello");
+    assert!(run_write_command(prefix, &input).is_ok());
+    assert!(fl_ctx.dirs.contains("new"));
+    assert!(fl_ctx.dirs.contains("new/parents"));
+    assert_eq!(fl_ctx.files.get("new/parents/fl").unwrap(), &b"Hello".to_vec());
+}
+
+#[test]
+fn test_follow_links() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    fl_ctx.files.insert("link_target".to_string(), b"Target".to_vec());
+    fl_ctx.links.insert("link".to_string(), "link_target".to_string());
+
+    let (_, response) = run_read_command(PrefixFlags::empty(), "link");
+    assert_eq!(response.code, ResponseCode::InvalidInput);
+    let (content, response) = run_read_command(PrefixFlags::FOLLOW_LINKS, "link");
+    assert!(response.is_ok());
+    assert_eq!(content, b"Target".to_vec());
+
+    let (prefix, input) = write_input(PrefixFlags::OVERWRITE, "link", b"New");
+    assert_eq!(run_write_command(prefix, &input).code, ResponseCode::InvalidInput);
+    let (prefix, input) = write_input(PrefixFlags::OVERWRITE | PrefixFlags::FOLLOW_LINKS, "link", b"New");
+    assert!(run_write_command(prefix, &input).is_ok());
+    assert_eq!(fl_ctx.files.get("link_target").unwrap(), &b"New".to_vec());
+}
+
+#[test]
+fn test_write_verify() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    fl_ctx.corrupt_writes.insert("bad_storage".to_string());
+    let flags = PrefixFlags::empty().with_checksum(ChecksumKind::Sha256);
+
+    // Without verifying, the corruption goes unnoticed.
+    let (prefix, input) = write_input(flags, "bad_storage", b"Hello");
+    assert!(run_write_command(prefix, &input).is_ok());
+    fl_ctx.files.remove("bad_storage");
+
+    let (prefix, input) = write_input(flags | PrefixFlags::VERIFY, "bad_storage", b"Hello");
+    let response = run_write_command(prefix, &input);
+    assert_eq!(response.code, ResponseCode::ChecksumMismatch);
+    assert!(fl_ctx.files.get("bad_storage").is_none());
+
+    let (prefix, input) = write_input(flags | PrefixFlags::VERIFY, "good_storage", b"Hello");
+    assert!(run_write_command(prefix, &input).is_ok());
+}
+
+#[test]
+fn test_read_verify_and_recursive() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    fl_ctx.dirs.insert("tree".to_string());
+    fl_ctx.dirs.insert("tree/sub".to_string());
+    fl_ctx.files.insert("tree/a".to_string(), b"A".to_vec());
+    fl_ctx.files.insert("tree/sub/b".to_string(), b"B".to_vec());
+
+    let (content, response) = run_read_command(PrefixFlags::empty(), "tree/");
+    assert!(response.is_ok());
+    assert_eq!(content, b"a\0".to_vec());
+    let (content, response) = run_read_command(PrefixFlags::RECURSIVE, "tree/");
+    assert!(response.is_ok());
+    assert_eq!(content, b"a\0sub/b\0".to_vec());
+
+    let flags = PrefixFlags::VERIFY.with_checksum(ChecksumKind::Crc32c);
+    let (content, response) = run_read_command(flags, "tree/a");
+    assert_eq!(response.code, ResponseCode::Ok);
+    assert_eq!(content, b"A".to_vec());
+}
+
+#[test]
+fn test_flags_rejected() {
+    let (_, response) = run_read_command(PrefixFlags::OVERWRITE, "anything");
+    assert_eq!(response.code, ResponseCode::InvalidInput);
+    let (_, response) = run_read_command(PrefixFlags::VERIFY, "anything");
+    assert_eq!(response.code, ResponseCode::InvalidInput);
+    let (_, response) = run_read_command(PrefixFlags::from_bits(0x4000), "anything");
+    assert_eq!(response.code, ResponseCode::Protocol);
+
+    let flags = (PrefixFlags::OVERWRITE | PrefixFlags::APPEND).with_checksum(ChecksumKind::Sha256);
+    let (prefix, input) = write_input(flags, "never_written", b"Hello");
+    assert_eq!(run_write_command(prefix, &input).code, ResponseCode::InvalidInput);
+    let (prefix, input) = write_input(PrefixFlags::RECURSIVE, "never_written", b"Hello");
+    assert_eq!(run_write_command(prefix, &input).code, ResponseCode::InvalidInput);
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    assert!(fl_ctx.files.get("never_written").is_none());
+}
+
+#[test]
+fn test_opcode_prefix_parsing() {
+    let read_bts: [u8; PREFIX_LENGTH] = [
+        0x01, 0x00, 0x00, 0x20, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+    let read = ReadPrefix {
+        flags: PrefixFlags::RECURSIVE,
+        file_name_length: 16,
+    };
+    assert_eq!(Prefixes::decode(read_bts), Ok(Prefixes::Read(read)));
+    assert_eq!(read.serialize(), read_bts);
+
+    let write_bts: [u8; PREFIX_LENGTH] = [
+        0x02, 0x00, 0x00, 0x04, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00,
+    ];
+    let write = WritePrefix {
+        flags: PrefixFlags::OVERWRITE,
+        file_name_length: 16,
+        file_length: 4096,
+    };
+    assert_eq!(Prefixes::decode(write_bts), Ok(Prefixes::Write(write)));
+    assert_eq!(write.serialize(), write_bts);
+    assert_eq!(Prefixes::Write(write).opcode(), Opcode::Write);
+
+    for opcode in [Opcode::Handshake, Opcode::Read, Opcode::Write].iter() {
+        assert_eq!(Opcode::from_byte(opcode.to_byte()), Some(*opcode));
+    }
+    assert_eq!(Opcode::from_legacy_byte(0x00), Opcode::Read);
+    assert_eq!(Opcode::from_legacy_byte(0x03), Opcode::Read);
+    assert_eq!(Opcode::from_legacy_byte(0x80), Opcode::Write);
+}
+
+#[test]
+fn test_unknown_opcode_rejected() {
+    let mut bts = [0u8; PREFIX_LENGTH];
+    bts[0] = 0x42;
+    let err = Prefixes::decode(bts).unwrap_err();
+    assert!(err.contains("Unknown opcode 0x42"), "Unexpected error {}", err);
+    assert_eq!(Prefixes::parse_prefix(bts), None);
+
+    let frame = Frame::new(FrameKind::Prefix, bts.to_vec());
+    let mut command = CommandStates::<TestFileReader, TestFileWriter>::from_frame(&frame, PROTOCOL_VERSION).unwrap();
+    let mut usb_ctx = TestUsbDevice::empty();
+    run_command(&mut command, &mut usb_ctx);
+    let response = usb_ctx.pull_output_frame().parse_response().unwrap();
+    assert_eq!(response.code, ResponseCode::Protocol);
+
+    // Whatever the refused command sent after its prefix is dropped.
+    let stray = Frame::data(b"payload".to_vec());
+    assert!(CommandStates::<TestFileReader, TestFileWriter>::from_frame(&stray, PROTOCOL_VERSION).is_none());
+
+    let short = Frame::new(FrameKind::Prefix, vec![Opcode::Read.to_byte()]);
+    match CommandStates::<TestFileReader, TestFileWriter>::from_frame(&short, PROTOCOL_VERSION) {
+        Some(CommandStates::Rejected(_)) => {}
+        _ => panic!("A short prefix was not refused."),
+    }
+}
+
+#[test]
+fn test_handshake_legacy_client() {
+    let client = HandshakePrefix {
+        protocol_version: MIN_PROTOCOL_VERSION,
+        build_version: 0,
+        max_frame_payload: TEST_FRAME_PAYLOAD as u16,
+        features: SUPPORTED_FEATURES,
+    };
+    let mut usb_ctx = TestUsbDevice::empty();
+    let mut command = HandshakeCommandState::from_prefix(client);
+    run_command(&mut command, &mut usb_ctx);
+    assert!(command.check_compatible().is_ok());
+    let reply: HandshakePrefix = usb_ctx.pull_output_frame().parse_prefix().unwrap();
+    assert_eq!(reply.protocol_version, MIN_PROTOCOL_VERSION);
+    assert_eq!(command.protocol_version(), MIN_PROTOCOL_VERSION);
+
+    // Once a legacy version is agreed, prefixes are only read in the legacy
+    // layout, even where their first byte is also an opcode.
+    let start = |bts: &[u8; PREFIX_LENGTH], version| {
+        CommandStates::<TestFileReader, TestFileWriter>::from_frame(&Frame::new(FrameKind::Prefix, bts.to_vec()), version).unwrap()
+    };
+    let ranged_read = ReadPrefix {
+        flags: PrefixFlags::OFFSET | PrefixFlags::LIMIT,
+        file_name_length: 16,
+    };
+    let mut bts = [0u8; PREFIX_LENGTH];
+    bts[0] = 0x03;
+    bts[3] = 0x10;
+    assert_eq!(Prefixes::decode_legacy(bts), Ok(Prefixes::Read(ranged_read)));
+    match start(&bts, MIN_PROTOCOL_VERSION) {
+        CommandStates::Read(_) => {}
+        _ => panic!("A legacy ranged read was not read as a read."),
+    }
+    match start(&bts, PROTOCOL_VERSION) {
+        CommandStates::Probe(_) => {}
+        _ => panic!("An opcode prefix was not read by its opcode."),
+    }
+    match start(&client.serialize(), MIN_PROTOCOL_VERSION) {
+        CommandStates::Handshake(_) => {}
+        _ => panic!("A handshake was not read in the legacy layout."),
+    }
+
+    let client = HandshakePrefix {
+        protocol_version: MIN_PROTOCOL_VERSION - 1,
+        ..client
+    };
+    let mut usb_ctx = TestUsbDevice::empty();
+    let mut command = HandshakeCommandState::from_prefix(client);
+    run_command(&mut command, &mut usb_ctx);
+    assert!(command.check_compatible().is_err());
+}
+
+/// Builds the data that starts a command at an offset.
+#[cfg(test)]
+fn name_and_offset(name: &str, offset: u64) -> Vec<u8> {
+    let mut bytes = name.as_bytes().to_vec();
+    bytes.extend_from_slice(&prefixes::extract_bytes_u64(offset));
+    bytes
+}
+
+#[test]
+fn test_probe_file() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    fl_ctx.files.insert("probed".to_string(), b"Hello, Switch!".to_vec());
+    let probe = |length: u64, name: &str| {
+        let prefix = ProbePrefix {
+            flags: PrefixFlags::empty().with_checksum(ChecksumKind::Crc32c),
+            file_name_length: name.len() as u16,
+            length,
+        };
+        let mut usb_ctx = TestUsbDevice::empty();
+        usb_ctx.push_input_data(name.as_bytes());
+        let mut command = ProbeCommandState::<TestFileReader>::from_prefix(prefix);
+        run_command(&mut command, &mut usb_ctx);
+        let (data, end) = usb_ctx.pull_output_data();
+        (data, end.parse_response().unwrap())
+    };
+    let digest_of = |content: &[u8]| {
+        let mut check = Checksum::new(ChecksumKind::Crc32c);
+        check.update(content);
+        check.finish()
+    };
+
+    let (data, response) = probe(5, "probed");
+    assert_eq!(data, prefixes::extract_bytes_u64(14).to_vec());
+    assert!(response.is_ok());
+    assert_eq!(response.checksum, digest_of(b"Hello"));
+
+    // Asking for more than the file has hashes all of it.
+    let (_, response) = probe(100, "probed");
+    assert_eq!(response.checksum, digest_of(b"Hello, Switch!"));
+
+    let (data, response) = probe(5, "never_probed");
+    assert!(data.is_empty());
+    assert_eq!(response.code, ResponseCode::NotFound);
+}
+
+#[test]
+fn test_read_file_offset() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    fl_ctx.files.insert("read_at".to_string(), b"Hello, Switch!".to_vec());
+    let flags = PrefixFlags::OFFSET.with_checksum(ChecksumKind::Sha256);
+    let read = |offset: u64| {
+        let mut usb_ctx = TestUsbDevice::empty();
+        usb_ctx.push_input_data(&name_and_offset("read_at", offset));
+        let prefix = ReadPrefix {
+            flags,
+            file_name_length: 7,
+        };
+        let mut read_command = ReadCommandState::<TestFileReader>::from_prefix(prefix);
+        run_command(&mut read_command, &mut usb_ctx);
+        let (data, end) = usb_ctx.pull_output_data();
+        (data, end.parse_response().unwrap())
+    };
+
+    let (data, response) = read(7);
+    assert!(response.is_ok());
+    assert_eq!(&data[0..READ_HEADER_LENGTH], &prefixes::extract_bytes_u64(7));
+    assert_eq!(&data[READ_HEADER_LENGTH..], b"Switch!");
+    let mut check = Checksum::new(ChecksumKind::Sha256);
+    check.update(b"Switch!");
+    assert_eq!(response.checksum, check.finish());
+
+    let (data, response) = read(14);
+    assert!(response.is_ok());
+    assert_eq!(data, prefixes::extract_bytes_u64(0).to_vec());
+
+    let (data, response) = read(15);
+    assert!(data.is_empty());
+    assert_eq!(response.code, ResponseCode::InvalidInput);
+}
+
+#[test]
+fn test_write_file_resume() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    fl_ctx.files.insert("resumed".to_string(), b"Hello, Wo??".to_vec());
+    let resume = |offset: u64, content: &[u8], flags: PrefixFlags| {
+        let flags = flags | PrefixFlags::OFFSET.with_checksum(ChecksumKind::Crc32c);
+        let (prefix, mut input) = write_input(flags, "resumed", content);
+        let tail = input.split_off(prefix.file_name_length as usize);
+        input.extend_from_slice(&prefixes::extract_bytes_u64(offset));
+        input.extend_from_slice(&tail);
+        run_write_command(prefix, &input)
+    };
+
+    assert!(resume(9, b"rld", PrefixFlags::VERIFY).is_ok());
+    assert_eq!(fl_ctx.files.get("resumed").unwrap(), &b"Hello, World".to_vec());
+
+    assert_eq!(resume(13, b"!", PrefixFlags::empty()).code, ResponseCode::InvalidInput);
+    assert_eq!(resume(12, b"!", PrefixFlags::OVERWRITE).code, ResponseCode::InvalidInput);
+    assert_eq!(fl_ctx.files.get("resumed").unwrap(), &b"Hello, World".to_vec());
+
+    // A resumed write that fails keeps what was there before the offset.
+    fl_ctx.corrupt_writes.insert("resumed".to_string());
+    assert_eq!(resume(5, b"!!!", PrefixFlags::VERIFY).code, ResponseCode::ChecksumMismatch);
+    assert_eq!(fl_ctx.files.get("resumed").unwrap(), &b"Hello".to_vec());
+}
+
+#[test]
+fn test_read_file_range() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    fl_ctx.files.insert("ranged".to_string(), b"Hello, Switch!".to_vec());
+    let read = |flags: PrefixFlags, request: &[u8]| {
+        let mut usb_ctx = TestUsbDevice::empty();
+        usb_ctx.push_input_data(request);
+        let prefix = ReadPrefix {
+            flags: flags.with_checksum(ChecksumKind::Crc32c) | PrefixFlags::VERIFY,
+            file_name_length: 6,
+        };
+        let mut read_command = ReadCommandState::<TestFileReader>::from_prefix(prefix);
+        run_command(&mut read_command, &mut usb_ctx);
+        let (data, end) = usb_ctx.pull_output_data();
+        (data, end.parse_response().unwrap())
+    };
+
+    let mut request = name_and_offset("ranged", 2);
+    request.extend_from_slice(&prefixes::extract_bytes_u64(5));
+    let (data, response) = read(PrefixFlags::OFFSET | PrefixFlags::LIMIT, &request);
+    assert!(response.is_ok(), "Unexpected response {}", response);
+    assert_eq!(&data[0..READ_HEADER_LENGTH], &prefixes::extract_bytes_u64(5));
+    assert_eq!(&data[READ_HEADER_LENGTH..], b"llo, ");
+
+    // A range past the end of the file stops at the end.
+    let mut request = name_and_offset("ranged", 7);
+    request.extend_from_slice(&prefixes::extract_bytes_u64(100));
+    let (data, response) = read(PrefixFlags::OFFSET | PrefixFlags::LIMIT, &request);
+    assert!(response.is_ok());
+    assert_eq!(&data[READ_HEADER_LENGTH..], b"Switch!");
+
+    let request = name_and_offset("ranged", 4);
+    let (data, response) = read(PrefixFlags::LIMIT, &request);
+    assert!(response.is_ok());
+    assert_eq!(&data[READ_HEADER_LENGTH..], b"Hell");
+}
+
+#[test]
+fn test_write_file_abort() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    let abort = |name: &str, policy: AbortPolicy| {
+        let (prefix, input) = write_input(PrefixFlags::empty(), name, b"Hello, Switch!");
+        let mut usb_ctx = TestUsbDevice::empty();
+        usb_ctx.push_input_data(&input[0..name.len() + 5]);
+        usb_ctx.push_input_frame(Frame::abort(policy));
+        let mut write_command = CommandStates::<TestFileReader, TestFileWriter>::from_prefix(Prefixes::Write(prefix));
+        write_command.input_frame(usb_ctx.read_frame().unwrap()).unwrap();
+        assert!(write_command.needs_input());
+        let frame = usb_ctx.read_frame().unwrap();
+        assert_eq!(frame.kind, FrameKind::Abort);
+        let response = write_command.abort(frame.parse_abort().unwrap());
+        assert_eq!(response.code, ResponseCode::Aborted);
+        assert!(!write_command.needs_input() && !write_command.needs_output());
+    };
+
+    abort("aborted_deleted", AbortPolicy::Delete);
+    assert!(!fl_ctx.files.contains_key("aborted_deleted"));
+
+    abort("aborted_kept", AbortPolicy::Keep);
+    assert_eq!(fl_ctx.files.get("aborted_kept").unwrap(), &b"Hello".to_vec());
+}
+
+#[test]
+fn test_read_file_abort() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    let content: Vec<u8> = (0..TEST_FRAME_PAYLOAD * 10).map(|idx| idx as u8).collect();
+    fl_ctx.files.insert("aborted_read".to_string(), content);
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_data(b"aborted_read");
+    let prefix = ReadPrefix {
+        flags: PrefixFlags::empty(),
+        file_name_length: "aborted_read".len() as u16,
+    };
+    let mut read_command = CommandStates::<TestFileReader, TestFileWriter>::from_prefix(Prefixes::Read(prefix));
+    for _ in 0..4 {
+        assert!(!usb_ctx.step_command(&mut read_command, TEST_FRAME_PAYLOAD).unwrap());
+    }
+
+    // The abort is noticed between frames of output, long before the end of
+    // the file.
+    usb_ctx.push_input_frame(Frame::abort(AbortPolicy::Delete));
+    assert!(usb_ctx.step_command(&mut read_command, TEST_FRAME_PAYLOAD).unwrap());
+    let mut data_frames = 0;
+    let end = loop {
+        let frame = usb_ctx.pull_output_frame();
+        if frame.kind != FrameKind::Data {
+            break frame;
+        }
+        data_frames += 1;
+    };
+    assert_eq!(data_frames, 3);
+    assert_eq!(end.parse_response().unwrap().code, ResponseCode::Aborted);
+    assert!(!read_command.needs_input() && !read_command.needs_output());
+}
+
+#[test]
+fn test_refused_input_ends_command() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    let (prefix, input) = write_input(PrefixFlags::empty(), "refused_input", b"Hello world");
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_data(&input[0..16]);
+    usb_ctx.push_input_frame(Frame::prefix(&prefix));
+    usb_ctx.push_input_data(&input[16..]);
+    let mut write_command = CommandStates::<TestFileReader, TestFileWriter>::from_prefix(Prefixes::Write(prefix));
+    assert!(!usb_ctx.step_command(&mut write_command, TEST_FRAME_PAYLOAD).unwrap());
+
+    // A frame the command cannot take ends it with a response, and the
+    // partial file goes with it.
+    assert!(usb_ctx.step_command(&mut write_command, TEST_FRAME_PAYLOAD).unwrap());
+    let response = usb_ctx.pull_output_frame().parse_response().unwrap();
+    assert_eq!(response.code, ResponseCode::Protocol);
+    assert!(response.message.contains("Expected a data frame"), "Unexpected response {}", response);
+    assert!(!fl_ctx.files.contains_key("refused_input"));
+    let stray = usb_ctx.read_frame().unwrap();
+    assert!(CommandStates::<TestFileReader, TestFileWriter>::from_frame(&stray, PROTOCOL_VERSION).is_none());
+}
+
+#[test]
+fn test_new_session_resets_frames() {
+    let handshake = HandshakePrefix {
+        protocol_version: PROTOCOL_VERSION,
+        build_version: 0,
+        max_frame_payload: TEST_FRAME_PAYLOAD as u16,
+        features: SUPPORTED_FEATURES,
+    };
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_frame(Frame::prefix(&handshake));
+    usb_ctx.push_input_data(b"left over");
+    assert_eq!(usb_ctx.read_frame().unwrap().sequence, 0);
+    assert_eq!(usb_ctx.read_frame().unwrap().sequence, 1);
+    usb_ctx.write_frame(Frame::prefix(&handshake)).unwrap();
+    assert_eq!(usb_ctx.pull_output_frame().sequence, 0);
+
+    // A client that restarts numbers its frames from 0 again.
+    let mut new_client = FrameEncoder::new();
+    usb_ctx.push_input(&new_client.encode(Frame::prefix(&handshake)));
+    usb_ctx.push_input(&new_client.encode(Frame::data(b"name".to_vec())));
+    let frame = usb_ctx.read_frame().unwrap();
+    assert!(frame.starts_session());
+    assert_eq!(usb_ctx.read_frame().unwrap().payload, b"name".to_vec());
+    usb_ctx.start_session();
+    usb_ctx.write_frame(Frame::prefix(&handshake)).unwrap();
+    let mut new_client = FrameDecoder::new();
+    new_client.push_bytes(&usb_ctx.output_buf);
+    assert_eq!(new_client.next_frame().unwrap().unwrap().sequence, 0);
+
+    // A new session's handshake can arrive split right after its header.
+    let bytes = FrameEncoder::new().encode(Frame::prefix(&handshake));
+    usb_ctx.decoder.push_bytes(&bytes[0..FRAME_HEADER_LENGTH]);
+    assert!(usb_ctx.decoder.next_frame().unwrap().is_none());
+    usb_ctx.decoder.push_bytes(&bytes[FRAME_HEADER_LENGTH..]);
+    assert!(usb_ctx.decoder.next_frame().unwrap().unwrap().starts_session());
+
+    // Anything else numbered 0 is still a lost frame.
+    usb_ctx.push_input(&FrameEncoder::new().encode(Frame::data(b"stray".to_vec())));
+    assert!(usb_ctx.read_frame().is_err());
+}
+
+#[test]
+fn test_list_dir() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    fl_ctx.dirs.insert("listed".to_string());
+    fl_ctx.dirs.insert("listed/sub".to_string());
+    fl_ctx.files.insert("listed/b.txt".to_string(), b"Hello".to_vec());
+    fl_ctx.files.insert("listed/sub/hidden.txt".to_string(), Vec::new());
+    fl_ctx.fake_files.insert("listed/a.bin".to_string(), 0x1_0000_0000);
+    fl_ctx.mtimes.insert("listed/b.txt".to_string(), 1_500_000_000);
+    let list = |name: &str, flags: PrefixFlags, start: u32, max_entries: u32| {
+        let mut usb_ctx = TestUsbDevice::empty();
+        usb_ctx.push_input_data(name.as_bytes());
+        let prefix = ListPrefix {
+            flags: flags.with_checksum(ChecksumKind::Crc32c),
+            file_name_length: name.len() as u16,
+            start,
+            max_entries,
+        };
+        assert_eq!(Prefixes::decode(prefix.serialize()), Ok(Prefixes::List(prefix)));
+        let mut list_command = ListCommandState::<TestFileReader>::from_prefix(prefix);
+        run_command(&mut list_command, &mut usb_ctx);
+        let (data, end) = usb_ctx.pull_output_data();
+        let response = end.parse_response().unwrap();
+        let mut check = Checksum::new(ChecksumKind::Crc32c);
+        check.update(&data);
+        (data, response, check.finish())
+    };
+
+    let (data, response, digest) = list("listed", PrefixFlags::empty(), 0, 10);
+    assert!(response.is_ok(), "Unexpected response {}", response);
+    assert_eq!(response.checksum, digest);
+    let page = ListPage::parse(&data).unwrap();
+    assert_eq!(page.total, 3);
+    let entries: Vec<(&str, EntryKind, u64, u64)> = page
+        .entries
+        .iter()
+        .map(|ent| (ent.name.as_str(), ent.kind, ent.size, ent.modified))
+        .collect();
+    assert_eq!(
+        entries,
+        vec![
+            ("a.bin", EntryKind::File, 0x1_0000_0000, 0),
+            ("b.txt", EntryKind::File, 5, 1_500_000_000),
+            ("sub", EntryKind::Dir, 0, 0),
+        ]
+    );
+
+    // Later pages pick up where the last one stopped.
+    let (data, _, _) = list("listed/", PrefixFlags::empty(), 1, 1);
+    let page = ListPage::parse(&data).unwrap();
+    assert_eq!(page.total, 3);
+    assert_eq!(page.entries.len(), 1);
+    assert_eq!(page.entries[0].name, "b.txt");
+
+    // A recursive listing names everything by its path inside the directory,
+    // each directory just before its contents.
+    let (data, response, _) = list("listed", PrefixFlags::RECURSIVE, 0, 10);
+    assert!(response.is_ok(), "Unexpected response {}", response);
+    let page = ListPage::parse(&data).unwrap();
+    let names: Vec<&str> = page.entries.iter().map(|ent| ent.name.as_str()).collect();
+    assert_eq!(names, vec!["a.bin", "b.txt", "sub", "sub/hidden.txt"]);
+    assert_eq!(page.total, 4);
+
+    let (data, response, _) = list("not_listed", PrefixFlags::empty(), 0, 10);
+    assert!(data.is_empty());
+    assert_eq!(response.code, ResponseCode::NotFound);
+
+    // The server keeps its place between pages, so the next page comes from
+    // the first listing even if the directory has changed since.
+    let page_of = |start: u32, cursor: Option<commands::ListCursor>| {
+        let mut usb_ctx = TestUsbDevice::empty();
+        usb_ctx.push_input_data(b"listed");
+        let prefix = ListPrefix {
+            flags: PrefixFlags::empty(),
+            file_name_length: 6,
+            start,
+            max_entries: 2,
+        };
+        let mut command = CommandStates::<TestFileReader, TestFileWriter>::from_prefix(Prefixes::List(prefix));
+        command.resume_listing(cursor);
+        run_command(&mut command, &mut usb_ctx);
+        let (data, _) = usb_ctx.pull_output_data();
+        let names: Vec<String> = ListPage::parse(&data).unwrap().entries.into_iter().map(|ent| ent.name).collect();
+        (names, command.take_list_cursor())
+    };
+    let (names, cursor) = page_of(0, None);
+    assert_eq!(names, vec!["a.bin", "b.txt"]);
+    assert!(cursor.is_some());
+    fl_ctx.files.insert("listed/c.txt".to_string(), Vec::new());
+    let (names, cursor) = page_of(2, cursor);
+    assert_eq!(names, vec!["sub"]);
+    assert!(cursor.is_none());
+
+    // A page anywhere else lists the directory again.
+    let (_, cursor) = page_of(0, None);
+    let (names, _) = page_of(1, cursor);
+    assert_eq!(names, vec!["b.txt", "c.txt"]);
+    fl_ctx.files.remove("listed/c.txt");
+}
+
+#[test]
+fn test_fs_info() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    let sd_card = FsInfo {
+        total_bytes: 64 << 30,
+        free_bytes: 3 << 30,
+        cluster_size: 0x8000,
+        kind: FsKind::Fat32,
+    };
+    fl_ctx.mounts.insert("sdmc:/".to_string(), sd_card);
+    let fs_info = |name: &str| {
+        let mut usb_ctx = TestUsbDevice::empty();
+        usb_ctx.push_input_data(name.as_bytes());
+        let prefix = FsInfoPrefix {
+            flags: PrefixFlags::empty(),
+            file_name_length: name.len() as u16,
+        };
+        assert_eq!(Prefixes::decode(prefix.serialize()), Ok(Prefixes::FsInfo(prefix)));
+        let mut command = CommandStates::<TestFileReader, TestFileWriter>::from_prefix(Prefixes::FsInfo(prefix));
+        run_command(&mut command, &mut usb_ctx);
+        let (data, end) = usb_ctx.pull_output_data();
+        (data, end.parse_response().unwrap())
+    };
+
+    let (data, response) = fs_info("sdmc:/switch/tool.nro");
+    assert!(response.is_ok(), "Unexpected response {}", response);
+    assert_eq!(data.len(), nxusb::fsinfo::FS_INFO_LENGTH);
+    let info = FsInfo::parse(&data).unwrap();
+    assert_eq!(info, sd_card);
+    assert_eq!(info.space_for(0), 0);
+    assert_eq!(info.space_for(1), 0x8000);
+    assert_eq!(info.space_for(0x8001), 0x10000);
+
+    let (data, response) = fs_info("usb:/");
+    assert!(data.is_empty());
+    assert_eq!(response.code, ResponseCode::NotFound);
+}
+
+#[test]
+fn test_stat() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    fl_ctx.dirs.insert("statted".to_string());
+    fl_ctx.files.insert("statted/file.txt".to_string(), b"Hello".to_vec());
+    fl_ctx.mtimes.insert("statted/file.txt".to_string(), 1_500_000_000);
+    fl_ctx.links.insert("statted/link".to_string(), "statted/file.txt".to_string());
+    let stat = |name: &str, flags: PrefixFlags| {
+        let prefix = StatPrefix {
+            flags,
+            file_name_length: name.len() as u16,
+        };
+        assert_eq!(Prefixes::decode(prefix.serialize()), Ok(Prefixes::Stat(prefix)));
+        let (data, response) = run_named_command(Prefixes::Stat(prefix), name.as_bytes());
+        let entry = if data.is_empty() {
+            None
+        } else {
+            let (entry, used) = ListEntry::parse(&data).unwrap();
+            assert_eq!(used, data.len());
+            Some(entry)
+        };
+        (entry, response)
+    };
+
+    let (entry, response) = stat("statted/file.txt", PrefixFlags::empty());
+    assert!(response.is_ok());
+    assert_eq!(
+        entry,
+        Some(ListEntry {
+            name: "file.txt".to_owned(),
+            kind: EntryKind::File,
+            size: 5,
+            modified: 1_500_000_000,
+        })
+    );
+
+    let (entry, _) = stat("statted", PrefixFlags::empty());
+    assert_eq!(entry.unwrap().kind, EntryKind::Dir);
+
+    let (entry, _) = stat("statted/link", PrefixFlags::empty());
+    assert_eq!(entry.unwrap().kind, EntryKind::Other);
+    let (entry, _) = stat("statted/link", PrefixFlags::FOLLOW_LINKS);
+    let entry = entry.unwrap();
+    assert_eq!((entry.name.as_str(), entry.kind, entry.size), ("link", EntryKind::File, 5));
+
+    let (entry, response) = stat("statted/missing", PrefixFlags::empty());
+    assert_eq!(entry, None);
+    assert_eq!(response.code, ResponseCode::NotFound);
+}
+
+#[test]
+fn test_make_dir() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    let make_dir = |name: &str, flags: PrefixFlags| {
+        let prefix = MakeDirPrefix {
+            flags,
+            file_name_length: name.len() as u16,
+        };
+        assert_eq!(Prefixes::decode(prefix.serialize()), Ok(Prefixes::MakeDir(prefix)));
+        run_named_command(Prefixes::MakeDir(prefix), name.as_bytes()).1
+    };
+
+    assert_eq!(make_dir("made/newtool", PrefixFlags::empty()).code, ResponseCode::NotFound);
+    assert!(!fl_ctx.dirs.contains("made/newtool"));
+    assert!(make_dir("made/newtool", PrefixFlags::CREATE_PARENTS).is_ok());
+    assert!(fl_ctx.dirs.contains("made") && fl_ctx.dirs.contains("made/newtool"));
+
+    assert!(make_dir("made/newtool", PrefixFlags::CREATE_PARENTS).is_ok());
+    assert_eq!(make_dir("made/newtool", PrefixFlags::empty()).code, ResponseCode::Exists);
+    assert!(make_dir("made/newtool/bin", PrefixFlags::empty()).is_ok());
+    assert_eq!(make_dir("made/newtool", PrefixFlags::RECURSIVE).code, ResponseCode::InvalidInput);
+
+    // A push into the new tree then works without creating anything else.
+    let (prefix, input) = write_input(PrefixFlags::empty(), "made/newtool/bin/foo.nro", b"NRO0");
+    assert!(run_write_command(prefix, &input).is_ok());
+}
+
+#[test]
+fn test_remove() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    fl_ctx.dirs.insert("doomed".to_string());
+    fl_ctx.dirs.insert("doomed/sub".to_string());
+    fl_ctx.dirs.insert("doomed/empty".to_string());
+    fl_ctx.files.insert("doomed/a.txt".to_string(), b"A".to_vec());
+    fl_ctx.files.insert("doomed/sub/b.txt".to_string(), b"B".to_vec());
+    fl_ctx.files.insert("doomed/sub/kept.txt".to_string(), b"K".to_vec());
+    fl_ctx.links.insert("doomed/link".to_string(), "doomed/a.txt".to_string());
+    fl_ctx.locked.insert("doomed/sub/kept.txt".to_string());
+    let remove = |name: &str, flags: PrefixFlags, dir: bool| {
+        let prefix = RemovePrefix {
+            flags: flags.with_checksum(ChecksumKind::Crc32c),
+            file_name_length: name.len() as u16,
+            dir,
+        };
+        assert_eq!(Prefixes::decode(prefix.serialize()), Ok(Prefixes::Remove(prefix)));
+        let (data, response) = run_named_command(Prefixes::Remove(prefix), name.as_bytes());
+        if !data.is_empty() {
+            let mut check = Checksum::new(ChecksumKind::Crc32c);
+            check.update(&data);
+            assert_eq!(response.checksum, check.finish());
+        }
+        let records: Vec<(String, bool)> = nxusb::removal::parse_records(&data)
+            .unwrap()
+            .into_iter()
+            .map(|rec| (rec.path.clone(), rec.is_removed()))
+            .collect();
+        (records, response)
+    };
+
+    // Links are removed rather than followed, and directories are refused
+    // without the recursive flag.
+    let (records, response) = remove("doomed/link", PrefixFlags::empty(), false);
+    assert!(response.is_ok(), "Unexpected response {}", response);
+    assert_eq!(records, vec![("doomed/link".to_owned(), true)]);
+    assert!(fl_ctx.files.contains_key("doomed/a.txt"));
+    let (_, response) = remove("doomed", PrefixFlags::empty(), false);
+    assert_eq!(response.code, ResponseCode::InvalidInput);
+    let (_, response) = remove("doomed/a.txt", PrefixFlags::empty(), true);
+    assert_eq!(response.code, ResponseCode::InvalidInput);
+    let (_, response) = remove("doomed/sub", PrefixFlags::empty(), true);
+    assert_eq!(response.code, ResponseCode::Io);
+    let (records, response) = remove("doomed/empty", PrefixFlags::empty(), true);
+    assert!(response.is_ok(), "Unexpected response {}", response);
+    assert_eq!(records, vec![("doomed/empty".to_owned(), true)]);
+    assert_eq!(remove("doomed/empty", PrefixFlags::RECURSIVE, true).1.code, ResponseCode::InvalidInput);
+
+    // A recursive remove carries on past the locked file and reports it.
+    let (records, response) = remove("doomed", PrefixFlags::RECURSIVE, false);
+    assert_eq!(response.code, ResponseCode::Permission);
+    assert_eq!(
+        records,
+        vec![
+            ("doomed/sub/kept.txt".to_owned(), false),
+            ("doomed/sub/b.txt".to_owned(), true),
+            ("doomed/sub".to_owned(), false),
+            ("doomed/a.txt".to_owned(), true),
+            ("doomed".to_owned(), false),
+        ]
+    );
+    assert!(fl_ctx.files.contains_key("doomed/sub/kept.txt"));
+    assert!(!fl_ctx.files.contains_key("doomed/a.txt"));
+
+    let (records, response) = remove("doomed/missing", PrefixFlags::empty(), false);
+    assert_eq!(response.code, ResponseCode::NotFound);
+    assert_eq!(records, vec![("doomed/missing".to_owned(), false)]);
+}
+
+#[test]
+fn test_move() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    fl_ctx.dirs.insert("moving".to_string());
+    fl_ctx.dirs.insert("moving/tree".to_string());
+    fl_ctx.files.insert("moving/old.txt".to_string(), b"Old".to_vec());
+    fl_ctx.files.insert("moving/taken.txt".to_string(), b"Taken".to_vec());
+    fl_ctx.files.insert("moving/tree/leaf.txt".to_string(), b"Leaf".to_vec());
+    let move_path = |from: &str, to: &str, flags: PrefixFlags| {
+        let prefix = MovePrefix {
+            flags,
+            file_name_length: from.len() as u16,
+            dest_name_length: to.len() as u16,
+        };
+        assert_eq!(Prefixes::decode(prefix.serialize()), Ok(Prefixes::Move(prefix)));
+        run_named_command(Prefixes::Move(prefix), format!("{}{}", from, to).as_bytes()).1
+    };
+
+    assert!(move_path("moving/old.txt", "moving/new.txt", PrefixFlags::empty()).is_ok());
+    assert!(!fl_ctx.files.contains_key("moving/old.txt"));
+    assert_eq!(fl_ctx.files["moving/new.txt"], b"Old".to_vec());
+
+    let response = move_path("moving/new.txt", "moving/taken.txt", PrefixFlags::empty());
+    assert_eq!(response.code, ResponseCode::Exists);
+    assert_eq!(fl_ctx.files["moving/taken.txt"], b"Taken".to_vec());
+    assert!(move_path("moving/new.txt", "moving/taken.txt", PrefixFlags::OVERWRITE).is_ok());
+    assert_eq!(fl_ctx.files["moving/taken.txt"], b"Old".to_vec());
+
+    // Directories move along with everything in them.
+    assert!(move_path("moving/tree", "moving/branch", PrefixFlags::empty()).is_ok());
+    assert!(fl_ctx.dirs.contains("moving/branch") && !fl_ctx.dirs.contains("moving/tree"));
+    assert_eq!(fl_ctx.files["moving/branch/leaf.txt"], b"Leaf".to_vec());
+
+    assert_eq!(move_path("moving/gone.txt", "moving/any.txt", PrefixFlags::empty()).code, ResponseCode::NotFound);
+    assert_eq!(move_path("moving/taken.txt", "moving/x.txt", PrefixFlags::RECURSIVE).code, ResponseCode::InvalidInput);
+}
+
+#[test]
+fn test_write_overwrite_policy() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    fl_ctx.dirs.insert("policy".to_string());
+    fl_ctx.files.insert("policy/tool.nro".to_string(), b"Version 1".to_vec());
+    let push = |policy: OverwritePolicy, content: &[u8]| {
+        let flags = policy.flags().with_checksum(ChecksumKind::Crc32c) | PrefixFlags::VERIFY;
+        let (prefix, input) = write_input(flags, "policy/tool.nro", content);
+        run_write_command(prefix, &input)
+    };
+
+    assert_eq!(push(OverwritePolicy::Fail, b"Version 2").code, ResponseCode::Exists);
+
+    let response = push(OverwritePolicy::SkipIdentical, b"Version 1");
+    assert!(response.is_ok(), "Unexpected response {}", response);
+    assert!(response.message.contains("identical"), "Unexpected message {}", response.message);
+    // Same length but different content, then a different length.
+    let response = push(OverwritePolicy::SkipIdentical, b"Version 2");
+    assert!(response.is_ok() && response.message.is_empty(), "Unexpected response {}", response);
+    assert_eq!(fl_ctx.files["policy/tool.nro"], b"Version 2".to_vec());
+    assert!(push(OverwritePolicy::SkipIdentical, b"Version 10").is_ok());
+    assert_eq!(fl_ctx.files["policy/tool.nro"], b"Version 10".to_vec());
+
+    let response = push(OverwritePolicy::Rename, b"Version 11");
+    assert!(response.message.contains("policy/tool (1).nro"), "Unexpected response {}", response);
+    assert!(push(OverwritePolicy::Rename, b"Version 12").is_ok());
+    assert_eq!(fl_ctx.files["policy/tool.nro"], b"Version 10".to_vec());
+    assert_eq!(fl_ctx.files["policy/tool (1).nro"], b"Version 11".to_vec());
+    assert_eq!(fl_ctx.files["policy/tool (2).nro"], b"Version 12".to_vec());
+
+    assert!(push(OverwritePolicy::Overwrite, b"Version 13").is_ok());
+    assert_eq!(fl_ctx.files["policy/tool.nro"], b"Version 13".to_vec());
+    let flags = PrefixFlags::OVERWRITE | PrefixFlags::NEW_NAME;
+    let (prefix, input) = write_input(flags, "policy/tool.nro", b"Version 14");
+    assert_eq!(run_write_command(prefix, &input).code, ResponseCode::InvalidInput);
+
+    assert_eq!(commands::suffixed_name("sdmc:/a.b/tool", 3), "sdmc:/a.b/tool (3)");
+    assert_eq!(commands::suffixed_name(".hidden", 1), ".hidden (1)");
+}
+
+#[test]
+fn test_write_is_atomic() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    fl_ctx.files.insert("atomic.nro".to_string(), b"Old content".to_vec());
+    let temp = commands::temp_name("atomic.nro");
+    assert_eq!(temp, ".atomic.nro.nxusb-part");
+    assert_eq!(commands::temp_name("sdmc:/a/b.nro"), "sdmc:/a/.b.nro.nxusb-part");
+
+    // A bad checksum leaves the old file in place.
+    let flags = PrefixFlags::OVERWRITE.with_checksum(ChecksumKind::Crc32c);
+    let (prefix, mut input) = write_input(flags, "atomic.nro", b"New content");
+    let last = input.len() - 1;
+    input[last] ^= 0xFF;
+    assert_eq!(run_write_command(prefix, &input).code, ResponseCode::ChecksumMismatch);
+    assert_eq!(fl_ctx.files["atomic.nro"], b"Old content".to_vec());
+    assert!(!fl_ctx.files.contains_key(&temp));
+
+    // So does a write that is cut off partway.
+    let (prefix, input) = write_input(flags, "atomic.nro", b"New content");
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_data(&input[0.."atomic.nro".len() + 3]);
+    let mut write_command = WriteCommandState::<TestFileWriter>::from_prefix(prefix);
+    write_command.input_frame(usb_ctx.read_frame().unwrap()).unwrap();
+    assert_eq!(fl_ctx.files[&temp], b"New".to_vec());
+    assert_eq!(fl_ctx.files["atomic.nro"], b"Old content".to_vec());
+    assert_eq!(write_command.abort(AbortPolicy::Delete).code, ResponseCode::Aborted);
+    assert_eq!(fl_ctx.files["atomic.nro"], b"Old content".to_vec());
+    assert!(!fl_ctx.files.contains_key(&temp));
+
+    // Keeping a partial write never puts it in place of the old file, but
+    // keeps it when there was no old file.
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_data(&input[0.."atomic.nro".len() + 3]);
+    let mut write_command = WriteCommandState::<TestFileWriter>::from_prefix(prefix);
+    write_command.input_frame(usb_ctx.read_frame().unwrap()).unwrap();
+    assert_eq!(write_command.abort(AbortPolicy::Keep).code, ResponseCode::Aborted);
+    assert_eq!(fl_ctx.files["atomic.nro"], b"Old content".to_vec());
+    assert!(!fl_ctx.files.contains_key(&temp));
+    let (new_prefix, new_input) = write_input(flags, "atomic_new.nro", b"New content");
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_data(&new_input[0.."atomic_new.nro".len() + 3]);
+    let mut write_command = WriteCommandState::<TestFileWriter>::from_prefix(new_prefix);
+    write_command.input_frame(usb_ctx.read_frame().unwrap()).unwrap();
+    assert_eq!(write_command.abort(AbortPolicy::Keep).code, ResponseCode::Aborted);
+    assert_eq!(fl_ctx.files["atomic_new.nro"], b"New".to_vec());
+    assert!(!fl_ctx.files.contains_key(&commands::temp_name("atomic_new.nro")));
+
+    assert!(run_write_command(prefix, &input).is_ok());
+    assert_eq!(fl_ctx.files["atomic.nro"], b"New content".to_vec());
+    assert!(!fl_ctx.files.contains_key(&temp));
+}
+
+#[test]
+fn test_split_files() {
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    let fat32 = FsInfo {
+        total_bytes: 32 << 30,
+        free_bytes: 16 << 30,
+        cluster_size: 0x8000,
+        kind: FsKind::Fat32,
+    };
+    fl_ctx.mounts.insert("fat:/".to_string(), fat32);
+    fl_ctx.mounts.insert("exfat:/".to_string(), FsInfo { kind: FsKind::ExFat, ..fat32 });
+    fl_ctx.part_sizes.insert("fat:/".to_string(), 100);
+    fl_ctx.dirs.insert("fat:".to_string());
+    fl_ctx.dirs.insert("exfat:".to_string());
+    let content: Vec<u8> = (0..250u32).map(|idx| (idx % 251) as u8).collect();
+    let flags = PrefixFlags::empty().with_checksum(ChecksumKind::Crc32c);
+
+    // A write past the part size on FAT32 lands in a concatenation directory.
+    let (prefix, input) = write_input(flags, "fat:/game.nsp", &content);
+    assert!(run_write_command(prefix, &input).is_ok());
+    assert!(!fl_ctx.files.contains_key("fat:/game.nsp"));
+    assert!(fl_ctx.split_dirs.contains("fat:/game.nsp"));
+    assert_eq!(fl_ctx.files["fat:/game.nsp/00"], content[0..100].to_vec());
+    assert_eq!(fl_ctx.files["fat:/game.nsp/01"], content[100..200].to_vec());
+    assert_eq!(fl_ctx.files["fat:/game.nsp/02"], content[200..250].to_vec());
+    let (prefix, input) = write_input(flags, "exfat:/game.nsp", &content);
+    assert!(run_write_command(prefix, &input).is_ok());
+    assert_eq!(fl_ctx.files["exfat:/game.nsp"], content);
+
+    // It reads back, lists and describes itself as one file.
+    let (data, response) = run_read_command(flags, "fat:/game.nsp");
+    assert!(response.is_ok(), "Unexpected response {}", response);
+    assert_eq!(data, content);
+    let stat = TestFileReader::stat("fat:/game.nsp", PrefixFlags::empty()).unwrap();
+    assert_eq!((stat.kind, stat.size), (EntryKind::File, 250));
+    let listed = TestFileReader::list_dir("fat:", PrefixFlags::RECURSIVE).unwrap();
+    let listed: Vec<(&str, EntryKind, u64)> = listed.iter().map(|ent| (ent.name.as_str(), ent.kind, ent.size)).collect();
+    assert_eq!(listed, vec![("game.nsp", EntryKind::File, 250)]);
+
+    // Appending carries on into a new part.
+    let (prefix, input) = write_input(flags | PrefixFlags::APPEND, "fat:/game.nsp", &content[0..60]);
+    assert!(run_write_command(prefix, &input).is_ok());
+    assert_eq!(fl_ctx.files["fat:/game.nsp/02"], content[200..250].iter().chain(&content[0..50]).cloned().collect::<Vec<u8>>());
+    assert_eq!(fl_ctx.files["fat:/game.nsp/03"], content[50..60].to_vec());
+
+    // A small file replacing it is a single file again, and removing a split
+    // file reports it as one entry.
+    let (prefix, input) = write_input(flags | PrefixFlags::OVERWRITE, "fat:/game.nsp", b"Small");
+    assert!(run_write_command(prefix, &input).is_ok());
+    assert_eq!(fl_ctx.files["fat:/game.nsp"], b"Small".to_vec());
+    assert!(!fl_ctx.split_dirs.contains("fat:/game.nsp"));
+    assert!(!fl_ctx.files.contains_key("fat:/game.nsp/00"));
+    let (prefix, input) = write_input(flags, "fat:/other.nsp", &content);
+    assert!(run_write_command(prefix, &input).is_ok());
+    let report = TestFileWriter::remove_path("fat:/other.nsp", PrefixFlags::empty(), false);
+    assert_eq!(report, vec![RemovalRecord::removed("fat:/other.nsp".to_owned(), EntryKind::File)]);
+    assert!(fl_ctx.entry_names().iter().all(|name| !name.starts_with("fat:/other.nsp")));
+}
+
+#[test]
+fn test_delta_transfers() {
+    use commands::{ReadDeltaCommandState, SignatureCommandState};
+    use nxusb::delta::{self, Decoded, DeltaDecoder, DeltaEncoder, DeltaOp, DeltaPlanner, Signature, SignatureBuilder};
+    use prefixes::{ReadDeltaPrefix, SignaturePrefix};
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    let old: Vec<u8> = (0..20_000u64).map(|idx| (idx * 7919 % 251) as u8).collect();
+    let mut new = old.clone();
+    new[5000..5100].copy_from_slice(&[0xAA; 100]);
+    new.splice(12_000..12_000, vec![0x55; 300]);
+    fl_ctx.files.insert("delta.bin".to_string(), old.clone());
+    let kind = ChecksumKind::Sha256;
+    let digest_of = |content: &[u8]| {
+        let mut check = Checksum::new(kind);
+        check.update(content);
+        check.finish()
+    };
+    let signature_of = |content: &[u8]| {
+        let mut builder = SignatureBuilder::new(2048, kind);
+        builder.input(content);
+        builder.finish()
+    };
+
+    // The server's signature of its copy is the one the client would make.
+    let prefix = SignaturePrefix {
+        flags: PrefixFlags::empty().with_checksum(kind),
+        file_name_length: 9,
+        block_size: 2048,
+    };
+    let mut usb_ctx = TestUsbDevice::empty();
+    usb_ctx.push_input_data(b"delta.bin");
+    let mut command = SignatureCommandState::<TestFileReader>::from_prefix(prefix);
+    run_command(&mut command, &mut usb_ctx);
+    let (data, end) = usb_ctx.pull_output_data();
+    let response = end.parse_response().unwrap();
+    assert!(response.is_ok(), "Unexpected response {}", response);
+    assert_eq!(response.checksum, digest_of(&data));
+    let signature = Signature::parse(&data, kind).unwrap();
+    assert_eq!(signature, signature_of(&old));
+
+    // A delta write against it rebuilds the new version from the old one and
+    // the few bytes that changed.
+    let mut planner = DeltaPlanner::new(signature);
+    planner.input(&new);
+    let ops = planner.finish();
+    assert!(delta::literal_len(&ops) < 2 * 2048 + 400);
+    let mut stream = vec![0u8; delta::encoded_len(&ops) as usize];
+    let filled = DeltaEncoder::new(ops).fill(&mut stream, |offset, buffer| {
+        let start = offset as usize;
+        buffer.copy_from_slice(&new[start..start + buffer.len()]);
+        Ok(buffer.len())
+    });
+    assert_eq!(filled, Ok(stream.len()));
+    let flags = (PrefixFlags::OVERWRITE | PrefixFlags::DELTA).with_checksum(kind);
+    let prefix = WritePrefix {
+        flags,
+        file_name_length: 9,
+        file_length: stream.len() as u64,
+    };
+    let mut input = b"delta.bin".to_vec();
+    input.extend_from_slice(&stream);
+    input.extend_from_slice(&digest_of(&new));
+    let response = run_write_command(prefix, &input);
+    assert!(response.is_ok(), "Unexpected response {}", response);
+    assert_eq!(fl_ctx.files["delta.bin"], new);
+
+    // A delta write has to replace a file and say what it should hash to.
+    let (prefix, input) = write_input(PrefixFlags::DELTA.with_checksum(kind), "delta.bin", &[]);
+    assert_eq!(run_write_command(prefix, &input).code, ResponseCode::InvalidInput);
+    let (prefix, input) = write_input(PrefixFlags::OVERWRITE | PrefixFlags::DELTA, "delta.bin", &[]);
+    assert_eq!(run_write_command(prefix, &input).code, ResponseCode::InvalidInput);
+    assert_eq!(fl_ctx.files["delta.bin"], new);
+
+    // A delta read sends the new version against the client's old copy.
+    let client_signature = signature_of(&old).serialize();
+    let prefix = ReadDeltaPrefix {
+        flags: PrefixFlags::empty().with_checksum(kind),
+        file_name_length: 9,
+        signature_length: client_signature.len() as u64,
+    };
+    let mut input = b"delta.bin".to_vec();
+    input.extend_from_slice(&client_signature);
+    let mut usb_ctx = TestUsbDevice::empty();
+    for chunk in input.chunks(TEST_FRAME_PAYLOAD) {
+        usb_ctx.push_input_data(chunk);
+    }
+    let mut command = ReadDeltaCommandState::<TestFileReader>::from_prefix(prefix);
+    run_command(&mut command, &mut usb_ctx);
+    let (data, end) = usb_ctx.pull_output_data();
+    let response = end.parse_response().unwrap();
+    assert!(response.is_ok(), "Unexpected response {}", response);
+    assert_eq!(response.checksum, digest_of(&new));
+    assert_eq!(prefixes::combine_bytes_u64(&data[0..delta::DELTA_HEADER_LENGTH]), new.len() as u64);
+    let mut rebuilt = Vec::new();
+    let mut decoder = DeltaDecoder::new();
+    let mut rest = &data[delta::DELTA_HEADER_LENGTH..];
+    while !rest.is_empty() {
+        let (used, piece) = decoder.input(rest).unwrap();
+        match piece {
+            Some(Decoded::Literal(bytes)) => rebuilt.extend_from_slice(bytes),
+            Some(Decoded::Copy { offset, length }) => rebuilt.extend_from_slice(&old[offset as usize..(offset + length) as usize]),
+            None => {}
+        }
+        rest = &rest[used..];
+    }
+    assert!(decoder.is_between_ops());
+    assert_eq!(rebuilt, new);
+
+    // Planning a large file is spread over frames, and an unchanged one is a
+    // single copy sent once the plan is done.
+    let large: Vec<u8> = (0..20 * 1024 * 1024u64).map(|idx| (idx * 7919 % 251) as u8).collect();
+    fl_ctx.files.insert("delta_large.bin".to_string(), large.clone());
+    let mut builder = SignatureBuilder::new(delta::MAX_BLOCK_SIZE, ChecksumKind::Crc32);
+    builder.input(&large);
+    let client_signature = builder.finish().serialize();
+    let prefix = ReadDeltaPrefix {
+        flags: PrefixFlags::empty().with_checksum(ChecksumKind::Crc32),
+        file_name_length: 15,
+        signature_length: client_signature.len() as u64,
+    };
+    let mut input = b"delta_large.bin".to_vec();
+    input.extend_from_slice(&client_signature);
+    let mut usb_ctx = TestUsbDevice::empty();
+    for chunk in input.chunks(TEST_FRAME_PAYLOAD) {
+        usb_ctx.push_input_data(chunk);
+    }
+    let mut command = ReadDeltaCommandState::<TestFileReader>::from_prefix(prefix);
+    run_command(&mut command, &mut usb_ctx);
+    assert_eq!(usb_ctx.pull_output_frame().payload, prefixes::extract_bytes_u64(large.len() as u64).to_vec());
+    let copy = DeltaOp::Copy {
+        offset: 0,
+        length: large.len() as u64,
+    };
+    assert_eq!(usb_ctx.pull_output_frame().payload, copy.header());
+    assert!(usb_ctx.pull_output_frame().parse_response().unwrap().is_ok());
+
+    // A signature too long to buffer is refused before any of it is read.
+    let prefix = ReadDeltaPrefix {
+        flags: PrefixFlags::empty().with_checksum(kind),
+        file_name_length: 9,
+        signature_length: Signature::max_len(kind) + 1,
+    };
+    let mut usb_ctx = TestUsbDevice::empty();
+    let mut command = ReadDeltaCommandState::<TestFileReader>::from_prefix(prefix);
+    assert!(!command.needs_input());
+    run_command(&mut command, &mut usb_ctx);
+    assert_eq!(usb_ctx.pull_output_frame().parse_response().unwrap().code, ResponseCode::Protocol);
+}
+
+#[test]
+fn test_hash() {
+    use nxusb::hashing::{self, HashRecord};
+    use prefixes::HashPrefix;
+    let fl_ctx = unsafe { TestFileContext::get_context() };
+    fl_ctx.dirs.insert("hashed".to_string());
+    fl_ctx.dirs.insert("hashed/sub".to_string());
+    fl_ctx.files.insert("hashed/abc.txt".to_string(), b"abc".to_vec());
+    fl_ctx.files.insert("hashed/sub/digits.txt".to_string(), b"123456789".to_vec());
+    fl_ctx.fake_files.insert("hashed_large".to_string(), 20 * 1024 * 1024);
+    let hash = |name: &str, flags: PrefixFlags, kind: ChecksumKind| {
+        let mut usb_ctx = TestUsbDevice::empty();
+        usb_ctx.push_input_data(name.as_bytes());
+        let prefix = HashPrefix {
+            flags: flags.with_checksum(kind),
+            file_name_length: name.len() as u16,
+        };
+        assert_eq!(Prefixes::decode(prefix.serialize()), Ok(Prefixes::Hash(prefix)));
+        let mut command = CommandStates::<TestFileReader, TestFileWriter>::from_prefix(Prefixes::Hash(prefix));
+        run_command(&mut command, &mut usb_ctx);
+        let mut data = Vec::new();
+        let mut empty_frames = 0;
+        let end = loop {
+            let frame = usb_ctx.pull_output_frame();
+            if frame.kind != FrameKind::Data {
+                break frame;
+            }
+            if frame.payload.is_empty() {
+                empty_frames += 1;
+            }
+            data.extend_from_slice(&frame.payload);
+        };
+        let response = end.parse_response().unwrap();
+        if !data.is_empty() {
+            let mut check = Checksum::new(kind);
+            check.update(&data);
+            assert_eq!(response.checksum, check.finish());
+        }
+        let records = hashing::parse_records(&data, kind).unwrap();
+        (records, response, empty_frames)
+    };
+
+    let (records, response, _) = hash("hashed/abc.txt", PrefixFlags::empty(), ChecksumKind::Sha256);
+    assert!(response.is_ok(), "Unexpected response {}", response);
+    let digest = checksum::from_hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad").unwrap();
+    assert_eq!(records, vec![HashRecord::hashed("hashed/abc.txt".to_owned(), 3, digest)]);
+
+    // Trees need the recursive flag, and their files come back sorted by
+    // name.
+    let (records, response, _) = hash("hashed", PrefixFlags::empty(), ChecksumKind::Crc32);
+    assert_eq!(response.code, ResponseCode::InvalidInput);
+    assert!(records.is_empty());
+    let (records, response, _) = hash("hashed", PrefixFlags::RECURSIVE, ChecksumKind::Crc32);
+    assert!(response.is_ok(), "Unexpected response {}", response);
+    let names: Vec<&str> = records.iter().map(|rec| rec.name.as_str()).collect();
+    assert_eq!(names, vec!["hashed/abc.txt", "hashed/sub/digits.txt"]);
+    assert_eq!(checksum::to_hex(&records[1].digest), "cbf43926");
+
+    // A large file sends empty frames while it is hashed.
+    let (records, response, empty_frames) = hash("hashed_large", PrefixFlags::empty(), ChecksumKind::Crc32);
+    assert!(response.is_ok(), "Unexpected response {}", response);
+    assert_eq!(records[0].size, 20 * 1024 * 1024);
+    assert!(empty_frames > 0);
+
+    assert_eq!(hash("hashed/missing", PrefixFlags::empty(), ChecksumKind::Sha256).1.code, ResponseCode::NotFound);
+    assert_eq!(hash("hashed/abc.txt", PrefixFlags::empty(), ChecksumKind::None).1.code, ResponseCode::InvalidInput);
+}
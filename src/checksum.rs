@@ -0,0 +1,331 @@
+/// The bits of a command's flags that select its checksum.
+pub const CHECKSUM_FLAG_MASK: u16 = 0x0003;
+
+/// The algorithms that can be used to check a transfer end to end.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum ChecksumKind {
+    None,
+    Crc32c,
+    Sha256,
+    /// The CRC-32 of zip and gzip, which only the hash command uses.
+    Crc32,
+}
+
+impl ChecksumKind {
+    /// Reads the checksum kind out of a command's flags, returning `None` if
+    /// the bits do not name a known algorithm.
+    pub fn from_flags(flags: u16) -> Option<ChecksumKind> {
+        match flags & CHECKSUM_FLAG_MASK {
+            0 => Some(ChecksumKind::None),
+            1 => Some(ChecksumKind::Crc32c),
+            2 => Some(ChecksumKind::Sha256),
+            3 => Some(ChecksumKind::Crc32),
+            _ => None,
+        }
+    }
+
+    /// The flag bits selecting this checksum kind.
+    pub fn to_flags(&self) -> u16 {
+        match self {
+            ChecksumKind::None => 0,
+            ChecksumKind::Crc32c => 1,
+            ChecksumKind::Sha256 => 2,
+            ChecksumKind::Crc32 => 3,
+        }
+    }
+
+    /// The number of bytes in a digest of this kind.
+    pub fn digest_len(&self) -> usize {
+        match self {
+            ChecksumKind::None => 0,
+            ChecksumKind::Crc32c | ChecksumKind::Crc32 => 4,
+            ChecksumKind::Sha256 => 32,
+        }
+    }
+}
+
+/// A running checksum over a stream of bytes.
+#[derive(Clone, Debug)]
+pub enum Checksum {
+    None,
+    Crc32c(Crc32c),
+    Sha256(Sha256),
+    Crc32(Crc32),
+}
+
+impl Checksum {
+    pub fn new(kind: ChecksumKind) -> Checksum {
+        match kind {
+            ChecksumKind::None => Checksum::None,
+            ChecksumKind::Crc32c => Checksum::Crc32c(Crc32c::new()),
+            ChecksumKind::Sha256 => Checksum::Sha256(Sha256::new()),
+            ChecksumKind::Crc32 => Checksum::Crc32(Crc32::new()),
+        }
+    }
+
+    pub fn kind(&self) -> ChecksumKind {
+        match self {
+            Checksum::None => ChecksumKind::None,
+            Checksum::Crc32c(_) => ChecksumKind::Crc32c,
+            Checksum::Sha256(_) => ChecksumKind::Sha256,
+            Checksum::Crc32(_) => ChecksumKind::Crc32,
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Checksum::None => {}
+            Checksum::Crc32c(c) => c.update(bytes),
+            Checksum::Sha256(s) => s.update(bytes),
+            Checksum::Crc32(c) => c.update(bytes),
+        }
+    }
+
+    /// Finishes the checksum, returning its big-endian digest.
+    pub fn finish(self) -> Vec<u8> {
+        match self {
+            Checksum::None => Vec::new(),
+            Checksum::Crc32c(c) => {
+                let crc = c.finish();
+                vec![(crc >> 24) as u8, (crc >> 16) as u8, (crc >> 8) as u8, crc as u8]
+            }
+            Checksum::Sha256(s) => s.finish().to_vec(),
+            Checksum::Crc32(c) => {
+                let crc = c.finish();
+                vec![(crc >> 24) as u8, (crc >> 16) as u8, (crc >> 8) as u8, crc as u8]
+            }
+        }
+    }
+}
+
+/// Finishes a running checksum and leaves `None` in its place, or returns an
+/// empty digest if there was none.
+pub fn take_digest(checksum: &mut Option<Checksum>) -> Vec<u8> {
+    checksum.take().map(|ck| ck.finish()).unwrap_or_default()
+}
+
+/// Formats a digest as lowercase hex.
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|bt| format!("{:02x}", bt)).collect()
+}
+
+/// Parses a digest written as hex in either case.
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16).ok())
+        .collect()
+}
+
+/// Builds the lookup table for a reflected CRC-32 with the given polynomial.
+fn crc_table(poly: u32) -> [u32; 256] {
+    let mut table = [0u32; 256];
+    for (idx, entry) in table.iter_mut().enumerate() {
+        let mut crc = idx as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ poly } else { crc >> 1 };
+        }
+        *entry = crc;
+    }
+    table
+}
+
+/// CRC-32C (Castagnoli), as used by iSCSI and ext4.
+#[derive(Clone)]
+pub struct Crc32c {
+    table: [u32; 256],
+    crc: u32,
+}
+
+impl Crc32c {
+    pub fn new() -> Crc32c {
+        Crc32c {
+            table: crc_table(0x82F6_3B78),
+            crc: 0xFFFF_FFFF,
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for bt in bytes {
+            self.crc = self.table[((self.crc ^ *bt as u32) & 0xFF) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        self.crc ^ 0xFFFF_FFFF
+    }
+}
+
+impl ::std::fmt::Debug for Crc32c {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Crc32c({:08x})", self.crc)
+    }
+}
+
+impl Default for Crc32c {
+    fn default() -> Crc32c {
+        Crc32c::new()
+    }
+}
+
+/// CRC-32 (IEEE 802.3), as used by zip and gzip.
+#[derive(Clone)]
+pub struct Crc32 {
+    table: [u32; 256],
+    crc: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32 {
+            table: crc_table(0xEDB8_8320),
+            crc: 0xFFFF_FFFF,
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for bt in bytes {
+            self.crc = self.table[((self.crc ^ *bt as u32) & 0xFF) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        self.crc ^ 0xFFFF_FFFF
+    }
+}
+
+impl ::std::fmt::Debug for Crc32 {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Crc32({:08x})", self.crc)
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Crc32 {
+        Crc32::new()
+    }
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256, as specified in FIPS 180-4.
+#[derive(Clone, Debug)]
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Sha256 {
+    pub fn new() -> Sha256 {
+        Sha256 {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.total_len += bytes.len() as u64;
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            let taken = (64 - self.block_len).min(bytes.len());
+            self.block[self.block_len..self.block_len + taken].copy_from_slice(&bytes[0..taken]);
+            self.block_len += taken;
+            bytes = &bytes[taken..];
+            if self.block_len == 64 {
+                let block = self.block;
+                self.compress(&block);
+                self.block_len = 0;
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> [u8; 32] {
+        let bit_len = self.total_len.wrapping_mul(8);
+        let mut padding = [0u8; 72];
+        padding[0] = 0x80;
+        let pad_len = if self.block_len < 56 { 56 - self.block_len } else { 120 - self.block_len };
+        for idx in 0..8 {
+            padding[pad_len + idx] = (bit_len >> (56 - 8 * idx)) as u8;
+        }
+        let total_len = self.total_len;
+        self.update(&padding[0..pad_len + 8]);
+        self.total_len = total_len;
+
+        let mut digest = [0u8; 32];
+        for (idx, word) in self.state.iter().enumerate() {
+            digest[4 * idx] = (word >> 24) as u8;
+            digest[4 * idx + 1] = (word >> 16) as u8;
+            digest[4 * idx + 2] = (word >> 8) as u8;
+            digest[4 * idx + 3] = *word as u8;
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8; 64]) {
+        let mut w = [0u32; 64];
+        for idx in 0..16 {
+            w[idx] = (block[4 * idx] as u32) << 24
+                | (block[4 * idx + 1] as u32) << 16
+                | (block[4 * idx + 2] as u32) << 8
+                | (block[4 * idx + 3] as u32);
+        }
+        for idx in 16..64 {
+            let s0 = w[idx - 15].rotate_right(7) ^ w[idx - 15].rotate_right(18) ^ (w[idx - 15] >> 3);
+            let s1 = w[idx - 2].rotate_right(17) ^ w[idx - 2].rotate_right(19) ^ (w[idx - 2] >> 10);
+            w[idx] = w[idx - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[idx - 7])
+                .wrapping_add(s1);
+        }
+
+        let mut h = self.state;
+        for idx in 0..64 {
+            let s1 = h[4].rotate_right(6) ^ h[4].rotate_right(11) ^ h[4].rotate_right(25);
+            let ch = (h[4] & h[5]) ^ (!h[4] & h[6]);
+            let temp1 = h[7]
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[idx])
+                .wrapping_add(w[idx]);
+            let s0 = h[0].rotate_right(2) ^ h[0].rotate_right(13) ^ h[0].rotate_right(22);
+            let maj = (h[0] & h[1]) ^ (h[0] & h[2]) ^ (h[1] & h[2]);
+            let temp2 = s0.wrapping_add(maj);
+            h[7] = h[6];
+            h[6] = h[5];
+            h[5] = h[4];
+            h[4] = h[3].wrapping_add(temp1);
+            h[3] = h[2];
+            h[2] = h[1];
+            h[1] = h[0];
+            h[0] = temp1.wrapping_add(temp2);
+        }
+        for (word, add) in self.state.iter_mut().zip(h.iter()) {
+            *word = word.wrapping_add(*add);
+        }
+    }
+}
+
+impl Default for Sha256 {
+    fn default() -> Sha256 {
+        Sha256::new()
+    }
+}
@@ -0,0 +1,581 @@
+use checksum::{Checksum, ChecksumKind};
+use prefixes::{combine_bytes_u32, combine_bytes_u64, extract_bytes_u32, extract_bytes_u64};
+use std::collections::HashMap;
+
+/// The smallest block a signature splits a file into.
+pub const MIN_BLOCK_SIZE: u32 = 2048;
+
+/// The largest block a signature splits a file into, which is also the most
+/// a server accepts.
+pub const MAX_BLOCK_SIZE: u32 = 128 * 1024;
+
+/// The length of the header before a signature's blocks: the file length as a
+/// big-endian `u64`, and then the block size and the number of blocks, each a
+/// big-endian `u32`. Each block after it is its weak checksum as a big-endian
+/// `u32` followed by its strong digest.
+pub const SIGNATURE_HEADER_LENGTH: usize = 16; //Bytes
+
+/// The length of the header before a delta read's stream: the length of the
+/// file it rebuilds as a big-endian `u64`. The stream runs until the response
+/// frame, as the server sends instructions as soon as it finds them.
+pub const DELTA_HEADER_LENGTH: usize = 8; //Bytes
+
+/// Starts an instruction to copy a range of the old file: the offset and then
+/// the length, each a big-endian `u64`.
+const COPY_TAG: u8 = 0x01;
+/// Starts an instruction to take new bytes from the stream: their length as a
+/// big-endian `u64`, and then the bytes themselves.
+const LITERAL_TAG: u8 = 0x02;
+
+/// How far a `DeltaPlanner` gets through its window before dropping what is
+/// behind it.
+const PLANNER_KEEP: usize = 1 << 20;
+
+/// The most blocks a signature may have: as many as the planner's window has
+/// bytes. This bounds what a server buffers for the signature of one delta
+/// read, and still covers a file of 128 GiB in the largest blocks.
+pub const MAX_SIGNATURE_BLOCKS: u32 = PLANNER_KEEP as u32;
+
+/// Picks the block size for a signature of a file of the given length: about
+/// its square root, so that the signature and the cost of a changed block
+/// grow together.
+pub fn block_size_for(len: u64) -> u32 {
+    let mut size = MIN_BLOCK_SIZE;
+    while (size as u64) * (size as u64) < len && size < MAX_BLOCK_SIZE {
+        size *= 2;
+    }
+    size
+}
+
+/// The weak checksum of a block, which can be moved along a file one byte at
+/// a time. This is the one rsync uses.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    pub fn new(block: &[u8]) -> RollingChecksum {
+        let len = block.len() as u32;
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (idx, bt) in block.iter().enumerate() {
+            a = a.wrapping_add(*bt as u32);
+            b = b.wrapping_add((len - idx as u32).wrapping_mul(*bt as u32));
+        }
+        RollingChecksum { a, b, len }
+    }
+
+    /// Moves the block on by one byte, dropping `out` from its start and
+    /// adding `inp` to its end.
+    pub fn roll(&mut self, out: u8, inp: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(inp as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+
+    pub fn value(&self) -> u32 {
+        (self.a & 0xFFFF) | (self.b << 16)
+    }
+}
+
+/// The checksums of one block of a file.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: Vec<u8>,
+}
+
+impl BlockSignature {
+    pub fn of(block: &[u8], kind: ChecksumKind) -> BlockSignature {
+        let mut strong = Checksum::new(kind);
+        strong.update(block);
+        BlockSignature {
+            weak: RollingChecksum::new(block).value(),
+            strong: strong.finish(),
+        }
+    }
+
+    pub fn serialize_into(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&extract_bytes_u32(self.weak));
+        buffer.extend_from_slice(&self.strong);
+    }
+}
+
+/// The checksums of every block of a file, which is all the other side needs
+/// to work out a delta against it.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Signature {
+    pub file_len: u64,
+    pub block_size: u32,
+    /// The kind of the strong digests.
+    pub kind: ChecksumKind,
+    pub blocks: Vec<BlockSignature>,
+}
+
+impl Signature {
+    /// How many blocks a file of the given length is split into; the last
+    /// may be shorter than the rest.
+    pub fn block_count(file_len: u64, block_size: u32) -> u32 {
+        file_len.div_ceil(block_size as u64) as u32
+    }
+
+    /// The header a signature of a file of the given length starts with.
+    pub fn header(file_len: u64, block_size: u32) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(SIGNATURE_HEADER_LENGTH);
+        bytes.extend_from_slice(&extract_bytes_u64(file_len));
+        bytes.extend_from_slice(&extract_bytes_u32(block_size));
+        bytes.extend_from_slice(&extract_bytes_u32(Signature::block_count(file_len, block_size)));
+        bytes
+    }
+
+    /// The longest a signature with digests of the given kind can be.
+    pub fn max_len(kind: ChecksumKind) -> u64 {
+        SIGNATURE_HEADER_LENGTH as u64 + MAX_SIGNATURE_BLOCKS as u64 * (4 + kind.digest_len()) as u64
+    }
+
+    /// The length of the block at the given index.
+    pub fn block_len(&self, idx: usize) -> u64 {
+        let start = idx as u64 * self.block_size as u64;
+        (self.file_len - start).min(self.block_size as u64)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Signature::header(self.file_len, self.block_size);
+        for block in &self.blocks {
+            block.serialize_into(&mut bytes);
+        }
+        bytes
+    }
+
+    pub fn parse(buffer: &[u8], kind: ChecksumKind) -> Result<Signature, String> {
+        if buffer.len() < SIGNATURE_HEADER_LENGTH {
+            return Err(format!("A signature needs at least {} bytes, not {}.", SIGNATURE_HEADER_LENGTH, buffer.len()));
+        }
+        let file_len = combine_bytes_u64(&buffer[0..8]);
+        let block_size = combine_bytes_u32(&buffer[8..12]);
+        let count = combine_bytes_u32(&buffer[12..16]);
+        if block_size == 0 || block_size > MAX_BLOCK_SIZE {
+            return Err(format!("Signature block size {} is not between 1 and {}.", block_size, MAX_BLOCK_SIZE));
+        }
+        if count > MAX_SIGNATURE_BLOCKS {
+            return Err(format!("A signature cannot have more than {} blocks, not {}.", MAX_SIGNATURE_BLOCKS, count));
+        }
+        if count != Signature::block_count(file_len, block_size) {
+            return Err(format!("A signature of {} bytes in blocks of {} cannot have {} blocks.", file_len, block_size, count));
+        }
+        let entry_len = 4 + kind.digest_len();
+        if buffer.len() != SIGNATURE_HEADER_LENGTH + count as usize * entry_len {
+            return Err(format!(
+                "A signature of {} blocks should be {} bytes, not {}.",
+                count,
+                SIGNATURE_HEADER_LENGTH + count as usize * entry_len,
+                buffer.len()
+            ));
+        }
+        let blocks = buffer[SIGNATURE_HEADER_LENGTH..]
+            .chunks(entry_len)
+            .map(|entry| BlockSignature {
+                weak: combine_bytes_u32(&entry[0..4]),
+                strong: entry[4..].to_vec(),
+            })
+            .collect();
+        Ok(Signature {
+            file_len,
+            block_size,
+            kind,
+            blocks,
+        })
+    }
+}
+
+/// Builds the signature of a file from its content, given in pieces of any
+/// size.
+#[derive(Debug)]
+pub struct SignatureBuilder {
+    signature: Signature,
+    pending: Vec<u8>,
+}
+
+impl SignatureBuilder {
+    pub fn new(block_size: u32, kind: ChecksumKind) -> SignatureBuilder {
+        SignatureBuilder {
+            signature: Signature {
+                file_len: 0,
+                block_size,
+                kind,
+                blocks: Vec::new(),
+            },
+            pending: Vec::with_capacity(block_size as usize),
+        }
+    }
+
+    pub fn input(&mut self, bytes: &[u8]) {
+        let block_size = self.signature.block_size as usize;
+        self.signature.file_len += bytes.len() as u64;
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let taken = (block_size - self.pending.len()).min(rest.len());
+            self.pending.extend_from_slice(&rest[0..taken]);
+            rest = &rest[taken..];
+            if self.pending.len() == block_size {
+                self.signature.blocks.push(BlockSignature::of(&self.pending, self.signature.kind));
+                self.pending.clear();
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> Signature {
+        if !self.pending.is_empty() {
+            self.signature.blocks.push(BlockSignature::of(&self.pending, self.signature.kind));
+        }
+        self.signature
+    }
+}
+
+/// One instruction of a delta, which rebuilds the new file from the old one.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum DeltaOp {
+    /// Copies `length` bytes of the old file, starting at `offset`.
+    Copy { offset: u64, length: u64 },
+    /// Sends `length` bytes of the new file, starting at `offset`, as they
+    /// are.
+    Literal { offset: u64, length: u64 },
+}
+
+impl DeltaOp {
+    /// The instruction as it is sent, without a literal's bytes.
+    pub fn header(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(17);
+        match *self {
+            DeltaOp::Copy { offset, length } => {
+                bytes.push(COPY_TAG);
+                bytes.extend_from_slice(&extract_bytes_u64(offset));
+                bytes.extend_from_slice(&extract_bytes_u64(length));
+            }
+            DeltaOp::Literal { length, .. } => {
+                bytes.push(LITERAL_TAG);
+                bytes.extend_from_slice(&extract_bytes_u64(length));
+            }
+        }
+        bytes
+    }
+
+    /// The number of bytes the instruction takes up in the stream.
+    pub fn encoded_len(&self) -> u64 {
+        match *self {
+            DeltaOp::Copy { .. } => 17,
+            DeltaOp::Literal { length, .. } => 9 + length,
+        }
+    }
+}
+
+/// The length of the stream that sends the given instructions.
+pub fn encoded_len(ops: &[DeltaOp]) -> u64 {
+    ops.iter().map(|op| op.encoded_len()).sum()
+}
+
+/// How many bytes of the new file the instructions send as they are.
+pub fn literal_len(ops: &[DeltaOp]) -> u64 {
+    ops.iter()
+        .map(|op| match *op {
+            DeltaOp::Literal { length, .. } => length,
+            DeltaOp::Copy { .. } => 0,
+        })
+        .sum()
+}
+
+/// Works out the instructions that rebuild a new file from an old one with
+/// the given signature, reading the new file in pieces of any size. Only a
+/// window of the new file is kept, so files of any length can be planned.
+#[derive(Debug)]
+pub struct DeltaPlanner {
+    signature: Signature,
+    /// The full-sized blocks of the old file, by weak checksum.
+    blocks_by_weak: HashMap<u32, Vec<usize>>,
+    window: Vec<u8>,
+    /// Where in the new file the window starts.
+    window_start: u64,
+    /// Where in the window the block being looked for starts.
+    pos: usize,
+    /// The weak checksum of the block at `pos`, if it has been worked out.
+    rolling: Option<RollingChecksum>,
+    /// Where in the new file the bytes that matched nothing start.
+    literal_start: u64,
+    ops: Vec<DeltaOp>,
+}
+
+impl DeltaPlanner {
+    pub fn new(signature: Signature) -> DeltaPlanner {
+        let mut blocks_by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (idx, block) in signature.blocks.iter().enumerate() {
+            if signature.block_len(idx) == signature.block_size as u64 {
+                blocks_by_weak.entry(block.weak).or_default().push(idx);
+            }
+        }
+        DeltaPlanner {
+            signature,
+            blocks_by_weak,
+            window: Vec::new(),
+            window_start: 0,
+            pos: 0,
+            rolling: None,
+            literal_start: 0,
+            ops: Vec::new(),
+        }
+    }
+
+    pub fn input(&mut self, bytes: &[u8]) {
+        self.window.extend_from_slice(bytes);
+        let block_size = self.signature.block_size as usize;
+        if self.blocks_by_weak.is_empty() {
+            self.pos = self.window.len().saturating_sub(block_size);
+        }
+        while !self.blocks_by_weak.is_empty() && self.window.len() - self.pos >= block_size {
+            let rolling = match self.rolling {
+                Some(rolling) => rolling,
+                None => RollingChecksum::new(&self.window[self.pos..self.pos + block_size]),
+            };
+            if let Some(idx) = self.find_block(rolling.value()) {
+                let start = self.window_start + self.pos as u64;
+                self.push_copy(start, idx);
+                self.pos += block_size;
+                self.rolling = None;
+                continue;
+            }
+            if self.pos + block_size == self.window.len() {
+                self.rolling = Some(rolling);
+                break;
+            }
+            let mut rolled = rolling;
+            rolled.roll(self.window[self.pos], self.window[self.pos + block_size]);
+            self.rolling = Some(rolled);
+            self.pos += 1;
+        }
+        if self.pos >= PLANNER_KEEP {
+            self.window.drain(0..self.pos);
+            self.window_start += self.pos as u64;
+            self.pos = 0;
+        }
+    }
+
+    /// Finds a full-sized block of the old file with the same content as the
+    /// one at `pos`.
+    fn find_block(&self, weak: u32) -> Option<usize> {
+        let candidates = self.blocks_by_weak.get(&weak)?;
+        let block = &self.window[self.pos..self.pos + self.signature.block_size as usize];
+        let strong = BlockSignature::of(block, self.signature.kind).strong;
+        candidates.iter().cloned().find(|&idx| self.signature.blocks[idx].strong == strong)
+    }
+
+    /// Ends any run of unmatched bytes before `start`, and adds a copy of the
+    /// old block at `idx`, joining it onto the copy before if they follow on.
+    fn push_copy(&mut self, start: u64, idx: usize) {
+        if self.literal_start < start {
+            self.ops.push(DeltaOp::Literal {
+                offset: self.literal_start,
+                length: start - self.literal_start,
+            });
+        }
+        let offset = idx as u64 * self.signature.block_size as u64;
+        let length = self.signature.block_len(idx);
+        let follows_on = match self.ops.last() {
+            Some(&DeltaOp::Copy { offset: last, length: last_len }) => last + last_len == offset,
+            _ => false,
+        };
+        if follows_on {
+            if let Some(&mut DeltaOp::Copy { length: ref mut last_len, .. }) = self.ops.last_mut() {
+                *last_len += length;
+            }
+        } else {
+            self.ops.push(DeltaOp::Copy { offset, length });
+        }
+        self.literal_start = start + length;
+    }
+
+    /// Takes the instructions that later input cannot change: everything
+    /// before the block being looked for, except a copy at the end, which
+    /// the next matching block may join onto.
+    pub fn take_ops(&mut self) -> Vec<DeltaOp> {
+        let settled = self.window_start + self.pos as u64;
+        if self.literal_start < settled {
+            self.ops.push(DeltaOp::Literal {
+                offset: self.literal_start,
+                length: settled - self.literal_start,
+            });
+            self.literal_start = settled;
+        }
+        let keep = match self.ops.last() {
+            Some(&DeltaOp::Copy { .. }) => self.ops.len() - 1,
+            _ => self.ops.len(),
+        };
+        let rest = self.ops.split_off(keep);
+        std::mem::replace(&mut self.ops, rest)
+    }
+
+    /// Finishes the plan once all of the new file has been given, returning
+    /// the instructions not already taken.
+    pub fn finish(mut self) -> Vec<DeltaOp> {
+        let end = self.window_start + self.window.len() as u64;
+        // The old file's last block may be shorter than the rest, so only the
+        // very end of the new file can match it.
+        if let Some(last) = self.signature.blocks.len().checked_sub(1) {
+            let last_len = self.signature.block_len(last) as usize;
+            let matches = last_len < self.signature.block_size as usize && self.window.len() - self.pos >= last_len && {
+                let tail = &self.window[self.window.len() - last_len..];
+                BlockSignature::of(tail, self.signature.kind) == self.signature.blocks[last]
+            };
+            if matches {
+                self.push_copy(end - last_len as u64, last);
+            }
+        }
+        if self.literal_start < end {
+            self.ops.push(DeltaOp::Literal {
+                offset: self.literal_start,
+                length: end - self.literal_start,
+            });
+        }
+        self.ops
+    }
+}
+
+/// Turns instructions into the stream that sends them, a piece at a time.
+#[derive(Debug)]
+pub struct DeltaEncoder {
+    ops: Vec<DeltaOp>,
+    op_idx: usize,
+    header: Vec<u8>,
+    header_idx: usize,
+    /// How many bytes of the current literal have been sent.
+    literal_done: u64,
+}
+
+impl DeltaEncoder {
+    pub fn new(ops: Vec<DeltaOp>) -> DeltaEncoder {
+        let header = ops.first().map(|op| op.header()).unwrap_or_default();
+        DeltaEncoder {
+            ops,
+            op_idx: 0,
+            header,
+            header_idx: 0,
+            literal_done: 0,
+        }
+    }
+
+    /// Whether everything pushed so far has been sent.
+    pub fn is_idle(&self) -> bool {
+        self.op_idx == self.ops.len()
+    }
+
+    /// Adds an instruction to the end of the stream.
+    pub fn push(&mut self, op: DeltaOp) {
+        if self.is_idle() {
+            self.header = op.header();
+            self.header_idx = 0;
+            self.literal_done = 0;
+        }
+        self.ops.push(op);
+    }
+
+    /// Fills `out` with the next bytes of the stream, returning how many it
+    /// filled. The bytes of literals come from `read_new`, which is given an
+    /// offset in the new file and a buffer to fill from it.
+    pub fn fill<F>(&mut self, out: &mut [u8], mut read_new: F) -> Result<usize, String>
+    where
+        F: FnMut(u64, &mut [u8]) -> Result<usize, String>,
+    {
+        let mut filled = 0;
+        while filled < out.len() && self.op_idx < self.ops.len() {
+            if self.header_idx < self.header.len() {
+                let taken = (self.header.len() - self.header_idx).min(out.len() - filled);
+                out[filled..filled + taken].copy_from_slice(&self.header[self.header_idx..self.header_idx + taken]);
+                self.header_idx += taken;
+                filled += taken;
+                continue;
+            }
+            if let DeltaOp::Literal { offset, length } = self.ops[self.op_idx] {
+                if self.literal_done < length {
+                    let want = (length - self.literal_done).min((out.len() - filled) as u64) as usize;
+                    let read = read_new(offset + self.literal_done, &mut out[filled..filled + want])?;
+                    if read == 0 {
+                        return Err(format!("The new file ended before byte {}.", offset + self.literal_done));
+                    }
+                    self.literal_done += read as u64;
+                    filled += read;
+                    continue;
+                }
+            }
+            self.op_idx += 1;
+            self.header = self.ops.get(self.op_idx).map(|op| op.header()).unwrap_or_default();
+            self.header_idx = 0;
+            self.literal_done = 0;
+        }
+        Ok(filled)
+    }
+}
+
+/// What a piece of a delta stream asks for.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Decoded<'a> {
+    /// New bytes to add to the file.
+    Literal(&'a [u8]),
+    /// A range of the old file to add to the file.
+    Copy { offset: u64, length: u64 },
+}
+
+/// Reads instructions out of a delta stream that arrives in pieces of any
+/// size.
+#[derive(Debug, Default)]
+pub struct DeltaDecoder {
+    header: Vec<u8>,
+    /// How many bytes of the current literal are still to come.
+    literal_left: u64,
+}
+
+impl DeltaDecoder {
+    pub fn new() -> DeltaDecoder {
+        DeltaDecoder::default()
+    }
+
+    /// Whether the stream so far ends between instructions, as a whole
+    /// stream must.
+    pub fn is_between_ops(&self) -> bool {
+        self.header.is_empty() && self.literal_left == 0
+    }
+
+    /// Takes bytes from the start of the block, returning how many it used
+    /// and anything they complete. Call it again with the rest of the block
+    /// until all of it is used.
+    pub fn input<'b>(&mut self, block: &'b [u8]) -> Result<(usize, Option<Decoded<'b>>), String> {
+        if block.is_empty() {
+            return Ok((0, None));
+        }
+        if self.literal_left > 0 {
+            let taken = self.literal_left.min(block.len() as u64) as usize;
+            self.literal_left -= taken as u64;
+            return Ok((taken, Some(Decoded::Literal(&block[0..taken]))));
+        }
+        let header_len = match self.header.first().unwrap_or(&block[0]) {
+            &COPY_TAG => 17,
+            &LITERAL_TAG => 9,
+            tag => return Err(format!("Unknown delta instruction {:#04x}.", tag)),
+        };
+        let taken = (header_len - self.header.len()).min(block.len());
+        self.header.extend_from_slice(&block[0..taken]);
+        if self.header.len() < header_len {
+            return Ok((taken, None));
+        }
+        let decoded = if self.header[0] == COPY_TAG {
+            Some(Decoded::Copy {
+                offset: combine_bytes_u64(&self.header[1..9]),
+                length: combine_bytes_u64(&self.header[9..17]),
+            })
+        } else {
+            self.literal_left = combine_bytes_u64(&self.header[1..9]);
+            None
+        };
+        self.header.clear();
+        Ok((taken, decoded))
+    }
+}
@@ -0,0 +1,282 @@
+use prefixes::{self, CommandPrefix, HANDSHAKE_MARKER, PREFIX_LENGTH};
+use response::Response;
+
+/// The first byte of every frame header, used to catch a client and server
+/// that have fallen out of step.
+pub const FRAME_MARKER: u8 = b'F';
+
+/// The number of bytes in a frame before its payload.
+///
+/// Layout: the `FRAME_MARKER` byte, the kind byte, 2 reserved bytes, the
+/// sequence number as a big-endian `u32`, and then the payload length as a
+/// big-endian `u32`.
+pub const FRAME_HEADER_LENGTH: usize = 12; //Bytes
+
+/// The largest payload either side will accept in a single frame.
+pub const MAX_FRAME_PAYLOAD: usize = 0xFFFF; //Bytes
+
+/// What a frame's payload holds.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum FrameKind {
+    /// A serialized command prefix, starting a new command.
+    Prefix,
+    /// Bytes belonging to the command in progress, such as a file name or
+    /// file content.
+    Data,
+    /// A serialized `Response`, ending the command in progress.
+    Response,
+    /// Stops the command in progress. The payload is a single
+    /// `AbortPolicy` byte.
+    Abort,
+}
+
+impl FrameKind {
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            FrameKind::Prefix => 1,
+            FrameKind::Data => 2,
+            FrameKind::Response => 3,
+            FrameKind::Abort => 4,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<FrameKind> {
+        match byte {
+            1 => Some(FrameKind::Prefix),
+            2 => Some(FrameKind::Data),
+            3 => Some(FrameKind::Response),
+            4 => Some(FrameKind::Abort),
+            _ => None,
+        }
+    }
+}
+
+/// What happens to a partly transferred file when its command is aborted.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum AbortPolicy {
+    /// Remove the partial file, or cut a file that was being added to back to
+    /// its old length.
+    Delete,
+    /// Keep whatever was transferred, so that the transfer can be resumed.
+    Keep,
+}
+
+impl AbortPolicy {
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            AbortPolicy::Delete => 0,
+            AbortPolicy::Keep => 1,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<AbortPolicy> {
+        match byte {
+            0 => Some(AbortPolicy::Delete),
+            1 => Some(AbortPolicy::Keep),
+            _ => None,
+        }
+    }
+}
+
+/// A single length-delimited message on the line.
+///
+/// The sequence number is stamped by the `FrameEncoder` that sends the frame,
+/// so frames built by commands can leave it as 0.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub sequence: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(kind: FrameKind, payload: Vec<u8>) -> Frame {
+        Frame {
+            kind,
+            sequence: 0,
+            payload,
+        }
+    }
+
+    pub fn data(payload: Vec<u8>) -> Frame {
+        Frame::new(FrameKind::Data, payload)
+    }
+
+    pub fn prefix<T: CommandPrefix>(prefix: &T) -> Frame {
+        Frame::new(FrameKind::Prefix, prefix.serialize().to_vec())
+    }
+
+    pub fn response(response: &Response) -> Frame {
+        Frame::new(FrameKind::Response, response.serialize())
+    }
+
+    pub fn abort(policy: AbortPolicy) -> Frame {
+        Frame::new(FrameKind::Abort, vec![policy.to_byte()])
+    }
+
+    /// Parses the policy carried by this abort frame.
+    pub fn parse_abort(&self) -> Result<AbortPolicy, String> {
+        match (self.kind, self.payload.len()) {
+            (FrameKind::Abort, 1) => {
+                AbortPolicy::from_byte(self.payload[0]).ok_or(format!("Unknown abort policy {}.", self.payload[0]))
+            }
+            (kind, len) => Err(format!(
+                "Expected an abort frame but got a {:?} frame with {} bytes.",
+                kind, len
+            )),
+        }
+    }
+
+    /// Checks whether this frame is a handshake numbered 0, which starts a
+    /// new session.
+    pub fn starts_session(&self) -> bool {
+        self.kind == FrameKind::Prefix
+            && self.sequence == 0
+            && self.payload.first() == Some(&HANDSHAKE_MARKER)
+    }
+
+    /// Parses the command prefix carried by this frame.
+    pub fn parse_prefix<T: CommandPrefix>(&self) -> Result<T, String> {
+        if self.kind != FrameKind::Prefix || self.payload.len() != PREFIX_LENGTH {
+            return Err(format!(
+                "Expected a prefix frame but got a {:?} frame with {} bytes.",
+                self.kind,
+                self.payload.len()
+            ));
+        }
+        let mut prefix_bytes = [0u8; PREFIX_LENGTH];
+        prefix_bytes.copy_from_slice(&self.payload);
+        T::parse_prefix(prefix_bytes).ok_or(format!("Could not parse prefix bytes {:?}", prefix_bytes))
+    }
+
+    /// Parses the response carried by this frame.
+    pub fn parse_response(&self) -> Result<Response, String> {
+        if self.kind != FrameKind::Response {
+            return Err(format!(
+                "Expected a response frame but got a {:?} frame with {} bytes.",
+                self.kind,
+                self.payload.len()
+            ));
+        }
+        Response::parse(&self.payload)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(FRAME_HEADER_LENGTH + self.payload.len());
+        bytes.push(FRAME_MARKER);
+        bytes.push(self.kind.to_byte());
+        bytes.extend_from_slice(&[0, 0]);
+        bytes.extend_from_slice(&prefixes::extract_bytes_u32(self.sequence));
+        bytes.extend_from_slice(&prefixes::extract_bytes_u32(self.payload.len() as u32));
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+}
+
+/// Turns frames into bytes for the line, numbering them in the order they
+/// are sent.
+#[derive(Debug, Default)]
+pub struct FrameEncoder {
+    next_sequence: u32,
+}
+
+impl FrameEncoder {
+    pub fn new() -> FrameEncoder {
+        FrameEncoder { next_sequence: 0 }
+    }
+
+    /// Starts numbering frames from 0 again, for a new session.
+    pub fn reset(&mut self) {
+        self.next_sequence = 0;
+    }
+
+    /// Stamps the frame with the next sequence number and serializes it.
+    pub fn encode(&mut self, mut frame: Frame) -> Vec<u8> {
+        frame.sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        frame.serialize()
+    }
+}
+
+/// Collects bytes from the line, however they were split into transfers,
+/// and cuts them back into frames.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    next_sequence: u32,
+}
+
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        FrameDecoder {
+            buffer: Vec::new(),
+            next_sequence: 0,
+        }
+    }
+
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Returns the next complete frame, or `None` if more bytes are needed.
+    /// Fails if the bytes are not a frame or a frame was lost.
+    ///
+    /// A handshake numbered 0 is always accepted and starts the numbering
+    /// over, since it comes from a client starting a new session.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, String> {
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+        if self.buffer[0] == HANDSHAKE_MARKER {
+            return Err("Got an unframed handshake; the other side is older than this build and needs to be updated.".to_owned());
+        }
+        if self.buffer[0] != FRAME_MARKER {
+            return Err(format!(
+                "Expected a frame but got bytes {:?}.",
+                &self.buffer[0..self.buffer.len().min(FRAME_HEADER_LENGTH)]
+            ));
+        }
+        if self.buffer.len() < FRAME_HEADER_LENGTH {
+            return Ok(None);
+        }
+        let kind = FrameKind::from_byte(self.buffer[1])
+            .ok_or(format!("Unknown frame kind {}.", self.buffer[1]))?;
+        let sequence = prefixes::combine_bytes_u32(&self.buffer[4..8]);
+        let payload_len = prefixes::combine_bytes_u32(&self.buffer[8..12]) as usize;
+        // Only the first byte of the payload tells a new session's handshake
+        // from a lost frame, so wait for it.
+        if kind == FrameKind::Prefix
+            && sequence == 0
+            && sequence != self.next_sequence
+            && self.buffer.len() < FRAME_HEADER_LENGTH + 1
+        {
+            return Ok(None);
+        }
+        let new_session = kind == FrameKind::Prefix
+            && sequence == 0
+            && self.buffer.get(FRAME_HEADER_LENGTH) == Some(&HANDSHAKE_MARKER);
+        if sequence != self.next_sequence && !new_session {
+            return Err(format!(
+                "Frame sequence mismatch: expected frame {} but got frame {}.",
+                self.next_sequence, sequence
+            ));
+        }
+        if payload_len > MAX_FRAME_PAYLOAD {
+            return Err(format!(
+                "Frame {} has a payload of {} bytes, more than the limit of {}.",
+                sequence, payload_len, MAX_FRAME_PAYLOAD
+            ));
+        }
+        if self.buffer.len() < FRAME_HEADER_LENGTH + payload_len {
+            return Ok(None);
+        }
+        let payload = self.buffer[FRAME_HEADER_LENGTH..FRAME_HEADER_LENGTH + payload_len].to_vec();
+        self.buffer.drain(0..FRAME_HEADER_LENGTH + payload_len);
+        self.next_sequence = sequence.wrapping_add(1);
+        Ok(Some(Frame {
+            kind,
+            sequence,
+            payload,
+        }))
+    }
+}
@@ -0,0 +1,94 @@
+use prefixes::{combine_bytes_u32, combine_bytes_u64, extract_bytes_u32, extract_bytes_u64};
+
+/// The length of a serialized `FsInfo`: the total and free bytes as
+/// big-endian `u64`s, the cluster size as a big-endian `u32`, and then the
+/// kind byte.
+pub const FS_INFO_LENGTH: usize = 21; //Bytes
+
+/// The length of every part of a split file but the last, as Horizon lays
+/// out its concatenation directories.
+pub const SPLIT_PART_SIZE: u64 = 0xFFFF_0000;
+
+/// The name of a part inside a concatenation directory: `00`, `01` and so on.
+pub fn split_part_name(index: usize) -> String {
+    format!("{:02}", index)
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum FsKind {
+    Fat32,
+    ExFat,
+    /// Anything else, or a filesystem the server could not identify.
+    Unknown,
+}
+
+impl FsKind {
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            FsKind::Fat32 => 0,
+            FsKind::ExFat => 1,
+            FsKind::Unknown => 0xFF,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> FsKind {
+        match byte {
+            0 => FsKind::Fat32,
+            1 => FsKind::ExFat,
+            _ => FsKind::Unknown,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FsKind::Fat32 => "FAT32",
+            FsKind::ExFat => "exFAT",
+            FsKind::Unknown => "unknown",
+        }
+    }
+}
+
+/// The size and kind of the filesystem a path is on.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct FsInfo {
+    pub total_bytes: u64,
+    /// The bytes a write can still use.
+    pub free_bytes: u64,
+    /// The unit space is handed out in, so that a file takes up its length
+    /// rounded up to a multiple of this.
+    pub cluster_size: u32,
+    pub kind: FsKind,
+}
+
+impl FsInfo {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(FS_INFO_LENGTH);
+        bytes.extend_from_slice(&extract_bytes_u64(self.total_bytes));
+        bytes.extend_from_slice(&extract_bytes_u64(self.free_bytes));
+        bytes.extend_from_slice(&extract_bytes_u32(self.cluster_size));
+        bytes.push(self.kind.to_byte());
+        bytes
+    }
+
+    pub fn parse(buffer: &[u8]) -> Result<FsInfo, String> {
+        if buffer.len() != FS_INFO_LENGTH {
+            return Err(format!(
+                "Filesystem info should be {} bytes, not {}.",
+                FS_INFO_LENGTH,
+                buffer.len()
+            ));
+        }
+        Ok(FsInfo {
+            total_bytes: combine_bytes_u64(&buffer[0..8]),
+            free_bytes: combine_bytes_u64(&buffer[8..16]),
+            cluster_size: combine_bytes_u32(&buffer[16..20]),
+            kind: FsKind::from_byte(buffer[20]),
+        })
+    }
+
+    /// The space a file of the given length takes up on this filesystem.
+    pub fn space_for(&self, len: u64) -> u64 {
+        let cluster = self.cluster_size.max(1) as u64;
+        len.div_ceil(cluster) * cluster
+    }
+}
@@ -0,0 +1,166 @@
+use checksum::ChecksumKind;
+use prefixes::HandshakePrefix;
+
+/// The version of the wire protocol spoken by this build of the crate.
+///
+/// This must be bumped whenever the bytes sent over the line for any command
+/// change, so that mismatched clients and servers refuse to talk instead of
+/// misreading each other.
+pub const PROTOCOL_VERSION: u8 = 19;
+
+/// The oldest client protocol version a server still serves, by answering in
+/// that version. Version 6 clients send read and write prefixes in the legacy
+/// layout without opcodes.
+pub const MIN_PROTOCOL_VERSION: u8 = 6;
+
+/// The first protocol version whose prefixes all start with an opcode.
+pub const OPCODE_PROTOCOL_VERSION: u8 = 7;
+
+/// Whether a client that negotiated `version` sends prefixes in the legacy
+/// layout.
+pub fn uses_legacy_layout(version: u8) -> bool {
+    version < OPCODE_PROTOCOL_VERSION
+}
+
+/// The side of the link supports reading files from the Switch.
+pub const FEATURE_READ: u16 = 0x0001;
+/// The side of the link supports writing files to the Switch.
+pub const FEATURE_WRITE: u16 = 0x0002;
+
+/// The side of the link can check transfers with CRC-32C.
+pub const FEATURE_CRC32C: u16 = 0x0004;
+/// The side of the link can check transfers with SHA-256.
+pub const FEATURE_SHA256: u16 = 0x0008;
+
+/// The side of the link can probe files and resume transfers at an offset.
+pub const FEATURE_RESUME: u16 = 0x0010;
+
+/// The side of the link can read a range of bytes out of a file.
+pub const FEATURE_RANGE: u16 = 0x0020;
+
+/// The side of the link understands abort frames.
+pub const FEATURE_ABORT: u16 = 0x0040;
+
+/// The side of the link can list a directory with the list command.
+pub const FEATURE_LIST: u16 = 0x0080;
+
+/// The side of the link can describe a single path with the stat command.
+pub const FEATURE_STAT: u16 = 0x0100;
+
+/// The side of the link can create directories with the make directory
+/// command.
+pub const FEATURE_MKDIR: u16 = 0x0200;
+
+/// The side of the link can delete files and directories with the remove
+/// commands.
+pub const FEATURE_REMOVE: u16 = 0x0400;
+
+/// The side of the link can move and rename paths with the move command.
+pub const FEATURE_MOVE: u16 = 0x0800;
+
+/// The side of the link honours the `SKIP_IDENTICAL` and `NEW_NAME` overwrite
+/// policies on writes.
+pub const FEATURE_OVERWRITE_POLICY: u16 = 0x1000;
+
+/// The side of the link can report the size and kind of a filesystem with
+/// the filesystem info command.
+pub const FEATURE_FS_INFO: u16 = 0x2000;
+
+/// The side of the link can send block signatures, rebuild a written file
+/// from a delta, and send a file as a delta against a signature.
+pub const FEATURE_DELTA: u16 = 0x4000;
+
+/// The side of the link can hash files and trees with the hash command, and
+/// knows the CRC-32 checksum kind it uses.
+pub const FEATURE_HASH: u16 = 0x8000;
+
+/// All features supported by this build of the crate.
+pub const SUPPORTED_FEATURES: u16 = FEATURE_READ
+    | FEATURE_WRITE
+    | FEATURE_CRC32C
+    | FEATURE_SHA256
+    | FEATURE_RESUME
+    | FEATURE_RANGE
+    | FEATURE_ABORT
+    | FEATURE_LIST
+    | FEATURE_STAT
+    | FEATURE_MKDIR
+    | FEATURE_REMOVE
+    | FEATURE_MOVE
+    | FEATURE_OVERWRITE_POLICY
+    | FEATURE_FS_INFO
+    | FEATURE_DELTA
+    | FEATURE_HASH;
+
+/// Picks the strongest checksum both sides support.
+pub fn negotiate_checksum(local_features: u16, remote_features: u16) -> ChecksumKind {
+    let common = local_features & remote_features;
+    if common & FEATURE_SHA256 != 0 {
+        ChecksumKind::Sha256
+    } else if common & FEATURE_CRC32C != 0 {
+        ChecksumKind::Crc32c
+    } else {
+        ChecksumKind::None
+    }
+}
+
+/// Picks the protocol version a server answers a client's handshake with:
+/// the client's own if the server can still serve it, or the server's
+/// otherwise so that the mismatch is reported.
+pub fn negotiate_protocol_version(server_version: u8, client_version: u8) -> u8 {
+    if client_version >= MIN_PROTOCOL_VERSION && client_version <= server_version {
+        client_version
+    } else {
+        server_version
+    }
+}
+
+/// Picks the largest frame payload both sides accept.
+pub fn negotiate_frame_payload(local: &HandshakePrefix, remote: &HandshakePrefix) -> usize {
+    local.max_frame_payload.min(remote.max_frame_payload) as usize
+}
+
+/// Packs a `major.minor.patch` build version into 16 bits, using 4 bits for
+/// the major version and 6 bits each for the minor and patch versions.
+pub fn pack_build_version(major: u8, minor: u8, patch: u8) -> u16 {
+    ((major as u16 & 0xF) << 12) | ((minor as u16 & 0x3F) << 6) | (patch as u16 & 0x3F)
+}
+
+/// Packs the version strings as given by cargo's `CARGO_PKG_VERSION_*`
+/// variables, treating unparseable components as 0.
+pub fn parse_build_version(major: &str, minor: &str, patch: &str) -> u16 {
+    pack_build_version(
+        major.parse().unwrap_or(0),
+        minor.parse().unwrap_or(0),
+        patch.parse().unwrap_or(0),
+    )
+}
+
+/// Formats a packed build version as `major.minor.patch`.
+pub fn format_build_version(version: u16) -> String {
+    format!(
+        "{}.{}.{}",
+        (version >> 12) & 0xF,
+        (version >> 6) & 0x3F,
+        version & 0x3F
+    )
+}
+
+/// Checks whether the side that sent `remote` can work with the side that
+/// sent `local`, returning a user-facing explanation if it can't.
+pub fn check_compatible(local: &HandshakePrefix, remote: &HandshakePrefix) -> Result<(), String> {
+    if local.protocol_version != remote.protocol_version {
+        Err(format!(
+            "Protocol version mismatch: this side (build {}) speaks version {} but the other side (build {}) speaks version {}. Please update the {} binary.",
+            format_build_version(local.build_version),
+            local.protocol_version,
+            format_build_version(remote.build_version),
+            remote.protocol_version,
+            if local.protocol_version > remote.protocol_version { "other" } else { "local" }
+        ))
+    } else if remote.max_frame_payload == 0 {
+        Err("The other side does not accept any frame payload.".to_owned())
+    } else {
+        Ok(())
+    }
+}
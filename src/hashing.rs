@@ -0,0 +1,160 @@
+use checksum::{self, ChecksumKind};
+use prefixes::{combine_bytes_u64, extract_bytes_u64};
+use response::ResponseCode;
+
+/// The length of each record before its name: the code byte, the name length
+/// and message length as big-endian `u16`s, and the file size as a big-endian
+/// `u64`. The name is followed by the digest, which is only there if the file
+/// was hashed, and then the message.
+pub const RECORD_HEADER_LENGTH: usize = 13; //Bytes
+
+/// The digest of one file a hash command looked at, or why it has none.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct HashRecord {
+    /// The full path of the file on the server.
+    pub name: String,
+    /// The number of bytes that were hashed.
+    pub size: u64,
+    /// `ResponseCode::Ok` if the file was hashed, or why it was not.
+    pub code: ResponseCode,
+    /// The digest of the content, and empty if the file was not hashed.
+    pub digest: Vec<u8>,
+    /// Explains the failure, and is empty if the file was hashed.
+    pub message: String,
+}
+
+impl HashRecord {
+    pub fn hashed(name: String, size: u64, digest: Vec<u8>) -> HashRecord {
+        HashRecord {
+            name,
+            size,
+            code: ResponseCode::Ok,
+            digest,
+            message: String::new(),
+        }
+    }
+
+    pub fn failed(name: String, code: ResponseCode, message: String) -> HashRecord {
+        HashRecord {
+            name,
+            size: 0,
+            code,
+            digest: Vec::new(),
+            message,
+        }
+    }
+
+    pub fn is_hashed(&self) -> bool {
+        self.code == ResponseCode::Ok
+    }
+
+    pub fn serialize_into(&self, buffer: &mut Vec<u8>) {
+        let name = self.name.as_bytes();
+        let name_len = name.len().min(u16::MAX as usize);
+        let message = self.message.as_bytes();
+        let message_len = message.len().min(u16::MAX as usize);
+        buffer.push(self.code.to_byte());
+        buffer.push(((name_len & 0xFF00) >> 8) as u8);
+        buffer.push((name_len & 0xFF) as u8);
+        buffer.push(((message_len & 0xFF00) >> 8) as u8);
+        buffer.push((message_len & 0xFF) as u8);
+        buffer.extend_from_slice(&extract_bytes_u64(self.size));
+        buffer.extend_from_slice(&name[0..name_len]);
+        if self.is_hashed() {
+            buffer.extend_from_slice(&self.digest);
+        }
+        buffer.extend_from_slice(&message[0..message_len]);
+    }
+
+    /// Parses a record from the start of the buffer, returning it and the
+    /// number of bytes it took up. The digests are as long as the kind's.
+    pub fn parse(buffer: &[u8], kind: ChecksumKind) -> Result<(HashRecord, usize), String> {
+        if buffer.len() < RECORD_HEADER_LENGTH {
+            return Err("Hash report ended partway through a record header.".to_owned());
+        }
+        let code = ResponseCode::from_byte(buffer[0]);
+        let name_len = (buffer[1] as usize) << 8 | (buffer[2] as usize);
+        let message_len = (buffer[3] as usize) << 8 | (buffer[4] as usize);
+        let size = combine_bytes_u64(&buffer[5..RECORD_HEADER_LENGTH]);
+        let name_end = RECORD_HEADER_LENGTH + name_len;
+        let digest_end = if code == ResponseCode::Ok {
+            name_end + kind.digest_len()
+        } else {
+            name_end
+        };
+        let end = digest_end + message_len;
+        if buffer.len() < end {
+            return Err("Hash report ended partway through a record.".to_owned());
+        }
+        let name = String::from_utf8(buffer[RECORD_HEADER_LENGTH..name_end].to_vec())
+            .map_err(|e| format!("UTF8 Error: {:?}", e))?;
+        let message = String::from_utf8_lossy(&buffer[digest_end..end]).into_owned();
+        let record = HashRecord {
+            name,
+            size,
+            code,
+            digest: buffer[name_end..digest_end].to_vec(),
+            message,
+        };
+        Ok((record, end))
+    }
+}
+
+/// Parses every record in a hash report.
+pub fn parse_records(buffer: &[u8], kind: ChecksumKind) -> Result<Vec<HashRecord>, String> {
+    let mut records = Vec::new();
+    let mut idx = 0;
+    while idx < buffer.len() {
+        let (record, used) = HashRecord::parse(&buffer[idx..], kind)?;
+        records.push(record);
+        idx += used;
+    }
+    Ok(records)
+}
+
+/// Formats a digest and a file name as a line of a `sha256sum` manifest,
+/// without the line break. Names with a backslash or a line break in them are
+/// escaped and the line starts with a backslash, as `sha256sum` does.
+pub fn manifest_line(digest: &[u8], name: &str) -> String {
+    if name.contains('\\') || name.contains('\n') {
+        let escaped = name.replace('\\', "\\\\").replace('\n', "\\n");
+        format!("\\{}  {}", checksum::to_hex(digest), escaped)
+    } else {
+        format!("{}  {}", checksum::to_hex(digest), name)
+    }
+}
+
+/// Parses a line of a `sha256sum` manifest into the digest and the file name.
+/// Both the text (`"  "`) and binary (`" *"`) separators are accepted.
+pub fn parse_manifest_line(line: &str) -> Result<(Vec<u8>, String), String> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let split = line
+        .find(' ')
+        .ok_or(format!("Manifest line {:?} has no file name.", line))?;
+    let (hex, rest) = line.split_at(split);
+    if !(rest.starts_with("  ") || rest.starts_with(" *")) || rest.len() < 3 {
+        return Err(format!("Manifest line {:?} has no file name.", line));
+    }
+    let digest = checksum::from_hex(hex).ok_or(format!("{:?} is not a hex digest.", hex))?;
+    let name = &rest[2..];
+    if !escaped {
+        return Ok((digest, name.to_owned()));
+    }
+    let mut unescaped = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('n') => unescaped.push('\n'),
+            other => return Err(format!("Unknown escape {:?} in manifest name {:?}.", other, name)),
+        }
+    }
+    Ok((digest, unescaped))
+}
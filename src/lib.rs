@@ -1,2 +1,10 @@
-
-pub mod prefixes;
\ No newline at end of file
+pub mod checksum;
+pub mod delta;
+pub mod frame;
+pub mod fsinfo;
+pub mod handshake;
+pub mod hashing;
+pub mod listing;
+pub mod prefixes;
+pub mod removal;
+pub mod response;
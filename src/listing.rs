@@ -0,0 +1,131 @@
+use prefixes::{combine_bytes_u32, combine_bytes_u64, extract_bytes_u32, extract_bytes_u64};
+
+/// The length of the header before a page of entries: the number of entries
+/// in the whole directory and then the number in this page, each a big-endian
+/// `u32`.
+pub const PAGE_HEADER_LENGTH: usize = 8; //Bytes
+
+/// The length of each entry before its name: the kind byte, a reserved byte,
+/// the name length as a big-endian `u16`, the size as a big-endian `u64` and
+/// the modification time as a big-endian `u64`.
+pub const ENTRY_HEADER_LENGTH: usize = 20; //Bytes
+
+/// How many entries a client asks for at a time unless told otherwise.
+pub const DEFAULT_PAGE_ENTRIES: u32 = 256;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum EntryKind {
+    File,
+    Dir,
+    /// Anything else, such as a symbolic link that was not followed.
+    Other,
+}
+
+impl EntryKind {
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            EntryKind::File => 0,
+            EntryKind::Dir => 1,
+            EntryKind::Other => 2,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<EntryKind> {
+        match byte {
+            0 => Some(EntryKind::File),
+            1 => Some(EntryKind::Dir),
+            2 => Some(EntryKind::Other),
+            _ => None,
+        }
+    }
+}
+
+/// One thing inside a listed directory.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct ListEntry {
+    /// The name within the directory, or in a recursive listing the path
+    /// inside it, like `sub/file`.
+    pub name: String,
+    pub kind: EntryKind,
+    /// The length in bytes of a file, and 0 for anything else.
+    pub size: u64,
+    /// The last modification time in seconds since the Unix epoch, or 0 if it
+    /// is not known.
+    pub modified: u64,
+}
+
+impl ListEntry {
+    pub fn serialize_into(&self, buffer: &mut Vec<u8>) {
+        let name = self.name.as_bytes();
+        let name_len = name.len().min(u16::MAX as usize);
+        buffer.push(self.kind.to_byte());
+        buffer.push(0);
+        buffer.push(((name_len & 0xFF00) >> 8) as u8);
+        buffer.push((name_len & 0xFF) as u8);
+        buffer.extend_from_slice(&extract_bytes_u64(self.size));
+        buffer.extend_from_slice(&extract_bytes_u64(self.modified));
+        buffer.extend_from_slice(&name[0..name_len]);
+    }
+
+    /// Parses an entry from the start of the buffer, returning it and the
+    /// number of bytes it took up.
+    pub fn parse(buffer: &[u8]) -> Result<(ListEntry, usize), String> {
+        if buffer.len() < ENTRY_HEADER_LENGTH {
+            return Err("Listing ended partway through an entry header.".to_owned());
+        }
+        let kind = EntryKind::from_byte(buffer[0]).ok_or(format!("Unknown entry kind {}.", buffer[0]))?;
+        let name_len = (buffer[2] as usize) << 8 | (buffer[3] as usize);
+        let end = ENTRY_HEADER_LENGTH + name_len;
+        if buffer.len() < end {
+            return Err("Listing ended partway through an entry name.".to_owned());
+        }
+        let name = String::from_utf8(buffer[ENTRY_HEADER_LENGTH..end].to_vec())
+            .map_err(|e| format!("UTF8 Error: {:?}", e))?;
+        let entry = ListEntry {
+            name,
+            kind,
+            size: combine_bytes_u64(&buffer[4..12]),
+            modified: combine_bytes_u64(&buffer[12..20]),
+        };
+        Ok((entry, end))
+    }
+}
+
+/// Some of the entries in a directory, as sent in answer to one list command.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct ListPage {
+    /// The number of entries in the whole directory.
+    pub total: u32,
+    pub entries: Vec<ListEntry>,
+}
+
+impl ListPage {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(PAGE_HEADER_LENGTH + self.entries.len() * ENTRY_HEADER_LENGTH);
+        buffer.extend_from_slice(&extract_bytes_u32(self.total));
+        buffer.extend_from_slice(&extract_bytes_u32(self.entries.len() as u32));
+        for entry in &self.entries {
+            entry.serialize_into(&mut buffer);
+        }
+        buffer
+    }
+
+    pub fn parse(buffer: &[u8]) -> Result<ListPage, String> {
+        if buffer.len() < PAGE_HEADER_LENGTH {
+            return Err(format!("Listing of {} bytes is too short for its header.", buffer.len()));
+        }
+        let total = combine_bytes_u32(&buffer[0..4]);
+        let count = combine_bytes_u32(&buffer[4..8]) as usize;
+        let mut entries = Vec::with_capacity(count);
+        let mut idx = PAGE_HEADER_LENGTH;
+        for _ in 0..count {
+            let (entry, used) = ListEntry::parse(&buffer[idx..])?;
+            entries.push(entry);
+            idx += used;
+        }
+        if idx != buffer.len() {
+            return Err(format!("Got {} bytes past the last entry of the listing.", buffer.len() - idx));
+        }
+        Ok(ListPage { total, entries })
+    }
+}
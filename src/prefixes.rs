@@ -1,3 +1,6 @@
+use checksum::{self, ChecksumKind};
+use std::ops::BitOr;
+
 #[inline]
 fn extract_bytes_u16(inp: u16) -> (u8, u8) {
     let first = ((inp & 0xFF00) >> 8) as u8;
@@ -5,23 +8,294 @@ fn extract_bytes_u16(inp: u16) -> (u8, u8) {
     (first, second)
 }
 
+/// Splits a `u64` into its big-endian bytes.
+#[inline]
+pub fn extract_bytes_u64(inp: u64) -> [u8; 8] {
+    let mut bytes = [0u8; 8];
+    for (idx, bt) in bytes.iter_mut().enumerate() {
+        *bt = ((inp >> (8 * (7 - idx))) & 0xFF) as u8;
+    }
+    bytes
+}
+
+/// Splits a `u32` into its big-endian bytes.
 #[inline]
-fn extract_bytes_u32(inp: u32) -> (u8, u8, u8, u8) {
-    let last = (inp & 0xFF) as u8;
-    let third = ((inp & 0xFF00) >> 8) as u8;
-    let second = ((inp & 0xFF0000) >> 16) as u8;
-    let head = ((inp & 0xFF000000) >> 24) as u8;
+pub fn extract_bytes_u32(inp: u32) -> [u8; 4] {
+    let mut bytes = [0u8; 4];
+    for (idx, bt) in bytes.iter_mut().enumerate() {
+        *bt = ((inp >> (8 * (3 - idx))) & 0xFF) as u8;
+    }
+    bytes
+}
+
+/// Joins the first 4 bytes of the slice, read as big-endian, into a `u32`.
+#[inline]
+pub fn combine_bytes_u32(bytes: &[u8]) -> u32 {
+    bytes[0..4]
+        .iter()
+        .fold(0u32, |acc, bt| (acc << 8) | (*bt as u32))
+}
+
+/// Joins the first 8 bytes of the slice, read as big-endian, into a `u64`.
+#[inline]
+pub fn combine_bytes_u64(bytes: &[u8]) -> u64 {
+    bytes[0..8]
+        .iter()
+        .fold(0u64, |acc, bt| (acc << 8) | (*bt as u64))
+}
+
+/// The options a command was started with.
+///
+/// The low 2 bits hold the `ChecksumKind`. In the legacy layout the top bit
+/// told writes from reads, so it is never part of the flags.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub struct PrefixFlags {
+    bits: u16,
+}
+
+impl PrefixFlags {
+    /// Replace a file that already exists instead of failing.
+    pub const OVERWRITE: PrefixFlags = PrefixFlags { bits: 0x0004 };
+    /// Add to the end of a file that already exists instead of failing.
+    pub const APPEND: PrefixFlags = PrefixFlags { bits: 0x0008 };
+    /// Create any missing parent directories of the target.
+    pub const CREATE_PARENTS: PrefixFlags = PrefixFlags { bits: 0x0010 };
+    /// Apply the command to everything under a directory.
+    pub const RECURSIVE: PrefixFlags = PrefixFlags { bits: 0x0020 };
+    /// Act on the target of a symbolic link instead of refusing it.
+    pub const FOLLOW_LINKS: PrefixFlags = PrefixFlags { bits: 0x0040 };
+    /// Read the file back from storage after the transfer and check it
+    /// against the transfer's checksum.
+    pub const VERIFY: PrefixFlags = PrefixFlags { bits: 0x0080 };
+    /// A byte offset of `OFFSET_LENGTH` bytes follows the file name. Reads
+    /// start sending from it, and writes keep that many bytes of the existing
+    /// file and put the content after them.
+    pub const OFFSET: PrefixFlags = PrefixFlags { bits: 0x0100 };
+    /// A byte count of `OFFSET_LENGTH` bytes follows the file name and any
+    /// offset. Reads send at most that many bytes.
+    pub const LIMIT: PrefixFlags = PrefixFlags { bits: 0x0200 };
+    /// Leave a file that already exists alone if it has the same content as
+    /// the write, and replace it otherwise.
+    pub const SKIP_IDENTICAL: PrefixFlags = PrefixFlags { bits: 0x0400 };
+    /// Write under a free name with a numbered suffix if the file already
+    /// exists, instead of failing.
+    pub const NEW_NAME: PrefixFlags = PrefixFlags { bits: 0x0800 };
+    /// The content of a write is a `delta` stream that rebuilds the file from
+    /// the one it replaces. The file length is the length of the stream, and
+    /// the checksum is of the rebuilt file.
+    pub const DELTA: PrefixFlags = PrefixFlags { bits: 0x1000 };
+
+    /// The bits the legacy layout used for the command, which can never be
+    /// flags.
+    pub const COMMAND_BITS: u16 = 0x8000;
+
+    /// Every bit with a meaning, including the checksum bits.
+    pub const KNOWN_BITS: u16 = 0x1FFF;
+
+    pub fn empty() -> PrefixFlags {
+        PrefixFlags { bits: 0 }
+    }
+
+    /// Builds flags from raw bits, dropping the command bits but keeping
+    /// unknown ones so that they can be rejected.
+    pub fn from_bits(bits: u16) -> PrefixFlags {
+        PrefixFlags {
+            bits: bits & !PrefixFlags::COMMAND_BITS,
+        }
+    }
+
+    pub fn bits(&self) -> u16 {
+        self.bits
+    }
+
+    /// Checks whether all of the given flags are set.
+    pub fn contains(&self, other: PrefixFlags) -> bool {
+        self.bits & other.bits == other.bits
+    }
+
+    /// The set bits that this build gives no meaning to.
+    pub fn unknown_bits(&self) -> u16 {
+        self.bits & !PrefixFlags::KNOWN_BITS
+    }
+
+    /// The checksum asked for, or `None` if the bits do not name a known
+    /// algorithm.
+    pub fn checksum(&self) -> Option<ChecksumKind> {
+        ChecksumKind::from_flags(self.bits)
+    }
+
+    pub fn with_checksum(self, kind: ChecksumKind) -> PrefixFlags {
+        PrefixFlags {
+            bits: (self.bits & !checksum::CHECKSUM_FLAG_MASK) | kind.to_flags(),
+        }
+    }
+}
+
+impl BitOr for PrefixFlags {
+    type Output = PrefixFlags;
+
+    fn bitor(self, other: PrefixFlags) -> PrefixFlags {
+        PrefixFlags {
+            bits: self.bits | other.bits,
+        }
+    }
+}
+
+/// What a write does when its file already exists, carried in the write's
+/// flags.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum OverwritePolicy {
+    /// Refuse the write with `ResponseCode::Exists`.
+    Fail,
+    /// Replace the file, the `OVERWRITE` flag.
+    Overwrite,
+    /// Leave the file alone if it has the same size and content, and replace
+    /// it otherwise, the `SKIP_IDENTICAL` flag.
+    SkipIdentical,
+    /// Write next to the file under a name with a numbered suffix, the
+    /// `NEW_NAME` flag.
+    Rename,
+}
+
+impl OverwritePolicy {
+    /// The flag that asks for this policy.
+    pub fn flags(&self) -> PrefixFlags {
+        match self {
+            OverwritePolicy::Fail => PrefixFlags::empty(),
+            OverwritePolicy::Overwrite => PrefixFlags::OVERWRITE,
+            OverwritePolicy::SkipIdentical => PrefixFlags::SKIP_IDENTICAL,
+            OverwritePolicy::Rename => PrefixFlags::NEW_NAME,
+        }
+    }
+
+    /// Finds the policy the flags ask for, or `None` if they ask for more than
+    /// one.
+    pub fn from_flags(flags: PrefixFlags) -> Option<OverwritePolicy> {
+        let policies = [
+            OverwritePolicy::Overwrite,
+            OverwritePolicy::SkipIdentical,
+            OverwritePolicy::Rename,
+        ];
+        let mut found = policies.iter().filter(|policy| flags.contains(policy.flags()));
+        match (found.next(), found.next()) {
+            (None, _) => Some(OverwritePolicy::Fail),
+            (Some(policy), None) => Some(*policy),
+            (Some(_), Some(_)) => None,
+        }
+    }
 
-    (head, second, third, last)
+    /// Parses the name of a policy as given on the command line.
+    pub fn parse(name: &str) -> Option<OverwritePolicy> {
+        match name {
+            "fail" => Some(OverwritePolicy::Fail),
+            "overwrite" => Some(OverwritePolicy::Overwrite),
+            "skip" | "skip-identical" => Some(OverwritePolicy::SkipIdentical),
+            "rename" => Some(OverwritePolicy::Rename),
+            _ => None,
+        }
+    }
 }
 
+/// The command a prefix starts, given by the prefix's first byte.
+///
+/// This is the registry of every command the protocol knows. A new command
+/// gets a new variant here, a byte from the free range in `to_byte`, and a
+/// matching variant in `Prefixes`.
+///
+/// Clients that negotiated a protocol version from before opcodes send reads
+/// and writes in the legacy layout, where the first 2 bytes are flags and the
+/// top bit picks a write over a read. Any byte can start one of those, so they
+/// are decoded by `Prefixes::decode_legacy` alone and never guessed at here.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Opcode {
+    Handshake,
+    Read,
+    Write,
+    Probe,
+    List,
+    Stat,
+    MakeDir,
+    Remove,
+    RemoveDir,
+    Move,
+    FsInfo,
+    Signature,
+    ReadDelta,
+    Hash,
+}
+
+impl Opcode {
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            Opcode::Handshake => HANDSHAKE_MARKER,
+            Opcode::Read => 0x01,
+            Opcode::Write => 0x02,
+            Opcode::Probe => 0x03,
+            Opcode::List => 0x04,
+            Opcode::Stat => 0x05,
+            Opcode::MakeDir => 0x06,
+            Opcode::Remove => 0x07,
+            Opcode::RemoveDir => 0x08,
+            Opcode::Move => 0x09,
+            Opcode::FsInfo => 0x0A,
+            Opcode::Signature => 0x0B,
+            Opcode::ReadDelta => 0x0C,
+            Opcode::Hash => 0x0D,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Opcode> {
+        match byte {
+            HANDSHAKE_MARKER => Some(Opcode::Handshake),
+            0x01 => Some(Opcode::Read),
+            0x02 => Some(Opcode::Write),
+            0x03 => Some(Opcode::Probe),
+            0x04 => Some(Opcode::List),
+            0x05 => Some(Opcode::Stat),
+            0x06 => Some(Opcode::MakeDir),
+            0x07 => Some(Opcode::Remove),
+            0x08 => Some(Opcode::RemoveDir),
+            0x09 => Some(Opcode::Move),
+            0x0A => Some(Opcode::FsInfo),
+            0x0B => Some(Opcode::Signature),
+            0x0C => Some(Opcode::ReadDelta),
+            0x0D => Some(Opcode::Hash),
+            _ => None,
+        }
+    }
+
+    /// Reads the first byte of a prefix in the legacy layout, whose top bit
+    /// picks a write over a read.
+    pub fn from_legacy_byte(byte: u8) -> Opcode {
+        if byte & 0x80 != 0 {
+            Opcode::Write
+        } else {
+            Opcode::Read
+        }
+    }
+
+    /// Finds the command that a prefix in the current layout starts.
+    pub fn from_prefix(prefix: &[u8; PREFIX_LENGTH]) -> Result<Opcode, String> {
+        Opcode::from_byte(prefix[0]).ok_or(format!("Unknown opcode {:#04x}.", prefix[0]))
+    }
+}
+
+/// Layout: the opcode, a reserved byte, 2 bytes of flags, and then 2 bytes of
+/// file name length.
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub struct ReadPrefix {
-    pub flags: u16,
+    pub flags: PrefixFlags,
     pub file_name_length: u16,
 }
 
-pub const PREFIX_LENGTH: usize = 8; //Bytes
+pub const PREFIX_LENGTH: usize = 16; //Bytes
+
+/// The length of the big-endian file size sent before a read's file content.
+pub const READ_HEADER_LENGTH: usize = 8; //Bytes
+
+/// The length of the big-endian offset or byte count sent after the file name
+/// when a command has the `OFFSET` or `LIMIT` flag.
+pub const OFFSET_LENGTH: usize = 8; //Bytes
 
 pub trait CommandPrefix
 where
@@ -32,13 +306,35 @@ where
     fn serialize(&self) -> [u8; PREFIX_LENGTH];
 }
 
+/// Reads the flags and file name length shared by reads and writes, from
+/// wherever the prefix's layout puts them.
+fn parse_flags_and_name(prefix: &[u8; PREFIX_LENGTH], legacy: bool) -> (PrefixFlags, u16) {
+    let start = if legacy { 0 } else { 2 };
+    let flags = PrefixFlags::from_bits((prefix[start] as u16) << 8 | (prefix[start + 1] as u16));
+    let file_name_length: u16 = (prefix[start + 2] as u16) << 8 | (prefix[start + 3] as u16);
+    (flags, file_name_length)
+}
+
+/// Builds the start of a prefix in the current layout.
+fn serialize_opcode_header(opcode: Opcode, flags: PrefixFlags, file_name_length: u16) -> [u8; PREFIX_LENGTH] {
+    let flag_bytes = extract_bytes_u16(flags.bits());
+    let name_length_bytes = extract_bytes_u16(file_name_length);
+    let mut bytes = [0u8; PREFIX_LENGTH];
+    bytes[0] = opcode.to_byte();
+    bytes[2] = flag_bytes.0;
+    bytes[3] = flag_bytes.1;
+    bytes[4] = name_length_bytes.0;
+    bytes[5] = name_length_bytes.1;
+    bytes
+}
+
 impl CommandPrefix for ReadPrefix {
     fn parse_prefix(prefix: [u8; PREFIX_LENGTH]) -> Option<ReadPrefix> {
-        if prefix[0] & 128 != 0 {
-            return None;
-        }
-        let flags: u16 = (prefix[0] as u16) << 8 | (prefix[1] as u16);
-        let file_name_length: u16 = (prefix[2] as u16) << 8 | (prefix[3] as u16);
+        match Opcode::from_prefix(&prefix) {
+            Ok(Opcode::Read) => {}
+            _ => return None,
+        };
+        let (flags, file_name_length) = parse_flags_and_name(&prefix, false);
         Some(ReadPrefix {
             flags,
             file_name_length,
@@ -46,39 +342,27 @@ impl CommandPrefix for ReadPrefix {
     }
 
     fn serialize(&self) -> [u8; PREFIX_LENGTH] {
-        let flag_bytes = extract_bytes_u16(self.flags);
-        let name_length_bytes = extract_bytes_u16(self.file_name_length);
-        [
-            flag_bytes.0,
-            flag_bytes.1,
-            name_length_bytes.0,
-            name_length_bytes.1,
-            0,
-            0,
-            0,
-            0,
-        ]
+        serialize_opcode_header(Opcode::Read, self.flags, self.file_name_length)
     }
 }
 
+/// Layout: the opcode, a reserved byte, 2 bytes of flags, 2 bytes of file name
+/// length, 2 reserved bytes, and then the file length as a big-endian `u64`.
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub struct WritePrefix {
-    pub flags: u16,
+    pub flags: PrefixFlags,
     pub file_name_length: u16,
-    pub file_length: u32,
+    pub file_length: u64,
 }
 
 impl CommandPrefix for WritePrefix {
     fn parse_prefix(prefix: [u8; PREFIX_LENGTH]) -> Option<WritePrefix> {
-        if prefix[0] & 128 == 0 {
-            return None;
-        }
-        let flags: u16 = (prefix[0] as u16) << 8 | (prefix[1] as u16);
-        let file_name_length: u16 = (prefix[2] as u16) << 8 | (prefix[3] as u16);
-        let file_length: u32 = (prefix[4] as u32) << 24
-            | (prefix[5] as u32) << 16
-            | (prefix[6] as u32) << 8
-            | (prefix[7] as u32);
+        match Opcode::from_prefix(&prefix) {
+            Ok(Opcode::Write) => {}
+            _ => return None,
+        };
+        let (flags, file_name_length) = parse_flags_and_name(&prefix, false);
+        let file_length: u64 = combine_bytes_u64(&prefix[8..16]);
         Some(WritePrefix {
             flags,
             file_name_length,
@@ -87,40 +371,559 @@ impl CommandPrefix for WritePrefix {
     }
 
     fn serialize(&self) -> [u8; PREFIX_LENGTH] {
-        let flag_bytes = extract_bytes_u16(self.flags);
-        let name_length_bytes = extract_bytes_u16(self.file_name_length);
-        let file_length_bytes = extract_bytes_u32(self.file_length);
+        let mut bytes = serialize_opcode_header(Opcode::Write, self.flags, self.file_name_length);
+        bytes[8..16].copy_from_slice(&extract_bytes_u64(self.file_length));
+        bytes
+    }
+}
 
-        [
-            flag_bytes.0,
-            flag_bytes.1,
-            name_length_bytes.0,
-            name_length_bytes.1,
-            file_length_bytes.0,
-            file_length_bytes.1,
-            file_length_bytes.2,
-            file_length_bytes.3,
-        ]
+/// Asks how much of a file exists, so that a transfer of it can be resumed.
+///
+/// The server answers with a data frame holding the file's length as a
+/// big-endian `u64`, and then a response carrying the checksum of the first
+/// `length` bytes of the file, or of all of it if it is shorter.
+///
+/// Layout: the opcode, a reserved byte, 2 bytes of flags, 2 bytes of file name
+/// length, 2 reserved bytes, and then `length` as a big-endian `u64`.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct ProbePrefix {
+    pub flags: PrefixFlags,
+    pub file_name_length: u16,
+    pub length: u64,
+}
+
+impl CommandPrefix for ProbePrefix {
+    fn parse_prefix(prefix: [u8; PREFIX_LENGTH]) -> Option<ProbePrefix> {
+        match Opcode::from_prefix(&prefix) {
+            Ok(Opcode::Probe) => {}
+            _ => return None,
+        };
+        let (flags, file_name_length) = parse_flags_and_name(&prefix, false);
+        let length: u64 = combine_bytes_u64(&prefix[8..16]);
+        Some(ProbePrefix {
+            flags,
+            file_name_length,
+            length,
+        })
+    }
+
+    fn serialize(&self) -> [u8; PREFIX_LENGTH] {
+        let mut bytes = serialize_opcode_header(Opcode::Probe, self.flags, self.file_name_length);
+        bytes[8..16].copy_from_slice(&extract_bytes_u64(self.length));
+        bytes
+    }
+}
+
+/// Asks for a page of the entries in a directory, each with its kind, size
+/// and modification time.
+///
+/// The server answers with data frames holding a `listing::ListPage` of at
+/// most `max_entries` entries, sorted by name and starting from entry number
+/// `start`, and then a response carrying the checksum of the page. With the
+/// `RECURSIVE` flag, everything in the subdirectories is listed too, named by
+/// its path inside the directory. The server keeps its place between
+/// commands, so a page that starts where the last one of the same directory
+/// and flags stopped carries on without listing the directory again.
+///
+/// Layout: the opcode, a reserved byte, 2 bytes of flags, 2 bytes of file name
+/// length, 2 reserved bytes, and then `start` and `max_entries` as big-endian
+/// `u32`s.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct ListPrefix {
+    pub flags: PrefixFlags,
+    pub file_name_length: u16,
+    pub start: u32,
+    pub max_entries: u32,
+}
+
+impl CommandPrefix for ListPrefix {
+    fn parse_prefix(prefix: [u8; PREFIX_LENGTH]) -> Option<ListPrefix> {
+        match Opcode::from_prefix(&prefix) {
+            Ok(Opcode::List) => {}
+            _ => return None,
+        };
+        let (flags, file_name_length) = parse_flags_and_name(&prefix, false);
+        Some(ListPrefix {
+            flags,
+            file_name_length,
+            start: combine_bytes_u32(&prefix[8..12]),
+            max_entries: combine_bytes_u32(&prefix[12..16]),
+        })
+    }
+
+    fn serialize(&self) -> [u8; PREFIX_LENGTH] {
+        let mut bytes = serialize_opcode_header(Opcode::List, self.flags, self.file_name_length);
+        bytes[8..12].copy_from_slice(&extract_bytes_u32(self.start));
+        bytes[12..16].copy_from_slice(&extract_bytes_u32(self.max_entries));
+        bytes
+    }
+}
+
+/// Asks whether something exists at a path, and if so what kind of thing it
+/// is, how large it is and when it last changed.
+///
+/// The server answers with a data frame holding a `listing::ListEntry` for
+/// the path, named after its last component, and then a response. If nothing
+/// is there, only the response is sent, with `ResponseCode::NotFound`.
+///
+/// Layout: the opcode, a reserved byte, 2 bytes of flags, 2 bytes of file name
+/// length, and then 10 reserved bytes.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct StatPrefix {
+    pub flags: PrefixFlags,
+    pub file_name_length: u16,
+}
+
+impl CommandPrefix for StatPrefix {
+    fn parse_prefix(prefix: [u8; PREFIX_LENGTH]) -> Option<StatPrefix> {
+        match Opcode::from_prefix(&prefix) {
+            Ok(Opcode::Stat) => {}
+            _ => return None,
+        };
+        let (flags, file_name_length) = parse_flags_and_name(&prefix, false);
+        Some(StatPrefix {
+            flags,
+            file_name_length,
+        })
+    }
+
+    fn serialize(&self) -> [u8; PREFIX_LENGTH] {
+        serialize_opcode_header(Opcode::Stat, self.flags, self.file_name_length)
+    }
+}
+
+/// Creates a directory. With the `CREATE_PARENTS` flag any missing parents
+/// are created too, and a directory that already exists is not an error.
+///
+/// The server answers with a single response.
+///
+/// Layout: the opcode, a reserved byte, 2 bytes of flags, 2 bytes of file name
+/// length, and then 10 reserved bytes.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct MakeDirPrefix {
+    pub flags: PrefixFlags,
+    pub file_name_length: u16,
+}
+
+impl CommandPrefix for MakeDirPrefix {
+    fn parse_prefix(prefix: [u8; PREFIX_LENGTH]) -> Option<MakeDirPrefix> {
+        match Opcode::from_prefix(&prefix) {
+            Ok(Opcode::MakeDir) => {}
+            _ => return None,
+        };
+        let (flags, file_name_length) = parse_flags_and_name(&prefix, false);
+        Some(MakeDirPrefix {
+            flags,
+            file_name_length,
+        })
+    }
+
+    fn serialize(&self) -> [u8; PREFIX_LENGTH] {
+        serialize_opcode_header(Opcode::MakeDir, self.flags, self.file_name_length)
+    }
+}
+
+/// Deletes a path. `Opcode::Remove` deletes a file or link, or with the
+/// `RECURSIVE` flag a directory and everything in it. `Opcode::RemoveDir`
+/// only deletes an empty directory. Links are removed, never followed.
+///
+/// The server answers with data frames holding a `removal::RemovalRecord` for
+/// every entry it tried to delete, and then a response carrying the checksum
+/// of the records. The response is an error if any entry could not be
+/// deleted.
+///
+/// Layout: the opcode, a reserved byte, 2 bytes of flags, 2 bytes of file name
+/// length, and then 10 reserved bytes.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct RemovePrefix {
+    pub flags: PrefixFlags,
+    pub file_name_length: u16,
+    /// Whether this is `Opcode::RemoveDir` rather than `Opcode::Remove`.
+    pub dir: bool,
+}
+
+impl RemovePrefix {
+    pub fn opcode(&self) -> Opcode {
+        if self.dir {
+            Opcode::RemoveDir
+        } else {
+            Opcode::Remove
+        }
+    }
+}
+
+impl CommandPrefix for RemovePrefix {
+    fn parse_prefix(prefix: [u8; PREFIX_LENGTH]) -> Option<RemovePrefix> {
+        let dir = match Opcode::from_prefix(&prefix) {
+            Ok(Opcode::Remove) => false,
+            Ok(Opcode::RemoveDir) => true,
+            _ => return None,
+        };
+        let (flags, file_name_length) = parse_flags_and_name(&prefix, false);
+        Some(RemovePrefix {
+            flags,
+            file_name_length,
+            dir,
+        })
+    }
+
+    fn serialize(&self) -> [u8; PREFIX_LENGTH] {
+        serialize_opcode_header(self.opcode(), self.flags, self.file_name_length)
+    }
+}
+
+/// Moves or renames a path on the server, copying it and deleting the
+/// original if the destination is on another file system. Fails with
+/// `ResponseCode::Exists` if something is already at the destination, unless
+/// the flags have `OVERWRITE`.
+///
+/// The data that follows is the source path and then the destination path.
+/// The server answers with a single response.
+///
+/// Layout: the opcode, a reserved byte, 2 bytes of flags, 2 bytes of source
+/// name length, 2 bytes of destination name length, and then 8 reserved
+/// bytes.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct MovePrefix {
+    pub flags: PrefixFlags,
+    pub file_name_length: u16,
+    pub dest_name_length: u16,
+}
+
+impl CommandPrefix for MovePrefix {
+    fn parse_prefix(prefix: [u8; PREFIX_LENGTH]) -> Option<MovePrefix> {
+        match Opcode::from_prefix(&prefix) {
+            Ok(Opcode::Move) => {}
+            _ => return None,
+        };
+        let (flags, file_name_length) = parse_flags_and_name(&prefix, false);
+        Some(MovePrefix {
+            flags,
+            file_name_length,
+            dest_name_length: (prefix[6] as u16) << 8 | (prefix[7] as u16),
+        })
+    }
+
+    fn serialize(&self) -> [u8; PREFIX_LENGTH] {
+        let mut bytes = serialize_opcode_header(Opcode::Move, self.flags, self.file_name_length);
+        let dest_length_bytes = extract_bytes_u16(self.dest_name_length);
+        bytes[6] = dest_length_bytes.0;
+        bytes[7] = dest_length_bytes.1;
+        bytes
+    }
+}
+
+/// The first byte of every handshake prefix, which doubles as its opcode. It
+/// never changes so that any two builds can read each other's handshakes.
+pub const HANDSHAKE_MARKER: u8 = 0x7F;
+
+/// The first prefix sent on a new connection, used by both sides to describe
+/// themselves. The client sends its own and the server replies with one in a
+/// prefix frame of its own.
+///
+/// All of its fields live in the first 8 bytes so that builds with different
+/// prefix lengths can still read each other's handshakes.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct HandshakePrefix {
+    pub protocol_version: u8,
+    pub build_version: u16,
+    /// The largest frame payload the sender will send or accept.
+    pub max_frame_payload: u16,
+    pub features: u16,
+}
+
+impl HandshakePrefix {
+    /// Checks whether the sender of this handshake supports all of the given
+    /// feature bits.
+    pub fn supports(&self, features: u16) -> bool {
+        self.features & features == features
+    }
+}
+
+impl CommandPrefix for HandshakePrefix {
+    fn parse_prefix(prefix: [u8; PREFIX_LENGTH]) -> Option<HandshakePrefix> {
+        if prefix[0] != HANDSHAKE_MARKER {
+            return None;
+        }
+        let protocol_version = prefix[1];
+        let build_version: u16 = (prefix[2] as u16) << 8 | (prefix[3] as u16);
+        let max_frame_payload: u16 = (prefix[4] as u16) << 8 | (prefix[5] as u16);
+        let features: u16 = (prefix[6] as u16) << 8 | (prefix[7] as u16);
+        Some(HandshakePrefix {
+            protocol_version,
+            build_version,
+            max_frame_payload,
+            features,
+        })
+    }
+
+    fn serialize(&self) -> [u8; PREFIX_LENGTH] {
+        let build_bytes = extract_bytes_u16(self.build_version);
+        let payload_bytes = extract_bytes_u16(self.max_frame_payload);
+        let feature_bytes = extract_bytes_u16(self.features);
+        let mut bytes = [0u8; PREFIX_LENGTH];
+        bytes[0] = HANDSHAKE_MARKER;
+        bytes[1] = self.protocol_version;
+        bytes[2] = build_bytes.0;
+        bytes[3] = build_bytes.1;
+        bytes[4] = payload_bytes.0;
+        bytes[5] = payload_bytes.1;
+        bytes[6] = feature_bytes.0;
+        bytes[7] = feature_bytes.1;
+        bytes
+    }
+}
+
+/// Asks how large the filesystem holding a path is, how much of it is free,
+/// its cluster size and whether it is FAT32 or exFAT.
+///
+/// The server answers with a data frame holding a `fsinfo::FsInfo`, and then
+/// a response. If the path cannot be looked up, only the response is sent.
+///
+/// Layout: the opcode, a reserved byte, 2 bytes of flags, 2 bytes of file name
+/// length, and then 10 reserved bytes.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct FsInfoPrefix {
+    pub flags: PrefixFlags,
+    pub file_name_length: u16,
+}
+
+impl CommandPrefix for FsInfoPrefix {
+    fn parse_prefix(prefix: [u8; PREFIX_LENGTH]) -> Option<FsInfoPrefix> {
+        match Opcode::from_prefix(&prefix) {
+            Ok(Opcode::FsInfo) => {}
+            _ => return None,
+        };
+        let (flags, file_name_length) = parse_flags_and_name(&prefix, false);
+        Some(FsInfoPrefix {
+            flags,
+            file_name_length,
+        })
+    }
+
+    fn serialize(&self) -> [u8; PREFIX_LENGTH] {
+        serialize_opcode_header(Opcode::FsInfo, self.flags, self.file_name_length)
+    }
+}
+
+/// Asks for the block signature of a file, so that the client can send it a
+/// delta instead of the whole of a new version.
+///
+/// The server answers with data frames holding a `delta::Signature` of the
+/// file in blocks of `block_size` bytes, with strong digests of the checksum
+/// kind in the flags, and then a response carrying the checksum of the
+/// signature. If the file cannot be read, only the response is sent.
+///
+/// Layout: the opcode, a reserved byte, 2 bytes of flags, 2 bytes of file name
+/// length, 2 reserved bytes, `block_size` as a big-endian `u32`, and then 4
+/// reserved bytes.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct SignaturePrefix {
+    pub flags: PrefixFlags,
+    pub file_name_length: u16,
+    pub block_size: u32,
+}
+
+impl CommandPrefix for SignaturePrefix {
+    fn parse_prefix(prefix: [u8; PREFIX_LENGTH]) -> Option<SignaturePrefix> {
+        match Opcode::from_prefix(&prefix) {
+            Ok(Opcode::Signature) => {}
+            _ => return None,
+        };
+        let (flags, file_name_length) = parse_flags_and_name(&prefix, false);
+        Some(SignaturePrefix {
+            flags,
+            file_name_length,
+            block_size: combine_bytes_u32(&prefix[8..12]),
+        })
+    }
+
+    fn serialize(&self) -> [u8; PREFIX_LENGTH] {
+        let mut bytes = serialize_opcode_header(Opcode::Signature, self.flags, self.file_name_length);
+        bytes[8..12].copy_from_slice(&extract_bytes_u32(self.block_size));
+        bytes
+    }
+}
+
+/// Reads a file as a delta against the signature of the client's old copy
+/// of it.
+///
+/// The data that follows is the file name and then `signature_length` bytes
+/// of `delta::Signature`, with strong digests of the checksum kind in the
+/// flags. The server answers with data frames holding the
+/// `delta::DELTA_HEADER_LENGTH` header and then the delta stream, and then a
+/// response carrying the checksum of the whole file. While it works out the
+/// delta of a large file the server may send empty data frames. If the file
+/// cannot be read, only the response is sent.
+///
+/// Layout: the opcode, a reserved byte, 2 bytes of flags, 2 bytes of file name
+/// length, 2 reserved bytes, and then `signature_length` as a big-endian
+/// `u64`.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct ReadDeltaPrefix {
+    pub flags: PrefixFlags,
+    pub file_name_length: u16,
+    pub signature_length: u64,
+}
+
+impl CommandPrefix for ReadDeltaPrefix {
+    fn parse_prefix(prefix: [u8; PREFIX_LENGTH]) -> Option<ReadDeltaPrefix> {
+        match Opcode::from_prefix(&prefix) {
+            Ok(Opcode::ReadDelta) => {}
+            _ => return None,
+        };
+        let (flags, file_name_length) = parse_flags_and_name(&prefix, false);
+        Some(ReadDeltaPrefix {
+            flags,
+            file_name_length,
+            signature_length: combine_bytes_u64(&prefix[8..16]),
+        })
+    }
+
+    fn serialize(&self) -> [u8; PREFIX_LENGTH] {
+        let mut bytes = serialize_opcode_header(Opcode::ReadDelta, self.flags, self.file_name_length);
+        bytes[8..16].copy_from_slice(&extract_bytes_u64(self.signature_length));
+        bytes
+    }
+}
+
+/// Hashes a file, or with the `RECURSIVE` flag every file under a directory,
+/// on the server, with the checksum kind in the flags, without sending the
+/// content.
+///
+/// The server answers with data frames holding a `hashing::HashRecord` for
+/// each file, and then a response carrying the checksum of the records. The
+/// response is an error if any file could not be hashed. While a large file
+/// is being hashed the server sends empty data frames, so that the client
+/// knows it is still working.
+///
+/// Layout: the opcode, a reserved byte, 2 bytes of flags, 2 bytes of file name
+/// length, and then 10 reserved bytes.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct HashPrefix {
+    pub flags: PrefixFlags,
+    pub file_name_length: u16,
+}
+
+impl CommandPrefix for HashPrefix {
+    fn parse_prefix(prefix: [u8; PREFIX_LENGTH]) -> Option<HashPrefix> {
+        match Opcode::from_prefix(&prefix) {
+            Ok(Opcode::Hash) => {}
+            _ => return None,
+        };
+        let (flags, file_name_length) = parse_flags_and_name(&prefix, false);
+        Some(HashPrefix {
+            flags,
+            file_name_length,
+        })
+    }
+
+    fn serialize(&self) -> [u8; PREFIX_LENGTH] {
+        serialize_opcode_header(Opcode::Hash, self.flags, self.file_name_length)
     }
 }
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub enum Prefixes {
+    Handshake(HandshakePrefix),
     Write(WritePrefix),
     Read(ReadPrefix),
+    Probe(ProbePrefix),
+    List(ListPrefix),
+    Stat(StatPrefix),
+    MakeDir(MakeDirPrefix),
+    Remove(RemovePrefix),
+    Move(MovePrefix),
+    FsInfo(FsInfoPrefix),
+    Signature(SignaturePrefix),
+    ReadDelta(ReadDeltaPrefix),
+    Hash(HashPrefix),
+}
+
+impl Prefixes {
+    /// Parses a prefix of any command, explaining why if it can't.
+    pub fn decode(prefix: [u8; PREFIX_LENGTH]) -> Result<Prefixes, String> {
+        let parsed = match Opcode::from_prefix(&prefix)? {
+            Opcode::Handshake => HandshakePrefix::parse_prefix(prefix).map(Prefixes::Handshake),
+            Opcode::Read => ReadPrefix::parse_prefix(prefix).map(Prefixes::Read),
+            Opcode::Write => WritePrefix::parse_prefix(prefix).map(Prefixes::Write),
+            Opcode::Probe => ProbePrefix::parse_prefix(prefix).map(Prefixes::Probe),
+            Opcode::List => ListPrefix::parse_prefix(prefix).map(Prefixes::List),
+            Opcode::Stat => StatPrefix::parse_prefix(prefix).map(Prefixes::Stat),
+            Opcode::MakeDir => MakeDirPrefix::parse_prefix(prefix).map(Prefixes::MakeDir),
+            Opcode::Remove | Opcode::RemoveDir => RemovePrefix::parse_prefix(prefix).map(Prefixes::Remove),
+            Opcode::Move => MovePrefix::parse_prefix(prefix).map(Prefixes::Move),
+            Opcode::FsInfo => FsInfoPrefix::parse_prefix(prefix).map(Prefixes::FsInfo),
+            Opcode::Signature => SignaturePrefix::parse_prefix(prefix).map(Prefixes::Signature),
+            Opcode::ReadDelta => ReadDeltaPrefix::parse_prefix(prefix).map(Prefixes::ReadDelta),
+            Opcode::Hash => HashPrefix::parse_prefix(prefix).map(Prefixes::Hash),
+        };
+        parsed.ok_or(format!("Could not parse prefix bytes {:?}", prefix))
+    }
+
+    /// Parses a prefix from a client that negotiated a protocol version from
+    /// before opcodes, which only sends handshakes and reads and writes in the
+    /// legacy layout.
+    pub fn decode_legacy(prefix: [u8; PREFIX_LENGTH]) -> Result<Prefixes, String> {
+        if prefix[0] == HANDSHAKE_MARKER {
+            return HandshakePrefix::parse_prefix(prefix)
+                .map(Prefixes::Handshake)
+                .ok_or(format!("Could not parse prefix bytes {:?}", prefix));
+        }
+        let (flags, file_name_length) = parse_flags_and_name(&prefix, true);
+        let parsed = match Opcode::from_legacy_byte(prefix[0]) {
+            Opcode::Write => Prefixes::Write(WritePrefix {
+                flags,
+                file_name_length,
+                file_length: combine_bytes_u64(&prefix[8..16]),
+            }),
+            _ => Prefixes::Read(ReadPrefix {
+                flags,
+                file_name_length,
+            }),
+        };
+        Ok(parsed)
+    }
+
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            Prefixes::Handshake(_) => Opcode::Handshake,
+            Prefixes::Read(_) => Opcode::Read,
+            Prefixes::Write(_) => Opcode::Write,
+            Prefixes::Probe(_) => Opcode::Probe,
+            Prefixes::List(_) => Opcode::List,
+            Prefixes::Stat(_) => Opcode::Stat,
+            Prefixes::MakeDir(_) => Opcode::MakeDir,
+            Prefixes::Remove(r) => r.opcode(),
+            Prefixes::Move(_) => Opcode::Move,
+            Prefixes::FsInfo(_) => Opcode::FsInfo,
+            Prefixes::Signature(_) => Opcode::Signature,
+            Prefixes::ReadDelta(_) => Opcode::ReadDelta,
+            Prefixes::Hash(_) => Opcode::Hash,
+        }
+    }
 }
 
 impl CommandPrefix for Prefixes {
     fn parse_prefix(prefix: [u8; PREFIX_LENGTH]) -> Option<Prefixes> {
-        WritePrefix::parse_prefix(prefix)
-            .map(|w| Prefixes::Write(w))
-            .or(ReadPrefix::parse_prefix(prefix).map(|r| Prefixes::Read(r)))
+        Prefixes::decode(prefix).ok()
     }
 
     fn serialize(&self) -> [u8; PREFIX_LENGTH] {
         match self {
+            Prefixes::Handshake(h) => h.serialize(),
             Prefixes::Write(w) => w.serialize(),
             Prefixes::Read(r) => r.serialize(),
+            Prefixes::Probe(p) => p.serialize(),
+            Prefixes::List(l) => l.serialize(),
+            Prefixes::Stat(s) => s.serialize(),
+            Prefixes::MakeDir(m) => m.serialize(),
+            Prefixes::Remove(r) => r.serialize(),
+            Prefixes::Move(m) => m.serialize(),
+            Prefixes::FsInfo(f) => f.serialize(),
+            Prefixes::Signature(s) => s.serialize(),
+            Prefixes::ReadDelta(r) => r.serialize(),
+            Prefixes::Hash(h) => h.serialize(),
         }
     }
 }
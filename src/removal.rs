@@ -0,0 +1,96 @@
+use listing::EntryKind;
+use response::ResponseCode;
+
+/// The length of each record before its path and message: the code byte, the
+/// kind byte, and then the path length and message length as big-endian
+/// `u16`s.
+pub const RECORD_HEADER_LENGTH: usize = 6; //Bytes
+
+/// What happened to one thing a remove command tried to delete.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct RemovalRecord {
+    /// The full path of the entry on the server.
+    pub path: String,
+    pub kind: EntryKind,
+    /// `ResponseCode::Ok` if the entry was removed, or why it was not.
+    pub code: ResponseCode,
+    /// Explains the failure, and is empty if the entry was removed.
+    pub message: String,
+}
+
+impl RemovalRecord {
+    pub fn removed(path: String, kind: EntryKind) -> RemovalRecord {
+        RemovalRecord {
+            path,
+            kind,
+            code: ResponseCode::Ok,
+            message: String::new(),
+        }
+    }
+
+    pub fn failed(path: String, kind: EntryKind, code: ResponseCode, message: String) -> RemovalRecord {
+        RemovalRecord {
+            path,
+            kind,
+            code,
+            message,
+        }
+    }
+
+    pub fn is_removed(&self) -> bool {
+        self.code == ResponseCode::Ok
+    }
+
+    pub fn serialize_into(&self, buffer: &mut Vec<u8>) {
+        let path = self.path.as_bytes();
+        let path_len = path.len().min(u16::MAX as usize);
+        let message = self.message.as_bytes();
+        let message_len = message.len().min(u16::MAX as usize);
+        buffer.push(self.code.to_byte());
+        buffer.push(self.kind.to_byte());
+        buffer.push(((path_len & 0xFF00) >> 8) as u8);
+        buffer.push((path_len & 0xFF) as u8);
+        buffer.push(((message_len & 0xFF00) >> 8) as u8);
+        buffer.push((message_len & 0xFF) as u8);
+        buffer.extend_from_slice(&path[0..path_len]);
+        buffer.extend_from_slice(&message[0..message_len]);
+    }
+
+    /// Parses a record from the start of the buffer, returning it and the
+    /// number of bytes it took up.
+    pub fn parse(buffer: &[u8]) -> Result<(RemovalRecord, usize), String> {
+        if buffer.len() < RECORD_HEADER_LENGTH {
+            return Err("Removal report ended partway through a record header.".to_owned());
+        }
+        let kind = EntryKind::from_byte(buffer[1]).ok_or(format!("Unknown entry kind {}.", buffer[1]))?;
+        let path_len = (buffer[2] as usize) << 8 | (buffer[3] as usize);
+        let message_len = (buffer[4] as usize) << 8 | (buffer[5] as usize);
+        let path_end = RECORD_HEADER_LENGTH + path_len;
+        let end = path_end + message_len;
+        if buffer.len() < end {
+            return Err("Removal report ended partway through a record.".to_owned());
+        }
+        let path = String::from_utf8(buffer[RECORD_HEADER_LENGTH..path_end].to_vec())
+            .map_err(|e| format!("UTF8 Error: {:?}", e))?;
+        let message = String::from_utf8_lossy(&buffer[path_end..end]).into_owned();
+        let record = RemovalRecord {
+            path,
+            kind,
+            code: ResponseCode::from_byte(buffer[0]),
+            message,
+        };
+        Ok((record, end))
+    }
+}
+
+/// Parses every record in a removal report.
+pub fn parse_records(buffer: &[u8]) -> Result<Vec<RemovalRecord>, String> {
+    let mut records = Vec::new();
+    let mut idx = 0;
+    while idx < buffer.len() {
+        let (record, used) = RemovalRecord::parse(&buffer[idx..])?;
+        records.push(record);
+        idx += used;
+    }
+    Ok(records)
+}
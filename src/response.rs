@@ -0,0 +1,219 @@
+use std::fmt;
+use std::io;
+
+/// The first byte of every serialized response, used to catch a client and
+/// server that have fallen out of step.
+pub const RESPONSE_MARKER: u8 = b'R';
+
+/// The number of bytes in a response before its checksum and message.
+pub const RESPONSE_HEADER_LENGTH: usize = 5;
+
+/// The outcome of a command, as reported by the server.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum ResponseCode {
+    Ok,
+    NotFound,
+    Exists,
+    Permission,
+    NoSpace,
+    InvalidInput,
+    Io,
+    Protocol,
+    ChecksumMismatch,
+    /// The command was stopped by an abort frame.
+    Aborted,
+    Unknown,
+}
+
+impl ResponseCode {
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            ResponseCode::Ok => 0,
+            ResponseCode::NotFound => 1,
+            ResponseCode::Exists => 2,
+            ResponseCode::Permission => 3,
+            ResponseCode::NoSpace => 4,
+            ResponseCode::InvalidInput => 5,
+            ResponseCode::Io => 6,
+            ResponseCode::Protocol => 7,
+            ResponseCode::ChecksumMismatch => 8,
+            ResponseCode::Aborted => 9,
+            ResponseCode::Unknown => 255,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> ResponseCode {
+        match byte {
+            0 => ResponseCode::Ok,
+            1 => ResponseCode::NotFound,
+            2 => ResponseCode::Exists,
+            3 => ResponseCode::Permission,
+            4 => ResponseCode::NoSpace,
+            5 => ResponseCode::InvalidInput,
+            6 => ResponseCode::Io,
+            7 => ResponseCode::Protocol,
+            8 => ResponseCode::ChecksumMismatch,
+            9 => ResponseCode::Aborted,
+            _ => ResponseCode::Unknown,
+        }
+    }
+
+    /// Maps an IO error to the closest response code.
+    pub fn from_io_error(err: &io::Error) -> ResponseCode {
+        // ENOSPC has the same value in both newlib and glibc.
+        const ENOSPC: i32 = 28;
+        match err.kind() {
+            io::ErrorKind::NotFound => ResponseCode::NotFound,
+            io::ErrorKind::AlreadyExists => ResponseCode::Exists,
+            io::ErrorKind::PermissionDenied => ResponseCode::Permission,
+            io::ErrorKind::InvalidInput => ResponseCode::InvalidInput,
+            _ if err.raw_os_error() == Some(ENOSPC) => ResponseCode::NoSpace,
+            _ => ResponseCode::Io,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            ResponseCode::Ok => "ok",
+            ResponseCode::NotFound => "not found",
+            ResponseCode::Exists => "already exists",
+            ResponseCode::Permission => "permission denied",
+            ResponseCode::NoSpace => "no space left on device",
+            ResponseCode::InvalidInput => "invalid input",
+            ResponseCode::Io => "io error",
+            ResponseCode::Protocol => "protocol error",
+            ResponseCode::ChecksumMismatch => "checksum mismatch",
+            ResponseCode::Aborted => "aborted",
+            ResponseCode::Unknown => "unknown error",
+        }
+    }
+}
+
+/// A status sent by the server at the end of every command.
+///
+/// Layout: the `RESPONSE_MARKER` byte, the code byte, the message length as a
+/// big-endian `u16`, the checksum length byte, the checksum of the
+/// transferred content (if any), and then the UTF-8 message.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Response {
+    pub code: ResponseCode,
+    pub message: String,
+    pub checksum: Vec<u8>,
+}
+
+impl Response {
+    pub fn ok() -> Response {
+        Response {
+            code: ResponseCode::Ok,
+            message: String::new(),
+            checksum: Vec::new(),
+        }
+    }
+
+    pub fn error(code: ResponseCode, message: String) -> Response {
+        Response {
+            code,
+            message,
+            checksum: Vec::new(),
+        }
+    }
+
+    /// Attaches the server's checksum of the transferred content.
+    pub fn with_checksum(mut self, checksum: Vec<u8>) -> Response {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Builds an error response from an IO error, prefixing its message with
+    /// the given context.
+    pub fn from_io_error(context: &str, err: &io::Error) -> Response {
+        Response::error(ResponseCode::from_io_error(err), format!("{}: {}", context, err))
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.code == ResponseCode::Ok
+    }
+
+    /// Converts this response into a `Result`, with error responses becoming
+    /// their display string.
+    pub fn into_result(self) -> Result<(), String> {
+        if self.is_ok() {
+            Ok(())
+        } else {
+            Err(format!("Server error: {}", self))
+        }
+    }
+
+    /// Writes this response to the start of the buffer, truncating the message
+    /// if it does not fit, and returns the number of bytes written.
+    pub fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, String> {
+        let checksum_len = self.checksum.len();
+        if buffer.len() < RESPONSE_HEADER_LENGTH + checksum_len || checksum_len > 0xFF {
+            return Err(format!(
+                "Buffer of {} bytes is too small for a response.",
+                buffer.len()
+            ));
+        }
+        let message_start = RESPONSE_HEADER_LENGTH + checksum_len;
+        let message = self.message.as_bytes();
+        let message_len = message
+            .len()
+            .min(buffer.len() - message_start)
+            .min(u16::MAX as usize);
+        buffer[0] = RESPONSE_MARKER;
+        buffer[1] = self.code.to_byte();
+        buffer[2] = ((message_len & 0xFF00) >> 8) as u8;
+        buffer[3] = (message_len & 0xFF) as u8;
+        buffer[4] = checksum_len as u8;
+        buffer[RESPONSE_HEADER_LENGTH..message_start].copy_from_slice(&self.checksum);
+        buffer[message_start..message_start + message_len]
+            .copy_from_slice(&message[0..message_len]);
+        Ok(message_start + message_len)
+    }
+
+    /// Serializes this response into a buffer of exactly the right size.
+    pub fn serialize(&self) -> Vec<u8> {
+        let message_len = self.message.len().min(u16::MAX as usize);
+        let mut buffer = vec![0u8; RESPONSE_HEADER_LENGTH + self.checksum.len() + message_len];
+        let written = self.serialize_into(&mut buffer).unwrap_or(0);
+        buffer.truncate(written);
+        buffer
+    }
+
+    /// Parses a response from the start of the buffer.
+    pub fn parse(buffer: &[u8]) -> Result<Response, String> {
+        if buffer.len() < RESPONSE_HEADER_LENGTH || buffer[0] != RESPONSE_MARKER {
+            return Err(format!(
+                "Expected a response from the server but got bytes {:?}.",
+                &buffer[0..buffer.len().min(RESPONSE_HEADER_LENGTH)]
+            ));
+        }
+        let code = ResponseCode::from_byte(buffer[1]);
+        let message_len = (buffer[2] as usize) << 8 | (buffer[3] as usize);
+        let message_start = (RESPONSE_HEADER_LENGTH + buffer[4] as usize).min(buffer.len());
+        let checksum = buffer[RESPONSE_HEADER_LENGTH..message_start].to_vec();
+        let message_end = (message_start + message_len).min(buffer.len());
+        let message = String::from_utf8_lossy(&buffer[message_start..message_end]).into_owned();
+        Ok(Response {
+            code,
+            message,
+            checksum,
+        })
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.message.is_empty() {
+            write!(f, "{}", self.code.description())
+        } else {
+            write!(f, "{} ({})", self.code.description(), self.message)
+        }
+    }
+}
+
+impl From<String> for Response {
+    fn from(message: String) -> Response {
+        Response::error(ResponseCode::Unknown, message)
+    }
+}